@@ -0,0 +1,277 @@
+// Auto-save and crash recovery via swap files
+use crate::{
+    cbs,
+    settings::{self, AutoSave},
+    state::{MyBuffer, State, STATE},
+    utils,
+};
+use fltk::{app, dialog};
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+const SWAP_HEADER: &str = "RED-SWAP";
+// Delay after the last edit before a buffer's swap file is written
+const SWAP_DELAY: f64 = 1.0;
+
+static SAVING: AtomicBool = AtomicBool::new(false);
+
+thread_local! {
+    // Buffers whose auto-save failed, reported once until they save again
+    static FAILED: RefCell<HashSet<usize>> = RefCell::new(HashSet::new());
+}
+
+fn swap_dir() -> Option<PathBuf> {
+    let dir = utils::data_dir()?.join("swap");
+    fs::create_dir_all(&dir).ok()?;
+    Some(dir)
+}
+
+fn swap_path(mybuf: &MyBuffer) -> Option<PathBuf> {
+    Some(swap_dir()?.join(format!("{}-{}.swp", std::process::id(), mybuf.id)))
+}
+
+pub fn write_swap(mybuf: &MyBuffer) {
+    if let Some(p) = swap_path(mybuf) {
+        let file = mybuf
+            .current_file
+            .as_ref()
+            .map(|f| f.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let contents = format!("{}\n{}\n{}", SWAP_HEADER, file, mybuf.buf.text());
+        fs::write(p, contents).ok();
+    }
+}
+
+pub fn remove_swap(mybuf: &MyBuffer) {
+    if let Some(p) = swap_path(mybuf) {
+        if p.exists() {
+            fs::remove_file(p).ok();
+        }
+    }
+}
+
+// Removes the swap files of this process, called on a clean exit
+pub fn remove_all_swaps() {
+    STATE.with(|s| {
+        for v in s.map.values() {
+            remove_swap(v);
+        }
+    });
+}
+
+// Called from the editor callback, writes the swap file once editing pauses
+pub fn schedule_swap(id: usize, seq: u64) {
+    app::add_timeout3(SWAP_DELAY, move |_| {
+        STATE.with(move |s| {
+            if let Some(mb) = s.map.get(&id) {
+                if mb.change_seq == seq && mb.modified {
+                    write_swap(mb);
+                }
+            }
+        });
+    });
+}
+
+fn save_all_modified() {
+    if SAVING.swap(true, Ordering::Relaxed) {
+        return;
+    }
+    let ids: Vec<usize> = STATE.with(|s| {
+        s.map
+            .iter()
+            .filter(|(_, v)| v.modified && v.current_file.is_some())
+            .map(|(k, _)| *k)
+            .collect()
+    });
+    for id in ids {
+        if let Err(e) = cbs::autosave_buffer(id) {
+            if FAILED.with(|f| f.borrow_mut().insert(id)) {
+                let name = STATE.with(move |s| s.map.get(&id).map(|b| b.name()));
+                dialog::alert_default(&format!(
+                    "Auto-save failed for {}:\n{}\n\nThe file on disk was left unchanged.",
+                    name.unwrap_or_default(),
+                    e
+                ));
+            }
+        }
+    }
+    SAVING.store(false, Ordering::Relaxed);
+}
+
+// Called when a buffer was saved, a later auto-save failure is reported again
+pub fn clear_failure(id: usize) {
+    FAILED.with(|f| f.borrow_mut().remove(&id));
+}
+
+// Call once at startup to drive interval based auto-save
+pub fn init() {
+    fn schedule(delay: f64) {
+        app::add_timeout3(delay, |_| {
+            let s = settings::get();
+            if s.autosave == AutoSave::Interval {
+                save_all_modified();
+            }
+            schedule(s.autosave_interval.max(1) as f64);
+        });
+    }
+    schedule(settings::get().autosave_interval.max(1) as f64);
+}
+
+pub fn on_focus_loss() {
+    if settings::get().autosave == AutoSave::FocusLoss {
+        // Deferred so that the save doesn't run inside the widget's event handler
+        app::add_timeout3(0.0, |_| save_all_modified());
+    }
+}
+
+pub fn on_tab_switch() {
+    if settings::get().autosave == AutoSave::TabSwitch {
+        save_all_modified();
+    }
+}
+
+struct Swap {
+    path: PathBuf,
+    file: Option<PathBuf>,
+    contents: String,
+}
+
+// Whether the instance that wrote a swap file still runs. When that can't be
+// told, it's taken as running so that its swap files are left alone.
+fn owner_alive(pid: u32) -> bool {
+    pid == std::process::id() || process_alive(pid)
+}
+
+#[cfg(unix)]
+fn process_alive(pid: u32) -> bool {
+    extern "C" {
+        fn kill(pid: i32, sig: i32) -> i32;
+    }
+    // 0 and negative pids signal process groups
+    if pid == 0 || pid > i32::MAX as u32 {
+        return true;
+    }
+    // signal 0 only checks the process, EPERM means it runs as another user
+    let sent = unsafe { kill(pid as i32, 0) } == 0;
+    sent || std::io::Error::last_os_error().kind() == std::io::ErrorKind::PermissionDenied
+}
+
+#[cfg(windows)]
+fn process_alive(pid: u32) -> bool {
+    use std::ffi::c_void;
+    const PROCESS_QUERY_LIMITED_INFORMATION: u32 = 0x1000;
+    const STILL_ACTIVE: u32 = 259;
+    extern "system" {
+        fn OpenProcess(access: u32, inherit: i32, pid: u32) -> *mut c_void;
+        fn GetExitCodeProcess(process: *mut c_void, code: *mut u32) -> i32;
+        fn CloseHandle(handle: *mut c_void) -> i32;
+    }
+    unsafe {
+        let h = OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, 0, pid);
+        if h.is_null() {
+            // no such process, unless it's just not ours to open
+            return std::io::Error::last_os_error().kind() == std::io::ErrorKind::PermissionDenied;
+        }
+        let mut code = 0;
+        let ok = GetExitCodeProcess(h, &mut code);
+        CloseHandle(h);
+        ok == 0 || code == STILL_ACTIVE
+    }
+}
+
+#[cfg(not(any(unix, windows)))]
+fn process_alive(_pid: u32) -> bool {
+    true
+}
+
+// Swap files left behind by instances that didn't exit cleanly
+fn orphaned_swaps() -> Vec<Swap> {
+    let mut v = Vec::new();
+    let dir = match swap_dir() {
+        Some(d) => d,
+        None => return v,
+    };
+    let entries = match fs::read_dir(dir) {
+        Ok(e) => e,
+        Err(_) => return v,
+    };
+    for e in entries.flatten() {
+        let path = e.path();
+        if path.extension().and_then(|x| x.to_str()) != Some("swp") {
+            continue;
+        }
+        let pid = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .and_then(|n| n.split('-').next())
+            .and_then(|n| n.parse::<u32>().ok());
+        if pid.map(owner_alive).unwrap_or(true) {
+            continue;
+        }
+        if let Ok(txt) = fs::read_to_string(&path) {
+            let mut parts = txt.splitn(3, '\n');
+            if parts.next() != Some(SWAP_HEADER) {
+                continue;
+            }
+            let file = parts.next().filter(|f| !f.is_empty()).map(PathBuf::from);
+            let contents = parts.next().unwrap_or_default().to_string();
+            v.push(Swap {
+                path,
+                file,
+                contents,
+            });
+        }
+    }
+    v
+}
+
+// Offers to restore unsaved edits from a previous crash, called from init_state
+pub fn recover(state: &mut State) {
+    let swaps = orphaned_swaps();
+    if swaps.is_empty() {
+        return;
+    }
+    let mut msg = String::from("Unsaved changes from a previous session were found:\n");
+    for s in &swaps {
+        let name = s
+            .file
+            .as_ref()
+            .map(|f| f.display().to_string())
+            .unwrap_or_else(|| "untitled".to_string());
+        msg.push_str(&format!("\n    {}", name));
+    }
+    match dialog::choice2_default(&msg, "Later", "Recover", "Discard") {
+        Some(1) => {
+            for s in &swaps {
+                let existing = s.file.clone().filter(|f| f.exists());
+                let id = match state.append(existing.clone()) {
+                    Ok(id) => id,
+                    // kept for the next start
+                    Err(_) => continue,
+                };
+                if let Some(mut buf) = state.map.get(&id).map(|mb| mb.buf.clone()) {
+                    buf.set_text(&s.contents);
+                }
+                // a file that's gone is bound like after Save as, with its
+                // tab label, watcher and highlighting
+                if let (None, Some(file)) = (existing, s.file.clone()) {
+                    state.rebind(id, file);
+                }
+                state.set_modified(id, true);
+                if let Some(mb) = state.map.get(&id) {
+                    write_swap(mb);
+                }
+                fs::remove_file(&s.path).ok();
+            }
+        }
+        Some(2) => {
+            for s in &swaps {
+                fs::remove_file(&s.path).ok();
+            }
+        }
+        _ => (),
+    }
+}
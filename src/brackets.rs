@@ -0,0 +1,448 @@
+// Bracket pairs: the bracket matching the one next to the caret is outlined,
+// typing an opening bracket or quote inserts the closing one as well (or wraps
+// the selection in the pair) and typing the closing one steps over it. The
+// pairs come from the language (see highlight), brackets and quotes in
+// strings and comments don't pair with the code around them.
+use crate::{state::STATE, utils};
+use fltk::{draw, enums::*, prelude::*, text};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+// Files without a language
+const DEFAULT_PAIRS: &[(char, char)] =
+    &[('(', ')'), ('[', ']'), ('{', '}'), ('"', '"'), ('\'', '\'')];
+
+type Literals = Option<Rc<Vec<Range<usize>>>>;
+
+// The text of a buffer and its literal ranges, for a buffer generation and path
+struct Cached {
+    gen: u64,
+    path: Option<PathBuf>,
+    text: Rc<String>,
+    literals: Literals,
+}
+
+thread_local! {
+    // The last buffer looked at, until its text changes
+    static CACHED: RefCell<Option<Cached>> = const { RefCell::new(None) };
+    // The pair outlined in every view, in the document, the view is redrawn
+    // when it changes
+    static SHOWN: RefCell<HashMap<usize, (usize, usize)>> = RefCell::new(HashMap::new());
+}
+
+fn view_of(ed: &text::TextEditor) -> usize {
+    ed.as_widget_ptr() as usize
+}
+
+fn path_of(ed: &text::TextEditor) -> Option<PathBuf> {
+    let view = view_of(ed);
+    STATE.with(move |s| {
+        s.map
+            .get(&s.doc_id(view))
+            .and_then(|d| d.current_file.clone())
+    })
+}
+
+fn pairs(_path: Option<&Path>) -> &'static [(char, char)] {
+    #[cfg(feature = "highlight")]
+    if let Some(p) = _path.and_then(crate::highlight::pairs) {
+        return p;
+    }
+    DEFAULT_PAIRS
+}
+
+// The text of the buffer and its strings and comments, None without a syntax
+// tree. Copied and parsed again only after an edit.
+fn contents(buf: &text::TextBuffer, path: Option<&Path>) -> (Rc<String>, Literals) {
+    let gen = utils::buffer_generation(buf);
+    let hit = CACHED.with(|c| {
+        c.borrow()
+            .as_ref()
+            .filter(|c| c.gen == gen && c.path.as_deref() == path)
+            .map(|c| (c.text.clone(), c.literals.clone()))
+    });
+    if let Some(hit) = hit {
+        return hit;
+    }
+    let text = Rc::new(buf.text());
+    #[cfg(feature = "highlight")]
+    let literals = path
+        .and_then(|p| crate::highlight::literal_ranges(p, buf, &text))
+        .map(Rc::new);
+    #[cfg(not(feature = "highlight"))]
+    let literals = None;
+    CACHED.with(|c| {
+        *c.borrow_mut() = Some(Cached {
+            gen,
+            path: path.map(Path::to_path_buf),
+            text: text.clone(),
+            literals: literals.clone(),
+        })
+    });
+    (text, literals)
+}
+
+fn literal_at(literals: &[Range<usize>], pos: usize) -> Option<Range<usize>> {
+    let i = literals.partition_point(|r| r.end <= pos);
+    literals.get(i).filter(|r| r.start <= pos).cloned()
+}
+
+// Scans from the bracket at `at` for the one closing (or opening) it. A bracket
+// in a string or comment pairs within it, one in code skips them. Brackets are
+// ASCII, so the text is scanned by bytes.
+fn find_match(
+    text: &[u8],
+    at: usize,
+    other: u8,
+    forward: bool,
+    literals: &[Range<usize>],
+) -> Option<usize> {
+    let first = text[at];
+    let within = literal_at(literals, at);
+    let bounds = within.clone().unwrap_or(0..text.len());
+    let mut depth = 0;
+    let mut p = at;
+    loop {
+        let skip = match within {
+            None if p != at => literal_at(literals, p),
+            _ => None,
+        };
+        if let Some(r) = skip {
+            p = if forward {
+                r.end
+            } else {
+                r.start.checked_sub(1)?
+            };
+        } else {
+            if text[p] == first {
+                depth += 1;
+            } else if text[p] == other {
+                depth -= 1;
+            }
+            if depth == 0 {
+                return Some(p);
+            }
+            p = if forward { p + 1 } else { p.checked_sub(1)? };
+        }
+        if !bounds.contains(&p) {
+            return None;
+        }
+    }
+}
+
+// The brackets of the language, the pairs with different ends
+fn bracket_pairs(pairs: &[(char, char)]) -> Vec<(u8, u8)> {
+    pairs
+        .iter()
+        .filter(|(o, c)| o != c && o.is_ascii() && c.is_ascii())
+        .map(|(o, c)| (*o as u8, *c as u8))
+        .collect()
+}
+
+// Positions are in the document, a folded view shows a copy without the
+// folded lines (see folds.rs)
+struct Context {
+    buf: text::TextBuffer,
+    text: Rc<String>,
+    pairs: &'static [(char, char)],
+    literals: Literals,
+}
+
+impl Context {
+    fn new(ed: &text::TextEditor) -> Option<Self> {
+        let buf = crate::folds::doc_buffer(ed)?;
+        let path = path_of(ed);
+        let (text, literals) = contents(&buf, path.as_deref());
+        Some(Self {
+            buf,
+            text,
+            pairs: pairs(path.as_deref()),
+            literals,
+        })
+    }
+
+    fn literal_slice(&self) -> &[Range<usize>] {
+        self.literals
+            .as_deref()
+            .map(|l| l.as_slice())
+            .unwrap_or(&[])
+    }
+
+    // The bracket at pos and the one matching it
+    fn match_at(&self, at: usize) -> Option<usize> {
+        let c = *self.text.as_bytes().get(at)?;
+        let (other, forward) = bracket_pairs(self.pairs).into_iter().find_map(|(o, cl)| {
+            if c == o {
+                Some((cl, true))
+            } else if c == cl {
+                Some((o, false))
+            } else {
+                None
+            }
+        })?;
+        find_match(
+            self.text.as_bytes(),
+            at,
+            other,
+            forward,
+            self.literal_slice(),
+        )
+    }
+
+    // The bracket after pos (or else the one before it) and its match
+    fn match_near(&self, pos: usize) -> Option<(usize, usize)> {
+        [Some(pos), pos.checked_sub(1)]
+            .into_iter()
+            .flatten()
+            .find_map(|at| Some((at, self.match_at(at)?)))
+    }
+
+    // The opening bracket of the innermost pair around pos
+    fn enclosing(&self, pos: usize) -> Option<usize> {
+        let bytes = self.text.as_bytes();
+        let brackets = bracket_pairs(self.pairs);
+        let literals = self.literal_slice();
+        let mut depth = vec![0; brackets.len()];
+        let mut p = pos.min(bytes.len());
+        while p > 0 {
+            p -= 1;
+            if let Some(r) = literal_at(literals, p) {
+                p = r.start;
+                continue;
+            }
+            if let Some(i) = brackets.iter().position(|(_, c)| *c == bytes[p]) {
+                depth[i] += 1;
+            } else if let Some(i) = brackets.iter().position(|(o, _)| *o == bytes[p]) {
+                if depth[i] == 0 {
+                    return Some(p);
+                }
+                depth[i] -= 1;
+            }
+        }
+        None
+    }
+
+    // Inside a string or comment, past its first character
+    fn in_literal(&self, pos: usize) -> bool {
+        literal_at(self.literal_slice(), pos).is_some_and(|r| r.start < pos)
+    }
+
+    fn char_before(&self, pos: usize) -> Option<char> {
+        self.text.get(..pos)?.chars().next_back()
+    }
+
+    fn char_after(&self, pos: usize) -> Option<char> {
+        self.text.get(pos..)?.chars().next()
+    }
+}
+
+// Moves the caret to the matching bracket, or else to the bracket opening the
+// block around it
+pub fn jump_to_match() {
+    let mut ed = match STATE.with(|s| s.current_editor()) {
+        Some(e) => e,
+        None => return,
+    };
+    let cx = match Context::new(&ed) {
+        Some(c) => c,
+        None => return,
+    };
+    let pos = crate::folds::to_doc(&ed, ed.insert_position()) as usize;
+    let to = cx
+        .match_near(pos)
+        .map(|(_, m)| m)
+        .or_else(|| cx.enclosing(pos));
+    if let Some(to) = to {
+        let (to, _) = crate::folds::reveal(&mut ed, to as i32, to as i32 + 1);
+        ed.set_insert_position(to);
+        ed.show_insert_position();
+        refresh(&mut ed);
+    }
+}
+
+// Redraws the view when the outlined pair changed
+fn refresh(ed: &mut text::TextEditor) {
+    let pos = crate::folds::to_doc(ed, ed.insert_position()) as usize;
+    let pair = Context::new(ed).and_then(|cx| cx.match_near(pos));
+    let view = view_of(ed);
+    let changed = SHOWN.with(|s| {
+        let mut s = s.borrow_mut();
+        let old = match pair {
+            Some(p) => s.insert(view, p),
+            None => s.remove(&view),
+        };
+        old != pair
+    });
+    if changed {
+        ed.redraw();
+    }
+}
+
+// Outlines the bracket next to the caret and its match, drawn after the text
+pub fn draw(ed: &text::TextEditor) {
+    let (a, b) = match SHOWN.with(|s| s.borrow().get(&view_of(ed)).copied()) {
+        Some(p) => p,
+        None => return,
+    };
+    draw::set_font(ed.text_font(), ed.text_size());
+    let h = draw::height();
+    draw::push_clip(
+        ed.x() + ed.linenumber_width(),
+        ed.y(),
+        ed.w() - ed.linenumber_width(),
+        ed.h(),
+    );
+    draw::set_draw_color(ed.cursor_color());
+    for p in [a as i32, b as i32] {
+        // a bracket in a fold isn't shown
+        let shown = crate::folds::to_shown(ed, p);
+        if crate::folds::to_doc(ed, shown) != p {
+            continue;
+        }
+        let p = shown;
+        let (x1, y) = ed.position_to_xy(p);
+        let (x2, _) = ed.position_to_xy(p + 1);
+        // positions out of view are reported at 0, 0
+        if (x1, y) != (0, 0) {
+            draw::draw_rect(x1, y, (x2 - x1).max(2), h);
+        }
+    }
+    draw::pop_clip();
+}
+
+// Replaces start..end with text as one undo step and places the caret
+fn edit(
+    ed: &mut text::TextEditor,
+    cx: &mut Context,
+    range: Range<usize>,
+    text: &str,
+    caret: usize,
+) {
+    cx.buf.replace(range.start as i32, range.end as i32, text);
+    ed.set_insert_position(crate::folds::to_shown(ed, caret as i32));
+    ed.show_insert_position();
+    ed.do_callback();
+}
+
+fn key_down(ed: &mut text::TextEditor) -> bool {
+    let state = crate::macros::event_state();
+    if state.intersects(Shortcut::Ctrl | Shortcut::Alt | Shortcut::Meta) {
+        return false;
+    }
+    let mut cx = match Context::new(ed) {
+        Some(c) => c,
+        None => return false,
+    };
+    let pos = crate::folds::to_doc(ed, ed.insert_position()) as usize;
+    let selection = crate::folds::selection(ed)
+        .filter(|(s, e)| s != e)
+        .map(|(s, e)| (s as usize, e as usize));
+    if crate::macros::event_key() == Key::BackSpace {
+        // deletes an empty pair as a whole
+        let (before, after) = (cx.char_before(pos), cx.char_after(pos));
+        let empty = cx
+            .pairs
+            .iter()
+            .any(|(o, c)| Some(*o) == before && Some(*c) == after);
+        if selection.is_some() || !empty || cx.in_literal(pos.saturating_sub(1)) {
+            return false;
+        }
+        let start = pos - before.map(char::len_utf8).unwrap_or(0);
+        let end = pos + after.map(char::len_utf8).unwrap_or(0);
+        cx.buf.remove(start as i32, end as i32);
+        ed.set_insert_position(crate::folds::to_shown(ed, start as i32));
+        ed.show_insert_position();
+        ed.do_callback();
+        return true;
+    }
+    let typed = crate::macros::event_text();
+    let mut chars = typed.chars();
+    let c = match (chars.next(), chars.next()) {
+        (Some(c), None) => c,
+        _ => return false,
+    };
+    let opening = cx.pairs.iter().find(|(o, _)| *o == c).map(|p| p.1);
+    let closing = cx.pairs.iter().any(|(_, cl)| *cl == c);
+    if let Some((start, end)) = selection {
+        // wraps the selection, which stays selected inside the pair
+        let close = match opening {
+            Some(close) => close,
+            None => return false,
+        };
+        let inner = cx.text[start..end].to_string();
+        let (from, to) = (start + c.len_utf8(), start + c.len_utf8() + inner.len());
+        edit(
+            ed,
+            &mut cx,
+            start..end,
+            &format!("{}{}{}", c, inner, close),
+            to,
+        );
+        // the view's buffer, the copy of the document if folded
+        if let Some(mut buf) = ed.buffer() {
+            let shown = |p: usize| crate::folds::to_shown(ed, p as i32);
+            buf.select(shown(from), shown(to));
+        }
+        return true;
+    }
+    let after = cx.char_after(pos);
+    if closing && after == Some(c) {
+        // steps over a closing bracket that's already matched, or over a
+        // quote unless it starts a string
+        let over = if opening == Some(c) {
+            literal_at(cx.literal_slice(), pos).is_none_or(|r| r.end == pos + 1)
+        } else {
+            cx.match_at(pos).is_some()
+        };
+        if over {
+            ed.set_insert_position(crate::folds::to_shown(ed, (pos + c.len_utf8()) as i32));
+            ed.show_insert_position();
+            return true;
+        }
+    }
+    let close = match opening {
+        Some(close) => close,
+        None => return false,
+    };
+    // pairs only before blanks and closing characters
+    let free = match after {
+        None => true,
+        Some(a) => {
+            a.is_whitespace() || cx.pairs.iter().any(|(_, cl)| *cl == a) || ",;:".contains(a)
+        }
+    };
+    if !free {
+        return false;
+    }
+    if c == close {
+        // quotes don't pair after a word, e.g. an apostrophe, or in a string
+        let before = cx.char_before(pos);
+        if before.is_some_and(|b| b.is_alphanumeric() || b == c) || cx.in_literal(pos) {
+            return false;
+        }
+    }
+    edit(
+        ed,
+        &mut cx,
+        pos..pos,
+        &format!("{}{}", c, close),
+        pos + c.len_utf8(),
+    );
+    true
+}
+
+// Called from the editor's handler, takes the typed brackets and quotes and
+// follows the caret to outline the matching bracket
+pub fn handle(ed: &mut text::TextEditor, ev: Event) -> bool {
+    match ev {
+        Event::KeyDown if crate::settings::get().auto_close => key_down(ed),
+        Event::KeyUp | Event::Released => {
+            refresh(ed);
+            false
+        }
+        _ => false,
+    }
+}
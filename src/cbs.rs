@@ -1,6 +1,9 @@
-use crate::state::STATE;
+use crate::{ctx, encoding, state::STATE, tabs, utils};
 use fltk::{enums::*, prelude::*, *};
-use std::{fs, path::PathBuf};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
 
 fn nfc_get_file(mode: dialog::NativeFileChooserType) -> PathBuf {
     let mut nfc = dialog::NativeFileChooser::new(mode);
@@ -8,52 +11,326 @@ fn nfc_get_file(mode: dialog::NativeFileChooserType) -> PathBuf {
     nfc.filename()
 }
 
-fn find() {
-    let mut dlg: window::Window = app::widget_from_id("find").unwrap();
-    let main_win = app::first_window().unwrap();
-    dlg.resize(
-        main_win.x() + main_win.w() - 400,
-        main_win.y() + 30,
-        400,
-        50,
-    );
+// Shows a search dialog at the top right of the main window it was invoked from
+fn show_dialog(id: &str, h: i32) {
+    let mut dlg: window::Window = app::widget_from_id(id).unwrap();
+    let main_win: window::Window = ctx::widget("win").unwrap();
+    dlg.resize(main_win.x() + main_win.w() - 400, main_win.y() + 30, 400, h);
     dlg.hide();
     dlg.show();
 }
 
-fn replace() {
-    let mut dlg: window::Window = app::widget_from_id("replace").unwrap();
-    let main_win = app::first_window().unwrap();
-    dlg.resize(
-        main_win.x() + main_win.w() - 400,
-        main_win.y() + 30,
-        400,
-        80,
+pub fn find() {
+    show_dialog("find", 50);
+}
+
+pub fn replace() {
+    show_dialog("replace", 80);
+}
+
+// Encodes a buffer in its original format and writes it to path, reporting failures
+fn write_buffer(id: usize, path: &Path) -> bool {
+    if !confirm_encoding(id) {
+        return false;
+    }
+    match write_file(id, path, true) {
+        Some(Err(e)) => {
+            dialog::alert_default(&format!(
+                "Failed to save {}:\n{}\n\nThe file on disk was left unchanged.",
+                path.display(),
+                e
+            ));
+            false
+        }
+        Some(Ok(())) => true,
+        None => false,
+    }
+}
+
+// Asks what to do when the encoding of a buffer can't represent its text,
+// false if the save was cancelled
+fn confirm_encoding(id: usize) -> bool {
+    let lossy = STATE.with(move |s| {
+        s.map
+            .get(&id)
+            .filter(|e| !e.encoding.can_encode(&e.buf.text()))
+            .map(|e| (e.name(), e.encoding.name()))
+    });
+    let (name, enc) = match lossy {
+        Some(v) => v,
+        None => return true,
+    };
+    let msg = format!(
+        "{} has characters that {} can't represent, they would be saved as '?'.",
+        name, enc
     );
-    dlg.hide();
-    dlg.show();
+    match dialog::choice2_default(&msg, "Cancel", "Save as UTF-8", "Save Anyway") {
+        Some(1) => {
+            STATE.with(move |s| {
+                if let Some(mb) = s.map.get_mut(&id) {
+                    mb.encoding = encoding::Encoding::Utf8;
+                }
+                s.update_format_status();
+            });
+            true
+        }
+        Some(2) => true,
+        _ => false,
+    }
+}
+
+// Encodes a buffer and writes it to path, None if there's no such buffer.
+// Text the encoding can't represent fails the write unless lossy.
+fn write_file(id: usize, path: &Path, lossy: bool) -> Option<std::io::Result<()>> {
+    let contents = STATE.with(move |s| {
+        s.map.get(&id).map(|e| {
+            let text = e.buf.text();
+            if !lossy && !e.encoding.can_encode(&text) {
+                return Err(e.encoding.name());
+            }
+            Ok(encoding::encode(&text, e.encoding, e.line_ending))
+        })
+    })?;
+    let contents = match contents {
+        Ok(c) => c,
+        Err(name) => {
+            return Some(Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("the text has characters {} can't represent", name),
+            )))
+        }
+    };
+    let backup = crate::settings::get().backup_path(path);
+    Some(utils::atomic_write(path, &contents, backup.as_deref()))
+}
+
+// Marks a buffer written to its file as saved
+fn saved(id: usize) {
+    crate::autosave::clear_failure(id);
+    let _lsp_path = STATE.with(move |s| {
+        s.set_modified(id, false);
+        s.map.get(&id).and_then(|mb| mb.lsp_path())
+    });
+    #[cfg(feature = "lsp")]
+    if let Some(path) = _lsp_path {
+        crate::lsp::with_client(&path, |c| c.did_save(&path));
+    }
+}
+
+// Saves a buffer to its file without prompting or reporting, for auto-save
+pub fn autosave_buffer(id: usize) -> std::io::Result<()> {
+    let path = STATE.with(move |s| s.map.get(&id).and_then(|e| e.current_file.clone()));
+    if let Some(Err(e)) = path.as_ref().and_then(|p| write_file(id, p, false)) {
+        return Err(e);
+    }
+    if path.is_some() {
+        saved(id);
+    }
+    Ok(())
+}
+
+// Writes a buffer to its file, returns false if it couldn't be saved.
+// Untitled buffers prompt for a path first.
+pub fn save_buffer(id: usize) -> bool {
+    let current_file = match STATE.with(move |s| s.map.get(&id).map(|e| e.current_file.clone())) {
+        Some(v) => v,
+        None => return false,
+    };
+    let current_file = match current_file {
+        Some(f) => f,
+        None => return save_buffer_as(id),
+    };
+    if !write_buffer(id, &current_file) {
+        return false;
+    }
+    saved(id);
+    true
+}
+
+// Prompts for a path and saves the buffer there, the tab then refers to the new file
+pub fn save_buffer_as(id: usize) -> bool {
+    let mut nfc = dialog::NativeFileChooser::new(dialog::NativeFileChooserType::BrowseSaveFile);
+    nfc.set_option(dialog::FileDialogOptions::SaveAsConfirm);
+    nfc.show();
+    let path = nfc.filename();
+    if path.as_os_str().is_empty() {
+        return false;
+    }
+    // two documents of one file would overwrite each other
+    let target = path.canonicalize().unwrap_or_else(|_| path.clone());
+    let open_elsewhere = STATE.with({
+        let target = target.clone();
+        move |s| {
+            s.map
+                .iter()
+                .any(|(k, v)| *k != id && v.current_file.as_ref() == Some(&target))
+        }
+    });
+    if open_elsewhere {
+        dialog::alert_default(&format!(
+            "{} is open in another tab.\nClose it first to save over it.",
+            target.display()
+        ));
+        return false;
+    }
+    if !write_buffer(id, &path) {
+        return false;
+    }
+    let path = path.canonicalize().unwrap_or(path);
+    crate::autosave::clear_failure(id);
+    let _lsp_path = STATE.with(move |s| {
+        s.rebind(id, path.clone());
+        s.set_modified(id, false);
+        s.update_format_status();
+        s.map.get(&id).and_then(|mb| mb.lsp_path())
+    });
+    #[cfg(feature = "lsp")]
+    if let Some(path) = _lsp_path {
+        crate::lsp::with_client(&path, |c| c.did_save(&path));
+    }
+    true
 }
 
-pub fn win_cb(_: &mut window::Window) {
+// Asks what to do with unsaved buffers, returns false if closing was cancelled
+fn confirm_close(modified: Vec<(usize, String)>) -> bool {
+    if modified.is_empty() {
+        return true;
+    }
+    let mut msg = String::from("The following files have unsaved changes:\n");
+    for (_, name) in &modified {
+        msg.push_str(&format!("\n    {}", name));
+    }
+    match dialog::choice2_default(&msg, "Cancel", "Save All", "Discard") {
+        Some(1) => modified.iter().all(|(id, _)| save_buffer(*id)),
+        Some(2) => true,
+        _ => false,
+    }
+}
+
+pub fn quit() {
+    if !confirm_close(STATE.with(|s| s.modified_buffers())) {
+        return;
+    }
+    crate::session::save();
+    crate::autosave::remove_all_swaps();
+    app::quit();
+}
+
+// Opens another main window on a workspace root, restoring its session
+fn open_window(root: PathBuf) {
+    crate::gui::new_window(true, &root);
+    #[cfg(feature = "lsp")]
+    crate::lsp::init(root.clone());
+    STATE.with(move |s| {
+        if let Some(session) = crate::session::load(&root) {
+            crate::session::restore(s, &session);
+        }
+        s.update_format_status();
+    });
+}
+
+// Re-roots the active window on another folder: the file browser, the workspace
+// root used by the session and the language server, and the terminal follow
+pub fn open_folder(dir: PathBuf) {
+    let win = ctx::active();
+    let dir = dir.canonicalize().unwrap_or(dir);
+    let old = ctx::root(win);
+    if old == dir {
+        return;
+    }
+    // the tabs stay open, the old workspace keeps them in its session
+    crate::session::save_window(win);
+    ctx::set_root(win, dir.clone());
+    crate::fbr::chdir(win, dir.clone());
+    if let Some(mut w) = ctx::widget::<window::Window>("win") {
+        w.set_label(&crate::gui::window_title(&dir));
+    }
+    #[cfg(feature = "term")]
+    crate::gui::term_cd(win, &dir);
+    #[cfg(feature = "lsp")]
+    {
+        // other windows may still be rooted on the old folder
+        if !ctx::windows().into_iter().any(|w| ctx::root(w) == old) {
+            crate::lsp::shutdown(&old);
+        }
+        if !crate::lsp::has_client(&dir) {
+            crate::lsp::init(dir.clone());
+            // documents already open under the new root
+            let docs: Vec<(PathBuf, String, i32)> = STATE.with({
+                let dir = dir.clone();
+                move |s| {
+                    s.map
+                        .values()
+                        .filter_map(|b| b.lsp_path().map(|p| (p, b.buf.text(), b.version)))
+                        .filter(|(p, _, _)| p.starts_with(&dir))
+                        .collect()
+                }
+            });
+            for (path, text, version) in docs {
+                crate::lsp::with_client(&path, |c| c.did_open(&path, &text, version));
+            }
+        }
+        crate::gui::schedule_status_refresh();
+    }
+    crate::recent::add_folder(&dir);
+}
+
+// Closes a main window along with its tabs, closing the last one quits
+fn close_window(w: &mut window::Window, win: usize) {
+    if ctx::windows().len() <= 1 {
+        quit();
+        return;
+    }
+    let docs = crate::state::window_docs(win);
+    let modified = STATE.with(move |s| {
+        s.modified_buffers()
+            .into_iter()
+            .filter(|(id, _)| docs.contains(id))
+            .collect()
+    });
+    if !confirm_close(modified) {
+        return;
+    }
+    crate::session::save_window(win);
+    for id in crate::state::window_docs(win) {
+        if let Some(mut g) = tabs::tab_of(id) {
+            close_tab(&mut g);
+        }
+    }
+    crate::fbr::remove(win);
+    #[cfg(feature = "term")]
+    crate::gui::close_term(win);
+    ctx::remove(win);
+    if ctx::active() == win {
+        if let Some(first) = ctx::windows().first() {
+            ctx::set_active(*first);
+        }
+    }
+    w.hide();
+    // deleted once the event handling is done with it
+    app::delete_widget(w.clone());
+}
+
+pub fn win_cb(w: &mut window::Window, win: usize) {
     if app::event() == Event::Close {
-        app::quit();
+        close_window(w, win);
     }
 }
 
-pub fn editor_cb(_e: &mut text::TextEditor) {
-    app::add_timeout3(0.01, |_| STATE.with(|s| s.was_modified(true)));
+pub fn editor_cb(e: &mut text::TextEditor) {
+    // split views report changes of the document they display
+    let view = e.as_widget_ptr() as usize;
+    app::add_timeout3(0.01, move |_| {
+        STATE.with(move |s| {
+            let id = s.doc_id(view);
+            s.set_modified(id, true)
+        })
+    });
     // Debounced didChange: bump change_seq, schedule a send in configurable delay
-    let debounce_ms: f64 = std::env::var("RED_LSP_DEBOUNCE_MS")
-        .ok()
-        .and_then(|v| v.parse::<u64>().ok())
-        .map(|v| v as f64 / 1000.0)
-        .unwrap_or(0.20);
-    if let Some((id, seq)) = STATE.with(|s| {
-        if let Some(current_id) = s.current_id() {
-            s.map.get(&current_id).map(|mb| (current_id, mb.change_seq))
-        } else {
-            None
-        }
+    let debounce_ms: f64 = crate::settings::get().lsp_debounce_ms as f64 / 1000.0;
+    if let Some((id, seq)) = STATE.with(move |s| {
+        let id = s.doc_id(view);
+        s.map.get(&id).map(|mb| (id, mb.change_seq))
     }) {
         // bump seq
         STATE.with(move |s| {
@@ -62,6 +339,7 @@ pub fn editor_cb(_e: &mut text::TextEditor) {
             }
         });
         let scheduled_seq = seq + 1;
+        crate::autosave::schedule_swap(id, scheduled_seq);
         app::add_timeout3(debounce_ms, move |_| {
             STATE.with(move |s2| {
                 if let Some(mb) = s2.map.get_mut(&id) {
@@ -70,9 +348,11 @@ pub fn editor_cb(_e: &mut text::TextEditor) {
                         mb.version += 1;
                         let version = mb.version;
                         #[cfg(feature = "lsp")]
-                        if let Some(path) = mb.current_file.clone() {
+                        if let Some(path) = mb.lsp_path() {
                             let text = mb.buf.text();
-                            crate::lsp::with_client(|c| c.did_change_full(&path, &text, version));
+                            crate::lsp::with_client(&path, |c| {
+                                c.did_change_full(&path, &text, version)
+                            });
                         }
                     }
                 }
@@ -84,166 +364,255 @@ pub fn editor_cb(_e: &mut text::TextEditor) {
 pub fn new_file() {
     let dlg = dialog::input_default("Enter file name", "");
     if let Some(f) = dlg {
-        fs::File::create(f).ok();
+        fs::File::create(ctx::dir(ctx::active()).join(f)).ok();
     }
 }
 
 pub fn new_dir() {
     let dlg = dialog::input_default("Enter directory name", "");
     if let Some(f) = dlg {
-        fs::create_dir(f).ok();
+        fs::create_dir(ctx::dir(ctx::active()).join(f)).ok();
     }
 }
 
-pub fn menu_cb(m: &mut impl MenuExt) {
-    if let Ok(mpath) = m.item_pathname(None) {
-        match mpath.as_str() {
-            "&File/New File...\t" => {
-                new_file();
-            }
-            "&File/New Dir...\t" => {
-                new_dir();
-            }
-            "&File/Open...\t" => {
-                let c = nfc_get_file(dialog::NativeFileChooserType::BrowseFile);
-                if c.exists() {
-                    STATE.with(move |s| {
-                        s.append(Some(c.canonicalize().unwrap()));
-                    });
-                }
-            }
-            "&File/Save\t" => {
-                STATE.with(|s| {
-                    if let Some(id) = s.current_id() {
-                        let (modified, current_file, contents) = {
-                            let e = s.map.get(&id).unwrap();
-                            (e.modified, e.current_file.clone(), e.buf.text())
-                        };
-                        if let Some(ref current_file) = current_file {
-                            if modified && current_file.exists() {
-                                fs::write(current_file, contents).ok();
-                                s.was_modified(false);
-                                #[cfg(feature = "lsp")]
-                                crate::lsp::with_client(|c| c.did_save(current_file));
-                            }
-                        }
-                    }
-                });
-            }
-            "&File/Save as...\t" => {
-                let c = nfc_get_file(dialog::NativeFileChooserType::BrowseSaveFile);
-                if c.exists() {
-                    STATE.with(move |s| {
-                        if let Some(buf) = s.buf().as_ref() {
-                            fs::write(&c, buf.text()).expect("Failed to write to file!");
-                            s.was_modified(false);
-                            #[cfg(feature = "lsp")]
-                            crate::lsp::with_client(|cl| cl.did_save(&c));
-                        }
-                    });
-                }
-            }
-            "&File/Save All\t" => {
-                STATE.with(|s| {
-                    for v in s.map.values_mut() {
-                        if v.modified && v.current_file.as_ref().unwrap().exists() {
-                            fs::write(v.current_file.as_ref().unwrap(), v.buf.text()).ok();
-                            v.modified = true;
-                        }
-                    }
-                });
-            }
-            "&File/Quit\t" => app::quit(),
-            "/Undo\t" | "&Edit/Undo\t" => STATE.with(|s| {
-                if let Some(e) = s.current_editor() {
-                    e.undo()
-                }
-            }),
-            "/Redo\t" | "&Edit/Redo\t" => STATE.with(|s| {
-                if let Some(e) = s.current_editor() {
-                    e.redo()
-                }
-            }),
-            "/Cut\t" | "&Edit/Cut\t" => STATE.with(|s| {
-                if let Some(e) = s.current_editor() {
-                    e.cut()
-                }
-            }),
-            "/Copy\t" | "&Edit/Copy\t" => STATE.with(|s| {
-                if let Some(e) = s.current_editor() {
-                    e.copy()
-                }
-            }),
-            "/Paste\t" | "&Edit/Paste\t" => STATE.with(|s| {
-                if let Some(e) = s.current_editor() {
-                    e.paste()
-                }
-            }),
-            "/Find\t" | "&Edit/Find\t" => find(),
-            "/Replace\t" | "&Edit/Replace\t" => replace(),
-            "&View/File browser\t" => {
-                let mut item = m.at(m.value()).unwrap();
-                let fbr: group::Group = app::widget_from_id("fbr_group").unwrap();
-                let mut parent = group::Flex::from_dyn_widget(&fbr.parent().unwrap()).unwrap();
-                if !item.value() {
-                    parent.fixed(&fbr, 1);
-                    item.clear();
-                } else {
-                    parent.fixed(&fbr, 180);
-                    item.set();
-                }
-                app::redraw();
+pub fn new_window() {
+    let mut nfc = dialog::NativeFileChooser::new(dialog::NativeFileChooserType::BrowseDir);
+    nfc.show();
+    let dir = nfc.filename();
+    if dir.is_dir() {
+        open_window(dir.canonicalize().unwrap_or(dir));
+    }
+}
+
+pub fn open_file() {
+    let c = nfc_get_file(dialog::NativeFileChooserType::BrowseFile);
+    if c.exists() {
+        crate::state::open(c);
+    }
+}
+
+pub fn choose_folder() {
+    let mut nfc = dialog::NativeFileChooser::new(dialog::NativeFileChooserType::BrowseDir);
+    nfc.show();
+    let dir = nfc.filename();
+    if dir.is_dir() {
+        open_folder(dir);
+    }
+}
+
+pub fn save_all() {
+    let ids: Vec<usize> = STATE.with(|s| {
+        s.map
+            .iter()
+            .filter(|(_, v)| v.modified)
+            .map(|(k, _)| *k)
+            .collect()
+    });
+    for id in ids {
+        if !save_buffer(id) {
+            break;
+        }
+    }
+}
+
+// Runs f on the editor view that has the focus in the active window
+pub fn with_editor<F: FnOnce(&mut text::TextEditor)>(f: F) {
+    if let Some(mut e) = STATE.with(|s| s.current_editor()) {
+        f(&mut e);
+    }
+}
+
+pub fn show_settings() {
+    let mut dlg: window::Window = app::widget_from_id("settings").unwrap();
+    dlg.show();
+}
+
+pub fn show_key_bindings() {
+    crate::dialogs::keymap_dialog();
+}
+
+// Keeps a toggle item of the active window's menu in sync with what it shows
+fn set_toggle(path: &str, on: bool) {
+    if let Some(m) = ctx::widget::<menu::SysMenuBar>("menu") {
+        if let Some(mut item) = m.find_item(path) {
+            if on {
+                item.set();
+            } else {
+                item.clear();
             }
-            "&View/Terminal\t" => {
-                let mut item = m.at(m.value()).unwrap();
-                let term: group::Scroll = app::widget_from_id("term_group").unwrap();
-                let mut parent = group::Flex::from_dyn_widget(&term.parent().unwrap()).unwrap();
-                if !item.value() {
-                    parent.fixed(&term, 1);
-                    item.clear();
-                } else {
-                    parent.fixed(&term, 160);
-                    item.set();
-                }
-                app::redraw();
+        }
+    }
+}
+
+pub fn toggle_fbr() {
+    let fbr: group::Group = ctx::widget("fbr_group").unwrap();
+    let mut parent = group::Flex::from_dyn_widget(&fbr.parent().unwrap()).unwrap();
+    let show = fbr.w() <= 1;
+    parent.fixed(&fbr, if show { 180 } else { 1 });
+    set_toggle("&View/File browser\t", show);
+    app::redraw();
+}
+
+#[cfg(feature = "term")]
+pub fn toggle_term() {
+    let term: group::Scroll = ctx::widget("term_group").unwrap();
+    let mut parent = group::Flex::from_dyn_widget(&term.parent().unwrap()).unwrap();
+    let show = term.h() <= 1;
+    parent.fixed(&term, if show { 160 } else { 1 });
+    set_toggle("&View/Terminal\t", show);
+    app::redraw();
+}
+
+pub fn about() {
+    dialog::message_title("About");
+    dialog::message_default("A minimal text editor written using fltk-rs!")
+}
+
+pub fn tabs_cb(_t: &mut group::Tabs) {
+    crate::autosave::on_tab_switch();
+    STATE.with(|s| {
+        if let Some(id) = s.current_id() {
+            s.touch(id);
+        }
+        s.update_format_status()
+    });
+}
+
+// Converts the current buffer to another encoding, applied on save
+pub fn set_encoding(enc: encoding::Encoding) {
+    let text = STATE.with(|s| s.buf().map(|b| b.text()).unwrap_or_default());
+    if !enc.can_encode(&text) {
+        let msg = format!(
+            "Some characters can't be represented in {} and will be replaced by '?' on save. Continue?",
+            enc.name()
+        );
+        if dialog::choice2_default(&msg, "Cancel", "Convert", "") != Some(1) {
+            return;
+        }
+    }
+    STATE.with(move |s| {
+        if let Some(id) = s.current_id() {
+            if let Some(mb) = s.map.get_mut(&id) {
+                mb.encoding = enc;
             }
-            "&Help/About\t" => {
-                dialog::message_title("About");
-                dialog::message_default("A minimal text editor written using fltk-rs!")
+            s.was_modified(true);
+        }
+        s.update_format_status();
+    });
+}
+
+// Converts the line endings of the current buffer, applied on save
+pub fn set_line_ending(eol: encoding::LineEnding) {
+    STATE.with(move |s| {
+        if let Some(id) = s.current_id() {
+            if let Some(mb) = s.map.get_mut(&id) {
+                mb.line_ending = eol;
             }
-            _ => unreachable!(),
+            s.was_modified(true);
         }
-    }
+        s.update_format_status();
+    });
 }
 
 pub fn tab_close_cb(g: &mut impl GroupExt) {
     if app::callback_reason() == CallbackReason::Closed {
         let ed = text::TextEditor::from_dyn_widget(&g.child(0).unwrap()).unwrap();
-        let edid = ed.as_widget_ptr() as usize;
-        let buf = ed.buffer().unwrap();
-        // LSP didClose for this file if any
-        #[cfg(feature = "lsp")]
-        STATE.with(move |s| {
-            if let Some(v) = s.map.get(&edid) {
-                if let Some(path) = v.current_file.as_ref() {
-                    crate::lsp::with_client(|c| c.did_close(path));
+        close_doc(ed.as_widget_ptr() as usize);
+    }
+}
+
+// Closes a document's tab, asking to save it first if it has unsaved changes
+pub fn close_doc(edid: usize) {
+    let modified = STATE.with(move |s| s.map.get(&edid).map(|v| (v.modified, v.name())));
+    if let Some((true, name)) = modified {
+        let msg = format!("Save changes to {} before closing?", name);
+        match dialog::choice2_default(&msg, "Cancel", "Save", "Discard") {
+            Some(1) => {
+                if !save_buffer(edid) {
+                    return;
                 }
             }
-        });
-        let mut parent = g.parent().unwrap();
-        parent.remove(g);
-        unsafe {
-            text::TextBuffer::delete(buf);
+            Some(2) => (),
+            _ => return,
+        }
+    }
+    if let Some(mut g) = tabs::tab_of(edid) {
+        close_tab(&mut g);
+    }
+}
+
+// Closes a document's tab, dropping its unsaved changes
+pub fn discard_doc(edid: usize) {
+    if let Some(mut g) = tabs::tab_of(edid) {
+        close_tab(&mut g);
+    }
+}
+
+// Closes several documents, asking once about those with unsaved changes
+pub fn close_docs(ids: Vec<usize>) -> bool {
+    let modified = {
+        let ids = ids.clone();
+        STATE.with(move |s| {
+            s.modified_buffers()
+                .into_iter()
+                .filter(|(id, _)| ids.contains(id))
+                .collect()
+        })
+    };
+    if !confirm_close(modified) {
+        return false;
+    }
+    for id in ids {
+        if let Some(mut g) = tabs::tab_of(id) {
+            close_tab(&mut g);
+        }
+    }
+    true
+}
+
+// Removes a tab and releases its document, without asking about unsaved changes
+fn close_tab(g: &mut impl GroupExt) {
+    let ed = text::TextEditor::from_dyn_widget(&g.child(0).unwrap()).unwrap();
+    let edid = ed.as_widget_ptr() as usize;
+    let buf = crate::folds::doc_buffer(&ed).unwrap();
+    // LSP didClose for this file if any
+    #[cfg(feature = "lsp")]
+    STATE.with(move |s| {
+        if let Some(v) = s.map.get(&edid) {
+            if let Some(path) = v.lsp_path() {
+                crate::lsp::with_client(&path, |c| c.did_close(&path));
+            }
+        }
+    });
+    STATE.with(move |s| {
+        if let Some(v) = s.map.get(&edid) {
+            crate::autosave::remove_swap(v);
+            if let Some(path) = v.current_file.as_ref() {
+                crate::watch::unwatch(path);
+            }
         }
-        STATE.with(move |s| s.map.remove(&edid));
-        parent.set_damage(true);
+    });
+    STATE.with(move |s| s.close_views(edid));
+    crate::folds::close(edid, &buf);
+    #[cfg(feature = "highlight")]
+    crate::highlight::forget(&buf);
+    let mut parent = g.parent().unwrap();
+    parent.remove(g);
+    unsafe {
+        text::TextBuffer::delete(buf);
     }
+    STATE.with(move |s| {
+        s.map.remove(&edid);
+        s.mru.retain(|x| *x != edid);
+        s.update_format_status();
+    });
+    parent.set_damage(true);
 }
 
 #[cfg(feature = "term")]
 pub fn tab_splitter_cb(f: &mut frame::Frame, ev: Event) -> bool {
     let mut parent = group::Flex::from_dyn_widget(&f.parent().unwrap()).unwrap();
-    let term = app::widget_from_id::<group::Scroll>("term_group").unwrap();
+    let term = ctx::widget::<group::Scroll>("term_group").unwrap();
     match ev {
         Event::Push => true,
         Event::Drag => {
@@ -265,7 +634,7 @@ pub fn tab_splitter_cb(f: &mut frame::Frame, ev: Event) -> bool {
 
 pub fn fbr_splitter_cb(f: &mut frame::Frame, ev: Event) -> bool {
     let mut parent = group::Flex::from_dyn_widget(&f.parent().unwrap()).unwrap();
-    let fbr: group::Group = app::widget_from_id("fbr_group").unwrap();
+    let fbr: group::Group = ctx::widget("fbr_group").unwrap();
     match ev {
         Event::Push => true,
         Event::Drag => {
@@ -0,0 +1,520 @@
+// Registry of the editor's commands. Menus, the command palette and key bindings
+// refer to commands by id instead of matching on menu labels.
+use crate::{cbs, encoding, keymap, state::STATE, tabs};
+use fltk::{enums::*, menu, prelude::*};
+use std::sync::{Arc, Mutex, OnceLock};
+
+#[derive(Clone)]
+pub struct Command {
+    pub id: String,
+    pub title: String,
+    // Path in the main menu, if the command is listed there
+    pub menu: Option<String>,
+    pub shortcut: Shortcut,
+    pub flag: menu::MenuFlag,
+    run: Arc<dyn Fn() + Send + Sync>,
+}
+
+static REGISTRY: OnceLock<Mutex<Vec<Command>>> = OnceLock::new();
+
+fn registry() -> &'static Mutex<Vec<Command>> {
+    REGISTRY.get_or_init(|| Mutex::new(Vec::new()))
+}
+
+// "&Edit/Encoding/UTF-8\t" reads as "Edit: Encoding: UTF-8"
+fn title_from_menu(path: &str) -> String {
+    path.trim_end_matches('\t')
+        .replace('&', "")
+        .split('/')
+        .map(|s| s.trim_end_matches("..."))
+        .collect::<Vec<_>>()
+        .join(": ")
+}
+
+// Registers a command listed in the main menu, titled after its menu path
+pub fn register<F: Fn() + Send + Sync + 'static>(
+    id: &str,
+    menu: &str,
+    shortcut: Shortcut,
+    flag: menu::MenuFlag,
+    f: F,
+) {
+    add(Command {
+        id: id.to_string(),
+        title: title_from_menu(menu),
+        menu: Some(menu.to_string()),
+        shortcut,
+        flag,
+        run: Arc::new(f),
+    });
+}
+
+// Registers a command that is only reachable through the palette and key bindings
+pub fn register_action<F: Fn() + Send + Sync + 'static>(
+    id: &str,
+    title: &str,
+    shortcut: Shortcut,
+    f: F,
+) {
+    add(Command {
+        id: id.to_string(),
+        title: title.to_string(),
+        menu: None,
+        shortcut,
+        flag: menu::MenuFlag::Normal,
+        run: Arc::new(f),
+    });
+}
+
+fn add(cmd: Command) {
+    if let Ok(mut r) = registry().lock() {
+        r.retain(|c| c.id != cmd.id);
+        r.push(cmd);
+    }
+}
+
+pub fn get(id: &str) -> Option<Command> {
+    registry()
+        .lock()
+        .ok()
+        .and_then(|r| r.iter().find(|c| c.id == id).cloned())
+}
+
+// All commands, in registration order
+pub fn all() -> Vec<Command> {
+    registry().lock().map(|r| r.clone()).unwrap_or_default()
+}
+
+pub fn run(id: &str) {
+    // the registry isn't locked while running, commands may look it up
+    if let Some(cmd) = get(id) {
+        crate::macros::record_command(id);
+        (cmd.run)();
+    }
+}
+
+// A command's path in a menu. Popup menus drop the top level menu, "&Edit/Undo\t"
+// becomes "Undo\t".
+pub fn menu_path(cmd: &Command, popup: bool) -> String {
+    let path = cmd
+        .menu
+        .clone()
+        .unwrap_or_else(|| format!("{}\t", cmd.title));
+    if popup {
+        path.split_once('/')
+            .map(|(_, l)| l.to_string())
+            .unwrap_or(path)
+    } else {
+        path
+    }
+}
+
+// Adds a command to a menu with its current key binding
+pub fn add_to_menu(m: &mut impl MenuExt, id: &str, popup: bool) -> i32 {
+    let cmd = match get(id) {
+        Some(c) => c,
+        None => return -1,
+    };
+    let id = cmd.id.clone();
+    m.add(
+        &menu_path(&cmd, popup),
+        keymap::menu_shortcut(&id),
+        cmd.flag,
+        move |_| run(&id),
+    )
+}
+
+fn encoding_id(prefix: &str, name: &str) -> String {
+    format!(
+        "{}.{}",
+        prefix,
+        name.to_lowercase().replace([' ', '-'], "_")
+    )
+}
+
+pub fn encoding_command(name: &str) -> String {
+    encoding_id("edit.encoding", name)
+}
+
+pub fn line_ending_command(name: &str) -> String {
+    encoding_id("edit.line_ending", name)
+}
+
+// The built-in commands, in main menu order
+pub fn init() {
+    let normal = menu::MenuFlag::Normal;
+    let divider = menu::MenuFlag::MenuDivider;
+    let toggle = menu::MenuFlag::Toggle;
+    let ctrl = Shortcut::Ctrl;
+    let shift = Shortcut::Shift;
+    let alt = Shortcut::Alt;
+    register(
+        "file.new_file",
+        "&File/New File...\t",
+        ctrl | 'n',
+        normal,
+        cbs::new_file,
+    );
+    register(
+        "file.new_dir",
+        "&File/New Dir...\t",
+        ctrl | shift | 'n',
+        normal,
+        cbs::new_dir,
+    );
+    register(
+        "file.new_window",
+        "&File/New Window\t",
+        ctrl | alt | 'n',
+        divider,
+        cbs::new_window,
+    );
+    register(
+        "file.open",
+        "&File/Open...\t",
+        ctrl | 'o',
+        normal,
+        cbs::open_file,
+    );
+    register(
+        "file.go_to_file",
+        "&File/Go to File...\t",
+        ctrl | 'p',
+        normal,
+        crate::goto::show,
+    );
+    register(
+        "file.open_folder",
+        "&File/Open Folder...\t",
+        ctrl | shift | 'o',
+        normal,
+        cbs::choose_folder,
+    );
+    register("file.save", "&File/Save\t", ctrl | 's', normal, || {
+        if let Some(id) = STATE.with(|s| s.current_id()) {
+            cbs::save_buffer(id);
+        }
+    });
+    register(
+        "file.save_as",
+        "&File/Save as...\t",
+        ctrl | shift | 'w',
+        normal,
+        || {
+            if let Some(id) = STATE.with(|s| s.current_id()) {
+                cbs::save_buffer_as(id);
+            }
+        },
+    );
+    register(
+        "file.save_all",
+        "&File/Save All\t",
+        Shortcut::None,
+        divider,
+        cbs::save_all,
+    );
+    register(
+        "file.close_tab",
+        "&File/Close Tab\t",
+        ctrl | 'w',
+        divider,
+        || {
+            if let Some(id) = STATE.with(|s| s.current_id()) {
+                cbs::close_doc(id);
+            }
+        },
+    );
+    register("file.quit", "&File/Quit\t", ctrl | 'q', normal, cbs::quit);
+    register("edit.undo", "&Edit/Undo\t", ctrl | 'z', normal, || {
+        cbs::with_editor(|e| {
+            crate::folds::undo(e);
+        })
+    });
+    register("edit.redo", "&Edit/Redo\t", ctrl | 'y', divider, || {
+        cbs::with_editor(|e| {
+            crate::folds::redo(e);
+        })
+    });
+    // with several carets, every selection is cut or copied, see cursors.rs
+    register("edit.cut", "&Edit/Cut\t", ctrl | 'x', normal, || {
+        cbs::with_editor(|e| {
+            if !crate::cursors::copy(e, true) {
+                e.cut()
+            }
+        })
+    });
+    register("edit.copy", "&Edit/Copy\t", ctrl | 'c', normal, || {
+        cbs::with_editor(|e| {
+            if !crate::cursors::copy(e, false) {
+                e.copy()
+            }
+        })
+    });
+    register("edit.paste", "&Edit/Paste\t", ctrl | 'v', divider, || {
+        cbs::with_editor(|e| e.paste())
+    });
+    register(
+        "edit.add_next_occurrence",
+        "&Edit/Add Next Occurrence\t",
+        ctrl | 'd',
+        normal,
+        crate::cursors::add_next_occurrence,
+    );
+    register(
+        "edit.select_all_occurrences",
+        "&Edit/Select All Occurrences\t",
+        ctrl | shift | 'l',
+        normal,
+        crate::cursors::select_all_occurrences,
+    );
+    register(
+        "edit.add_caret_above",
+        "&Edit/Add Caret Above\t",
+        alt | shift | Key::Up,
+        normal,
+        || crate::cursors::add_caret_vertical(false),
+    );
+    register(
+        "edit.add_caret_below",
+        "&Edit/Add Caret Below\t",
+        alt | shift | Key::Down,
+        divider,
+        || crate::cursors::add_caret_vertical(true),
+    );
+    register_action(
+        "edit.column_select_left",
+        "Edit: Column Select Left",
+        alt | shift | Key::Left,
+        || crate::cursors::extend_block(0, -1),
+    );
+    register_action(
+        "edit.column_select_right",
+        "Edit: Column Select Right",
+        alt | shift | Key::Right,
+        || crate::cursors::extend_block(0, 1),
+    );
+    register(
+        "edit.jump_to_bracket",
+        "&Edit/Jump to Matching Bracket\t",
+        ctrl | 'm',
+        normal,
+        crate::brackets::jump_to_match,
+    );
+    register(
+        "edit.reindent",
+        "&Edit/Reindent Selection\t",
+        ctrl | alt | 'i',
+        divider,
+        crate::indent::reindent,
+    );
+    register("edit.find", "&Edit/Find\t", ctrl | 'f', normal, cbs::find);
+    register(
+        "edit.replace",
+        "&Edit/Replace\t",
+        ctrl | 'h',
+        normal,
+        cbs::replace,
+    );
+    register(
+        "macro.record",
+        "&Edit/Macros/Start or Stop Recording\t",
+        ctrl | shift | 'r',
+        normal,
+        crate::macros::toggle_record,
+    );
+    register(
+        "macro.play",
+        "&Edit/Macros/Play\t",
+        ctrl | shift | 'm',
+        normal,
+        crate::macros::play,
+    );
+    register(
+        "macro.play_times",
+        "&Edit/Macros/Play Several Times...\t",
+        Shortcut::None,
+        normal,
+        crate::macros::play_times,
+    );
+    register(
+        "macro.play_to_end",
+        "&Edit/Macros/Play to End of File\t",
+        Shortcut::None,
+        divider,
+        crate::macros::play_to_end,
+    );
+    register(
+        "macro.save",
+        "&Edit/Macros/Save...\t",
+        Shortcut::None,
+        normal,
+        crate::macros::save,
+    );
+    register(
+        "macro.play_saved",
+        "&Edit/Macros/Play Saved...\t",
+        Shortcut::None,
+        normal,
+        crate::macros::play_saved,
+    );
+    for (name, enc) in encoding::ENCODINGS {
+        let enc = *enc;
+        register(
+            &encoding_command(name),
+            &format!("&Edit/Encoding/{}\t", name),
+            Shortcut::None,
+            normal,
+            move || cbs::set_encoding(enc),
+        );
+    }
+    for (name, eol) in encoding::LINE_ENDINGS {
+        let eol = *eol;
+        register(
+            &line_ending_command(name),
+            &format!("&Edit/Line Endings/{}\t", name),
+            Shortcut::None,
+            normal,
+            move || cbs::set_line_ending(eol),
+        );
+    }
+    register(
+        "edit.settings",
+        "&Edit/Settings...\t",
+        ctrl | ',',
+        normal,
+        cbs::show_settings,
+    );
+    register(
+        "edit.key_bindings",
+        "&Edit/Key Bindings...\t",
+        Shortcut::None,
+        normal,
+        cbs::show_key_bindings,
+    );
+    register(
+        "edit.vim_mode",
+        "&Edit/Vim Mode\t",
+        Shortcut::None,
+        toggle,
+        crate::vim::toggle,
+    );
+    for (id, title, f) in crate::emacs::COMMANDS {
+        register_action(id, title, Shortcut::None, *f);
+    }
+    #[cfg(feature = "lsp")]
+    register_action(
+        "edit.trigger_completion",
+        "Edit: Trigger Completion",
+        ctrl | ' ',
+        || cbs::with_editor(|e| crate::completion::request(e)),
+    );
+    register(
+        "view.command_palette",
+        "&View/Command Palette...\t",
+        ctrl | shift | 'p',
+        divider,
+        crate::palette::show,
+    );
+    register(
+        "view.file_browser",
+        "&View/File browser\t",
+        Shortcut::None,
+        toggle,
+        cbs::toggle_fbr,
+    );
+    #[cfg(feature = "term")]
+    register(
+        "view.terminal",
+        "&View/Terminal\t",
+        Shortcut::None,
+        toggle,
+        cbs::toggle_term,
+    );
+    register(
+        "view.next_tab",
+        "&View/Next Tab\t",
+        ctrl | Key::Tab,
+        normal,
+        || tabs::cycle(true),
+    );
+    register(
+        "view.previous_tab",
+        "&View/Previous Tab\t",
+        ctrl | shift | Key::Tab,
+        divider,
+        || tabs::cycle(false),
+    );
+    register(
+        "view.split_right",
+        "&View/Split Right\t",
+        ctrl | '\\',
+        normal,
+        || STATE.with(|s| s.split(false)),
+    );
+    register(
+        "view.split_down",
+        "&View/Split Down\t",
+        ctrl | shift | '\\',
+        normal,
+        || STATE.with(|s| s.split(true)),
+    );
+    register(
+        "view.cycle_split",
+        "&View/Next Buffer in Split\t",
+        ctrl | alt | '\\',
+        normal,
+        || STATE.with(|s| s.cycle_split()),
+    );
+    register(
+        "view.unsplit",
+        "&View/Unsplit\t",
+        Shortcut::None,
+        normal,
+        || STATE.with(|s| s.unsplit()),
+    );
+    // see folds.rs
+    register(
+        "view.fold",
+        "&View/Folding/Fold\t",
+        ctrl | shift | '[',
+        normal,
+        crate::folds::fold,
+    );
+    register(
+        "view.unfold",
+        "&View/Folding/Unfold\t",
+        ctrl | shift | ']',
+        divider,
+        crate::folds::unfold,
+    );
+    register(
+        "view.fold_all",
+        "&View/Folding/Fold All\t",
+        ctrl | alt | '[',
+        normal,
+        crate::folds::fold_all,
+    );
+    register(
+        "view.unfold_all",
+        "&View/Folding/Unfold All\t",
+        ctrl | alt | ']',
+        divider,
+        crate::folds::unfold_all,
+    );
+    for level in 1..=3 {
+        register(
+            &format!("view.fold_level_{}", level),
+            &format!("&View/Folding/Fold Level {}\t", level),
+            Shortcut::None,
+            normal,
+            move || crate::folds::fold_level(level),
+        );
+    }
+    register(
+        "help.about",
+        "&Help/About\t",
+        Shortcut::None,
+        normal,
+        cbs::about,
+    );
+}
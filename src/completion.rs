@@ -11,6 +11,65 @@ pub struct CompletionEntry {
     pub detail: Option<String>,
 }
 
+// Requests completions at the caret of the editor and shows them in the popup
+pub fn request(e: &text::TextEditor) {
+    // Avoid activity if LSP is unavailable
+    if !crate::lsp::is_available() {
+        return;
+    }
+    // request completion at current caret position
+    // positions go by the document, which a folded view doesn't show whole
+    if let Some(buf) = crate::folds::doc_buffer(e) {
+        let pos = crate::folds::to_doc(e, e.insert_position());
+        let text = buf.text();
+        let lsp_pos = crate::lsp::compute_position_from_offset(&text, pos as usize);
+        let ed_ptr = e.as_widget_ptr() as usize;
+        // find path for current editor
+        STATE.with(move |s| {
+            if let Some(v) = s.map.get(&s.doc_id(ed_ptr)) {
+                if let Some(path) = v.lsp_path() {
+                    crate::lsp::with_client(&path.clone(), move |c| {
+                        c.completion(&path, lsp_pos, move |resp| {
+                            // Build popup entries
+                            let items = match resp {
+                                lsp::CompletionResponse::Array(arr) => arr,
+                                lsp::CompletionResponse::List(list) => list.items,
+                            };
+                            let mut entries = Vec::with_capacity(items.len());
+                            for item in items.into_iter() {
+                                let insert_text = item
+                                    .insert_text
+                                    .clone()
+                                    .unwrap_or_else(|| item.label.clone());
+                                let edit_range: Option<lsp::Range> =
+                                    item.text_edit.as_ref().map(|te| match te {
+                                        lsp::CompletionTextEdit::Edit(ed_) => ed_.range,
+                                        lsp::CompletionTextEdit::InsertAndReplace(ir) => ir.replace,
+                                    });
+                                entries.push(CompletionEntry {
+                                    label: item.label,
+                                    insert_text,
+                                    edit_range,
+                                    kind: item.kind,
+                                    detail: item.detail,
+                                });
+                            }
+                            let entries_arc = std::sync::Arc::new(entries);
+                            app::awake_callback(move || {
+                                let entries_vec = (*entries_arc).clone();
+                                let maybe_ed = STATE.with(|s3| s3.current_editor());
+                                if let Some(ed_now) = maybe_ed {
+                                    show_popup(&ed_now, entries_vec);
+                                }
+                            });
+                        });
+                    });
+                }
+            }
+        });
+    }
+}
+
 pub fn show_popup(ed: &text::TextEditor, entries: Vec<CompletionEntry>) {
     if entries.is_empty() {
         return;
@@ -51,13 +110,9 @@ pub fn show_popup(ed: &text::TextEditor, entries: Vec<CompletionEntry>) {
         });
         // Handle Space/Enter on the list as well
         list.handle(move |l, ev| match ev {
-            enums::Event::KeyDown => {
-                if space_or_enter() {
-                    l.do_callback();
-                    true
-                } else {
-                    false
-                }
+            enums::Event::KeyDown if space_or_enter() => {
+                l.do_callback();
+                true
             }
             _ => false,
         });
@@ -86,10 +141,10 @@ fn insert_entry_owned(ent: CompletionEntry) {
     } = ent;
     let (mut buf_opt, start, end) = STATE.with(move |s| {
         if let Some(ed) = s.current_editor() {
-            if let Some(v) = s.map.get(&(ed.as_widget_ptr() as usize)) {
+            if let Some(v) = s.map.get(&s.doc_id(ed.as_widget_ptr() as usize)) {
                 if v.current_file.is_some() {
                     let b = v.buf.clone();
-                    let mut start = ed.insert_position();
+                    let mut start = crate::folds::to_doc(&ed, ed.insert_position());
                     let mut end = start;
                     if let Some(r) = edit_range {
                         let text = b.text();
@@ -103,6 +158,18 @@ fn insert_entry_owned(ent: CompletionEntry) {
         }
         (None, 0, 0)
     });
+    if buf_opt.is_none() {
+        return;
+    }
+    // With several carets the same range around each of them is replaced
+    if let Some(mut ed) = STATE.with(|s| s.current_editor()) {
+        let pos = crate::folds::to_doc(&ed, ed.insert_position());
+        crate::macros::record_completion(pos - start, end - pos, &insert_text);
+        if crate::cursors::replace_around(&mut ed, pos - start, end - pos, &insert_text) {
+            let _ = ed.take_focus();
+            return;
+        }
+    }
     if let Some(ref mut b) = buf_opt {
         // Perform replacement first
         b.replace(start, end, &insert_text);
@@ -110,7 +177,7 @@ fn insert_entry_owned(ent: CompletionEntry) {
         let new_pos = start.saturating_add(insert_text.len() as i32);
         STATE.with(move |s| {
             if let Some(mut ed) = s.current_editor() {
-                ed.set_insert_position(new_pos);
+                ed.set_insert_position(crate::folds::to_shown(&ed, new_pos));
                 let _ = ed.take_focus();
             }
         });
@@ -0,0 +1,103 @@
+// Per main window context. The widgets of every main window are registered with
+// ids suffixed by the window's index, lookups go to the active window, which is
+// the main window that last received an event.
+use fltk::app;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Mutex, OnceLock,
+};
+
+static ACTIVE: AtomicUsize = AtomicUsize::new(0);
+static NEXT: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Clone, Debug, Default)]
+struct Ctx {
+    // Workspace root, used for the session and the language server
+    root: PathBuf,
+    // Directory shown by the file browser
+    dir: PathBuf,
+}
+
+static CTXS: OnceLock<Mutex<HashMap<usize, Ctx>>> = OnceLock::new();
+
+fn with_ctxs<T, F: FnOnce(&mut HashMap<usize, Ctx>) -> T>(f: F) -> Option<T> {
+    let m = CTXS.get_or_init(|| Mutex::new(HashMap::new()));
+    m.lock().ok().map(|mut c| f(&mut c))
+}
+
+// Registers a new main window, returns its index
+pub fn add(root: PathBuf) -> usize {
+    let win = NEXT.fetch_add(1, Ordering::Relaxed);
+    with_ctxs(move |c| {
+        c.insert(
+            win,
+            Ctx {
+                dir: root.clone(),
+                root,
+            },
+        )
+    });
+    win
+}
+
+pub fn remove(win: usize) {
+    with_ctxs(|c| c.remove(&win));
+}
+
+// Indices of the open main windows
+pub fn windows() -> Vec<usize> {
+    let mut v = with_ctxs(|c| c.keys().copied().collect::<Vec<_>>()).unwrap_or_default();
+    v.sort_unstable();
+    v
+}
+
+pub fn active() -> usize {
+    ACTIVE.load(Ordering::Relaxed)
+}
+
+pub fn set_active(win: usize) {
+    ACTIVE.store(win, Ordering::Relaxed);
+}
+
+pub fn root(win: usize) -> PathBuf {
+    with_ctxs(|c| c.get(&win).map(|x| x.root.clone()))
+        .flatten()
+        .unwrap_or_default()
+}
+
+pub fn set_root(win: usize, root: PathBuf) {
+    with_ctxs(|c| {
+        if let Some(x) = c.get_mut(&win) {
+            x.root = root;
+        }
+    });
+}
+
+pub fn dir(win: usize) -> PathBuf {
+    with_ctxs(|c| c.get(&win).map(|x| x.dir.clone()))
+        .flatten()
+        .unwrap_or_default()
+}
+
+pub fn set_dir(win: usize, dir: PathBuf) {
+    with_ctxs(|c| {
+        if let Some(x) = c.get_mut(&win) {
+            x.dir = dir;
+        }
+    });
+}
+
+pub fn id_in(win: usize, name: &str) -> String {
+    format!("{}{}", name, win)
+}
+
+pub fn widget_in<T: 'static + Clone>(win: usize, name: &str) -> Option<T> {
+    app::widget_from_id(&id_in(win, name))
+}
+
+// Looks up a widget of the active window
+pub fn widget<T: 'static + Clone>(name: &str) -> Option<T> {
+    widget_in(active(), name)
+}
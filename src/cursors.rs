@@ -0,0 +1,868 @@
+// Multiple carets. FLTK's editor has a single insert position and selection,
+// which stay the primary caret; the others are kept here per editor view, drawn
+// over the text and edited together with the primary one. Every edit replaces
+// the span from the first to the last caret at once, so it's a single undo step.
+// A rectangular selection is a caret per line, each selecting the same columns.
+// Carets are positions in the view, which in a folded view is a copy of the
+// document without the folded lines (see folds.rs), edits go to the document.
+use crate::state::STATE;
+use fltk::{app, draw, enums::*, prelude::*, text};
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Caret {
+    pos: i32,
+    // Other end of the selection, pos when nothing is selected
+    anchor: i32,
+}
+
+impl Caret {
+    fn at(pos: i32) -> Self {
+        Self { pos, anchor: pos }
+    }
+    fn start(&self) -> i32 {
+        self.pos.min(self.anchor)
+    }
+    fn end(&self) -> i32 {
+        self.pos.max(self.anchor)
+    }
+    fn is_empty(&self) -> bool {
+        self.pos == self.anchor
+    }
+}
+
+struct Carets {
+    extra: Vec<Caret>,
+    // Generation of the buffer callback keeping them in place
+    gen: u64,
+}
+
+thread_local! {
+    // Extra carets of each editor view, keyed by its pointer
+    static CARETS: RefCell<HashMap<usize, Carets>> = RefCell::new(HashMap::new());
+    static GENERATION: Cell<u64> = const { Cell::new(0) };
+    // Set while the carets edit the buffer, they're placed afterwards
+    static EDITING: Cell<bool> = const { Cell::new(false) };
+    // The rectangular selection the carets were made from, if any
+    static BLOCK: Cell<Option<Block>> = const { Cell::new(None) };
+    // Set during an Alt+drag
+    static DRAGGING: Cell<bool> = const { Cell::new(false) };
+    // Last text copied from a rectangular selection, pasted as a column
+    static BLOCK_CLIP: RefCell<String> = const { RefCell::new(String::new()) };
+}
+
+// Corners of a rectangular selection as (line, display column)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Block {
+    view: usize,
+    anchor: (i32, usize),
+    head: (i32, usize),
+}
+
+fn view_of(ed: &text::TextEditor) -> usize {
+    ed.as_widget_ptr() as usize
+}
+
+fn extras(view: usize) -> Vec<Caret> {
+    CARETS.with(|c| {
+        c.borrow()
+            .get(&view)
+            .map(|c| c.extra.clone())
+            .unwrap_or_default()
+    })
+}
+
+// The editor has carets besides its own
+pub fn active(ed: &text::TextEditor) -> bool {
+    !extras(view_of(ed)).is_empty()
+}
+
+fn primary(ed: &text::TextEditor, buf: &text::TextBuffer) -> Caret {
+    let pos = ed.insert_position();
+    match buf.selection_position() {
+        Some((s, e)) if s != e && (pos == s || pos == e) => Caret {
+            pos,
+            anchor: if pos == s { e } else { s },
+        },
+        _ => Caret::at(pos),
+    }
+}
+
+fn set_primary(ed: &mut text::TextEditor, buf: &mut text::TextBuffer, c: Caret) {
+    if c.is_empty() {
+        buf.unselect();
+    } else {
+        buf.select(c.start(), c.end());
+    }
+    ed.set_insert_position(c.pos);
+}
+
+// Stores the extra carets of an editor. The first time, a modify callback is
+// added to its buffer to move them along with edits made elsewhere.
+fn store(ed: &mut text::TextEditor, extra: Vec<Caret>) {
+    let view = view_of(ed);
+    // carets set any other way aren't a rectangle anymore
+    BLOCK.with(|b| b.set(None));
+    let registered = CARETS.with(|c| {
+        let mut c = c.borrow_mut();
+        match c.get_mut(&view) {
+            Some(carets) => {
+                carets.extra = extra;
+                None
+            }
+            None => {
+                let gen = GENERATION.with(|g| {
+                    g.set(g.get() + 1);
+                    g.get()
+                });
+                c.insert(view, Carets { extra, gen });
+                Some(gen)
+            }
+        }
+    });
+    if let (Some(gen), Some(mut buf)) = (registered, ed.buffer()) {
+        buf.add_modify_callback(move |pos, inserted, deleted, _, _| {
+            if (inserted == 0 && deleted == 0) || EDITING.with(|e| e.get()) {
+                return;
+            }
+            CARETS.with(|c| {
+                if let Ok(mut c) = c.try_borrow_mut() {
+                    if let Some(carets) = c.get_mut(&view).filter(|c| c.gen == gen) {
+                        for caret in carets.extra.iter_mut() {
+                            caret.pos = shift(caret.pos, pos, inserted, deleted);
+                            caret.anchor = shift(caret.anchor, pos, inserted, deleted);
+                        }
+                    }
+                }
+            });
+        });
+    }
+    ed.redraw();
+}
+
+fn shift(p: i32, pos: i32, inserted: i32, deleted: i32) -> i32 {
+    if p >= pos + deleted {
+        p + inserted - deleted
+    } else if p > pos {
+        pos
+    } else {
+        p
+    }
+}
+
+// Back to the primary caret only
+pub fn collapse(ed: &mut text::TextEditor) {
+    if active(ed) {
+        store(ed, Vec::new());
+    }
+    BLOCK.with(|b| b.set(None));
+}
+
+// Drops the carets of an editor view that's going away
+pub fn forget(view: usize) {
+    CARETS.with(|c| c.borrow_mut().remove(&view));
+}
+
+// Every caret of an editor, see merge
+fn all_carets(ed: &text::TextEditor, buf: &text::TextBuffer) -> (Vec<Caret>, usize) {
+    let main = primary(ed, buf);
+    let mut all = extras(view_of(ed));
+    all.push(main);
+    merge(all, main)
+}
+
+// Sorts the carets and merges the overlapping ones, the primary caret is
+// returned by its index
+fn merge(mut all: Vec<Caret>, main: Caret) -> (Vec<Caret>, usize) {
+    all.sort_by_key(|c| (c.start(), c.end()));
+    let mut merged: Vec<Caret> = Vec::with_capacity(all.len());
+    let mut index = 0;
+    for c in all {
+        match merged.last_mut() {
+            Some(last) if c.start() < last.end() || c == *last => {
+                let (s, e) = (last.start(), last.end().max(c.end()));
+                // keeps the direction of the selection
+                *last = if last.anchor <= last.pos {
+                    Caret { pos: e, anchor: s }
+                } else {
+                    Caret { pos: s, anchor: e }
+                };
+                if c == main {
+                    index = merged.len() - 1;
+                }
+            }
+            _ => {
+                if c == main {
+                    index = merged.len();
+                }
+                merged.push(c);
+            }
+        }
+    }
+    (merged, index)
+}
+
+fn place(ed: &mut text::TextEditor, buf: &mut text::TextBuffer, mut all: Vec<Caret>, index: usize) {
+    let main = all.remove(index.min(all.len() - 1));
+    store(ed, all);
+    set_primary(ed, buf, main);
+    ed.show_insert_position();
+}
+
+// Replaces a range around every caret, returned by f as (start, end, text), in
+// a single buffer change. The carets end up after their replacement.
+fn edit<F: Fn(&text::TextBuffer, Caret) -> (i32, i32, String)>(ed: &mut text::TextEditor, f: F) {
+    let mut buf = match ed.buffer() {
+        Some(b) => b,
+        None => return,
+    };
+    let (all, index) = all_carets(ed, &buf);
+    let mut edits = Vec::with_capacity(all.len());
+    let mut last_end = 0;
+    let mut same_lines = true;
+    for c in &all {
+        let (s, e, text) = f(&buf, *c);
+        // ranges growing into the previous one are cut
+        let s = s.max(last_end);
+        let e = e.max(s);
+        last_end = e;
+        same_lines &=
+            !text.contains('\n') && !buf.text_range(s, e).unwrap_or_default().contains('\n');
+        edits.push((s, e, text));
+    }
+    let block = block_of(ed);
+    let carets = match replace_all(ed, &edits) {
+        Some(c) => c,
+        None => return,
+    };
+    let col = carets.iter().map(|c| column(&buf, c.pos)).max();
+    place(ed, &mut buf, carets, index);
+    // typing in a rectangle leaves a column of carets, still a rectangle
+    if let (Some(b), Some(col), true) = (block, col, same_lines) {
+        BLOCK.with(|cell| {
+            cell.set(Some(Block {
+                anchor: (b.anchor.0, col),
+                head: (b.head.0, col),
+                ..b
+            }))
+        });
+    }
+    // the editor's callback marks the document modified and notifies the server
+    ed.do_callback();
+}
+
+// Makes edits, sorted ranges of the view that don't overlap, in a single
+// change of the document. Returns the view positions after each replacement.
+// Folds between the first and the last edit open.
+fn replace_all(ed: &text::TextEditor, edits: &[(i32, i32, String)]) -> Option<Vec<Caret>> {
+    let mut doc = crate::folds::doc_buffer(ed)?;
+    let edits: Vec<(i32, i32, &str)> = edits
+        .iter()
+        .map(|(s, e, text)| {
+            let s = crate::folds::to_doc(ed, *s);
+            (s, crate::folds::to_doc(ed, *e).max(s), text.as_str())
+        })
+        .collect();
+    let span_start = edits.first()?.0;
+    let span_end = edits.last()?.1;
+    let mut new_text = String::new();
+    let mut ends = Vec::with_capacity(edits.len());
+    let mut prev = span_start;
+    for (s, e, text) in edits {
+        new_text.push_str(&doc.text_range(prev, s).unwrap_or_default());
+        new_text.push_str(text);
+        ends.push(span_start + new_text.len() as i32);
+        prev = e;
+    }
+    EDITING.with(|e| e.set(true));
+    doc.replace(span_start, span_end, &new_text);
+    EDITING.with(|e| e.set(false));
+    Some(
+        ends.into_iter()
+            .map(|p| Caret::at(crate::folds::to_shown(ed, p)))
+            .collect(),
+    )
+}
+
+fn insert(ed: &mut text::TextEditor, text: &str) {
+    edit(ed, |_, c| (c.start(), c.end(), text.to_string()));
+}
+
+fn next(buf: &text::TextBuffer, pos: i32) -> i32 {
+    match buf.char_at(pos) {
+        Some(c) if pos < buf.length() => pos + c.len_utf8() as i32,
+        _ => buf.length(),
+    }
+}
+
+fn prev(buf: &text::TextBuffer, pos: i32) -> i32 {
+    if pos <= 0 {
+        0
+    } else {
+        buf.utf8_align(pos - 1)
+    }
+}
+
+// Columns taken by text at the start of a line, tabs go up to the next stop
+fn width(text: &str, tab_distance: i32) -> usize {
+    let tab = tab_distance.max(1) as usize;
+    text.chars().fold(0, |col, c| {
+        if c == '\t' {
+            (col / tab + 1) * tab
+        } else {
+            col + 1
+        }
+    })
+}
+
+// Display column of a position
+fn column(buf: &text::TextBuffer, pos: i32) -> usize {
+    width(
+        &buf.text_range(buf.line_start(pos), pos).unwrap_or_default(),
+        buf.tab_distance(),
+    )
+}
+
+// Position at a display column of a line, or its end when it's shorter
+fn at_column(buf: &text::TextBuffer, line_start: i32, col: usize) -> i32 {
+    let tab = buf.tab_distance().max(1) as usize;
+    let end = buf.line_end(line_start);
+    let mut p = line_start;
+    let mut c = 0;
+    while p < end {
+        c = match buf.char_at(p) {
+            Some('\t') => (c / tab + 1) * tab,
+            _ => c + 1,
+        };
+        if c > col {
+            break;
+        }
+        p = next(buf, p);
+    }
+    p
+}
+
+// The position on the line above or below, None on the first or last line
+fn vertical(buf: &text::TextBuffer, pos: i32, down: bool) -> Option<i32> {
+    let ls = if down {
+        let le = buf.line_end(pos);
+        if le >= buf.length() {
+            return None;
+        }
+        le + 1
+    } else {
+        let ls = buf.line_start(pos);
+        if ls == 0 {
+            return None;
+        }
+        buf.line_start(ls - 1)
+    };
+    Some(at_column(buf, ls, column(buf, pos)))
+}
+
+// Moves every caret, extending their selections with Shift
+fn move_carets<F: Fn(&text::TextBuffer, Caret) -> i32>(
+    ed: &mut text::TextEditor,
+    extend: bool,
+    f: F,
+) {
+    let mut buf = match ed.buffer() {
+        Some(b) => b,
+        None => return,
+    };
+    let (all, index) = all_carets(ed, &buf);
+    let moved: Vec<Caret> = all
+        .into_iter()
+        .map(|c| {
+            let pos = f(&buf, c);
+            if extend {
+                Caret {
+                    pos,
+                    anchor: c.anchor,
+                }
+            } else {
+                Caret::at(pos)
+            }
+        })
+        .collect();
+    // carets that ran into each other become one
+    let main = moved[index];
+    let (merged, index) = merge(moved, main);
+    place(ed, &mut buf, merged, index);
+}
+
+fn key_down(ed: &mut text::TextEditor) -> bool {
+    let state = crate::macros::event_state();
+    if state.intersects(Shortcut::Ctrl | Shortcut::Alt | Shortcut::Meta) {
+        return false;
+    }
+    let extend = state.contains(Shortcut::Shift);
+    match crate::macros::event_key() {
+        Key::Escape => collapse(ed),
+        Key::BackSpace => edit(ed, |buf, c| {
+            if c.is_empty() {
+                (prev(buf, c.pos), c.pos, String::new())
+            } else {
+                (c.start(), c.end(), String::new())
+            }
+        }),
+        Key::Delete => edit(ed, |buf, c| {
+            if c.is_empty() {
+                (c.pos, next(buf, c.pos), String::new())
+            } else {
+                (c.start(), c.end(), String::new())
+            }
+        }),
+        Key::Enter | Key::KPEnter => insert(ed, "\n"),
+        Key::Tab => insert(ed, "\t"),
+        // without Shift, a selection collapses to its side
+        Key::Left => move_carets(ed, extend, |buf, c| {
+            if extend || c.is_empty() {
+                prev(buf, c.pos).max(buf.line_start(c.pos))
+            } else {
+                c.start()
+            }
+        }),
+        Key::Right => move_carets(ed, extend, |buf, c| {
+            if extend || c.is_empty() {
+                next(buf, c.pos).min(buf.line_end(c.pos))
+            } else {
+                c.end()
+            }
+        }),
+        Key::Up => move_carets(ed, extend, |buf, c| {
+            vertical(buf, c.pos, false).unwrap_or(c.pos)
+        }),
+        Key::Down => move_carets(ed, extend, |buf, c| {
+            vertical(buf, c.pos, true).unwrap_or(c.pos)
+        }),
+        Key::Home => move_carets(ed, extend, |buf, c| buf.line_start(c.pos)),
+        Key::End => move_carets(ed, extend, |buf, c| buf.line_end(c.pos)),
+        _ => {
+            let text = crate::macros::event_text();
+            if text.is_empty() || text.chars().any(|c| c.is_control()) {
+                return false;
+            }
+            insert(ed, &text);
+        }
+    }
+    true
+}
+
+// Pastes at every caret, one line each when the clipboard has as many lines
+fn paste(ed: &mut text::TextEditor, text: &str) {
+    let count = match ed.buffer() {
+        Some(buf) => all_carets(ed, &buf).0.len(),
+        None => return,
+    };
+    let lines: Vec<&str> = text.lines().collect();
+    if lines.len() == count {
+        let index = Cell::new(0);
+        edit(ed, |_, c| {
+            let i = index.get();
+            index.set(i + 1);
+            (c.start(), c.end(), lines[i].to_string())
+        });
+    } else {
+        insert(ed, text);
+    }
+}
+
+// Called from the editor's handler, takes the keys and pastes while there
+// are several carets. Ctrl+click adds a caret, a plain click drops them.
+pub fn handle(ed: &mut text::TextEditor, ev: Event) -> bool {
+    match ev {
+        // Alt+drag selects a rectangle
+        Event::Push if app::event_button() == 1 && app::event_state().contains(Shortcut::Alt) => {
+            let cell = match mouse_cell(ed) {
+                Some(c) => c,
+                None => return false,
+            };
+            ed.take_focus().ok();
+            DRAGGING.with(|d| d.set(true));
+            let view = view_of(ed);
+            select_block(
+                ed,
+                Block {
+                    view,
+                    anchor: cell,
+                    head: cell,
+                },
+            );
+            true
+        }
+        Event::Drag if DRAGGING.with(|d| d.get()) => {
+            if let (Some(b), Some(head)) = (block_of(ed), mouse_cell(ed)) {
+                if head != b.head {
+                    select_block(ed, Block { head, ..b });
+                }
+            }
+            true
+        }
+        Event::Released if DRAGGING.with(|d| d.get()) => {
+            DRAGGING.with(|d| d.set(false));
+            true
+        }
+        Event::Push => {
+            if app::event_button() != 1 {
+                return false;
+            }
+            if app::event_state().contains(Shortcut::Ctrl) {
+                // the click moves the primary caret, the current one stays
+                if let Some(buf) = ed.buffer() {
+                    let mut extra = extras(view_of(ed));
+                    extra.push(primary(ed, &buf));
+                    store(ed, extra);
+                }
+            } else {
+                collapse(ed);
+            }
+            false
+        }
+        Event::Paste if active(ed) => {
+            paste(ed, &app::event_text());
+            true
+        }
+        Event::Paste if is_block_clip(&app::event_text()) => {
+            paste_column(ed, &app::event_text());
+            true
+        }
+        Event::KeyDown if active(ed) => key_down(ed),
+        _ => false,
+    }
+}
+
+// Draws the extra carets and outlines their selections over the text
+pub fn draw(ed: &text::TextEditor) {
+    let carets = extras(view_of(ed));
+    let buf = match ed.buffer() {
+        Some(b) => b,
+        None => return,
+    };
+    if carets.is_empty() {
+        return;
+    }
+    draw::set_font(ed.text_font(), ed.text_size());
+    let h = draw::height();
+    let left = ed.x() + ed.linenumber_width();
+    draw::push_clip(left, ed.y(), ed.w() - ed.linenumber_width(), ed.h());
+    // positions out of view are reported at 0, 0
+    let visible = |(x, y): (i32, i32)| (x, y) != (0, 0);
+    for c in carets {
+        if !c.is_empty() {
+            draw::set_draw_color(Color::Selection);
+            let mut ls = c.start();
+            loop {
+                let le = buf.line_end(ls).min(c.end());
+                let (x1, y1) = ed.position_to_xy(ls);
+                let (x2, _) = ed.position_to_xy(le);
+                if visible((x1, y1)) {
+                    draw::draw_rect(x1, y1, (x2 - x1).max(2), h);
+                }
+                if le >= c.end() {
+                    break;
+                }
+                ls = le + 1;
+            }
+        }
+        let (x, y) = ed.position_to_xy(c.pos);
+        if visible((x, y)) {
+            draw::set_draw_color(ed.cursor_color());
+            draw::draw_rectf(x, y, 2, h);
+        }
+    }
+    draw::pop_clip();
+}
+
+fn current_editor() -> Option<text::TextEditor> {
+    STATE.with(|s| s.current_editor())
+}
+
+// The selection of the primary caret, or the word under it once selected
+fn needle(ed: &mut text::TextEditor, buf: &mut text::TextBuffer) -> Option<(String, bool)> {
+    let main = primary(ed, buf);
+    if !main.is_empty() {
+        return buf.text_range(main.start(), main.end()).map(|t| (t, false));
+    }
+    let (s, e) = (buf.word_start(main.pos), buf.word_end(main.pos));
+    if s == e {
+        return None;
+    }
+    set_primary(ed, buf, Caret { pos: e, anchor: s });
+    ed.redraw();
+    buf.text_range(s, e).map(|t| (t, true))
+}
+
+// Ctrl+D: selects the word under the caret, then adds a caret at each next
+// occurrence of the selection
+pub fn add_next_occurrence() {
+    let mut ed = match current_editor() {
+        Some(e) => e,
+        None => return,
+    };
+    let mut buf = match ed.buffer() {
+        Some(b) => b,
+        None => return,
+    };
+    let text = match needle(&mut ed, &mut buf) {
+        Some((_, true)) | None => return,
+        Some((t, false)) => t,
+    };
+    let (all, _) = all_carets(&ed, &buf);
+    let from = primary(&ed, &buf).end();
+    let found = buf
+        .search_forward(from, &text, true)
+        .or_else(|| buf.search_forward(0, &text, true));
+    let start = match found {
+        Some(s) => s,
+        None => return,
+    };
+    let end = start + text.len() as i32;
+    if all.iter().any(|c| c.start() == start && c.end() == end) {
+        // every occurrence has a caret
+        return;
+    }
+    let mut extra = extras(view_of(&ed));
+    extra.push(primary(&ed, &buf));
+    store(&mut ed, extra);
+    set_primary(
+        &mut ed,
+        &mut buf,
+        Caret {
+            pos: end,
+            anchor: start,
+        },
+    );
+    ed.show_insert_position();
+}
+
+// A caret at every occurrence of the selection or of the word under the caret
+pub fn select_all_occurrences() {
+    let mut ed = match current_editor() {
+        Some(e) => e,
+        None => return,
+    };
+    let mut buf = match ed.buffer() {
+        Some(b) => b,
+        None => return,
+    };
+    let text = match needle(&mut ed, &mut buf) {
+        Some((t, _)) => t,
+        None => return,
+    };
+    let main = primary(&ed, &buf);
+    let mut found = Vec::new();
+    let mut from = 0;
+    while let Some(s) = buf.search_forward(from, &text, true) {
+        let e = s + text.len() as i32;
+        found.push(Caret { pos: e, anchor: s });
+        from = e;
+    }
+    if found.is_empty() {
+        return;
+    }
+    // the occurrence under the caret stays the primary one
+    let index = found
+        .iter()
+        .position(|c| c.start() <= main.start() && main.start() <= c.end())
+        .unwrap_or(0);
+    place(&mut ed, &mut buf, found, index);
+}
+
+// Alt+Shift+Up/Down: grows the rectangular selection, or adds a caret on the
+// line above the topmost caret or below the bottommost one
+pub fn add_caret_vertical(down: bool) {
+    let mut ed = match current_editor() {
+        Some(e) => e,
+        None => return,
+    };
+    if !active(&ed) || block_of(&ed).is_some() {
+        extend_block(if down { 1 } else { -1 }, 0);
+        return;
+    }
+    let mut buf = match ed.buffer() {
+        Some(b) => b,
+        None => return,
+    };
+    let (all, _) = all_carets(&ed, &buf);
+    let edge = if down { all.last() } else { all.first() };
+    let pos = match edge.and_then(|c| vertical(&buf, c.pos, down)) {
+        Some(p) => p,
+        None => return,
+    };
+    let mut extra = extras(view_of(&ed));
+    extra.push(primary(&ed, &buf));
+    store(&mut ed, extra);
+    set_primary(&mut ed, &mut buf, Caret::at(pos));
+    ed.show_insert_position();
+}
+
+// Copies the selections of all carets, one per line. With cut they're removed.
+// Returns false when there's only the primary caret, for the editor to handle.
+pub fn copy(ed: &mut text::TextEditor, cut: bool) -> bool {
+    if !active(ed) {
+        return false;
+    }
+    let buf = match ed.buffer() {
+        Some(b) => b,
+        None => return true,
+    };
+    let (all, _) = all_carets(ed, &buf);
+    // a rectangle keeps its short lines, to be pasted back as a column
+    let block = block_of(ed).is_some();
+    // with the folded lines in them
+    let texts: Vec<String> = all
+        .iter()
+        .filter(|c| block || !c.is_empty())
+        .filter_map(|c| crate::folds::text_range(ed, c.start(), c.end()))
+        .collect();
+    if texts.iter().all(|t| t.is_empty()) {
+        return true;
+    }
+    let text = texts.join("\n");
+    BLOCK_CLIP.with(|c| *c.borrow_mut() = if block { text.clone() } else { String::new() });
+    app::copy(&text);
+    if cut {
+        edit(ed, |_, c| (c.start(), c.end(), String::new()));
+    }
+    true
+}
+
+// Replaces before..after around every caret with text, e.g. an accepted
+// completion. Returns false when there's only the primary caret.
+pub fn replace_around(ed: &mut text::TextEditor, before: i32, after: i32, text: &str) -> bool {
+    if !active(ed) {
+        return false;
+    }
+    edit(ed, |buf, c| {
+        let s = (c.pos - before.max(0)).max(buf.line_start(c.pos));
+        let e = (c.pos + after.max(0)).min(buf.line_end(c.pos));
+        (s, e, text.to_string())
+    });
+    true
+}
+
+fn block_of(ed: &text::TextEditor) -> Option<Block> {
+    BLOCK.with(|b| b.get()).filter(|b| b.view == view_of(ed))
+}
+
+// Line and display column under the mouse, past the end of lines too
+fn mouse_cell(ed: &mut text::TextEditor) -> Option<(i32, usize)> {
+    let buf = ed.buffer()?;
+    draw::set_font(ed.text_font(), ed.text_size());
+    let h = draw::height().max(1);
+    let top = ed.get_absolute_top_line_number().max(1) - 1;
+    let top_pos = ed.skip_lines(0, top, true);
+    let (x0, y0) = ed.position_to_xy(top_pos);
+    let last = buf.count_lines(0, buf.length());
+    let line = (top + (app::event_y() - y0).div_euclid(h)).clamp(0, last);
+    let col = ed.x_to_col((app::event_x() - x0) as f64).max(0.0) as usize;
+    Some((line, col))
+}
+
+// Replaces the carets with one per line of a rectangle, the primary one on the
+// line of its head
+fn select_block(ed: &mut text::TextEditor, block: Block) {
+    let mut buf = match ed.buffer() {
+        Some(b) => b,
+        None => return,
+    };
+    let (top, bottom) = (
+        block.anchor.0.min(block.head.0),
+        block.anchor.0.max(block.head.0),
+    );
+    let mut ls = ed.skip_lines(0, top, true);
+    let mut carets = Vec::new();
+    let mut index = 0;
+    for line in top..=bottom {
+        if line == block.head.0 {
+            index = carets.len();
+        }
+        carets.push(Caret {
+            pos: at_column(&buf, ls, block.head.1),
+            anchor: at_column(&buf, ls, block.anchor.1),
+        });
+        let le = buf.line_end(ls);
+        if le >= buf.length() {
+            break;
+        }
+        ls = le + 1;
+    }
+    place(ed, &mut buf, carets, index);
+    BLOCK.with(|b| b.set(Some(block)));
+}
+
+// Alt+Shift+arrows: moves the head of the rectangular selection, which starts
+// from the primary caret and its selection
+pub fn extend_block(lines: i32, cols: i32) {
+    let mut ed = match current_editor() {
+        Some(e) => e,
+        None => return,
+    };
+    let buf = match ed.buffer() {
+        Some(b) => b,
+        None => return,
+    };
+    let block = block_of(&ed).unwrap_or_else(|| {
+        let main = primary(&ed, &buf);
+        let cell = |p| (buf.count_lines(0, p), column(&buf, p));
+        Block {
+            view: view_of(&ed),
+            anchor: cell(main.anchor),
+            head: cell(main.pos),
+        }
+    });
+    let last = buf.count_lines(0, buf.length());
+    let head = (
+        (block.head.0 + lines).clamp(0, last),
+        (block.head.1 as i32 + cols).max(0) as usize,
+    );
+    select_block(&mut ed, Block { head, ..block });
+}
+
+fn is_block_clip(text: &str) -> bool {
+    !text.is_empty() && BLOCK_CLIP.with(|c| *c.borrow() == text)
+}
+
+// Pastes a copied rectangle at the caret, its lines going down the same
+// column. Short lines are padded and lines are added at the end as needed.
+fn paste_column(ed: &mut text::TextEditor, text: &str) {
+    let mut buf = match ed.buffer() {
+        Some(b) => b,
+        None => return,
+    };
+    let pos = ed.insert_position();
+    let col = column(&buf, pos);
+    let mut line = Some(buf.line_start(pos));
+    let mut edits = Vec::new();
+    for piece in text.split('\n') {
+        let edit = match line {
+            Some(ls) => {
+                let le = buf.line_end(ls);
+                let p = at_column(&buf, ls, col);
+                line = if le < buf.length() {
+                    Some(le + 1)
+                } else {
+                    None
+                };
+                let before = buf.text_range(ls, p).unwrap_or_default();
+                let pad = col.saturating_sub(width(&before, buf.tab_distance()));
+                (p, p, format!("{}{}", " ".repeat(pad), piece))
+            }
+            None => {
+                let end = buf.length();
+                (end, end, format!("\n{}{}", " ".repeat(col), piece))
+            }
+        };
+        edits.push(edit);
+    }
+    buf.unselect();
+    let carets = match replace_all(ed, &edits) {
+        Some(c) => c,
+        None => return,
+    };
+    let index = carets.len() - 1;
+    place(ed, &mut buf, carets, index);
+    ed.do_callback();
+}
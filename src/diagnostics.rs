@@ -1,12 +1,11 @@
+use fltk::app;
 use fltk::text::TextBuffer;
-use fltk::{app, frame, prelude::WidgetExt};
 use lsp_types as lsp;
 use std::collections::{HashMap, HashSet, VecDeque};
 use std::path::{Path, PathBuf};
 use std::sync::{Mutex, OnceLock};
 
 use crate::state::STATE;
-use fltk::enums::Align;
 
 #[derive(Default, Debug)]
 struct DiagState {
@@ -16,6 +15,8 @@ struct DiagState {
     pending: VecDeque<PathBuf>,
     // Latest diagnostics per file (LSP ranges)
     diags: HashMap<PathBuf, Vec<lsp::Diagnostic>>,
+    // Byte ranges of the underlined errors, reapplied when the highlighter restyles
+    underlines: HashMap<PathBuf, Vec<(usize, usize)>>,
 }
 
 static DIAG_STATE: OnceLock<Mutex<DiagState>> = OnceLock::new();
@@ -113,6 +114,7 @@ fn apply_for_path(path: &Path) {
         if styles.is_empty() || text.is_empty() {
             return;
         }
+        let mut ranges = Vec::new();
         // Clear existing underlines by normalizing any underlined style back to base (keep color)
         {
             let bytes = unsafe { styles.as_bytes_mut() };
@@ -130,16 +132,39 @@ fn apply_for_path(path: &Path) {
                         let sidx = start.min(len);
                         let eidx = end.min(len);
                         underline_range(bytes, sidx, eidx, base_styles);
+                        ranges.push((sidx, eidx));
                     }
                 }
             }
         }
+        let pbuf = path.to_path_buf();
+        with_state(move |s| {
+            s.underlines.insert(pbuf, ranges);
+        });
         sb.set_text(&styles);
         // Refresh footer to reflect current LSP status and errors
         update_status_bar();
     }
 }
 
+// Underlines the errors of the file again over freshly computed styles, which
+// start at byte `start` of the text
+#[cfg(feature = "highlight")]
+pub fn underline(path: &Path, start: usize, styles: &mut [u8]) {
+    with_state(|s| {
+        let base_styles = match s.styles.get(path) {
+            Some(v) => v.1,
+            None => return,
+        };
+        for &(sidx, eidx) in s.underlines.get(path).into_iter().flatten() {
+            let end = eidx.saturating_sub(start).min(styles.len());
+            if eidx > start && sidx < start + styles.len() {
+                underline_range(styles, sidx.saturating_sub(start), end, base_styles);
+            }
+        }
+    });
+}
+
 fn with_collect<T, F: FnOnce(&mut DiagState) -> Option<T>>(f: F) -> Option<T> {
     let m = DIAG_STATE.get_or_init(|| Mutex::new(DiagState::default()));
     if let Ok(mut s) = m.lock() {
@@ -208,8 +233,9 @@ fn pos_to_offset(text: &str, pos: lsp::Position) -> Option<usize> {
 // Update footer status line based on current directory, LSP status, and errors (if available)
 pub fn update_status_bar() {
     let (dir_text, lsp_status, err_opt): (String, String, Option<usize>) = STATE.with(|st| {
-        let dir = st.current_dir.display().to_string();
-        let lsp_status = crate::lsp::status_text();
+        let root = st.current_dir();
+        let dir = root.display().to_string();
+        let lsp_status = crate::lsp::status_text(&root);
         let mut err_count: Option<usize> = None;
         if let Some(path) = st.current_file() {
             with_state(|ds| {
@@ -228,15 +254,12 @@ pub fn update_status_bar() {
         }
         (dir, lsp_status, err_count)
     });
-    if let Some(mut info) = app::widget_from_id::<frame::Frame>("info") {
-        let label = match err_opt {
-            Some(n) => format!(
-                "Directory: {}   |   LSP: {}   |   Errors: {}",
-                dir_text, lsp_status, n
-            ),
-            None => format!("Directory: {}   |   LSP: {}", dir_text, lsp_status),
-        };
-        info.set_label(&label);
-        info.set_align(Align::Left | Align::Inside);
-    }
+    let label = match err_opt {
+        Some(n) => format!(
+            "Directory: {}   |   LSP: {}   |   Errors: {}",
+            dir_text, lsp_status, n
+        ),
+        None => format!("Directory: {}   |   LSP: {}", dir_text, lsp_status),
+    };
+    crate::gui::set_info(crate::ctx::active(), &label);
 }
@@ -4,6 +4,66 @@ use crate::state::STATE;
 use fltk::{prelude::*, *};
 use std::cell::RefCell;
 use std::rc::Rc;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+static FIND_SEQ: AtomicU64 = AtomicU64::new(0);
+
+// Counting copies the whole text, so for large buffers it waits until typing pauses
+fn update_count(mut status: frame::Frame, val: String, reg_val: bool) {
+    let seq = FIND_SEQ.fetch_add(1, Ordering::Relaxed) + 1;
+    let count = {
+        let status = status.clone();
+        move || {
+            if FIND_SEQ.load(Ordering::Relaxed) != seq {
+                return;
+            }
+            let val = val.clone();
+            let mut status = status.clone();
+            STATE.with(move |s| {
+                if let Some(buf) = s.buf().as_ref() {
+                    let text = buf.text();
+                    if reg_val {
+                        if let Ok(re) = regex::Regex::new(&val) {
+                            let total = re.find_iter(&text).count();
+                            status.set_label(&format!("{}", total));
+                        }
+                    } else {
+                        let total = text.match_indices(&val).count();
+                        status.set_label(&format!("{}", total));
+                    }
+                }
+            });
+        }
+    };
+    let large = STATE.with(|s| {
+        s.buf()
+            .map(|b| crate::settings::get().is_large(b.length() as usize))
+            .unwrap_or(false)
+    });
+    if large {
+        status.set_label("...");
+        app::add_timeout3(0.3, move |_| count());
+    } else {
+        count();
+    }
+}
+
+// Byte ranges of the matches of a pattern, an invalid regex matches nothing
+pub fn find_matches(text: &str, val: &str, reg_val: bool) -> Vec<std::ops::Range<usize>> {
+    if val.is_empty() {
+        return Vec::new();
+    }
+    if reg_val {
+        match regex::Regex::new(val) {
+            Ok(re) => re.find_iter(text).map(|m| m.range()).collect(),
+            Err(_) => Vec::new(),
+        }
+    } else {
+        text.match_indices(val)
+            .map(|(i, m)| i..i + m.len())
+            .collect()
+    }
+}
 
 pub struct FindDialog {
     win: window::Window,
@@ -18,9 +78,11 @@ impl FindDialog {
         row.set_margin(10);
         let f = frame::Frame::default().with_label("Find:");
         row.fixed(&f, 30);
-        let mut i = input::Input::default();
+        let mut i = input::Input::default().with_id("find_input");
         i.set_trigger(enums::CallbackTrigger::Changed);
-        let mut reg = button::ToggleButton::default().with_label(".*");
+        let mut reg = button::ToggleButton::default()
+            .with_label(".*")
+            .with_id("find_regex");
         reg.set_selection_color(reg.color().lighter());
         reg.set_tooltip("Use regex");
         row.fixed(&reg, 30);
@@ -42,23 +104,10 @@ impl FindDialog {
                 } else {
                     i.set_text_color(enums::Color::Foreground);
                 }
+                // an incremental search follows the typing, see emacs.rs
+                crate::emacs::isearch_update(&val, reg_val);
                 if !val.is_empty() {
-                    let val2 = val.clone();
-                    let mut status2 = status.clone();
-                    STATE.with(move |s| {
-                        if let Some(buf) = s.buf().as_ref() {
-                            let text = buf.text();
-                            if reg_val {
-                                if let Ok(re) = regex::Regex::new(&val2) {
-                                    let total = re.find_iter(&text).count();
-                                    status2.set_label(&format!("{}", total));
-                                }
-                            } else {
-                                let total = text.match_indices(&val2).count();
-                                status2.set_label(&format!("{}", total));
-                            }
-                        }
-                    });
+                    update_count(status.clone(), val, reg_val);
                     // reset the navigation index on pattern change
                     *idx_rc.borrow_mut() = 0;
                 } else {
@@ -85,22 +134,7 @@ impl FindDialog {
                     i.set_text_color(enums::Color::Foreground);
                 }
                 if !val.is_empty() {
-                    let val2 = val.clone();
-                    let mut status2 = status.clone();
-                    STATE.with(move |s| {
-                        if let Some(buf) = s.buf().as_ref() {
-                            let text = buf.text();
-                            if reg_val {
-                                if let Ok(re) = regex::Regex::new(&val2) {
-                                    let total = re.find_iter(&text).count();
-                                    status2.set_label(&format!("{}", total));
-                                }
-                            } else {
-                                let total = text.match_indices(&val2).count();
-                                status2.set_label(&format!("{}", total));
-                            }
-                        }
-                    });
+                    update_count(status.clone(), val, reg_val);
                     *idx_rc.borrow_mut() = 0;
                 } else {
                     status.set_label("");
@@ -126,69 +160,54 @@ impl FindDialog {
                     status.set_label("err");
                     return;
                 }
+                crate::macros::record_find(&val, reg_val, true);
                 let val2 = val.clone();
                 let mut status2 = status.clone();
                 let idx2 = idx_rc.clone();
                 STATE.with(move |s| {
                     if let Some(buf) = s.buf().as_mut() {
-                        let text = buf.text();
-                        if reg_val {
-                            if let Ok(re) = regex::Regex::new(&val2) {
-                                let v: Vec<_> = re.find_iter(&text).map(|m| m.range()).collect();
-                                if !v.is_empty() {
-                                    let mut idx = idx2.borrow_mut();
-                                    if *idx >= v.len() {
-                                        *idx = 0;
-                                    }
-                                    let curr = &v[*idx];
-                                    let mut ed: text::TextEditor = s.current_editor().unwrap();
-                                    buf.select(curr.start as i32, curr.end as i32);
-                                    ed.scroll(ed.count_lines(0, curr.start as i32, true), 0);
-                                    status2.set_label(&format!("{}/{}", *idx + 1, v.len()));
-                                    *idx += 1;
-                                    if *idx == v.len() {
-                                        *idx = 0;
-                                    }
-                                } else {
-                                    status2.set_label("0/0");
-                                }
+                        let v = find_matches(&buf.text(), &val2, reg_val);
+                        if !v.is_empty() {
+                            let mut idx = idx2.borrow_mut();
+                            if *idx >= v.len() {
+                                *idx = 0;
                             }
-                        } else {
-                            let v: Vec<_> = text.match_indices(&val2).collect();
-                            if !v.is_empty() {
-                                let mut idx = idx2.borrow_mut();
-                                if *idx >= v.len() {
-                                    *idx = 0;
-                                }
-                                let curr = v[*idx];
-                                let mut ed: text::TextEditor = s.current_editor().unwrap();
-                                buf.select(curr.0 as i32, (curr.0 + val2.len()) as i32);
-                                ed.scroll(ed.count_lines(0, curr.0 as i32, true), 0);
-                                status2.set_label(&format!("{}/{}", *idx + 1, v.len()));
-                                *idx += 1;
-                                if *idx == v.len() {
-                                    *idx = 0;
-                                }
-                            } else {
-                                status2.set_label("0/0");
+                            let curr = &v[*idx];
+                            let mut ed: text::TextEditor = s.current_editor().unwrap();
+                            // a match inside a fold opens it, see folds.rs
+                            let (start, end) =
+                                crate::folds::reveal(&mut ed, curr.start as i32, curr.end as i32);
+                            if let Some(mut shown) = ed.buffer() {
+                                shown.select(start, end);
+                            }
+                            ed.scroll(ed.count_lines(0, start, true), 0);
+                            status2.set_label(&format!("{}/{}", *idx + 1, v.len()));
+                            *idx += 1;
+                            if *idx == v.len() {
+                                *idx = 0;
                             }
+                        } else {
+                            status2.set_label("0/0");
                         }
                     }
                 });
             }
         });
 
-        // Enter key triggers navigation to next by calling button callback
+        // Enter key triggers navigation to next by calling button callback,
+        // or ends an incremental search
         i.handle({
             let mut b = b.clone();
             move |_, ev| match ev {
                 enums::Event::KeyDown => {
                     let k = app::event_key();
                     if k == enums::Key::Enter || k == enums::Key::KPEnter {
-                        b.do_callback();
+                        if !crate::emacs::isearch_finish() {
+                            b.do_callback();
+                        }
                         true
                     } else {
-                        false
+                        crate::emacs::isearch_key()
                     }
                 }
                 _ => false,
@@ -199,6 +218,7 @@ impl FindDialog {
         win.end();
         win.handle(|win, ev| match ev {
             enums::Event::Hide => {
+                crate::emacs::isearch_finish();
                 win.hide();
                 true
             }
@@ -212,6 +232,92 @@ impl FindDialog {
     }
 }
 
+// The range replacements are limited to, the selection of the editor with
+// sel_only
+fn replace_range(ed: &text::TextEditor, len: usize, sel_only: bool) -> (usize, usize) {
+    if sel_only {
+        if let Some((a, b)) = crate::folds::selection(ed) {
+            return (a as usize, b as usize);
+        }
+    }
+    (0usize, len)
+}
+
+// Replaces the next occurrence from the caret, wrapping around, and selects
+// the replacement. False when there's none.
+pub fn replace_next(search: &str, replace: &str, reg_val: bool, sel_only: bool) -> bool {
+    let (search, replace) = (search.to_string(), replace.to_string());
+    STATE.with(move |s| {
+        if let (Some(mut ed), Some(mut buf)) = (s.current_editor(), s.buf()) {
+            let text = buf.text();
+            let from = crate::folds::to_doc(&ed, ed.insert_position());
+            let (range_start, range_end) = replace_range(&ed, text.len(), sel_only);
+            let clamp_from = from.clamp(range_start as i32, range_end as i32) as usize;
+            let found = if reg_val {
+                let re = match regex::Regex::new(&search) {
+                    Ok(re) => re,
+                    Err(_) => return false,
+                };
+                let mut found = re
+                    .find_at(&text, clamp_from)
+                    .filter(|m| m.start() < range_end);
+                if found.is_none() {
+                    found = re
+                        .find_at(&text, range_start)
+                        .filter(|m| m.start() < range_end);
+                }
+                found.map(|m| {
+                    let rep = re.replace(&text[m.start()..m.end()], replace.as_str());
+                    (m.start(), m.end(), rep.into_owned())
+                })
+            } else {
+                let mut off = None;
+                if let Some(i) = text[clamp_from..range_end].find(&search) {
+                    off = Some(clamp_from + i);
+                } else if let Some(i) = text.find(&search) {
+                    if i >= range_start && i < range_end {
+                        off = Some(i);
+                    }
+                }
+                off.map(|start| (start, start + search.len(), replace.clone()))
+            };
+            if let Some((start, end, rep)) = found {
+                buf.replace(start as i32, end as i32, &rep);
+                let new_end = start + rep.len();
+                let (start, new_end) = crate::folds::reveal(&mut ed, start as i32, new_end as i32);
+                ed.scroll(ed.count_lines(0, start, true), 0);
+                if let Some(mut shown) = ed.buffer() {
+                    shown.select(start, new_end);
+                }
+                ed.set_insert_position(new_end);
+                return true;
+            }
+        }
+        false
+    })
+}
+
+// Replaces every occurrence as a single undo step, returns how many
+pub fn replace_all(search: &str, replace: &str, reg_val: bool, sel_only: bool) -> usize {
+    let (search, replace) = (search.to_string(), replace.to_string());
+    STATE.with(move |s| {
+        let mut replaced = 0;
+        if let (Some(ed), Some(mut buf)) = (s.current_editor(), s.buf()) {
+            let text = buf.text();
+            let (range_start, range_end) = replace_range(&ed, text.len(), sel_only);
+            let sub = &text[range_start..range_end];
+            let (nsub, n) = crate::utils::replace_matches(sub, &search, &replace, reg_val, false)
+                .unwrap_or_default();
+            if n > 0 {
+                buf.replace(range_start as i32, range_end as i32, &nsub);
+            }
+            replaced = n;
+            s.was_modified(true);
+        }
+        replaced
+    })
+}
+
 pub struct ReplaceDialog {
     win: window::Window,
 }
@@ -270,7 +376,6 @@ impl ReplaceDialog {
             let in_sel_btn = in_sel_btn.clone();
             move |_| {
                 let search = search.value();
-                let replace = replace.value();
                 let reg_val = reg.value();
                 if search.is_empty() {
                     return;
@@ -279,77 +384,15 @@ impl ReplaceDialog {
                     return;
                 }
                 let sel_only = in_sel_btn.value();
-                STATE.with({
-                    move |s| {
-                        if let (Some(mut ed), Some(mut buf)) = (s.current_editor(), s.buf()) {
-                            let text = buf.text();
-                            let from = ed.insert_position();
-                            let (range_start, range_end) = if sel_only {
-                                if let Some((a, b)) = buf.selection_position() {
-                                    let (a, b) = if a <= b {
-                                        (a as usize, b as usize)
-                                    } else {
-                                        (b as usize, a as usize)
-                                    };
-                                    (a, b)
-                                } else {
-                                    (0usize, text.len())
-                                }
-                            } else {
-                                (0usize, text.len())
-                            };
-                            let clamp_from =
-                                from.clamp(range_start as i32, range_end as i32) as usize;
-                            if reg_val {
-                                if let Ok(re) = regex::Regex::new(&search) {
-                                    let mut found = re
-                                        .find_at(&text, clamp_from)
-                                        .filter(|m| m.start() < range_end);
-                                    if found.is_none() {
-                                        found = re
-                                            .find_at(&text, range_start)
-                                            .filter(|m| m.start() < range_end);
-                                    }
-                                    if let Some(m) = found {
-                                        let start = m.start();
-                                        let end = m.end();
-                                        let rep_cow =
-                                            re.replace(&text[start..end], replace.as_str());
-                                        buf.replace(start as i32, end as i32, rep_cow.as_ref());
-                                        let new_end = start + rep_cow.len();
-                                        ed.scroll(ed.count_lines(0, start as i32, true), 0);
-                                        buf.select(start as i32, new_end as i32);
-                                        ed.set_insert_position(new_end as i32);
-                                    }
-                                }
-                            } else {
-                                let mut off = None;
-                                if let Some(i) = text[clamp_from..range_end].find(&search) {
-                                    off = Some(clamp_from + i);
-                                } else if let Some(i) = text.find(&search) {
-                                    if i >= range_start && i < range_end {
-                                        off = Some(i);
-                                    }
-                                }
-                                if let Some(start) = off {
-                                    let end = start + search.len();
-                                    buf.replace(start as i32, end as i32, &replace);
-                                    let new_end = start + replace.len();
-                                    ed.scroll(ed.count_lines(0, start as i32, true), 0);
-                                    buf.select(start as i32, new_end as i32);
-                                    ed.set_insert_position(new_end as i32);
-                                }
-                            }
-                        }
-                    }
-                });
+                let replace = replace.value();
+                crate::macros::record_replace(&search, &replace, reg_val, sel_only, false);
+                replace_next(&search, &replace, reg_val, sel_only);
             }
         });
 
         // Replace all occurrences as a single undo step
         b_all.set_callback(move |_| {
             let search = search.value();
-            let replace = replace.value();
             let reg_val = reg.value();
             if search.is_empty() {
                 return;
@@ -357,54 +400,11 @@ impl ReplaceDialog {
             if reg_val && regex::Regex::new(&search).is_err() {
                 return;
             }
-            let in_sel_btn = in_sel_btn.clone();
             let sel_only = in_sel_btn.value();
-            let mut status2 = status.clone();
-            STATE.with({
-                move |s| {
-                    if let Some(buf) = s.buf().as_mut() {
-                        let text = buf.text();
-                        let (range_start, range_end) = if sel_only {
-                            if let Some((a, b)) = buf.selection_position() {
-                                let (a, b) = if a <= b {
-                                    (a as usize, b as usize)
-                                } else {
-                                    (b as usize, a as usize)
-                                };
-                                (a, b)
-                            } else {
-                                (0usize, text.len())
-                            }
-                        } else {
-                            (0usize, text.len())
-                        };
-                        let mut replaced = 0usize;
-                        if reg_val {
-                            if let Ok(re) = regex::Regex::new(&search) {
-                                let sub = &text[range_start..range_end];
-                                replaced = re.find_iter(sub).count();
-                                if replaced > 0 {
-                                    let nsub = re.replace_all(sub, replace.as_str());
-                                    buf.replace(
-                                        range_start as i32,
-                                        range_end as i32,
-                                        nsub.as_ref(),
-                                    );
-                                }
-                            }
-                        } else {
-                            let sub = &text[range_start..range_end];
-                            replaced = sub.matches(&search).count();
-                            if replaced > 0 {
-                                let nsub = sub.replace(&search, &replace);
-                                buf.replace(range_start as i32, range_end as i32, &nsub);
-                            }
-                        }
-                        status2.set_label(&format!("Replaced: {}", replaced));
-                        s.was_modified(true);
-                    }
-                }
-            });
+            let replace = replace.value();
+            crate::macros::record_replace(&search, &replace, reg_val, sel_only, true);
+            let replaced = replace_all(&search, &replace, reg_val, sel_only);
+            status.clone().set_label(&format!("Replaced: {}", replaced));
         });
         win.handle(|win, ev| match ev {
             enums::Event::Hide => {
@@ -460,3 +460,634 @@ impl CompletionDialog {
         Self { win }
     }
 }
+
+// Tab switcher shown while Ctrl is held after Ctrl+Tab, spawned hidden at startup
+pub struct SwitcherDialog {
+    win: window::Window,
+}
+
+impl SwitcherDialog {
+    pub fn new() -> Self {
+        let mut win = window::Window::new(0, 0, 420, 240, "").with_id("switcher");
+        win.set_border(false);
+        let mut list = browser::HoldBrowser::new(0, 0, 420, 240, None).with_id("switcher_list");
+        list.set_column_widths(&[160]);
+        win.end();
+        list.set_callback(|_| {
+            if app::event() == enums::Event::Released {
+                crate::tabs::commit();
+            }
+        });
+        list.handle(|_, ev| match ev {
+            enums::Event::KeyDown => match app::event_key() {
+                enums::Key::Tab => {
+                    crate::tabs::cycle(!app::event_state().contains(enums::Shortcut::Shift));
+                    true
+                }
+                enums::Key::Enter | enums::Key::KPEnter => {
+                    crate::tabs::commit();
+                    true
+                }
+                _ => false,
+            },
+            // switch once Ctrl is released
+            enums::Event::KeyUp => {
+                if matches!(
+                    app::event_key(),
+                    enums::Key::ControlL | enums::Key::ControlR
+                ) {
+                    crate::tabs::commit();
+                    true
+                } else {
+                    false
+                }
+            }
+            _ => false,
+        });
+        // Escape hides it through the default window callback
+        win.hide();
+        Self { win }
+    }
+}
+
+// Command palette, spawned hidden at startup, see palette.rs
+pub struct PaletteDialog {
+    win: window::Window,
+}
+
+impl PaletteDialog {
+    pub fn new() -> Self {
+        let mut win = window::Window::new(0, 0, 560, 330, "").with_id("palette");
+        win.set_border(false);
+        let mut input = input::Input::new(0, 0, 560, 30, None).with_id("palette_input");
+        let mut list = browser::HoldBrowser::new(0, 30, 560, 300, None).with_id("palette_list");
+        list.set_column_widths(&[400]);
+        // Go to File previews the highlighted file below the list
+        let mut preview = text::TextDisplay::new(0, 330, 560, 260, None).with_id("palette_preview");
+        preview.set_buffer(text::TextBuffer::default());
+        preview.hide();
+        win.end();
+        // the preview is shown by growing the window, nothing is scaled
+        win.make_resizable(false);
+        input.set_trigger(enums::CallbackTrigger::Changed);
+        input.set_callback(|_| crate::palette::filter());
+        input.handle(|_, ev| match ev {
+            enums::Event::KeyDown => match app::event_key() {
+                enums::Key::Up => {
+                    crate::palette::move_selection(-1);
+                    true
+                }
+                enums::Key::Down => {
+                    crate::palette::move_selection(1);
+                    true
+                }
+                enums::Key::PageUp => {
+                    crate::palette::move_selection(-10);
+                    true
+                }
+                enums::Key::PageDown => {
+                    crate::palette::move_selection(10);
+                    true
+                }
+                enums::Key::Enter | enums::Key::KPEnter => {
+                    crate::palette::accept();
+                    true
+                }
+                _ => false,
+            },
+            _ => false,
+        });
+        list.set_callback(|_| {
+            if app::event() == enums::Event::Released {
+                crate::palette::accept();
+            } else {
+                crate::palette::update_preview();
+            }
+        });
+        // Escape hides it through the default window callback
+        win.hide();
+        Self { win }
+    }
+}
+
+pub struct VimCommandLine {
+    win: window::Window,
+}
+
+impl VimCommandLine {
+    pub fn new() -> Self {
+        let mut win = window::Window::new(0, 0, 560, 30, "").with_id("vim_cmd");
+        win.set_border(false);
+        let mut prefix = frame::Frame::new(0, 0, 20, 30, ":").with_id("vim_cmd_prefix");
+        prefix.set_frame(enums::FrameType::FlatBox);
+        let mut input = input::Input::new(20, 0, 540, 30, None).with_id("vim_cmd_input");
+        win.end();
+        win.resizable(&input);
+        input.handle(|_, ev| match ev {
+            enums::Event::KeyDown => match app::event_key() {
+                enums::Key::Enter | enums::Key::KPEnter => {
+                    crate::vim::run_prompt();
+                    true
+                }
+                _ => false,
+            },
+            _ => false,
+        });
+        // Escape goes back to the editor
+        win.set_callback(|_| crate::vim::close_prompt());
+        win.hide();
+        Self { win }
+    }
+}
+
+fn settings_row(col: &mut group::Flex, label: &str) -> group::Flex {
+    let mut row = group::Flex::default().row();
+    let f = frame::Frame::default()
+        .with_label(label)
+        .with_align(enums::Align::Left | enums::Align::Inside);
+    row.fixed(&f, 140);
+    col.fixed(&row, 30);
+    row
+}
+
+pub struct SettingsDialog {
+    win: window::Window,
+}
+
+impl SettingsDialog {
+    pub fn new() -> Self {
+        let mut win = window::Window::default()
+            .with_size(420, 660)
+            .with_label("Settings")
+            .with_id("settings");
+        let mut col = group::Flex::default_fill().column();
+        col.set_margin(10);
+        let row = settings_row(&mut col, "Theme:");
+        let mut theme = menu::Choice::default();
+        for (name, _) in crate::settings::THEMES {
+            theme.add_choice(name);
+        }
+        row.end();
+        let row = settings_row(&mut col, "Font:");
+        let mut font = menu::Choice::default();
+        for (name, _) in crate::settings::FONTS {
+            font.add_choice(name);
+        }
+        row.end();
+        let row = settings_row(&mut col, "Font size:");
+        let mut font_size = misc::Spinner::default();
+        font_size.set_range(6., 48.);
+        row.end();
+        let row = settings_row(&mut col, "Tab width:");
+        let mut tab_distance = misc::Spinner::default();
+        tab_distance.set_range(1., 16.);
+        row.end();
+        let row = settings_row(&mut col, "Line number width:");
+        let mut linenumber_width = misc::Spinner::default();
+        linenumber_width.set_range(0., 120.);
+        row.end();
+        let row = settings_row(&mut col, "LSP debounce (ms):");
+        let mut debounce = misc::Spinner::default();
+        debounce.set_range(0., 5000.);
+        debounce.set_step(50.);
+        row.end();
+        let row = settings_row(&mut col, "rust-analyzer path:");
+        let mut ra_path = input::Input::default();
+        ra_path.set_tooltip("Takes effect on the next start");
+        row.end();
+        let row = settings_row(&mut col, "Auto save:");
+        let mut autosave = menu::Choice::default();
+        for (name, _) in crate::settings::AUTOSAVE_MODES {
+            autosave.add_choice(name);
+        }
+        row.end();
+        let row = settings_row(&mut col, "Auto save interval (s):");
+        let mut autosave_interval = misc::Spinner::default();
+        autosave_interval.set_range(1., 3600.);
+        row.end();
+        let row = settings_row(&mut col, "Backup directory:");
+        let mut backup_dir = input::Input::default();
+        backup_dir.set_tooltip("Leave empty to keep <file>.bak next to the file");
+        row.end();
+        let row = settings_row(&mut col, "");
+        let backup = button::CheckButton::default().with_label("Keep a backup on save");
+        row.end();
+        let row = settings_row(&mut col, "Large file size (KB):");
+        let mut large_file_kb = misc::Spinner::default();
+        large_file_kb.set_range(1., 1048576.);
+        large_file_kb.set_step(256.);
+        large_file_kb.set_tooltip("Applies to files opened afterwards");
+        row.end();
+        let row = settings_row(&mut col, "");
+        let large_file_lsp = button::CheckButton::default().with_label("Sync large files with LSP");
+        row.end();
+        let row = settings_row(&mut col, "Key bindings:");
+        let mut key_profile = menu::Choice::default();
+        for (name, _) in crate::settings::KEY_PROFILES {
+            key_profile.add_choice(name);
+        }
+        key_profile.set_tooltip("Default bindings, keymap.toml still overrides them");
+        row.end();
+        let row = settings_row(&mut col, "");
+        let vim = button::CheckButton::default().with_label("Vim emulation");
+        row.end();
+        let row = settings_row(&mut col, "");
+        let auto_close =
+            button::CheckButton::default().with_label("Auto-close brackets and quotes");
+        row.end();
+        let row = settings_row(&mut col, "");
+        let auto_indent = button::CheckButton::default().with_label("Auto-indent on Enter");
+        row.end();
+        let row = settings_row(&mut col, "");
+        let mut lsp_enabled = button::CheckButton::default().with_label("Enable LSP");
+        lsp_enabled.set_tooltip("Takes effect on the next start");
+        row.end();
+        frame::Frame::default();
+        let mut row = group::Flex::default().row();
+        let mut key_bindings = button::Button::default().with_label("Key Bindings...");
+        key_bindings.set_callback(|_| keymap_dialog());
+        row.fixed(&key_bindings, 120);
+        frame::Frame::default();
+        let mut ok = button::Button::default().with_label("OK");
+        let mut apply = button::Button::default().with_label("Apply");
+        let mut cancel = button::Button::default().with_label("Cancel");
+        row.fixed(&ok, 80);
+        row.fixed(&apply, 80);
+        row.fixed(&cancel, 80);
+        row.end();
+        col.fixed(&row, 30);
+        col.end();
+        win.end();
+
+        let read = {
+            let theme = theme.clone();
+            let font = font.clone();
+            let font_size = font_size.clone();
+            let tab_distance = tab_distance.clone();
+            let linenumber_width = linenumber_width.clone();
+            let debounce = debounce.clone();
+            let ra_path = ra_path.clone();
+            let lsp_enabled = lsp_enabled.clone();
+            let autosave = autosave.clone();
+            let autosave_interval = autosave_interval.clone();
+            let backup = backup.clone();
+            let backup_dir = backup_dir.clone();
+            let large_file_kb = large_file_kb.clone();
+            let large_file_lsp = large_file_lsp.clone();
+            let vim = vim.clone();
+            let key_profile = key_profile.clone();
+            let auto_close = auto_close.clone();
+            let auto_indent = auto_indent.clone();
+            move || crate::settings::Settings {
+                theme: theme.choice().unwrap_or_default(),
+                font: font.choice().unwrap_or_default(),
+                font_size: font_size.value() as i32,
+                tab_distance: tab_distance.value() as i32,
+                linenumber_width: linenumber_width.value() as i32,
+                lsp_enabled: lsp_enabled.is_checked(),
+                lsp_debounce_ms: debounce.value() as u64,
+                ra_path: ra_path.value(),
+                autosave: crate::settings::AUTOSAVE_MODES
+                    .get(autosave.value().max(0) as usize)
+                    .map(|m| m.1)
+                    .unwrap_or(crate::settings::AutoSave::Off),
+                autosave_interval: autosave_interval.value() as i32,
+                backup: backup.is_checked(),
+                backup_dir: backup_dir.value(),
+                large_file_kb: large_file_kb.value() as i32,
+                large_file_lsp: large_file_lsp.is_checked(),
+                vim: vim.is_checked(),
+                key_profile: crate::settings::KEY_PROFILES
+                    .get(key_profile.value().max(0) as usize)
+                    .map(|p| p.1)
+                    .unwrap_or(crate::settings::KeyProfile::Default),
+                auto_close: auto_close.is_checked(),
+                auto_indent: auto_indent.is_checked(),
+            }
+        };
+        apply.set_callback({
+            let read = read.clone();
+            move |_| crate::settings::set(read())
+        });
+        ok.set_callback({
+            let mut win = win.clone();
+            move |_| {
+                crate::settings::set(read());
+                win.hide();
+            }
+        });
+        cancel.set_callback({
+            let mut win = win.clone();
+            move |_| win.hide()
+        });
+        // Populate the widgets from the current settings every time the dialog is shown
+        win.handle(move |win, ev| match ev {
+            enums::Event::Show => {
+                let s = crate::settings::stored();
+                let find =
+                    |list: &[&str], v: &str| list.iter().position(|n| *n == v).unwrap_or(0) as i32;
+                let themes: Vec<_> = crate::settings::THEMES.iter().map(|t| t.0).collect();
+                let fonts: Vec<_> = crate::settings::FONTS.iter().map(|f| f.0).collect();
+                theme.set_value(find(&themes, &s.theme));
+                font.set_value(find(&fonts, &s.font));
+                font_size.set_value(s.font_size as f64);
+                tab_distance.set_value(s.tab_distance as f64);
+                linenumber_width.set_value(s.linenumber_width as f64);
+                debounce.set_value(s.lsp_debounce_ms as f64);
+                ra_path.set_value(&s.ra_path);
+                lsp_enabled.set_checked(s.lsp_enabled);
+                let modes: Vec<_> = crate::settings::AUTOSAVE_MODES
+                    .iter()
+                    .map(|m| m.0)
+                    .collect();
+                autosave.set_value(find(&modes, s.autosave_name()));
+                autosave_interval.set_value(s.autosave_interval as f64);
+                backup.set_checked(s.backup);
+                backup_dir.set_value(&s.backup_dir);
+                large_file_kb.set_value(s.large_file_kb as f64);
+                large_file_lsp.set_checked(s.large_file_lsp);
+                vim.set_checked(s.vim);
+                let profiles: Vec<_> = crate::settings::KEY_PROFILES.iter().map(|p| p.0).collect();
+                key_profile.set_value(find(&profiles, s.key_profile_name()));
+                auto_close.set_checked(s.auto_close);
+                auto_indent.set_checked(s.auto_indent);
+                false
+            }
+            enums::Event::Close => {
+                win.hide();
+                true
+            }
+            _ => false,
+        });
+        Self { win }
+    }
+}
+
+// Lists the key bindings of every command for editing, conflicting ones in red.
+// Changes are written to keymap.toml, see keymap.rs.
+pub fn keymap_dialog() {
+    use crate::keymap;
+    let mut win = window::Window::default()
+        .with_size(680, 540)
+        .with_label("Key Bindings");
+    let mut col = group::Flex::default_fill().column();
+    col.set_margin(5);
+    let mut list = browser::HoldBrowser::default();
+    list.set_column_widths(&[260, 200]);
+    let mut row = group::Flex::default().row();
+    let mut keys = input::Input::default();
+    keys.set_tooltip("Chords separated by commas, e.g. Ctrl+K Ctrl+C, Ctrl+/");
+    let mut record = button::ToggleButton::default().with_label("Record");
+    record.set_tooltip("Type the keys to bind instead of their names");
+    let mut set = button::Button::default().with_label("Set");
+    let mut reset = button::Button::default().with_label("Reset");
+    row.fixed(&record, 80);
+    row.fixed(&set, 80);
+    row.fixed(&reset, 80);
+    row.end();
+    col.fixed(&row, 30);
+    let mut conflicts = text::TextDisplay::default();
+    conflicts.set_buffer(text::TextBuffer::default());
+    col.fixed(&conflicts, 100);
+    let mut row = group::Flex::default().row();
+    let mut open = button::Button::default().with_label("Open keymap.toml");
+    frame::Frame::default();
+    let mut close = button::Button::default().with_label("Close");
+    row.fixed(&open, 160);
+    row.fixed(&close, 80);
+    row.end();
+    col.fixed(&row, 30);
+    col.end();
+    win.end();
+
+    let ids: Rc<RefCell<Vec<String>>> = Rc::new(RefCell::new(Vec::new()));
+    let mut fill = {
+        let ids = ids.clone();
+        let mut list = list.clone();
+        let conflicts = conflicts.clone();
+        move || {
+            let line = list.value();
+            let conflicting = keymap::conflicting_ids();
+            let mut ids = ids.borrow_mut();
+            ids.clear();
+            list.clear();
+            for cmd in crate::commands::all() {
+                let f = if conflicting.contains(&cmd.id) {
+                    "@C1@."
+                } else {
+                    "@."
+                };
+                let changed = if keymap::is_overridden(&cmd.id) {
+                    " *"
+                } else {
+                    ""
+                };
+                list.add(&format!(
+                    "{f}{}\t{f}{}\t{f}{}{}",
+                    cmd.title,
+                    cmd.id,
+                    keymap::label(&cmd.id),
+                    changed
+                ));
+                ids.push(cmd.id);
+            }
+            if line > 0 {
+                list.select(line);
+            }
+            if let Some(mut buf) = conflicts.buffer() {
+                buf.set_text(&keymap::conflicts().join("\n"));
+            }
+        }
+    };
+    fill();
+    let selected = {
+        let ids = ids.clone();
+        let list = list.clone();
+        // nothing when no line is selected
+        move || match list.value() {
+            0 => None,
+            line => ids.borrow().get(line as usize - 1).cloned(),
+        }
+    };
+    list.set_callback({
+        let selected = selected.clone();
+        let mut keys = keys.clone();
+        move |_| {
+            if let Some(id) = selected() {
+                keys.set_value(&keymap::label(&id));
+            }
+        }
+    });
+    keys.handle({
+        let record = record.clone();
+        move |k, ev| {
+            if ev != enums::Event::KeyDown || !record.is_toggled() {
+                return false;
+            }
+            let press = keymap::KeyPress::from_event();
+            if !press.is_modifier() {
+                let v = k.value();
+                let sep = if v.is_empty() { "" } else { " " };
+                k.set_value(&format!("{}{}{}", v, sep, press));
+            }
+            true
+        }
+    });
+    record.set_callback({
+        let mut keys = keys.clone();
+        move |r| {
+            if r.is_toggled() {
+                keys.set_value("");
+                keys.take_focus().ok();
+            }
+        }
+    });
+    set.set_callback({
+        let selected = selected.clone();
+        let keys = keys.clone();
+        let mut record = record.clone();
+        let mut fill = fill.clone();
+        move |_| {
+            let id = match selected() {
+                Some(id) => id,
+                None => return,
+            };
+            record.set_value(false);
+            let mut chords = Vec::new();
+            for c in keys
+                .value()
+                .split(',')
+                .map(str::trim)
+                .filter(|c| !c.is_empty())
+            {
+                match keymap::parse_chord(c) {
+                    Some(chord) => chords.push(chord),
+                    None => {
+                        dialog::alert_default(&format!("Can't parse {}", c));
+                        return;
+                    }
+                }
+            }
+            if let Err(e) = keymap::set_bindings(&id, Some(chords)) {
+                dialog::alert_default(&format!("Could not save keymap.toml: {}", e));
+            }
+            fill();
+        }
+    });
+    reset.set_callback({
+        let mut keys = keys.clone();
+        let mut fill = fill.clone();
+        move |_| {
+            if let Some(id) = selected() {
+                if let Err(e) = keymap::set_bindings(&id, None) {
+                    dialog::alert_default(&format!("Could not save keymap.toml: {}", e));
+                }
+                keys.set_value(&keymap::label(&id));
+                fill();
+            }
+        }
+    });
+    open.set_callback({
+        let mut win = win.clone();
+        move |_| {
+            if let Err(e) = keymap::ensure_file() {
+                dialog::alert_default(&format!("Could not create keymap.toml: {}", e));
+                return;
+            }
+            if let Some(path) = keymap::path() {
+                win.hide();
+                crate::state::open(path);
+            }
+        }
+    });
+    close.set_callback({
+        let mut win = win.clone();
+        move |_| win.hide()
+    });
+    win.make_modal(true);
+    win.show();
+    while win.shown() {
+        app::wait();
+    }
+    window::Window::delete(win);
+}
+
+// Modal view of the differences between a buffer and its file on disk.
+// Returns true if the user chose to reload the file.
+pub fn diff_dialog(title: &str, mine: &str, disk: &str) -> bool {
+    use crate::utils::{line_diff, DiffOp};
+    let mut win = window::Window::default()
+        .with_size(760, 520)
+        .with_label(title);
+    let mut col = group::Flex::default_fill().column();
+    col.set_margin(5);
+    let legend = frame::Frame::default()
+        .with_label("- in editor   |   + on disk")
+        .with_align(enums::Align::Left | enums::Align::Inside);
+    col.fixed(&legend, 20);
+    let mut disp = text::TextDisplay::default();
+    let mut row = group::Flex::default().row();
+    frame::Frame::default();
+    let mut reload = button::Button::default().with_label("Reload");
+    let mut keep = button::Button::default().with_label("Keep Mine");
+    row.fixed(&reload, 100);
+    row.fixed(&keep, 100);
+    row.end();
+    col.fixed(&row, 30);
+    col.end();
+    win.end();
+
+    let mut txt = String::new();
+    let mut styles = String::new();
+    for (op, line) in line_diff(mine, disk) {
+        let (prefix, style) = match op {
+            DiffOp::Same => ("  ", 'A'),
+            DiffOp::Removed => ("- ", 'B'),
+            DiffOp::Added => ("+ ", 'C'),
+        };
+        let l = format!("{}{}\n", prefix, line);
+        styles.push_str(&style.to_string().repeat(l.len()));
+        txt.push_str(&l);
+    }
+    let font = crate::settings::get().font();
+    let entries: Vec<text::StyleTableEntry> = [
+        enums::Color::Foreground,
+        enums::Color::Red,
+        enums::Color::Green,
+    ]
+    .iter()
+    .map(|c| text::StyleTableEntry {
+        color: *c,
+        font,
+        size: app::font_size(),
+    })
+    .collect();
+    disp.set_buffer(text::TextBuffer::default());
+    disp.buffer().unwrap().set_text(&txt);
+    let mut sbuf = text::TextBuffer::default();
+    sbuf.set_text(&styles);
+    disp.set_highlight_data(sbuf, entries);
+    disp.set_text_font(font);
+
+    let choice = Rc::new(RefCell::new(false));
+    reload.set_callback({
+        let choice = choice.clone();
+        let mut win = win.clone();
+        move |_| {
+            *choice.borrow_mut() = true;
+            win.hide();
+        }
+    });
+    keep.set_callback({
+        let mut win = win.clone();
+        move |_| win.hide()
+    });
+    win.make_modal(true);
+    win.show();
+    while win.shown() {
+        app::wait();
+    }
+    window::Window::delete(win);
+    let reload = *choice.borrow();
+    reload
+}
@@ -0,0 +1,739 @@
+// Emacs key profile, chosen in the settings: the bindings below replace the
+// default ones (see keymap.rs). Motions extend the region while the mark is
+// active, kills go to a kill ring that also fills the clipboard, and C-s/C-r
+// search incrementally through the find dialog.
+use crate::{cbs, dialogs, palette, state::STATE, tabs, utils};
+use fltk::{app, button, enums::*, input, prelude::*, text, window};
+use std::cell::RefCell;
+
+const KILL_RING_MAX: usize = 60;
+
+// Command id and chord, a command may have several
+pub const BINDINGS: &[(&str, &str)] = &[
+    ("emacs.beginning_of_line", "Ctrl+A"),
+    ("emacs.end_of_line", "Ctrl+E"),
+    ("emacs.forward_char", "Ctrl+F"),
+    ("emacs.backward_char", "Ctrl+B"),
+    ("emacs.next_line", "Ctrl+N"),
+    ("emacs.previous_line", "Ctrl+P"),
+    ("emacs.forward_word", "Alt+F"),
+    ("emacs.backward_word", "Alt+B"),
+    ("emacs.beginning_of_buffer", "Alt+Shift+,"),
+    ("emacs.end_of_buffer", "Alt+Shift+."),
+    ("emacs.delete_char", "Ctrl+D"),
+    ("emacs.set_mark", "Ctrl+Space"),
+    ("emacs.exchange_point_and_mark", "Ctrl+X Ctrl+X"),
+    ("emacs.mark_whole_buffer", "Ctrl+X H"),
+    ("emacs.keyboard_quit", "Ctrl+G"),
+    ("emacs.kill_line", "Ctrl+K"),
+    ("emacs.kill_region", "Ctrl+W"),
+    ("emacs.copy_region", "Alt+W"),
+    ("emacs.yank", "Ctrl+Y"),
+    ("emacs.yank_pop", "Alt+Y"),
+    ("emacs.isearch_forward", "Ctrl+S"),
+    ("emacs.isearch_backward", "Ctrl+R"),
+    ("emacs.switch_buffer", "Ctrl+X B"),
+    ("file.open", "Ctrl+X Ctrl+F"),
+    ("file.save", "Ctrl+X Ctrl+S"),
+    ("file.save_as", "Ctrl+X Ctrl+W"),
+    ("file.save_all", "Ctrl+X S"),
+    ("file.close_tab", "Ctrl+X K"),
+    ("file.quit", "Ctrl+X Ctrl+C"),
+    ("edit.undo", "Ctrl+/"),
+    ("edit.undo", "Ctrl+X U"),
+    ("edit.trigger_completion", "Alt+/"),
+    ("view.unsplit", "Ctrl+X 1"),
+    ("view.split_down", "Ctrl+X 2"),
+    ("view.split_right", "Ctrl+X 3"),
+    ("view.cycle_split", "Ctrl+X O"),
+    ("macro.record", "Ctrl+X Shift+9"),
+    ("macro.record", "Ctrl+X Shift+0"),
+    ("macro.play", "Ctrl+X E"),
+    ("view.command_palette", "Alt+X"),
+];
+
+// Registered without a shortcut, the profile binds them
+pub const COMMANDS: &[(&str, &str, fn())] = &[
+    (
+        "emacs.beginning_of_line",
+        "Emacs: Beginning of Line",
+        beginning_of_line,
+    ),
+    ("emacs.end_of_line", "Emacs: End of Line", end_of_line),
+    ("emacs.forward_char", "Emacs: Forward Char", forward_char),
+    ("emacs.backward_char", "Emacs: Backward Char", backward_char),
+    ("emacs.next_line", "Emacs: Next Line", next_line),
+    ("emacs.previous_line", "Emacs: Previous Line", previous_line),
+    ("emacs.forward_word", "Emacs: Forward Word", forward_word),
+    ("emacs.backward_word", "Emacs: Backward Word", backward_word),
+    (
+        "emacs.beginning_of_buffer",
+        "Emacs: Beginning of Buffer",
+        beginning_of_buffer,
+    ),
+    ("emacs.end_of_buffer", "Emacs: End of Buffer", end_of_buffer),
+    ("emacs.delete_char", "Emacs: Delete Char", delete_char),
+    ("emacs.set_mark", "Emacs: Set Mark", set_mark),
+    (
+        "emacs.exchange_point_and_mark",
+        "Emacs: Exchange Point and Mark",
+        exchange_point_and_mark,
+    ),
+    (
+        "emacs.mark_whole_buffer",
+        "Emacs: Mark Whole Buffer",
+        mark_whole_buffer,
+    ),
+    ("emacs.keyboard_quit", "Emacs: Keyboard Quit", keyboard_quit),
+    ("emacs.kill_line", "Emacs: Kill Line", kill_line),
+    ("emacs.kill_region", "Emacs: Kill Region", kill_region),
+    ("emacs.copy_region", "Emacs: Copy Region", copy_region),
+    ("emacs.yank", "Emacs: Yank", yank),
+    ("emacs.yank_pop", "Emacs: Yank Pop", yank_pop),
+    (
+        "emacs.isearch_forward",
+        "Emacs: Incremental Search Forward",
+        isearch_forward,
+    ),
+    (
+        "emacs.isearch_backward",
+        "Emacs: Incremental Search Backward",
+        isearch_backward,
+    ),
+    ("emacs.switch_buffer", "Emacs: Switch Buffer", switch_buffer),
+];
+
+#[derive(Clone, Copy, Debug)]
+struct Mark {
+    view: usize,
+    pos: i32,
+    // Motions extend the region between the mark and the caret
+    active: bool,
+    // Length of the buffer when the region was last updated, typing deactivates it
+    len: i32,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Isearch {
+    view: usize,
+    // Positions in the document, which a folded view shows part of
+    origin: i32,
+    forward: bool,
+    // The current match
+    at: Option<(i32, i32)>,
+}
+
+#[derive(Default)]
+struct Emacs {
+    mark: Option<Mark>,
+    // Newest first
+    ring: Vec<String>,
+    // View, position and buffer length after the last kill, a kill from
+    // there is appended to it
+    last_kill: Option<(usize, i32, i32)>,
+    // View, range and ring index of the last yank, for yank-pop
+    last_yank: Option<(usize, i32, i32, usize)>,
+    isearch: Option<Isearch>,
+    last_search: String,
+}
+
+thread_local! {
+    static EMACS: RefCell<Emacs> = RefCell::new(Emacs::default());
+}
+
+fn view_of(ed: &text::TextEditor) -> usize {
+    ed.as_widget_ptr() as usize
+}
+
+fn with_editor<F: FnOnce(&mut text::TextEditor, &mut text::TextBuffer)>(f: F) {
+    cbs::with_editor(|ed| {
+        if let Some(mut buf) = ed.buffer() {
+            f(ed, &mut buf);
+        }
+    });
+}
+
+fn set_mark_at(ed: &text::TextEditor, buf: &text::TextBuffer, pos: i32, active: bool) {
+    let mark = Mark {
+        view: view_of(ed),
+        pos,
+        active,
+        len: buf.length(),
+    };
+    EMACS.with(|e| e.borrow_mut().mark = Some(mark));
+}
+
+fn deactivate_mark() {
+    EMACS.with(|e| {
+        if let Some(m) = e.borrow_mut().mark.as_mut() {
+            m.active = false;
+        }
+    });
+}
+
+fn mark(ed: &text::TextEditor, buf: &text::TextBuffer) -> Option<Mark> {
+    EMACS
+        .with(|e| e.borrow().mark)
+        .filter(|m| m.view == view_of(ed) && m.pos <= buf.length())
+}
+
+// The mark while the region is active. Typing or selecting something else
+// with the mouse deactivates it.
+fn active_mark(ed: &text::TextEditor, buf: &text::TextBuffer) -> Option<i32> {
+    let m = mark(ed, buf).filter(|m| m.active && m.len == buf.length())?;
+    let p = ed.insert_position();
+    let region = match buf.selection_position() {
+        Some((s, e)) if s != e => Some((s, e)),
+        _ => None,
+    };
+    let expected = if p == m.pos {
+        None
+    } else {
+        Some((p.min(m.pos), p.max(m.pos)))
+    };
+    if region == expected {
+        Some(m.pos)
+    } else {
+        None
+    }
+}
+
+// The selection, or the text between the mark and the caret
+fn region(ed: &text::TextEditor, buf: &text::TextBuffer) -> Option<(i32, i32)> {
+    match buf.selection_position() {
+        Some((s, e)) if s != e => Some((s, e)),
+        _ => {
+            let m = mark(ed, buf)?;
+            let p = ed.insert_position();
+            Some((p.min(m.pos), p.max(m.pos)))
+        }
+    }
+}
+
+// Moves the caret, the active region follows it
+fn motion<F: FnOnce(&mut text::TextEditor, &text::TextBuffer)>(f: F) {
+    with_editor(|ed, buf| {
+        let active = active_mark(ed, buf);
+        f(ed, buf);
+        let p = ed.insert_position();
+        match active {
+            Some(m) if m != p => buf.select(m.min(p), m.max(p)),
+            Some(_) => buf.unselect(),
+            None => {
+                deactivate_mark();
+                buf.unselect();
+            }
+        }
+        ed.show_insert_position();
+    });
+}
+
+fn beginning_of_line() {
+    motion(|ed, buf| ed.set_insert_position(buf.line_start(ed.insert_position())));
+}
+
+fn end_of_line() {
+    motion(|ed, buf| ed.set_insert_position(buf.line_end(ed.insert_position())));
+}
+
+fn forward_char() {
+    motion(|ed, _| {
+        ed.move_right().ok();
+    });
+}
+
+fn backward_char() {
+    motion(|ed, _| {
+        ed.move_left().ok();
+    });
+}
+
+fn next_line() {
+    motion(|ed, _| {
+        ed.move_down().ok();
+    });
+}
+
+fn previous_line() {
+    motion(|ed, _| {
+        ed.move_up().ok();
+    });
+}
+
+fn forward_word() {
+    motion(|ed, _| ed.next_word());
+}
+
+fn backward_word() {
+    motion(|ed, _| ed.previous_word());
+}
+
+// M-< and M-> leave the mark where the caret was
+fn beginning_of_buffer() {
+    motion(|ed, buf| {
+        if active_mark(ed, buf).is_none() {
+            set_mark_at(ed, buf, ed.insert_position(), false);
+        }
+        ed.set_insert_position(0);
+    });
+}
+
+fn end_of_buffer() {
+    motion(|ed, buf| {
+        if active_mark(ed, buf).is_none() {
+            set_mark_at(ed, buf, ed.insert_position(), false);
+        }
+        ed.set_insert_position(buf.length());
+    });
+}
+
+fn delete_char() {
+    cbs::with_editor(|ed| ed.kf_delete());
+}
+
+fn set_mark() {
+    with_editor(|ed, buf| {
+        set_mark_at(ed, buf, ed.insert_position(), true);
+        buf.unselect();
+    });
+}
+
+fn exchange_point_and_mark() {
+    with_editor(|ed, buf| {
+        let m = match mark(ed, buf) {
+            Some(m) => m,
+            None => return,
+        };
+        let p = ed.insert_position();
+        set_mark_at(ed, buf, p, true);
+        ed.set_insert_position(m.pos);
+        if p != m.pos {
+            buf.select(p.min(m.pos), p.max(m.pos));
+        }
+        ed.show_insert_position();
+    });
+}
+
+fn mark_whole_buffer() {
+    with_editor(|ed, buf| {
+        set_mark_at(ed, buf, buf.length(), true);
+        ed.set_insert_position(0);
+        buf.select(0, buf.length());
+        ed.show_insert_position();
+    });
+}
+
+fn keyboard_quit() {
+    with_editor(|ed, buf| {
+        deactivate_mark();
+        buf.unselect();
+        crate::cursors::collapse(ed);
+    });
+}
+
+// Removes a range into the kill ring and the clipboard. Kills right after
+// each other add up to one entry.
+fn kill(ed: &mut text::TextEditor, buf: &mut text::TextBuffer, s: i32, e: i32) {
+    if s >= e {
+        return;
+    }
+    // a folded view shows a copy of the document, the folded lines are
+    // killed along with the range (see folds.rs)
+    let text = crate::folds::text_range(ed, s, e).unwrap_or_default();
+    let view = view_of(ed);
+    let len = buf.length();
+    let top = EMACS.with(|em| {
+        let mut em = em.borrow_mut();
+        if em.last_kill == Some((view, s, len)) && !em.ring.is_empty() {
+            em.ring[0].push_str(&text);
+        } else {
+            em.ring.insert(0, text);
+            em.ring.truncate(KILL_RING_MAX);
+        }
+        em.last_kill = Some((view, s, len - (e - s)));
+        em.last_yank = None;
+        if let Some(m) = em.mark.as_mut() {
+            m.active = false;
+        }
+        em.ring[0].clone()
+    });
+    buf.unselect();
+    crate::folds::replace(ed, s, e, "");
+    ed.set_insert_position(s);
+    ed.show_insert_position();
+    app::copy(&top);
+    ed.do_callback();
+}
+
+// C-k: kills to the end of the line, or the line break when there's only
+// whitespace left
+fn kill_line() {
+    with_editor(|ed, buf| {
+        let p = ed.insert_position();
+        let le = buf.line_end(p);
+        let rest = buf.text_range(p, le).unwrap_or_default();
+        let e = if rest.trim().is_empty() {
+            (le + 1).min(buf.length())
+        } else {
+            le
+        };
+        kill(ed, buf, p, e);
+    });
+}
+
+fn kill_region() {
+    with_editor(|ed, buf| {
+        if let Some((s, e)) = region(ed, buf) {
+            kill(ed, buf, s, e);
+        }
+    });
+}
+
+fn copy_region() {
+    with_editor(|ed, buf| {
+        let text = match region(ed, buf).and_then(|(s, e)| crate::folds::text_range(ed, s, e)) {
+            Some(t) if !t.is_empty() => t,
+            _ => return,
+        };
+        EMACS.with(|em| {
+            let mut em = em.borrow_mut();
+            em.ring.insert(0, text.clone());
+            em.ring.truncate(KILL_RING_MAX);
+            em.last_kill = None;
+        });
+        deactivate_mark();
+        buf.unselect();
+        app::copy(&text);
+    });
+}
+
+// C-y: pastes the clipboard, which holds the last kill unless something newer
+// was copied since, e.g. in another application. That text joins the kill ring.
+// The mark is left at the start of the inserted text.
+fn yank() {
+    with_editor(|ed, buf| {
+        let top = EMACS.with(|e| e.borrow().ring.first().cloned());
+        let p = ed.insert_position();
+        let len = buf.length();
+        buf.unselect();
+        let end = if app::clipboard_contains(app::ClipboardContent::Text) {
+            ed.paste();
+            // the text may arrive later, from another application's selection
+            if buf.length() == len {
+                return;
+            }
+            let end = p + buf.length() - len;
+            let text = buf.text_range(p, end).unwrap_or_default();
+            if top.as_ref() != Some(&text) {
+                EMACS.with(|e| {
+                    let mut e = e.borrow_mut();
+                    e.ring.insert(0, text);
+                    e.ring.truncate(KILL_RING_MAX);
+                });
+            }
+            end
+        } else {
+            let text = match top {
+                Some(t) => t,
+                None => return,
+            };
+            crate::folds::replace(ed, p, p, &text);
+            p + text.len() as i32
+        };
+        ed.set_insert_position(end);
+        ed.show_insert_position();
+        set_mark_at(ed, buf, p, false);
+        let view = view_of(ed);
+        EMACS.with(|e| {
+            let mut e = e.borrow_mut();
+            e.last_yank = Some((view, p, end, 0));
+            e.last_kill = None;
+        });
+        ed.do_callback();
+    });
+}
+
+// M-y right after a yank: replaces the yanked text with the previous kill
+fn yank_pop() {
+    with_editor(|ed, buf| {
+        let view = view_of(ed);
+        let (s, e, old, new, index) = match EMACS.with(|em| {
+            let em = em.borrow();
+            let (v, s, e, i) = em.last_yank?;
+            if v != view || em.ring.len() < 2 {
+                return None;
+            }
+            let next = (i + 1) % em.ring.len();
+            Some((s, e, em.ring.get(i)?.clone(), em.ring[next].clone(), next))
+        }) {
+            Some(y) => y,
+            None => return,
+        };
+        if ed.insert_position() != e
+            || crate::folds::text_range(ed, s, e).as_deref() != Some(old.as_str())
+        {
+            return;
+        }
+        let end = s + new.len() as i32;
+        crate::folds::replace(ed, s, e, &new);
+        ed.set_insert_position(end);
+        ed.show_insert_position();
+        set_mark_at(ed, buf, s, false);
+        EMACS.with(|em| em.borrow_mut().last_yank = Some((view, s, end, index)));
+        ed.do_callback();
+    });
+}
+
+// The view of a search, None once it's closed
+fn editor_of(view: usize) -> Option<(text::TextEditor, text::TextBuffer)> {
+    let ed = STATE.with(move |s| s.editor(view))?;
+    let buf = ed.buffer()?;
+    Some((ed, buf))
+}
+
+fn search_widgets() -> Option<(input::Input, button::ToggleButton)> {
+    Some((
+        app::widget_from_id("find_input")?,
+        app::widget_from_id("find_regex")?,
+    ))
+}
+
+fn isearch_forward() {
+    isearch(true);
+}
+
+fn isearch_backward() {
+    isearch(false);
+}
+
+// Opens the find dialog for an incremental search from the caret
+fn isearch(forward: bool) {
+    let ed = match STATE.with(|s| s.current_editor()) {
+        Some(e) => e,
+        None => return,
+    };
+    // showing the dialog ends a search in progress
+    cbs::find();
+    let search = Isearch {
+        view: view_of(&ed),
+        origin: crate::folds::to_doc(&ed, ed.insert_position()),
+        forward,
+        at: None,
+    };
+    EMACS.with(|e| e.borrow_mut().isearch = Some(search));
+    if let Some((mut i, _)) = search_widgets() {
+        i.set_value("");
+        i.take_focus().ok();
+    }
+}
+
+// Selects a match in the document, the folds hiding it open
+fn select_match(ed: &mut text::TextEditor, is: &mut Isearch, m: (i32, i32)) {
+    let (s, e) = crate::folds::reveal(ed, m.0, m.1);
+    if let Some(mut shown) = ed.buffer() {
+        shown.select(s, e);
+    }
+    ed.set_insert_position(if is.forward { e } else { s });
+    ed.show_insert_position();
+    is.at = Some(m);
+}
+
+// The text searched, the whole document in a folded view
+fn search_text(ed: &text::TextEditor) -> String {
+    crate::folds::doc_buffer(ed)
+        .map(|b| b.text())
+        .unwrap_or_default()
+}
+
+// Called by the find dialog when the pattern changes: goes to the first match
+// from where the search started
+pub fn isearch_update(val: &str, reg_val: bool) {
+    let mut is = match EMACS.with(|e| e.borrow().isearch) {
+        Some(i) => i,
+        None => return,
+    };
+    let (mut ed, mut buf) = match editor_of(is.view) {
+        Some(e) => e,
+        // the view was closed during the search
+        None => return drop_isearch(),
+    };
+    let matches = dialogs::find_matches(&search_text(&ed), val, reg_val);
+    let origin = is.origin as usize;
+    let found = if is.forward {
+        matches
+            .iter()
+            .find(|m| m.start >= origin)
+            .or(matches.first())
+    } else {
+        matches
+            .iter()
+            .rev()
+            .find(|m| m.start <= origin)
+            .or(matches.last())
+    };
+    match found {
+        Some(m) => select_match(&mut ed, &mut is, (m.start as i32, m.end as i32)),
+        None => {
+            buf.unselect();
+            ed.set_insert_position(crate::folds::to_shown(&ed, is.origin));
+            ed.show_insert_position();
+            is.at = None;
+        }
+    }
+    EMACS.with(|e| e.borrow_mut().isearch = Some(is));
+}
+
+// C-s/C-r while searching: the next or previous match, wrapping around. With
+// an empty pattern, the previous search is repeated.
+fn isearch_repeat(forward: bool) {
+    let mut is = match EMACS.with(|e| e.borrow().isearch) {
+        Some(i) => i,
+        None => return,
+    };
+    let (mut i, reg) = match search_widgets() {
+        Some(w) => w,
+        None => return,
+    };
+    if i.value().is_empty() {
+        let last = EMACS.with(|e| e.borrow().last_search.clone());
+        if last.is_empty() {
+            return;
+        }
+        // set_value doesn't run the callback, which counts and searches
+        i.set_value(&last);
+        i.do_callback();
+        return;
+    }
+    let (mut ed, _) = match editor_of(is.view) {
+        Some(e) => e,
+        // the view was closed during the search
+        None => return drop_isearch(),
+    };
+    let matches = dialogs::find_matches(&search_text(&ed), &i.value(), reg.value());
+    let (from, strict) = match is.at {
+        Some((s, _)) => (s as usize, true),
+        None => (is.origin as usize, false),
+    };
+    let found = if forward {
+        matches
+            .iter()
+            .find(|m| m.start > from || (!strict && m.start == from))
+            .or(matches.first())
+    } else {
+        matches
+            .iter()
+            .rev()
+            .find(|m| m.start < from || (!strict && m.start == from))
+            .or(matches.last())
+    };
+    if let Some(m) = found {
+        is.forward = forward;
+        select_match(&mut ed, &mut is, (m.start as i32, m.end as i32));
+    }
+    EMACS.with(|e| e.borrow_mut().isearch = Some(is));
+}
+
+fn drop_isearch() {
+    EMACS.with(|e| e.borrow_mut().isearch = None);
+}
+
+// Keys of the find dialog's input during a search: C-s, C-r and C-g
+pub fn isearch_key() -> bool {
+    if EMACS.with(|e| e.borrow().isearch.is_none()) {
+        return false;
+    }
+    if !app::event_state().contains(Shortcut::Ctrl) {
+        return false;
+    }
+    match app::event_key() {
+        k if k == Key::from_char('s') => isearch_repeat(true),
+        k if k == Key::from_char('r') => isearch_repeat(false),
+        k if k == Key::from_char('g') => {
+            isearch_end(true);
+        }
+        _ => return false,
+    }
+    true
+}
+
+// Enter or hiding the dialog: stays on the match, with the mark where the
+// search started. Returns false when no search was going on.
+pub fn isearch_finish() -> bool {
+    isearch_end(false)
+}
+
+fn isearch_end(cancel: bool) -> bool {
+    let is = match EMACS.with(|e| e.borrow_mut().isearch.take()) {
+        Some(i) => i,
+        None => return false,
+    };
+    if let Some((i, regex)) = search_widgets() {
+        let val = i.value();
+        if !val.is_empty() {
+            if !cancel {
+                crate::macros::record_find(&val, regex.value(), is.forward);
+            }
+            EMACS.with(|e| e.borrow_mut().last_search = val);
+        }
+    }
+    if let Some((mut ed, mut buf)) = editor_of(is.view) {
+        buf.unselect();
+        let origin = crate::folds::to_shown(&ed, is.origin);
+        if cancel {
+            ed.set_insert_position(origin);
+        } else if ed.insert_position() != origin {
+            set_mark_at(&ed, &buf, origin, false);
+        }
+        ed.show_insert_position();
+        ed.take_focus().ok();
+    }
+    if let Some(mut win) = app::widget_from_id::<window::Window>("find") {
+        win.hide();
+    }
+    true
+}
+
+// C-x b: picks one of the open buffers in the palette, the current one last
+fn switch_buffer() {
+    let entries = STATE.with(|s| {
+        let current = s.current_id();
+        let mut ids: Vec<usize> = s
+            .mru
+            .iter()
+            .copied()
+            .filter(|d| s.map.contains_key(d))
+            .collect();
+        let mut rest: Vec<usize> = s.map.keys().copied().filter(|d| !ids.contains(d)).collect();
+        rest.sort_unstable();
+        ids.extend(rest);
+        if let Some(c) = current {
+            ids.retain(|d| *d != c);
+            ids.push(c);
+        }
+        ids.into_iter()
+            .filter_map(|d| {
+                let b = s.map.get(&d)?;
+                let dir = b
+                    .current_file
+                    .as_ref()
+                    .and_then(|p| p.parent())
+                    .map(utils::strip_unc_path)
+                    .unwrap_or_default();
+                Some(palette::Entry {
+                    key: d.to_string(),
+                    label: b.name(),
+                    detail: dir,
+                })
+            })
+            .collect::<Vec<_>>()
+    });
+    palette::open(
+        entries,
+        |key| {
+            if let Ok(id) = key.parse() {
+                tabs::activate(id);
+            }
+        },
+        None,
+    );
+}
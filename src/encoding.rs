@@ -0,0 +1,266 @@
+// Detection and round-tripping of file encodings and line endings.
+// Buffers always hold UTF-8 text with '\n' line endings, the original format is
+// restored when saving.
+use std::fs;
+use std::io;
+use std::path::Path;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Encoding {
+    #[default]
+    Utf8,
+    Utf8Bom,
+    Utf16Le,
+    Utf16Be,
+    // UTF-16 detected without a BOM is saved without one
+    Utf16LeNoBom,
+    Utf16BeNoBom,
+    Latin1,
+}
+
+pub const ENCODINGS: &[(&str, Encoding)] = &[
+    ("UTF-8", Encoding::Utf8),
+    ("UTF-8 with BOM", Encoding::Utf8Bom),
+    ("UTF-16 LE", Encoding::Utf16Le),
+    ("UTF-16 BE", Encoding::Utf16Be),
+    ("UTF-16 LE without BOM", Encoding::Utf16LeNoBom),
+    ("UTF-16 BE without BOM", Encoding::Utf16BeNoBom),
+    ("Latin-1", Encoding::Latin1),
+];
+
+impl Encoding {
+    pub fn name(&self) -> &'static str {
+        ENCODINGS
+            .iter()
+            .find(|(_, e)| e == self)
+            .map(|(n, _)| *n)
+            .unwrap_or("UTF-8")
+    }
+
+    // Whether every char of the text can be represented in this encoding
+    pub fn can_encode(&self, text: &str) -> bool {
+        match self {
+            Encoding::Latin1 => text.chars().all(|c| (c as u32) <= 0xFF),
+            _ => true,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LineEnding {
+    #[default]
+    Lf,
+    CrLf,
+    Cr,
+}
+
+pub const LINE_ENDINGS: &[(&str, LineEnding)] = &[
+    ("LF", LineEnding::Lf),
+    ("CRLF", LineEnding::CrLf),
+    ("CR", LineEnding::Cr),
+];
+
+impl LineEnding {
+    pub fn name(&self) -> &'static str {
+        LINE_ENDINGS
+            .iter()
+            .find(|(_, e)| e == self)
+            .map(|(n, _)| *n)
+            .unwrap_or("LF")
+    }
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            LineEnding::Lf => "\n",
+            LineEnding::CrLf => "\r\n",
+            LineEnding::Cr => "\r",
+        }
+    }
+}
+
+fn decode_utf16(bytes: &[u8], le: bool) -> String {
+    let units: Vec<u16> = bytes
+        .chunks_exact(2)
+        .map(|c| {
+            if le {
+                u16::from_le_bytes([c[0], c[1]])
+            } else {
+                u16::from_be_bytes([c[0], c[1]])
+            }
+        })
+        .collect();
+    String::from_utf16_lossy(&units)
+}
+
+// UTF-16 without a BOM is recognized by the zero high bytes of ASCII text
+fn guess_utf16(bytes: &[u8]) -> Option<Encoding> {
+    let sample = &bytes[..bytes.len().min(1024) & !1];
+    if sample.len() < 4 {
+        return None;
+    }
+    let pairs = sample.len() / 2;
+    let even_zeros = sample.iter().step_by(2).filter(|b| **b == 0).count();
+    let odd_zeros = sample
+        .iter()
+        .skip(1)
+        .step_by(2)
+        .filter(|b| **b == 0)
+        .count();
+    if odd_zeros * 10 > pairs * 4 && even_zeros == 0 {
+        Some(Encoding::Utf16LeNoBom)
+    } else if even_zeros * 10 > pairs * 4 && odd_zeros == 0 {
+        Some(Encoding::Utf16BeNoBom)
+    } else {
+        None
+    }
+}
+
+fn detect_line_ending(text: &str) -> LineEnding {
+    let crlf = text.matches("\r\n").count();
+    let cr = text.matches('\r').count() - crlf;
+    let lf = text.matches('\n').count() - crlf;
+    if crlf > 0 && crlf >= lf && crlf >= cr {
+        LineEnding::CrLf
+    } else if cr > lf {
+        LineEnding::Cr
+    } else {
+        LineEnding::Lf
+    }
+}
+
+pub fn decode(bytes: &[u8]) -> (String, Encoding, LineEnding) {
+    let (text, enc) = if let Some(rest) = bytes.strip_prefix(&[0xEF, 0xBB, 0xBF]) {
+        (String::from_utf8_lossy(rest).to_string(), Encoding::Utf8Bom)
+    } else if let Some(rest) = bytes.strip_prefix(&[0xFF, 0xFE]) {
+        (decode_utf16(rest, true), Encoding::Utf16Le)
+    } else if let Some(rest) = bytes.strip_prefix(&[0xFE, 0xFF]) {
+        (decode_utf16(rest, false), Encoding::Utf16Be)
+    } else if let Ok(s) = std::str::from_utf8(bytes) {
+        (s.to_string(), Encoding::Utf8)
+    } else if let Some(enc) = guess_utf16(bytes) {
+        (decode_utf16(bytes, enc == Encoding::Utf16LeNoBom), enc)
+    } else {
+        // Latin-1 maps every byte to the code point of the same value
+        (bytes.iter().map(|b| *b as char).collect(), Encoding::Latin1)
+    };
+    let eol = detect_line_ending(&text);
+    let text = match eol {
+        LineEnding::Lf => text,
+        _ => text.replace("\r\n", "\n").replace('\r', "\n"),
+    };
+    (text, enc, eol)
+}
+
+pub fn encode(text: &str, enc: Encoding, eol: LineEnding) -> Vec<u8> {
+    let text = match eol {
+        LineEnding::Lf => text.to_string(),
+        _ => text.replace('\n', eol.as_str()),
+    };
+    match enc {
+        Encoding::Utf8 => text.into_bytes(),
+        Encoding::Utf8Bom => {
+            let mut v = vec![0xEF, 0xBB, 0xBF];
+            v.extend_from_slice(text.as_bytes());
+            v
+        }
+        Encoding::Utf16Le | Encoding::Utf16LeNoBom => {
+            let mut v = match enc {
+                Encoding::Utf16Le => vec![0xFF, 0xFE],
+                _ => vec![],
+            };
+            for u in text.encode_utf16() {
+                v.extend_from_slice(&u.to_le_bytes());
+            }
+            v
+        }
+        Encoding::Utf16Be | Encoding::Utf16BeNoBom => {
+            let mut v = match enc {
+                Encoding::Utf16Be => vec![0xFE, 0xFF],
+                _ => vec![],
+            };
+            for u in text.encode_utf16() {
+                v.extend_from_slice(&u.to_be_bytes());
+            }
+            v
+        }
+        Encoding::Latin1 => text
+            .chars()
+            .map(|c| if (c as u32) <= 0xFF { c as u8 } else { b'?' })
+            .collect(),
+    }
+}
+
+pub fn read_file(p: &Path) -> io::Result<(String, Encoding, LineEnding)> {
+    Ok(decode(&fs::read(p)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(bytes: &[u8], enc: Encoding, eol: LineEnding, text: &str) {
+        let (t, e, l) = decode(bytes);
+        assert_eq!((t.as_str(), e, l), (text, enc, eol));
+        assert_eq!(encode(&t, e, l), bytes);
+    }
+
+    #[test]
+    fn utf8() {
+        round_trip(
+            "a\u{e9}\nb\n".as_bytes(),
+            Encoding::Utf8,
+            LineEnding::Lf,
+            "a\u{e9}\nb\n",
+        );
+    }
+
+    #[test]
+    fn utf8_bom() {
+        round_trip(
+            b"\xEF\xBB\xBFab\n",
+            Encoding::Utf8Bom,
+            LineEnding::Lf,
+            "ab\n",
+        );
+    }
+
+    #[test]
+    fn utf16_bom() {
+        round_trip(b"\xFF\xFEa\0b\0", Encoding::Utf16Le, LineEnding::Lf, "ab");
+        round_trip(b"\xFE\xFF\0a\0b", Encoding::Utf16Be, LineEnding::Lf, "ab");
+    }
+
+    #[test]
+    fn utf16_without_bom() {
+        round_trip(
+            b"a\0\xE9\0c\0",
+            Encoding::Utf16LeNoBom,
+            LineEnding::Lf,
+            "a\u{e9}c",
+        );
+        round_trip(
+            b"\0a\0\xE9\0c",
+            Encoding::Utf16BeNoBom,
+            LineEnding::Lf,
+            "a\u{e9}c",
+        );
+    }
+
+    #[test]
+    fn crlf() {
+        round_trip(b"a\r\nb\r\n", Encoding::Utf8, LineEnding::CrLf, "a\nb\n");
+        round_trip(b"a\rb\r", Encoding::Utf8, LineEnding::Cr, "a\nb\n");
+    }
+
+    #[test]
+    fn latin1() {
+        round_trip(
+            b"caf\xE9\n",
+            Encoding::Latin1,
+            LineEnding::Lf,
+            "caf\u{e9}\n",
+        );
+        assert!(!Encoding::Latin1.can_encode("\u{20ac}"));
+        assert_eq!(encode("\u{20ac}", Encoding::Latin1, LineEnding::Lf), b"?");
+    }
+}
@@ -1,53 +1,62 @@
 #![allow(clippy::single_match)]
 
-use crate::{cbs, state::STATE, utils};
+use crate::{commands, ctx, utils};
 use fltk::{enums::*, prelude::*, *};
-use notify::{event::EventKind, Event, RecursiveMode, Watcher};
-use std::{
-    env,
-    path::{Path, PathBuf},
-};
+use notify::{event::EventKind, Event, RecommendedWatcher, RecursiveMode, Watcher};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
 
-pub fn menu_cb(m: &mut impl MenuExt) {
-    if let Ok(mpath) = m.item_pathname(None) {
-        match mpath.as_str() {
-            "/New File...\t" => cbs::new_file(),
-            "/New Dir...\t" => cbs::new_dir(),
-            _ => (),
+// Watchers of the directories shown by the file browser of each main window
+static WATCHERS: OnceLock<Mutex<HashMap<usize, RecommendedWatcher>>> = OnceLock::new();
+
+fn with_watcher<F: FnOnce(&mut RecommendedWatcher)>(win: usize, f: F) {
+    let m = WATCHERS.get_or_init(|| Mutex::new(HashMap::new()));
+    if let Ok(mut w) = m.lock() {
+        if let Some(w) = w.get_mut(&win) {
+            f(w);
         }
     }
 }
 
+// Shows a directory in the file browser of a main window
+pub fn chdir(win: usize, path: PathBuf) {
+    let mut f: browser::FileBrowser = match ctx::widget_in(win, "fbr") {
+        Some(f) => f,
+        None => return,
+    };
+    if f.load(&path).is_err() {
+        return;
+    }
+    with_watcher(win, |w| {
+        w.unwatch(&ctx::dir(win)).ok();
+        w.watch(&path, RecursiveMode::NonRecursive).ok();
+    });
+    ctx::set_dir(win, path.clone());
+    crate::gui::set_info(win, &format!("Directory: {}", utils::strip_unc_path(&path)));
+    f.set_damage(true);
+}
+
+// Stops watching the directory of a closed window
+pub fn remove(win: usize) {
+    let m = WATCHERS.get_or_init(|| Mutex::new(HashMap::new()));
+    if let Ok(mut w) = m.lock() {
+        w.remove(&win);
+    }
+}
+
 pub fn init_menu(m: &mut (impl MenuExt + 'static)) {
-    m.add(
-        "New File...\t",
-        Shortcut::Ctrl | 'n',
-        menu::MenuFlag::Normal,
-        menu_cb,
-    );
-    m.add(
-        "New Dir...\t",
-        Shortcut::Ctrl | Shortcut::Shift | 'n',
-        menu::MenuFlag::Normal,
-        menu_cb,
-    );
+    commands::add_to_menu(m, "file.new_file", true);
+    commands::add_to_menu(m, "file.new_dir", true);
 }
 
-pub fn fbr_cb(f: &mut browser::FileBrowser, watcher: &mut dyn Watcher) {
+pub fn fbr_cb(f: &mut browser::FileBrowser, win: usize) {
     if let Some(path) = f.text(f.value()) {
-        let path = PathBuf::from(path);
+        // entries are relative to the directory shown by this window's browser
+        let path = ctx::dir(win).join(path);
         if path.exists() {
             if path.is_dir() {
-                watcher.watch(&path, RecursiveMode::NonRecursive).unwrap();
-                f.load(&path).expect("Couldn't load directory!");
-                let cwd = env::current_dir().unwrap();
-                env::set_current_dir(cwd.join(path)).unwrap();
-                let mut info: frame::Frame = app::widget_from_id("info").unwrap();
-                info.set_label(&format!(
-                    "Directory: {}",
-                    utils::strip_unc_path(&env::current_dir().unwrap())
-                ));
-                f.set_damage(true);
+                chdir(win, path.canonicalize().unwrap_or(path));
             } else {
                 let mut is_image = false;
                 if let Some(ext) = path.extension() {
@@ -63,9 +72,7 @@ pub fn fbr_cb(f: &mut browser::FileBrowser, watcher: &mut dyn Watcher) {
                     win.child(0).unwrap().set_image(Some(img));
                     win.show();
                 } else {
-                    STATE.with(move |s| {
-                        s.append(Some(path.canonicalize().unwrap()));
-                    });
+                    crate::state::open(path);
                 }
             }
         }
@@ -77,11 +84,11 @@ pub struct Fbr {
 }
 
 impl Fbr {
-    pub fn new(current_path: &Path) -> Self {
-        let mut g = group::Group::default().with_id("fbr_group");
+    pub fn new(win: usize, current_path: &Path) -> Self {
+        let mut g = group::Group::default().with_id(&ctx::id_in(win, "fbr_group"));
         let mut fbr = browser::FileBrowser::default()
             .with_type(browser::BrowserType::Hold)
-            .with_id("fbr");
+            .with_id(&ctx::id_in(win, "fbr"));
         fbr.load(current_path)
             .expect("Failed to load working directory");
         fbr.set_color(Color::Background.darker());
@@ -105,7 +112,7 @@ impl Fbr {
                         _ => (),
                     }
                     if needs_update {
-                        fbr.load(env::current_dir().unwrap()).unwrap();
+                        fbr.load(ctx::dir(win)).unwrap();
                     }
                 }
                 Err(e) => eprintln!("{}", e),
@@ -115,7 +122,12 @@ impl Fbr {
         watcher
             .watch(current_path, RecursiveMode::NonRecursive)
             .unwrap();
-        fbr.set_callback(move |f| fbr_cb(f, &mut watcher));
+        WATCHERS
+            .get_or_init(|| Mutex::new(HashMap::new()))
+            .lock()
+            .unwrap()
+            .insert(win, watcher);
+        fbr.set_callback(move |f| fbr_cb(f, win));
         g.resize_callback(move |_, x, y, w, h| {
             m.resize(x, y, w, h);
             fbr.resize(x, y, w, h);
@@ -0,0 +1,1152 @@
+// Code folding. FLTK can't hide lines of a buffer, so a view with folded lines
+// shows a copy of the document without them. Edits are mirrored between the
+// copy and the document, saving, the language server and the other views keep
+// working on the document, and undo goes to the document since the copy has
+// no history. An edit made elsewhere inside a fold opens it.
+// The ranges that fold come from the language server when it computes them,
+// else from the syntax tree (see highlight), and are marked in the line number
+// gutter, where a click folds or opens them.
+use crate::state::STATE;
+use fltk::{app, draw, enums::*, prelude::*, text};
+use std::cell::{Cell, RefCell};
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
+#[cfg(feature = "lsp")]
+use std::path::Path;
+use std::path::PathBuf;
+use std::rc::Rc;
+
+// Lines of the document that fold, the first one stays shown
+#[derive(Clone, Copy, PartialEq)]
+struct Region {
+    first: usize,
+    last: usize,
+}
+
+// Hidden bytes of the document, from the line break ending the first line of
+// a fold to the one ending its last line
+#[derive(Clone, Copy)]
+struct Fold {
+    start: usize,
+    end: usize,
+    // the hidden line breaks
+    lines: usize,
+}
+
+// A view with folded lines
+struct Folded {
+    doc: text::TextBuffer,
+    shown: text::TextBuffer,
+    // the document's style buffer and the copy shown with the text
+    styles: Option<(text::TextBuffer, text::TextBuffer)>,
+    path: Option<PathBuf>,
+    // in order, disjoint
+    folds: Vec<Fold>,
+    gen: u64,
+    // where the last edit of the document ended, the caret goes there on undo
+    edited: usize,
+}
+
+// A line of a view in sight
+struct Line {
+    end: usize,
+    y: i32,
+    doc_line: usize,
+    folded: bool,
+}
+
+// Ranges that fold and the generation of the text they're for
+type Ranges = (u64, Rc<Vec<Region>>);
+
+// Ranges from the language server and the document version they're for
+#[cfg(feature = "lsp")]
+type ServerRanges = (i32, Option<Vec<Region>>);
+
+thread_local! {
+    static FOLDED: RefCell<HashMap<usize, Folded>> = RefCell::new(HashMap::new());
+    // The view whose edit is being mirrored, so that it doesn't come back
+    static SYNCING: Cell<usize> = const { Cell::new(0) };
+    static GENERATION: Cell<u64> = const { Cell::new(0) };
+    // Documents and style buffers with the callback passing their edits to the
+    // views folded on them
+    static WATCHED: RefCell<HashSet<usize>> = RefCell::new(HashSet::new());
+    // The ranges that fold by view, with the generation of the text they're for
+    static RANGES: RefCell<HashMap<usize, Ranges>> = RefCell::new(HashMap::new());
+    // Views whose ranges are recomputed after drawing
+    static STALE: RefCell<HashSet<usize>> = RefCell::new(HashSet::new());
+    // The ranges from the language server by document and the version asked
+    // for, None until the answer comes
+    #[cfg(feature = "lsp")]
+    static SERVER: RefCell<HashMap<usize, ServerRanges>> = RefCell::new(HashMap::new());
+}
+
+fn view_of(ed: &text::TextEditor) -> usize {
+    ed.as_widget_ptr() as usize
+}
+
+fn path_of(ed: &text::TextEditor) -> Option<PathBuf> {
+    let view = view_of(ed);
+    STATE.with(move |s| {
+        s.map
+            .get(&s.doc_id(view))
+            .and_then(|d| d.current_file.clone())
+    })
+}
+
+fn syncing(view: usize) -> bool {
+    SYNCING.with(|s| s.get()) == view
+}
+
+// The view if it's still open, a timeout may outlive it
+fn editor(view: usize) -> Option<text::TextEditor> {
+    STATE.with(move |s| s.editor(view))
+}
+
+// A folded view, from the buffers' callbacks where STATE may be in use. Views
+// are forgotten before they're deleted, see forget and close.
+fn folded_editor(view: usize) -> Option<text::TextEditor> {
+    if !FOLDED.with(|f| f.borrow().contains_key(&view)) {
+        return None;
+    }
+    text::TextEditor::from_dyn_widget_ptr(view as *mut _)
+}
+
+fn folds_of(view: usize) -> Vec<Fold> {
+    FOLDED.with(|f| {
+        f.borrow()
+            .get(&view)
+            .map(|v| v.folds.clone())
+            .unwrap_or_default()
+    })
+}
+
+// The buffers and folds of a view folded in the generation
+fn folded(view: usize, gen: u64) -> Option<(text::TextBuffer, text::TextBuffer, Vec<Fold>)> {
+    FOLDED.with(|f| {
+        f.borrow()
+            .get(&view)
+            .filter(|v| v.gen == gen)
+            .map(|v| (v.doc.clone(), v.shown.clone(), v.folds.clone()))
+    })
+}
+
+fn store(view: usize, folds: Vec<Fold>, edited: Option<usize>) {
+    FOLDED.with(|f| {
+        if let Some(v) = f.borrow_mut().get_mut(&view) {
+            v.folds = folds;
+            if let Some(e) = edited {
+                v.edited = e;
+            }
+        }
+    });
+}
+
+// The text without the folded bytes
+fn hide(text: &str, folds: &[Fold]) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut from = 0;
+    for f in folds {
+        let start = f.start.clamp(from, text.len());
+        out.push_str(&text[from..start]);
+        from = f.end.clamp(start, text.len());
+    }
+    out.push_str(&text[from..]);
+    out
+}
+
+// A document position in the view, inside a fold it's the end of its first line
+fn shown_pos(folds: &[Fold], pos: usize) -> usize {
+    let mut hidden = 0;
+    for f in folds {
+        if pos >= f.end {
+            hidden += f.end - f.start;
+        } else if pos > f.start {
+            return f.start - hidden;
+        } else {
+            break;
+        }
+    }
+    pos - hidden
+}
+
+// A position in the view in the document, the end of a first line is before
+// the fold
+fn doc_pos(folds: &[Fold], pos: usize) -> usize {
+    let mut hidden = 0;
+    for f in folds {
+        if f.start - hidden < pos {
+            hidden += f.end - f.start;
+        } else {
+            break;
+        }
+    }
+    pos + hidden
+}
+
+// Where the folds start in the view
+fn shown_starts(folds: &[Fold]) -> Vec<usize> {
+    let mut hidden = 0;
+    folds
+        .iter()
+        .map(|f| {
+            let s = f.start - hidden;
+            hidden += f.end - f.start;
+            s
+        })
+        .collect()
+}
+
+fn shift(p: usize, pos: usize, inserted: usize, deleted: usize) -> usize {
+    if p >= pos + deleted {
+        p + inserted - deleted
+    } else if p > pos {
+        pos
+    } else {
+        p
+    }
+}
+
+// Where the lines of the text end
+fn line_ends(text: &str) -> Vec<usize> {
+    let mut ends: Vec<usize> = text.match_indices('\n').map(|(i, _)| i).collect();
+    ends.push(text.len());
+    ends
+}
+
+fn line_of(ends: &[usize], pos: usize) -> usize {
+    ends.partition_point(|&e| e < pos)
+}
+
+fn fold_of(ends: &[usize], r: Region) -> Option<Fold> {
+    if r.first >= r.last || r.last >= ends.len() {
+        return None;
+    }
+    Some(Fold {
+        start: ends[r.first],
+        end: ends[r.last],
+        lines: r.last - r.first,
+    })
+}
+
+// Folds for the ranges, the ones starting inside another go into it
+fn to_folds(ends: &[usize], regions: impl IntoIterator<Item = Region>) -> Vec<Fold> {
+    let mut regions: Vec<Region> = regions.into_iter().collect();
+    regions.sort_by_key(|r| (r.first, Reverse(r.last)));
+    let mut folds = Vec::new();
+    let mut last = None;
+    for r in regions {
+        if last.is_some_and(|l| r.first <= l) {
+            continue;
+        }
+        if let Some(f) = fold_of(ends, r) {
+            folds.push(f);
+            last = Some(r.last);
+        }
+    }
+    folds
+}
+
+// Mirrors an edit, an insertion stays one so that typing undoes at once
+fn apply(buf: &mut text::TextBuffer, start: usize, end: usize, text: &str) {
+    let (start, end) = (start as i32, end as i32);
+    if start == end {
+        buf.insert(start, text);
+    } else if text.is_empty() {
+        buf.remove(start, end);
+    } else {
+        buf.replace(start, end, text);
+    }
+}
+
+// The style table for a style buffer taken over from the document
+#[cfg(feature = "highlight")]
+fn table(path: Option<&std::path::Path>) -> Vec<text::StyleTableEntryExt> {
+    crate::highlight::styles(path)
+}
+
+#[cfg(not(feature = "highlight"))]
+fn table(_path: Option<&std::path::Path>) -> Vec<text::StyleTableEntryExt> {
+    Vec::new()
+}
+
+// Rebuilds the styles of the copy from the document's
+fn restyle(view: usize) {
+    let data = FOLDED.with(|f| {
+        f.borrow()
+            .get(&view)
+            .and_then(|v| Some((v.styles.clone()?, v.folds.clone(), v.doc.length())))
+    });
+    if let Some(((sb, mut sf), folds, len)) = data {
+        // the document's styles may not follow the edit yet
+        if sb.length() == len {
+            sf.set_text(&hide(&sb.text(), &folds));
+            if let Some(mut ed) = folded_editor(view) {
+                ed.redraw();
+            }
+        }
+    }
+}
+
+// The views folded on a document, or on a style buffer, with their generations
+fn folded_on(key: usize, styles: bool) -> Vec<(usize, u64)> {
+    FOLDED.with(|f| {
+        f.borrow()
+            .iter()
+            .filter(|(_, v)| {
+                let buf = match &v.styles {
+                    Some((sb, _)) if styles => sb,
+                    _ if styles => return false,
+                    _ => &v.doc,
+                };
+                crate::utils::buffer_key(buf) == key
+            })
+            .map(|(view, v)| (*view, v.gen))
+            .collect()
+    })
+}
+
+// Passes the edits of a document, or of a style buffer, to the views folded on
+// it. FLTK can't remove a modify callback, so a buffer gets one for good and
+// it finds the views when called.
+fn watch(buf: &mut text::TextBuffer, styles: bool) {
+    let key = crate::utils::buffer_key(buf);
+    if !WATCHED.with(|w| w.borrow_mut().insert(key)) {
+        return;
+    }
+    buf.add_modify_callback(move |pos, inserted, deleted, _, _| {
+        for (view, gen) in folded_on(key, styles) {
+            if !styles {
+                doc_modified(view, gen, pos, inserted, deleted);
+            } else if !syncing(view) {
+                restyle(view);
+            }
+        }
+    });
+}
+
+// Highlighting replaced the style buffer of a folded view, e.g. when it
+// finished after the folds were restored, it's the document's one
+fn adopt_styles(view: usize) {
+    app::add_timeout3(0.0, move |_| {
+        let (path, shown) = match FOLDED.with(|f| {
+            f.borrow()
+                .get(&view)
+                .map(|v| (v.path.clone(), v.styles.as_ref().map(|s| s.1.clone())))
+        }) {
+            Some(v) => v,
+            None => return,
+        };
+        let mut ed = match editor(view) {
+            Some(e) => e,
+            None => return,
+        };
+        let mut sb = match ed.style_buffer() {
+            Some(b) if Some(&b) != shown.as_ref() => b,
+            _ => return,
+        };
+        let sf = text::TextBuffer::default();
+        FOLDED.with(|f| {
+            if let Some(v) = f.borrow_mut().get_mut(&view) {
+                v.styles = Some((sb.clone(), sf.clone()));
+            }
+        });
+        watch(&mut sb, true);
+        ed.set_highlight_data_ext(sf, table(path.as_deref()));
+        restyle(view);
+    });
+}
+
+// An edit of the document made elsewhere, in another view or by undo
+fn doc_modified(view: usize, gen: u64, pos: i32, inserted: i32, deleted: i32) {
+    if syncing(view) || (inserted == 0 && deleted == 0) {
+        return;
+    }
+    let (doc, mut shown, folds) = match folded(view, gen) {
+        Some(v) => v,
+        None => return,
+    };
+    let (pos, ins, del) = (pos as usize, inserted as usize, deleted as usize);
+    let inside = |f: &Fold| {
+        if del == 0 {
+            f.start < pos && pos <= f.end
+        } else {
+            pos <= f.end && pos + del > f.start
+        }
+    };
+    let kept: Vec<Fold> = folds
+        .iter()
+        .filter(|f| !inside(f))
+        .map(|f| {
+            if f.start >= pos {
+                Fold {
+                    start: f.start + ins - del,
+                    end: f.end + ins - del,
+                    lines: f.lines,
+                }
+            } else {
+                *f
+            }
+        })
+        .collect();
+    SYNCING.with(|s| s.set(view));
+    if kept.len() == folds.len() {
+        let at = shown_pos(&folds, pos);
+        let text = doc
+            .text_range(pos as i32, (pos + ins) as i32)
+            .unwrap_or_default();
+        apply(&mut shown, at, at + del, &text);
+    } else {
+        // the folds edited open
+        let mut ed = folded_editor(view);
+        let caret = ed
+            .as_ref()
+            .map(|e| shift(doc_pos(&folds, e.insert_position() as usize), pos, ins, del));
+        shown.set_text(&hide(&doc.text(), &kept));
+        if let (Some(e), Some(c)) = (ed.as_mut(), caret) {
+            e.set_insert_position(shown_pos(&kept, c) as i32);
+        }
+    }
+    SYNCING.with(|s| s.set(0));
+    if kept.is_empty() {
+        unfolded(view, gen);
+    }
+    store(view, kept, Some(pos + ins));
+    restyle(view);
+}
+
+// An edit in the folded view, the lines of the folds it deletes the first
+// line break of go with it
+fn shown_modified(view: usize, gen: u64, pos: i32, inserted: i32, deleted: i32) {
+    if syncing(view) || (inserted == 0 && deleted == 0) {
+        return;
+    }
+    let (mut doc, shown, folds) = match folded(view, gen) {
+        Some(v) => v,
+        None => return,
+    };
+    let (pos, ins, del) = (pos as usize, inserted as usize, deleted as usize);
+    let start = doc_pos(&folds, pos);
+    let end = if del == 0 {
+        start
+    } else {
+        doc_pos(&folds, pos + del)
+    };
+    let text = shown
+        .text_range(pos as i32, (pos + ins) as i32)
+        .unwrap_or_default();
+    let kept: Vec<Fold> = folds
+        .iter()
+        .zip(shown_starts(&folds))
+        .filter(|(_, s)| del == 0 || *s < pos || *s >= pos + del)
+        .map(|(f, _)| {
+            if f.start >= end {
+                Fold {
+                    start: f.start + ins + start - end,
+                    end: f.end + ins + start - end,
+                    lines: f.lines,
+                }
+            } else {
+                *f
+            }
+        })
+        .collect();
+    SYNCING.with(|s| s.set(view));
+    apply(&mut doc, start, end, &text);
+    SYNCING.with(|s| s.set(0));
+    if kept.is_empty() {
+        unfolded(view, gen);
+    }
+    store(view, kept, None);
+    restyle(view);
+}
+
+// Shows the document again once the last fold opened, later since the
+// buffers are in the middle of notifying the edit
+fn unfolded(view: usize, gen: u64) {
+    app::add_timeout3(0.0, move |_| {
+        if folded(view, gen).is_some_and(|(_, _, f)| f.is_empty()) {
+            if let Some(mut ed) = editor(view) {
+                set_folds(&mut ed, Vec::new(), None);
+            }
+        }
+    });
+}
+
+fn activate(
+    ed: &mut text::TextEditor,
+    mut doc: text::TextBuffer,
+    folds: Vec<Fold>,
+    path: Option<PathBuf>,
+) {
+    let view = view_of(ed);
+    let gen = GENERATION.with(|g| {
+        g.set(g.get() + 1);
+        g.get()
+    });
+    let mut shown = text::TextBuffer::default();
+    crate::utils::track_edits(&mut shown);
+    shown.set_tab_distance(doc.tab_distance());
+    // undo goes to the document
+    shown.can_undo(false);
+    shown.set_text(&hide(&doc.text(), &folds));
+    let styles = ed
+        .style_buffer()
+        .map(|sb| (sb, text::TextBuffer::default()));
+    FOLDED.with(|f| {
+        f.borrow_mut().insert(
+            view,
+            Folded {
+                doc: doc.clone(),
+                shown: shown.clone(),
+                styles: styles.clone(),
+                path: path.clone(),
+                folds,
+                gen,
+                edited: 0,
+            },
+        )
+    });
+    watch(&mut doc, false);
+    shown.add_modify_callback(move |pos, inserted, deleted, _, _| {
+        shown_modified(view, gen, pos, inserted, deleted)
+    });
+    ed.set_buffer(shown);
+    if let Some((mut sb, sf)) = styles {
+        watch(&mut sb, true);
+        restyle(view);
+        ed.set_highlight_data_ext(sf, table(path.as_deref()));
+    }
+}
+
+fn deactivate(ed: &mut text::TextEditor) {
+    if let Some(v) = FOLDED.with(|f| f.borrow_mut().remove(&view_of(ed))) {
+        ed.set_buffer(v.doc);
+        if let Some((sb, sf)) = v.styles {
+            ed.set_highlight_data_ext(sb, table(v.path.as_deref()));
+            unsafe {
+                text::TextBuffer::delete(sf);
+            }
+        }
+        unsafe {
+            text::TextBuffer::delete(v.shown);
+        }
+    }
+}
+
+// Shows the view with these folds, the document itself without any. The
+// caret and the top line stay on their lines of the document. The path is
+// the document's, for its styles when the view wasn't folded.
+fn set_folds(ed: &mut text::TextEditor, folds: Vec<Fold>, path: Option<PathBuf>) {
+    let view = view_of(ed);
+    let current = FOLDED.with(|f| {
+        f.borrow()
+            .get(&view)
+            .map(|v| (v.doc.clone(), v.shown.clone(), v.folds.clone()))
+    });
+    let (doc, old) = match &current {
+        Some((d, _, f)) => (d.clone(), f.clone()),
+        None => match ed.buffer() {
+            Some(b) => (b, Vec::new()),
+            None => return,
+        },
+    };
+    let caret = doc_pos(&old, ed.insert_position() as usize);
+    let top = ed.get_absolute_top_line_number().max(1);
+    let top = doc_pos(&old, ed.skip_lines(0, top - 1, true) as usize);
+    match current {
+        None if folds.is_empty() => return,
+        None => activate(ed, doc, folds, path),
+        Some(_) if folds.is_empty() => deactivate(ed),
+        Some((_, mut shown, _)) => {
+            let text = hide(&doc.text(), &folds);
+            store(view, folds, None);
+            SYNCING.with(|s| s.set(view));
+            shown.set_text(&text);
+            SYNCING.with(|s| s.set(0));
+            restyle(view);
+        }
+    }
+    let folds = folds_of(view);
+    if let Some(buf) = ed.buffer() {
+        ed.set_insert_position(shown_pos(&folds, caret) as i32);
+        ed.scroll(buf.count_lines(0, shown_pos(&folds, top) as i32) + 1, 0);
+    }
+    // the carets of the other buffer are gone
+    crate::cursors::forget(view);
+    ed.redraw();
+}
+
+// The line ranges that fold in the document a view shows
+fn regions(view: usize) -> Rc<Vec<Region>> {
+    let (_doc, buf, _path, _lsp) = match STATE.with(move |s| {
+        let id = s.doc_id(view);
+        s.map.get(&id).map(|d| {
+            (
+                id,
+                d.buf.clone(),
+                d.current_file.clone(),
+                d.lsp_path().map(|p| (p, d.version)),
+            )
+        })
+    }) {
+        Some(d) => d,
+        None => return Rc::default(),
+    };
+    let key = crate::utils::buffer_generation(&buf);
+    if let Some((k, r)) = RANGES.with(|c| c.borrow().get(&view).cloned()) {
+        if k == key {
+            return r;
+        }
+    }
+    let r = if crate::settings::get().is_large(buf.length() as usize) {
+        Vec::new()
+    } else {
+        compute(_doc, _path, _lsp, &buf.text())
+    };
+    let r = Rc::new(r);
+    RANGES.with(|c| c.borrow_mut().insert(view, (key, r.clone())));
+    r
+}
+
+fn compute(
+    _doc: usize,
+    _path: Option<PathBuf>,
+    _lsp: Option<(PathBuf, i32)>,
+    _text: &str,
+) -> Vec<Region> {
+    #[cfg(feature = "lsp")]
+    if let Some(r) = _lsp.and_then(|(p, version)| server_regions(_doc, &p, version)) {
+        return r;
+    }
+    #[cfg(feature = "highlight")]
+    if let Some(r) = _path.and_then(|p| crate::highlight::fold_ranges(&p, _text)) {
+        return r
+            .into_iter()
+            .map(|(first, last)| Region { first, last })
+            .collect();
+    }
+    Vec::new()
+}
+
+// The ranges from the language server for this version of the document, they
+// are asked for the first time and the syntax tree is used until they come
+#[cfg(feature = "lsp")]
+fn server_regions(doc: usize, path: &Path, version: i32) -> Option<Vec<Region>> {
+    if let Some((v, r)) = SERVER.with(|s| s.borrow().get(&doc).cloned()) {
+        if v == version {
+            return r.filter(|r| !r.is_empty());
+        }
+    }
+    if !crate::lsp::is_available() {
+        return None;
+    }
+    SERVER.with(|s| s.borrow_mut().insert(doc, (version, None)));
+    let p = path.to_path_buf();
+    crate::lsp::with_client(path, move |c| {
+        if !c.supports_folding() {
+            return;
+        }
+        c.folding_range(&p, move |ranges| {
+            let mut regions: Vec<Region> = ranges
+                .iter()
+                .filter(|r| r.end_line > r.start_line)
+                .map(|r| Region {
+                    first: r.start_line as usize,
+                    last: r.end_line as usize,
+                })
+                .collect();
+            regions.sort_by_key(|r| (r.first, Reverse(r.last)));
+            regions.dedup_by_key(|r| r.first);
+            app::awake_callback(move || {
+                SERVER.with(|s| {
+                    let mut s = s.borrow_mut();
+                    if s.get(&doc).is_some_and(|(v, _)| *v == version) {
+                        s.insert(doc, (version, Some(regions.clone())));
+                    }
+                });
+                RANGES.with(|r| r.borrow_mut().clear());
+                app::redraw();
+            });
+        });
+    });
+    None
+}
+
+// The ranges drawn for the document, recomputed after drawing when it changed
+fn drawn_regions(view: usize, doc: &text::TextBuffer) -> Rc<Vec<Region>> {
+    if crate::settings::get().is_large(doc.length() as usize) {
+        return Rc::default();
+    }
+    let key = crate::utils::buffer_generation(doc);
+    let cached = RANGES.with(|c| c.borrow().get(&view).cloned());
+    if let Some((k, r)) = &cached {
+        if *k == key {
+            return r.clone();
+        }
+    }
+    if STALE.with(|s| s.borrow_mut().insert(view)) {
+        app::add_timeout3(0.0, move |_| {
+            STALE.with(|s| s.borrow_mut().remove(&view));
+            if !regions(view).is_empty() {
+                if let Some(mut ed) = editor(view) {
+                    ed.redraw();
+                }
+            }
+        });
+    }
+    cached.map(|(_, r)| r).unwrap_or_default()
+}
+
+// The lines of the view in sight, numbered in the document
+fn visible_lines(ed: &text::TextEditor, buf: &text::TextBuffer, folds: &[Fold]) -> Vec<Line> {
+    let top = (ed.get_absolute_top_line_number().max(1) - 1) as usize;
+    let len = buf.length() as usize;
+    let mut start = ed.clone().skip_lines(0, top as i32, true) as usize;
+    // past the last line
+    if top > 0 && buf.char_at(start as i32 - 1) != Some('\n') {
+        return Vec::new();
+    }
+    let starts = shown_starts(folds);
+    let mut doc_line = top
+        + folds
+            .iter()
+            .zip(&starts)
+            .filter(|(_, s)| **s < start)
+            .map(|(f, _)| f.lines)
+            .sum::<usize>();
+    let mut lines = Vec::new();
+    loop {
+        let (x, y) = ed.position_to_xy(start as i32);
+        // positions out of view are reported at 0, 0
+        if (x, y) == (0, 0) || y >= ed.y() + ed.h() {
+            break;
+        }
+        let end = buf.line_end(start as i32) as usize;
+        let fold = starts.binary_search(&end).ok();
+        lines.push(Line {
+            end,
+            y,
+            doc_line,
+            folded: fold.is_some(),
+        });
+        if end == len {
+            break;
+        }
+        doc_line += 1 + fold.map(|i| folds[i].lines).unwrap_or(0);
+        start = end + 1;
+    }
+    lines
+}
+
+// Draws the markers of the ranges that fold in the gutter and of the folded
+// ones after their first line. With folds, the view numbers the lines it
+// shows, so the numbers of the document are drawn over them.
+pub fn draw(ed: &text::TextEditor) {
+    let view = view_of(ed);
+    let state = FOLDED.with(|f| {
+        f.borrow().get(&view).map(|v| {
+            (
+                v.doc.clone(),
+                v.folds.clone(),
+                v.styles.as_ref().map(|s| s.1.clone()),
+            )
+        })
+    });
+    let buf = match ed.buffer() {
+        Some(b) => b,
+        None => return,
+    };
+    let gutter = ed.linenumber_width();
+    if gutter <= 0 && state.is_none() {
+        return;
+    }
+    let (doc, folds) = match &state {
+        Some((doc, folds, shown_styles)) => {
+            let styles = ed.style_buffer();
+            if styles.is_some() && styles != *shown_styles {
+                adopt_styles(view);
+            }
+            (doc.clone(), folds.clone())
+        }
+        None => (buf.clone(), Vec::new()),
+    };
+    let lines = visible_lines(ed, &buf, &folds);
+    draw::set_font(ed.text_font(), ed.text_size());
+    let h = draw::height();
+    if gutter > 0 {
+        let x = ed.x() + ed.frame().dx();
+        let starts: HashSet<usize> = drawn_regions(view, &doc).iter().map(|r| r.first).collect();
+        draw::push_clip(x, ed.y(), gutter, ed.h());
+        if !folds.is_empty() {
+            draw::set_draw_color(ed.linenumber_bgcolor());
+            draw::draw_rectf(x, ed.y(), gutter, ed.h());
+            draw::set_font(ed.linenumber_font(), ed.linenumber_size());
+            draw::set_draw_color(ed.linenumber_fgcolor());
+            for l in &lines {
+                let n = (l.doc_line + 1).to_string();
+                draw::draw_text2(&n, x, l.y, gutter - 4, h, ed.linenumber_align());
+            }
+        }
+        draw::set_draw_color(ed.linenumber_fgcolor());
+        let s = (h / 4).max(3);
+        for l in &lines {
+            let (mx, my) = (x + 2, l.y + h / 2);
+            if l.folded {
+                draw::draw_polygon(mx, my - s, mx + s, my, mx, my + s);
+            } else if starts.contains(&l.doc_line) {
+                draw::draw_polygon(mx, my - s / 2, mx + 2 * s, my - s / 2, mx + s, my + s / 2);
+            }
+        }
+        draw::pop_clip();
+    }
+    if folds.is_empty() {
+        return;
+    }
+    draw::set_font(ed.text_font(), ed.text_size());
+    let w = draw::width(" ... ") as i32;
+    draw::push_clip(ed.x() + gutter, ed.y(), ed.w() - gutter, ed.h());
+    draw::set_draw_color(ed.linenumber_fgcolor());
+    for l in lines.iter().filter(|l| l.folded) {
+        let (x, y) = ed.position_to_xy(l.end as i32);
+        if (x, y) != (0, 0) {
+            draw::draw_rect(x + 4, y + 1, w, h - 2);
+            draw::draw_text2("...", x + 4, y, w, h, Align::Center);
+        }
+    }
+    draw::pop_clip();
+}
+
+// Called from the editor's handler, a click on a line in the gutter opens
+// its fold or folds the range starting there
+pub fn handle(ed: &mut text::TextEditor, ev: Event) -> bool {
+    let gutter = ed.linenumber_width();
+    if ev != Event::Push || app::event_mouse_button() != app::MouseButton::Left || gutter <= 0 {
+        return false;
+    }
+    let x = ed.x() + ed.frame().dx();
+    if !(x..x + gutter).contains(&app::event_x()) {
+        return false;
+    }
+    let buf = match ed.buffer() {
+        Some(b) => b,
+        None => return false,
+    };
+    draw::set_font(ed.text_font(), ed.text_size());
+    let h = draw::height();
+    let y = app::event_y();
+    let line = visible_lines(ed, &buf, &folds_of(view_of(ed)))
+        .into_iter()
+        .find(|l| l.y <= y && y < l.y + h);
+    match line {
+        Some(l) => unfold_line(ed, l.doc_line) || fold_region(ed, |r| r.first == l.doc_line),
+        None => false,
+    }
+}
+
+// The buffer of the document a view shows, the view's own unless folded
+pub fn doc_buffer(ed: &text::TextEditor) -> Option<text::TextBuffer> {
+    FOLDED
+        .with(|f| f.borrow().get(&view_of(ed)).map(|v| v.doc.clone()))
+        .or_else(|| ed.buffer())
+}
+
+// The style buffer of the document a view shows, see highlight::share
+#[cfg(feature = "highlight")]
+pub fn style_buffer(ed: &text::TextEditor) -> Option<text::TextBuffer> {
+    FOLDED
+        .with(|f| {
+            f.borrow()
+                .get(&view_of(ed))
+                .map(|v| v.styles.as_ref().map(|s| s.0.clone()))
+        })
+        .unwrap_or_else(|| ed.style_buffer())
+}
+
+// A position in a view in the document
+pub fn to_doc(ed: &text::TextEditor, pos: i32) -> i32 {
+    doc_pos(&folds_of(view_of(ed)), pos.max(0) as usize) as i32
+}
+
+// A document position in a view
+pub fn to_shown(ed: &text::TextEditor, pos: i32) -> i32 {
+    shown_pos(&folds_of(view_of(ed)), pos.max(0) as usize) as i32
+}
+
+// The text of start..end of a view, with the folded lines in it
+pub fn text_range(ed: &text::TextEditor, start: i32, end: i32) -> Option<String> {
+    doc_buffer(ed)?.text_range(to_doc(ed, start), to_doc(ed, end))
+}
+
+// Replaces start..end of a view in the document, the view follows. Folds in
+// the range go with it.
+pub fn replace(ed: &text::TextEditor, start: i32, end: i32, text: &str) {
+    if let Some(mut doc) = doc_buffer(ed) {
+        doc.replace(to_doc(ed, start), to_doc(ed, end), text);
+    }
+}
+
+// The selection of a view in the document
+pub fn selection(ed: &text::TextEditor) -> Option<(i32, i32)> {
+    let (a, b) = ed.buffer()?.selection_position()?;
+    Some((to_doc(ed, a.min(b)), to_doc(ed, a.max(b))))
+}
+
+// Opens the folds hiding part of start..end, a range of the document, and
+// returns where it is in the view
+pub fn reveal(ed: &mut text::TextEditor, start: i32, end: i32) -> (i32, i32) {
+    let folds = folds_of(view_of(ed));
+    let (s, e) = (start as usize, end as usize);
+    let kept: Vec<Fold> = folds
+        .iter()
+        .filter(|f| !(s < f.end && e > f.start))
+        .copied()
+        .collect();
+    if kept.len() != folds.len() {
+        set_folds(ed, kept, None);
+    }
+    (to_shown(ed, start), to_shown(ed, end))
+}
+
+// Undo in a folded view goes to the document, the caret to where it changed
+pub fn undo(ed: &mut text::TextEditor) {
+    match FOLDED.with(|f| f.borrow().get(&view_of(ed)).map(|v| v.doc.clone())) {
+        Some(mut doc) => {
+            if doc.undo().is_ok() {
+                after_undo(ed);
+            }
+        }
+        None => ed.undo(),
+    }
+}
+
+pub fn redo(ed: &mut text::TextEditor) {
+    match FOLDED.with(|f| f.borrow().get(&view_of(ed)).map(|v| v.doc.clone())) {
+        Some(mut doc) => {
+            if doc.redo().is_ok() {
+                after_undo(ed);
+            }
+        }
+        None => ed.redo(),
+    }
+}
+
+fn after_undo(ed: &mut text::TextEditor) {
+    if let Some(pos) = FOLDED.with(|f| f.borrow().get(&view_of(ed)).map(|v| v.edited)) {
+        ed.set_insert_position(shown_pos(&folds_of(view_of(ed)), pos) as i32);
+    }
+    ed.show_insert_position();
+    ed.do_callback();
+}
+
+// Folded lines of a view as (first, last) lines of the document, saved with
+// the session
+pub fn folded_lines(ed: &text::TextEditor) -> Vec<(usize, usize)> {
+    let (doc, folds) = match FOLDED.with(|f| {
+        f.borrow()
+            .get(&view_of(ed))
+            .map(|v| (v.doc.clone(), v.folds.clone()))
+    }) {
+        Some(v) => v,
+        None => return Vec::new(),
+    };
+    let ends = line_ends(&doc.text());
+    folds
+        .iter()
+        .map(|f| {
+            let first = line_of(&ends, f.start);
+            (first, first + f.lines)
+        })
+        .collect()
+}
+
+// Folds the lines of a restored session
+pub fn restore(ed: &mut text::TextEditor, path: PathBuf, lines: &[(usize, usize)]) {
+    let text = match doc_buffer(ed) {
+        Some(b) => b.text(),
+        None => return,
+    };
+    let regions = lines.iter().map(|&(first, last)| Region { first, last });
+    let folds = to_folds(&line_ends(&text), regions);
+    set_folds(ed, folds, Some(path));
+}
+
+// Drops the folds of a closed view
+pub fn forget(view: usize) {
+    FOLDED.with(|f| f.borrow_mut().remove(&view));
+    RANGES.with(|r| r.borrow_mut().remove(&view));
+}
+
+// Drops what's kept for a closed document, its buffer is about to be deleted
+pub fn close(doc: usize, buf: &text::TextBuffer) {
+    forget(doc);
+    WATCHED.with(|w| w.borrow_mut().remove(&crate::utils::buffer_key(buf)));
+    #[cfg(feature = "lsp")]
+    SERVER.with(|s| s.borrow_mut().remove(&doc));
+}
+
+// Opens the fold on a line of the document
+fn unfold_line(ed: &mut text::TextEditor, line: usize) -> bool {
+    let doc = match doc_buffer(ed) {
+        Some(b) => b,
+        None => return false,
+    };
+    let ends = line_ends(&doc.text());
+    let folds = folds_of(view_of(ed));
+    let kept: Vec<Fold> = folds
+        .iter()
+        .filter(|f| line_of(&ends, f.start) != line)
+        .copied()
+        .collect();
+    if kept.len() == folds.len() {
+        return false;
+    }
+    set_folds(ed, kept, None);
+    true
+}
+
+// Folds the smallest range matching, the folds inside it become part of it
+fn fold_region(ed: &mut text::TextEditor, matching: impl Fn(&Region) -> bool) -> bool {
+    let view = view_of(ed);
+    let doc = match doc_buffer(ed) {
+        Some(b) => b,
+        None => return false,
+    };
+    let ends = line_ends(&doc.text());
+    let folds = folds_of(view);
+    let folded: HashSet<usize> = folds.iter().map(|f| line_of(&ends, f.start)).collect();
+    let region = regions(view)
+        .iter()
+        .filter(|r| matching(r) && !folded.contains(&r.first))
+        .min_by_key(|r| r.last - r.first)
+        .copied();
+    let fold = match region.and_then(|r| fold_of(&ends, r)) {
+        Some(f) => f,
+        None => return false,
+    };
+    // already hidden by another
+    if folds
+        .iter()
+        .any(|f| f.start < fold.start && fold.start <= f.end)
+    {
+        return false;
+    }
+    let mut folds: Vec<Fold> = folds
+        .into_iter()
+        .filter(|f| f.end < fold.start || f.start > fold.end)
+        .collect();
+    folds.push(fold);
+    folds.sort_by_key(|f| f.start);
+    let path = path_of(ed);
+    set_folds(ed, folds, path);
+    true
+}
+
+fn current_editor() -> Option<text::TextEditor> {
+    STATE.with(|s| s.current_editor())
+}
+
+// The line of the document the caret is on
+fn caret_line(ed: &text::TextEditor) -> Option<usize> {
+    let text = doc_buffer(ed)?.text();
+    let pos = to_doc(ed, ed.insert_position()) as usize;
+    Some(line_of(&line_ends(&text), pos))
+}
+
+// Folds the innermost range around the caret
+pub fn fold() {
+    if let Some(mut ed) = current_editor() {
+        if let Some(line) = caret_line(&ed) {
+            fold_region(&mut ed, |r| r.first <= line && line <= r.last);
+        }
+    }
+}
+
+// Opens the fold on the caret's line
+pub fn unfold() {
+    if let Some(mut ed) = current_editor() {
+        if let Some(line) = caret_line(&ed) {
+            unfold_line(&mut ed, line);
+        }
+    }
+}
+
+// Folds the ranges nested `level` deep and opens the others, 0 folds every
+// outermost range
+pub fn fold_level(level: usize) {
+    let mut ed = match current_editor() {
+        Some(e) => e,
+        None => return,
+    };
+    let text = match doc_buffer(&ed) {
+        Some(b) => b.text(),
+        None => return,
+    };
+    let regions = regions(view_of(&ed));
+    let depth = |r: &Region| {
+        1 + regions
+            .iter()
+            .filter(|o| o.first < r.first && r.last <= o.last)
+            .count()
+    };
+    let chosen = regions
+        .iter()
+        .filter(|r| level == 0 || depth(r) == level)
+        .copied();
+    let folds = to_folds(&line_ends(&text), chosen);
+    let path = path_of(&ed);
+    set_folds(&mut ed, folds, path);
+}
+
+pub fn fold_all() {
+    fold_level(0);
+}
+
+pub fn unfold_all() {
+    if let Some(mut ed) = current_editor() {
+        set_folds(&mut ed, Vec::new(), None);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEXT: &str = "fn a() {\n    1\n}\nfn b() {\n    2\n    3\n}\n";
+
+    fn folds() -> Vec<Fold> {
+        let ends = line_ends(TEXT);
+        to_folds(
+            &ends,
+            [
+                Region { first: 0, last: 2 },
+                Region { first: 3, last: 6 },
+                // inside the second one
+                Region { first: 4, last: 5 },
+            ],
+        )
+    }
+
+    #[test]
+    fn hides_the_lines_after_the_first() {
+        let f = folds();
+        assert_eq!(f.len(), 2);
+        assert_eq!(hide(TEXT, &f), "fn a() {\nfn b() {\n");
+        assert_eq!(shown_starts(&f), vec![8, 17]);
+    }
+
+    #[test]
+    fn positions_map_both_ways() {
+        let f = folds();
+        let shown = hide(TEXT, &f);
+        // every shown position is the same text in the document
+        for p in 0..shown.len() {
+            let d = doc_pos(&f, p);
+            assert_eq!(shown_pos(&f, d), p);
+            assert_eq!(TEXT[d..d + 1], shown[p..p + 1], "at {}", p);
+        }
+        // a hidden position shows at the end of the fold's first line
+        let hidden = TEXT.find('1').unwrap();
+        assert_eq!(shown_pos(&f, hidden), 8);
+        assert_eq!(doc_pos(&f, shown.len()), TEXT.len());
+    }
+}
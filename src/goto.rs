@@ -0,0 +1,111 @@
+// Go to File (Ctrl+P): fuzzy matches the paths of the files under the workspace
+// root in the palette. The walk respects .gitignore and skips target/, results
+// are listed as they're found.
+use crate::{ctx, palette, utils};
+use fltk::app;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Mutex,
+};
+
+const BATCH: usize = 256;
+const PREVIEW_BYTES: u64 = 64 * 1024;
+
+// Bumped on every show, so an older walk stops
+static GENERATION: AtomicUsize = AtomicUsize::new(0);
+// Generation of the last walk that finished
+static DONE: AtomicUsize = AtomicUsize::new(0);
+// Files found by the walks with their generation, stored until the main thread
+// adds them. An older walk may still append a batch after a new show.
+static PENDING: Mutex<Vec<(usize, PathBuf)>> = Mutex::new(Vec::new());
+
+pub fn show() {
+    let root = ctx::root(ctx::active());
+    let generation = GENERATION.fetch_add(1, Ordering::Relaxed) + 1;
+    PENDING.lock().unwrap().clear();
+    let opened = palette::open(Vec::new(), open_file, Some(preview));
+    let walk_root = root.clone();
+    std::thread::spawn(move || {
+        walk(&walk_root, generation);
+        DONE.store(generation, Ordering::Relaxed);
+        app::awake();
+    });
+    poll(root, generation, opened);
+}
+
+fn walk(root: &Path, generation: usize) {
+    let walker = ignore::WalkBuilder::new(root)
+        // .gitignore applies even outside of a git checkout
+        .require_git(false)
+        .filter_entry(|e| {
+            !(e.file_name() == "target" && e.file_type().map(|t| t.is_dir()).unwrap_or(false))
+        })
+        .build();
+    let mut batch = Vec::new();
+    for entry in walker.flatten() {
+        if GENERATION.load(Ordering::Relaxed) != generation {
+            return;
+        }
+        if entry.file_type().map(|t| t.is_file()).unwrap_or(false) {
+            batch.push((generation, entry.into_path()));
+            if batch.len() >= BATCH {
+                PENDING.lock().unwrap().append(&mut batch);
+                app::awake();
+            }
+        }
+    }
+    PENDING.lock().unwrap().append(&mut batch);
+}
+
+// Moves the files found so far into the palette until the walk is done
+fn poll(root: PathBuf, generation: usize, opened: usize) {
+    app::add_timeout3(0.05, move |_| {
+        if GENERATION.load(Ordering::Relaxed) != generation || !palette::is_open(opened) {
+            // closed, the walk isn't needed anymore
+            GENERATION
+                .compare_exchange(
+                    generation,
+                    generation + 1,
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                )
+                .ok();
+            return;
+        }
+        let done = DONE.load(Ordering::Relaxed) == generation;
+        let found = std::mem::take(&mut *PENDING.lock().unwrap());
+        palette::add_entries(
+            found
+                .into_iter()
+                .filter(|(g, _)| *g == generation)
+                .map(|(_, p)| palette::Entry {
+                    label: utils::strip_unc_path(p.strip_prefix(&root).unwrap_or(&p)),
+                    key: p.to_string_lossy().to_string(),
+                    detail: String::new(),
+                })
+                .collect(),
+        );
+        if !done {
+            poll(root.clone(), generation, opened);
+        }
+    });
+}
+
+fn open_file(key: &str) {
+    let path = PathBuf::from(key);
+    crate::state::open(path);
+}
+
+// The beginning of the file, or nothing for binary files
+fn preview(key: &str) -> String {
+    let mut bytes = Vec::new();
+    if let Ok(f) = std::fs::File::open(key) {
+        f.take(PREVIEW_BYTES).read_to_end(&mut bytes).ok();
+    }
+    if bytes.contains(&0) {
+        return String::new();
+    }
+    String::from_utf8_lossy(&bytes).to_string()
+}
@@ -1,11 +1,11 @@
-use crate::{cbs, dialogs, fbr, utils};
+use crate::encoding::{self, Encoding, LineEnding};
+use crate::{cbs, commands, ctx, dialogs, fbr, recent, settings, tabs, utils};
 #[cfg(feature = "lsp")]
 use crate::lsp;
 use fltk::{enums::*, prelude::*, *};
-use fltk_theme::color_themes::fleet;
-use fltk_theme::{ColorTheme, SchemeType, WidgetScheme};
-use std::fs;
+use fltk_theme::{SchemeType, WidgetScheme};
 use std::path::{Path, PathBuf};
+use std::{cell::RefCell, collections::HashMap};
 
 #[cfg(feature = "term")]
 use fltk_term as term;
@@ -17,13 +17,68 @@ const WIDTH: i32 = 1280;
 const HEIGHT: i32 = 800;
 const MENU_HEIGHT: i32 = if cfg!(target_os = "macos") { 1 } else { 30 };
 
+thread_local! {
+    // Status text of each main window, shown after the editing mode if any
+    static INFO: RefCell<HashMap<usize, String>> = RefCell::new(HashMap::new());
+}
+
+// Sets the status text of a window
+pub fn set_info(win: usize, text: &str) {
+    INFO.with(|i| i.borrow_mut().insert(win, text.to_string()));
+    show_info(win);
+}
+
+// Shows the editing mode again in every window after it changed
+pub fn refresh_info() {
+    for win in ctx::windows() {
+        show_info(win);
+    }
+}
+
+fn show_info(win: usize) {
+    if let Some(mut info) = ctx::widget_in::<frame::Frame>(win, "info") {
+        let text = INFO.with(|i| i.borrow().get(&win).cloned().unwrap_or_default());
+        info.set_label(&format!(
+            "{}{}{}",
+            crate::macros::indicator(),
+            crate::vim::indicator(),
+            text
+        ));
+    }
+}
+
+#[cfg(feature = "term")]
+thread_local! {
+    // Terminal of each main window, dropping one ends its shell
+    static TERMS: RefCell<HashMap<usize, term::PPTerm>> = RefCell::new(HashMap::new());
+}
+
+// Changes the working directory of a window's shell
+#[cfg(feature = "term")]
+pub fn term_cd(win: usize, dir: &Path) {
+    let dir = utils::strip_unc_path(dir);
+    #[cfg(windows)]
+    let cmd = format!("cd /d \"{}\"\r", dir);
+    #[cfg(not(windows))]
+    let cmd = format!("cd '{}'\r", dir.replace('\'', "'\\''"));
+    TERMS.with(|t| {
+        if let Some(term) = t.borrow().get(&win) {
+            term.write_all(cmd.as_bytes()).ok();
+        }
+    });
+}
+
+#[cfg(feature = "term")]
+pub fn close_term(win: usize) {
+    TERMS.with(|t| t.borrow_mut().remove(&win));
+}
+
 #[cfg(feature = "lsp")]
-fn schedule_status_refresh() {
-    // Poll LSP status until it is no longer "starting"
+pub fn schedule_status_refresh() {
+    // Poll LSP status until no client is "starting" anymore
     app::add_timeout3(0.3, |_| {
         crate::diagnostics::update_status_bar();
-        let st = crate::lsp::status_text();
-        if st == "starting" {
+        if crate::lsp::is_starting() {
             schedule_status_refresh();
         }
     });
@@ -31,37 +86,61 @@ fn schedule_status_refresh() {
 
 pub fn init_gui(current_file: &Option<PathBuf>, current_path: &Path) -> app::App {
     let a = app::App::default();
-    let color_theme = ColorTheme::new(&fleet::GRUVBOX_DARK);
-    color_theme.apply();
+    settings::apply_theme();
     let scheme = WidgetScheme::new(SchemeType::Fleet1);
     scheme.apply();
     app::set_menu_linespacing(10);
 
-    let mut buf = text::TextBuffer::default();
-    buf.set_tab_distance(4);
-
     let _find_dialog = dialogs::FindDialog::new();
     let _replace_dialog = dialogs::ReplaceDialog::new();
     let _image_dialog = dialogs::ImageDialog::new();
     let _completion_dialog = dialogs::CompletionDialog::new();
+    let _settings_dialog = dialogs::SettingsDialog::new();
+    let _switcher_dialog = dialogs::SwitcherDialog::new();
+    let _palette_dialog = dialogs::PaletteDialog::new();
+    let _vim_command_line = dialogs::VimCommandLine::new();
 
-    let mut popup = menu::MenuButton::default().with_type(menu::MenuButtonType::Popup3);
-    init_edit_menu(&mut popup, "");
+    new_window(current_file.is_none(), current_path);
+    a
+}
+
+pub fn window_title(root: &Path) -> String {
+    format!(
+        "RustyEd - {}",
+        root.file_name()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or_else(|| root.display().to_string())
+    )
+}
+
+// Builds a main window with its own tabs, file browser and terminal, rooted at
+// current_path. Its widgets are registered through ctx, the window becomes active.
+pub fn new_window(load_dir: bool, current_path: &Path) -> window::Window {
+    let idx = ctx::add(current_path.to_path_buf());
+    ctx::set_active(idx);
+
+    let mut popup = menu::MenuButton::default()
+        .with_type(menu::MenuButtonType::Popup3)
+        .with_id(&ctx::id_in(idx, "popup"));
+    init_edit_menu(&mut popup, true);
+    let mut tab_popup = menu::MenuButton::default().with_type(menu::MenuButtonType::Popup3);
+    tabs::init_menu(&mut tab_popup);
 
     let mut w = window::Window::default()
         .with_size(WIDTH, HEIGHT)
-        .with_label("RustyEd");
+        .with_label(&window_title(current_path));
     w.set_xclass("red");
+    w.set_id(&ctx::id_in(idx, "win"));
 
     let mut col0 = group::Flex::default_fill().column();
     col0.set_pad(2);
-    let mut m = menu::SysMenuBar::default().with_id("menu");
-    init_menu(&mut m, current_file.is_none());
+    let mut m = menu::SysMenuBar::default().with_id(&ctx::id_in(idx, "menu"));
+    init_menu(&mut m, load_dir);
     col0.fixed(&m, MENU_HEIGHT);
     let mut row = group::Flex::default();
     row.set_pad(0);
-    let fbr = fbr::Fbr::new(current_path);
-    if current_file.is_none() {
+    let fbr = fbr::Fbr::new(idx, current_path);
+    if load_dir {
         row.fixed(&*fbr, 180);
     } else {
         row.fixed(&*fbr, 1);
@@ -71,8 +150,9 @@ pub fn init_gui(current_file: &Option<PathBuf>, current_path: &Path) -> app::App
     row.fixed(&fbr_splitter, 4);
     let mut col = group::Flex::default().column();
     col.set_pad(0);
-    let mut tabs = group::Tabs::default().with_id("tabs");
-    tabs.handle(move |t, ev| tabs_handle(t, ev, &mut popup));
+    let mut tabs = group::Tabs::default().with_id(&ctx::id_in(idx, "tabs"));
+    tabs.handle(move |t, ev| tabs::handle(t, ev, &mut popup, &tab_popup));
+    tabs.set_callback(cbs::tabs_cb);
     tabs.handle_overflow(group::TabsOverflow::Pulldown);
     tabs.end();
     tabs.auto_layout();
@@ -82,253 +162,254 @@ pub fn init_gui(current_file: &Option<PathBuf>, current_path: &Path) -> app::App
         let mut tab_splitter = frame::Frame::default();
         tab_splitter.handle(cbs::tab_splitter_cb);
         col.fixed(&tab_splitter, 4);
-        let term = term::PPTerm::new_deferred(0, 0, 0, 0, None);
+        let mut term = term::PPTerm::new_deferred(0, 0, 0, 0, None);
+        term.set_id(&ctx::id_in(idx, "term_group"));
         col.fixed(&*term, 160);
         term
     };
     col.end();
     row.end();
     #[cfg(feature = "lsp")]
-    let lsp_status = lsp::status_text();
+    let lsp_status = lsp::status_text(current_path);
     #[cfg(not(feature = "lsp"))]
     let lsp_status = "disabled";
-    let info = frame::Frame::default()
-        .with_label(&format!(
-            "Directory: {}   |   LSP: {}",
-            utils::strip_unc_path(current_path),
-            lsp_status
-        ))
+    let mut status = group::Flex::default().row();
+    let _info = frame::Frame::default()
         .with_align(enums::Align::Left | enums::Align::Inside)
-        .with_id("info");
-    col0.fixed(&info, 20);
+        .with_id(&ctx::id_in(idx, "info"));
+    let mut fmt = menu::MenuButton::default().with_id(&ctx::id_in(idx, "fmt_status"));
+    fmt.set_frame(FrameType::FlatBox);
+    fmt.set_tooltip("Change encoding or line endings");
+    init_format_menu(&mut fmt, true);
+    status.fixed(&fmt, 180);
+    status.end();
+    col0.fixed(&status, 20);
     col0.end();
     w.resizable(&row);
     w.end();
     w.make_resizable(true);
+    // The window receives events before its children, so this tracks the active window
+    w.handle(move |_, ev| {
+        if matches!(
+            ev,
+            Event::Focus | Event::Push | Event::KeyDown | Event::Shortcut
+        ) {
+            ctx::set_active(idx);
+        }
+        // Keys the focused widget didn't take, e.g. in the file browser
+        ev == Event::KeyDown && crate::keymap::handle_key()
+    });
     w.show();
     w.wait_for_expose();
     #[cfg(feature = "term")]
     {
         term.start();
-        std::mem::forget(term);
+        TERMS.with(move |t| t.borrow_mut().insert(idx, term));
+        // the shell starts in the process' working directory, which is left
+        // alone as the windows have their own roots
+        if current_path != std::env::current_dir().unwrap_or_default() {
+            term_cd(idx, current_path);
+        }
     }
-    w.set_callback(cbs::win_cb);
+    set_info(
+        idx,
+        &format!(
+            "Directory: {}   |   LSP: {}",
+            utils::strip_unc_path(current_path),
+            lsp_status
+        ),
+    );
+    w.set_callback(move |w| cbs::win_cb(w, idx));
+    recent::add_folder(current_path);
     // Kick status refresh until LSP becomes ready/disabled/unavailable
     #[cfg(feature = "lsp")]
     schedule_status_refresh();
-    a
+    w
 }
 
-pub fn tabs_handle(t: &mut group::Tabs, ev: Event, popup: &mut menu::MenuButton) -> bool {
-    match ev {
-        Event::Push => {
-            if app::event_mouse_button() == app::MouseButton::Right
-                && app::event_y() > t.y() + 30
-                && t.children() > 0
-            {
-                popup.popup();
-                true
-            } else {
-                false
-            }
-        }
-        _ => false,
+pub fn init_edit_menu(m: &mut (impl MenuExt + 'static), popup: bool) {
+    for id in [
+        "edit.undo",
+        "edit.redo",
+        "edit.cut",
+        "edit.copy",
+        "edit.paste",
+        "edit.add_next_occurrence",
+        "edit.select_all_occurrences",
+        "edit.add_caret_above",
+        "edit.add_caret_below",
+        "edit.jump_to_bracket",
+        "edit.reindent",
+        "edit.find",
+        "edit.replace",
+    ] {
+        commands::add_to_menu(m, id, popup);
     }
 }
-
-pub fn init_edit_menu(m: &mut (impl MenuExt + 'static), header: &str) {
-    m.add(
-        &format!("{}Undo\t", header),
-        Shortcut::Ctrl | 'z',
-        menu::MenuFlag::Normal,
-        cbs::menu_cb,
-    );
-    m.add(
-        &format!("{}Redo\t", header),
-        Shortcut::Ctrl | 'y',
-        menu::MenuFlag::MenuDivider,
-        cbs::menu_cb,
-    );
-    m.add(
-        &format!("{}Cut\t", header),
-        Shortcut::Ctrl | 'x',
-        menu::MenuFlag::Normal,
-        cbs::menu_cb,
-    );
-    m.add(
-        &format!("{}Copy\t", header),
-        Shortcut::Ctrl | 'c',
-        menu::MenuFlag::Normal,
-        cbs::menu_cb,
-    );
-    m.add(
-        &format!("{}Paste\t", header),
-        Shortcut::Ctrl | 'v',
-        menu::MenuFlag::MenuDivider,
-        cbs::menu_cb,
-    );
-    m.add(
-        &format!("{}Find\t", header),
-        Shortcut::Ctrl | 'f',
-        menu::MenuFlag::Normal,
-        cbs::menu_cb,
-    );
-    m.add(
-        &format!("{}Replace\t", header),
-        Shortcut::Ctrl | 'h',
-        menu::MenuFlag::Normal,
-        cbs::menu_cb,
-    );
+pub fn init_format_menu(m: &mut (impl MenuExt + 'static), popup: bool) {
+    for (name, _) in encoding::ENCODINGS {
+        commands::add_to_menu(m, &commands::encoding_command(name), popup);
+    }
+    for (name, _) in encoding::LINE_ENDINGS {
+        commands::add_to_menu(m, &commands::line_ending_command(name), popup);
+    }
 }
+
 pub fn init_menu(m: &mut (impl MenuExt + 'static), load_dir: bool) {
-    m.add(
-        "&File/New File...\t",
-        Shortcut::Ctrl | 'n',
-        menu::MenuFlag::Normal,
-        cbs::menu_cb,
-    );
-    m.add(
-        "&File/New Dir...\t",
-        Shortcut::Ctrl | Shortcut::Shift | 'n',
-        menu::MenuFlag::Normal,
-        cbs::menu_cb,
-    );
-    m.add(
-        "&File/Open...\t",
-        Shortcut::Ctrl | 'o',
-        menu::MenuFlag::Normal,
-        cbs::menu_cb,
-    );
-    m.add(
-        "&File/Save\t",
-        Shortcut::Ctrl | 's',
-        menu::MenuFlag::Normal,
-        cbs::menu_cb,
-    );
-    m.add(
-        "&File/Save as...\t",
-        Shortcut::Ctrl | Shortcut::Shift | 'w',
-        menu::MenuFlag::Normal,
-        cbs::menu_cb,
-    );
-    m.add(
-        "&File/Save All\t",
-        Shortcut::None,
-        menu::MenuFlag::MenuDivider,
-        cbs::menu_cb,
-    );
-    let _idx = m.add(
-        "&File/Quit\t",
-        Shortcut::Ctrl | 'q',
-        menu::MenuFlag::Normal,
-        cbs::menu_cb,
-    );
-    // m.at(_idx).unwrap().set_label_color(Color::Red.darker());
-    init_edit_menu(m, "&Edit/");
-    let idx = m.add(
-        "&View/File browser\t",
-        Shortcut::None,
-        menu::MenuFlag::Toggle,
-        cbs::menu_cb,
-    );
+    for id in [
+        "file.new_file",
+        "file.new_dir",
+        "file.new_window",
+        "file.open",
+        "file.go_to_file",
+        "file.open_folder",
+    ] {
+        commands::add_to_menu(m, id, false);
+    }
+    recent::init_menu(m);
+    for id in [
+        "file.save",
+        "file.save_as",
+        "file.save_all",
+        "file.close_tab",
+        "file.quit",
+    ] {
+        commands::add_to_menu(m, id, false);
+    }
+    init_edit_menu(m, false);
+    for id in [
+        "macro.record",
+        "macro.play",
+        "macro.play_times",
+        "macro.play_to_end",
+        "macro.save",
+        "macro.play_saved",
+    ] {
+        commands::add_to_menu(m, id, false);
+    }
+    init_format_menu(m, false);
+    commands::add_to_menu(m, "edit.settings", false);
+    commands::add_to_menu(m, "edit.key_bindings", false);
+    let idx = commands::add_to_menu(m, "edit.vim_mode", false);
+    if settings::get().vim {
+        m.at(idx).unwrap().set();
+    }
+    commands::add_to_menu(m, "view.command_palette", false);
+    let idx = commands::add_to_menu(m, "view.file_browser", false);
     if load_dir {
         m.at(idx).unwrap().set();
     }
     #[cfg(feature = "term")]
     {
-        let idx = m.add(
-            "&View/Terminal\t",
-            Shortcut::None,
-            menu::MenuFlag::Toggle,
-            cbs::menu_cb,
-        );
+        let idx = commands::add_to_menu(m, "view.terminal", false);
         m.at(idx).unwrap().set();
     }
-    m.add(
-        "&Help/About\t",
-        Shortcut::None,
-        menu::MenuFlag::Normal,
-        cbs::menu_cb,
-    );
+    for id in [
+        "view.next_tab",
+        "view.previous_tab",
+        "view.split_right",
+        "view.split_down",
+        "view.cycle_split",
+        "view.unsplit",
+        "view.fold",
+        "view.unfold",
+        "view.fold_all",
+        "view.unfold_all",
+        "view.fold_level_1",
+        "view.fold_level_2",
+        "view.fold_level_3",
+        "help.about",
+    ] {
+        commands::add_to_menu(m, id, false);
+    }
 }
 
-pub fn init_editor(ed: &mut text::TextEditor) {
+// Appearance derived from the user settings, reapplied when they change
+pub fn style_editor(ed: &mut text::TextEditor) {
+    let s = settings::get();
     ed.set_color(Color::Background2.darker().darker());
-    ed.set_linenumber_width(40);
+    ed.set_linenumber_width(s.linenumber_width);
     ed.set_linenumber_size(12);
     ed.set_linenumber_fgcolor(Color::Yellow);
     ed.set_linenumber_bgcolor(Color::Background);
-    ed.set_text_font(Font::Courier);
+    ed.set_text_font(s.font());
+    ed.set_text_size(s.font_size);
+    ed.set_cursor_style(crate::vim::cursor_style());
+}
+
+pub fn init_editor(ed: &mut text::TextEditor) {
+    style_editor(ed);
+    // Needed to save and restore the scroll offset with the session
+    ed.maintain_absolute_top_line_number(true);
     ed.set_trigger(CallbackTrigger::Changed);
     ed.set_callback(cbs::editor_cb);
-    // Handle Ctrl+Space for completion
+    ed.draw(|e| {
+        crate::folds::draw(e);
+        crate::brackets::draw(e);
+        crate::cursors::draw(e);
+    });
     ed.handle(|e, ev| {
-        if let Event::Shortcut | Event::KeyDown = ev {
-            #[cfg(feature = "lsp")]
-            if app::event_state().contains(Shortcut::Ctrl) && app::event_key() == Key::from_char(' ') {
-                // Avoid activity if LSP is unavailable
-                if !crate::lsp::is_available() {
-                    return true;
-                }
-                // request completion at current caret position
-                if let Some(buf) = e.buffer() {
-                    let pos = e.insert_position();
-                    let text = buf.text();
-                    let lsp_pos = crate::lsp::compute_position_from_offset(&text, pos as usize);
-                    let ed_ptr = e.as_widget_ptr() as usize;
-                    // find path for current editor
-                    crate::state::STATE.with(move |s| {
-                        if let Some(v) = s.map.get(&ed_ptr) {
-                            if let Some(path) = v.current_file.as_ref() {
-                                let path = path.clone();
-                                crate::lsp::with_client(move |c| {
-                                    c.completion(&path, lsp_pos, move |resp| {
-                                        // Build popup entries
-                                        let items = match resp {
-                                            lsp_types::CompletionResponse::Array(arr) => arr,
-                                            lsp_types::CompletionResponse::List(list) => list.items,
-                                        };
-                                        let mut entries = Vec::with_capacity(items.len());
-                                        for item in items.into_iter() {
-                                            let insert_text = item.insert_text.clone().unwrap_or_else(|| item.label.clone());
-                                            let edit_range: Option<lsp_types::Range> = item.text_edit.as_ref().map(|te| match te {
-                                                lsp_types::CompletionTextEdit::Edit(ed_) => ed_.range,
-                                                lsp_types::CompletionTextEdit::InsertAndReplace(ir) => ir.replace,
-                                            });
-                                            entries.push(crate::completion::CompletionEntry {
-                                                label: item.label,
-                                                insert_text,
-                                                edit_range,
-                                                kind: item.kind,
-                                                detail: item.detail,
-                                            });
-                                        }
-                                        let entries_arc = std::sync::Arc::new(entries);
-                                        app::awake_callback(move || {
-                                            let entries_vec = (*entries_arc).clone();
-                                            let maybe_ed = crate::state::STATE.with(|s3| s3.current_editor());
-                                            if let Some(ed_now) = maybe_ed {
-                                                crate::completion::show_popup(&ed_now, entries_vec);
-                                            }
-                                        });
-                                    });
-                                });
-                            }
-                        }
-                    });
-                }
-                return true;
-            }
+        if ev == Event::Focus {
+            crate::state::set_focused(e.as_widget_ptr() as usize);
+            e.set_cursor_style(crate::vim::cursor_style());
+            return false;
+        }
+        if ev == Event::Unfocus {
+            crate::autosave::on_focus_loss();
+            return false;
+        }
+        // Clicks in the line number gutter fold, see folds.rs
+        if crate::folds::handle(e, ev) {
+            return true;
+        }
+        // Vim keys come before the bindings, see vim.rs
+        if crate::macros::handle_key(ev, || crate::vim::handle(e, ev)) {
+            return true;
+        }
+        // Extra carets take the typing, see cursors.rs
+        if crate::macros::handle_key(ev, || crate::cursors::handle(e, ev)) {
+            return true;
+        }
+        // Bound keys run their command, see keymap.rs
+        if ev == Event::KeyDown && crate::keymap::handle_key() {
+            return true;
+        }
+        // Brackets and quotes typed in pairs, see brackets.rs
+        if crate::macros::handle_key(ev, || crate::brackets::handle(e, ev)) {
+            return true;
+        }
+        // Enter indents the new line, see indent.rs
+        if crate::macros::handle_key(ev, || crate::indent::handle(e, ev)) {
+            return true;
         }
+        // Typing the editor handles is part of a recorded macro
+        crate::macros::record_key(ev);
         false
     });
 }
 
+// Adds a split view editing an existing buffer to a tab
+pub fn create_view(edrow: &mut group::Flex, buf: text::TextBuffer) -> text::TextEditor {
+    edrow.begin();
+    let mut ed = text::TextEditor::default();
+    init_editor(&mut ed);
+    edrow.end();
+    ed.set_buffer(buf);
+    edrow.layout();
+    edrow.redraw();
+    ed
+}
+
 pub fn create_ed(
     tabs: &mut group::Tabs,
     id: &str,
     current_path: &Option<PathBuf>,
-) -> text::TextEditor {
+) -> std::io::Result<(text::TextEditor, Encoding, LineEnding)> {
+    // a file that can't be read gets no tab, saving it would replace it with nothing
+    let contents = match current_path.as_ref() {
+        Some(p) if !p.is_dir() => Some(encoding::read_file(p)?),
+        _ => None,
+    };
     tabs.begin();
     let mut edrow = group::Flex::default()
         .row()
@@ -352,11 +433,15 @@ pub fn create_ed(
     tabs.set_value(&edrow).ok();
 
     let mut buf = text::TextBuffer::default();
-    buf.set_tab_distance(4);
-    if let Some(p) = current_path.as_ref() {
-        let txt = fs::read_to_string(p).unwrap_or_default();
+    utils::track_edits(&mut buf);
+    buf.set_tab_distance(settings::get().tab_distance);
+    let mut format = (Encoding::default(), LineEnding::default());
+    if let Some((txt, enc, eol)) = contents {
+        format = (enc, eol);
         buf.set_text(&txt);
-        #[cfg(feature = "highlight")]
+    }
+    #[cfg(feature = "highlight")]
+    if let Some(p) = current_path.as_ref() {
         std::thread::spawn({
             let p = p.clone();
             let mut ed = ed.clone();
@@ -367,5 +452,5 @@ pub fn create_ed(
         });
     }
     ed.set_buffer(buf);
-    ed
+    Ok((ed, format.0, format.1))
 }
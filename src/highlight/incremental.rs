@@ -0,0 +1,289 @@
+// Highlighting for large files: instead of re-running the highlighter over the
+// whole text, the syntax tree is edited and reparsed incrementally on every change
+// and only the regions whose syntax changed are restyled. The styles are returned
+// for the caller to write into the style buffer.
+use super::translate_style;
+use std::path::PathBuf;
+use tree_sitter::{InputEdit, Parser, Point, Query, QueryCursor, Range, StreamingIterator, Tree};
+use tree_sitter_highlight::HighlightConfiguration;
+
+pub struct Incremental {
+    parser: Parser,
+    tree: Option<Tree>,
+    query: Query,
+    // highlight index of every capture of the query
+    capture_styles: Vec<Option<usize>>,
+    exception_fn: Option<fn(usize, &str) -> char>,
+    // mirror of the buffer contents, kept in sync from the modify callback
+    text: String,
+    // where the lines of the text start, for the rows of the edits
+    lines: Vec<usize>,
+    // for the diagnostics underlined over the styles
+    #[cfg_attr(not(feature = "lsp"), allow(dead_code))]
+    path: PathBuf,
+}
+
+// Same matching as HighlightConfiguration::configure, the most specific name wins
+fn capture_styles(query: &Query, names: &[&str]) -> Vec<Option<usize>> {
+    query
+        .capture_names()
+        .iter()
+        .map(|capture| {
+            let parts: Vec<&str> = capture.split('.').collect();
+            let mut best = None;
+            let mut best_len = 0;
+            for (i, name) in names.iter().enumerate() {
+                let len = name.split('.').count();
+                if name.split('.').all(|p| parts.contains(&p)) && len > best_len {
+                    best = Some(i);
+                    best_len = len;
+                }
+            }
+            best
+        })
+        .collect()
+}
+
+fn line_starts(text: &str) -> Vec<usize> {
+    let mut v = vec![0];
+    v.extend(text.match_indices('\n').map(|(i, _)| i + 1));
+    v
+}
+
+fn point_after(start: Point, s: &str) -> Point {
+    match s.rfind('\n') {
+        Some(i) => Point::new(start.row + s.matches('\n').count(), s.len() - i - 1),
+        None => Point::new(start.row, start.column + s.len()),
+    }
+}
+
+impl Incremental {
+    pub fn new(
+        config: HighlightConfiguration,
+        names: &[&str],
+        exception_fn: Option<fn(usize, &str) -> char>,
+        path: PathBuf,
+    ) -> Option<Self> {
+        let mut parser = Parser::new();
+        parser.set_language(&config.language).ok()?;
+        let query = config.query;
+        let capture_styles = capture_styles(&query, names);
+        Some(Self {
+            parser,
+            tree: None,
+            query,
+            capture_styles,
+            exception_fn,
+            text: String::new(),
+            lines: vec![0],
+            path,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.text.len()
+    }
+
+    pub fn tree(&self) -> Option<Tree> {
+        self.tree.clone()
+    }
+
+    // Starts over from the whole text, when the mirror no longer matches, and
+    // returns the styles of all of it
+    pub fn resync(&mut self, text: String) -> String {
+        self.text = text;
+        self.lines = line_starts(&self.text);
+        self.tree = self.parser.parse(&self.text, None);
+        self.restyle(0, self.text.len())
+    }
+
+    // Called from the TextBuffer modify callback with the inserted text. The
+    // inserted bytes get the default style, and the returned styles are then
+    // written over the style buffer from their start. None when the edit doesn't
+    // apply to the mirror and it needs a resync.
+    pub fn edit(
+        &mut self,
+        pos: usize,
+        deleted: usize,
+        inserted: &str,
+    ) -> Option<Vec<(usize, String)>> {
+        let old_end = pos + deleted;
+        if old_end > self.text.len()
+            || !self.text.is_char_boundary(pos)
+            || !self.text.is_char_boundary(old_end)
+        {
+            return None;
+        }
+        let row = self.lines.partition_point(|&l| l <= pos) - 1;
+        let start_position = Point::new(row, pos - self.lines[row]);
+        let edit = InputEdit {
+            start_byte: pos,
+            old_end_byte: old_end,
+            new_end_byte: pos + inserted.len(),
+            start_position,
+            old_end_position: point_after(start_position, &self.text[pos..old_end]),
+            new_end_position: point_after(start_position, inserted),
+        };
+        self.text.replace_range(pos..old_end, inserted);
+        // the lines starting in the replaced text go, the ones after it move
+        let first = row + 1;
+        let last = self.lines.partition_point(|&l| l <= old_end);
+        let new_lines: Vec<usize> = inserted
+            .match_indices('\n')
+            .map(|(i, _)| pos + i + 1)
+            .collect();
+        self.lines.splice(first..last, new_lines);
+        let moved = first + inserted.matches('\n').count();
+        for l in &mut self.lines[moved..] {
+            *l = *l + inserted.len() - deleted;
+        }
+        let mut old_tree = match self.tree.take() {
+            Some(t) => t,
+            None => return Some(Vec::new()),
+        };
+        old_tree.edit(&edit);
+        self.tree = self.parser.parse(&self.text, Some(&old_tree));
+        let mut ranges: Vec<(usize, usize)> = vec![(pos, pos + inserted.len())];
+        if let Some(tree) = self.tree.as_ref() {
+            ranges.extend(
+                old_tree
+                    .changed_ranges(tree)
+                    .map(|r: Range| (r.start_byte, r.end_byte)),
+            );
+        }
+        ranges.sort_unstable();
+        let mut merged: Vec<(usize, usize)> = Vec::new();
+        for (start, end) in ranges {
+            let start = self.line_start(start);
+            let end = self.line_end(end);
+            match merged.last_mut() {
+                Some(last) if start <= last.1 => last.1 = last.1.max(end),
+                _ => merged.push((start, end)),
+            }
+        }
+        Some(
+            merged
+                .into_iter()
+                .map(|(start, end)| (start, self.restyle(start, end)))
+                .collect(),
+        )
+    }
+
+    fn line_start(&self, pos: usize) -> usize {
+        self.lines[self.lines.partition_point(|&l| l <= pos) - 1]
+    }
+
+    fn line_end(&self, pos: usize) -> usize {
+        match self.lines.get(self.lines.partition_point(|&l| l <= pos)) {
+            Some(next) => next - 1,
+            None => self.text.len(),
+        }
+    }
+
+    fn restyle(&self, start: usize, end: usize) -> String {
+        let tree = match self.tree.as_ref() {
+            Some(t) if start < end => t,
+            _ => return "A".repeat(end.saturating_sub(start)),
+        };
+        let mut styles = vec![b'A'; end - start];
+        let mut cursor = QueryCursor::new();
+        cursor.set_byte_range(start..end);
+        let mut captures = cursor.captures(&self.query, tree.root_node(), self.text.as_bytes());
+        while let Some((m, idx)) = captures.next() {
+            let cap = m.captures[*idx];
+            let style = match self.capture_styles.get(cap.index as usize) {
+                Some(Some(s)) => *s,
+                _ => continue,
+            };
+            let r = cap.node.byte_range();
+            let (s, e) = (r.start.max(start), r.end.min(end));
+            if s >= e {
+                continue;
+            }
+            let c = match self.exception_fn {
+                Some(f) => f(style, &self.text[r.start..r.end]),
+                None => translate_style(style),
+            };
+            // later (inner) captures override the enclosing ones
+            styles[s - start..e - start].fill(c as u8);
+        }
+        #[cfg(feature = "lsp")]
+        crate::diagnostics::underline(&self.path, start, &mut styles);
+        String::from_utf8(styles).unwrap_or_else(|_| "A".repeat(end - start))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEXT: &str = "fn main() {\n    let x = 1;\n}\n\nfn other() -> u8 {\n    2\n}\n";
+
+    // lang_data would read the font settings
+    fn rust() -> Incremental {
+        let config = HighlightConfiguration::new(
+            tree_sitter_rust::LANGUAGE.into(),
+            "rust",
+            tree_sitter_rust::HIGHLIGHTS_QUERY,
+            "",
+            "",
+        )
+        .unwrap();
+        let names: Vec<&str> = super::super::rust::STYLES.iter().map(|s| s.0).collect();
+        Incremental::new(config, &names, None, PathBuf::new()).unwrap()
+    }
+
+    // Applies an edit to the text and the styles the way the modify callback does
+    fn edit(inc: &mut Incremental, styles: &mut String, pos: usize, deleted: usize, ins: &str) {
+        let restyled = inc.edit(pos, deleted, ins).unwrap();
+        styles.replace_range(pos..pos + deleted, &"A".repeat(ins.len()));
+        for (start, s) in restyled {
+            styles.replace_range(start..start + s.len(), &s);
+        }
+    }
+
+    fn restyled_from_scratch(text: &str) -> String {
+        rust().resync(text.to_string())
+    }
+
+    #[test]
+    fn edits_match_a_full_restyle() {
+        let mut inc = rust();
+        let mut styles = inc.resync(TEXT.to_string());
+        assert_eq!(styles.len(), TEXT.len());
+        assert!(styles.bytes().any(|c| c != b'A'));
+        let mut text = TEXT.to_string();
+        for (pos, deleted, ins) in [
+            (16, 3, "let mut"),
+            (0, 0, "// note\n"),
+            (30, 0, "\"a\nb\""),
+            (9, 12, ""),
+        ] {
+            edit(&mut inc, &mut styles, pos, deleted, ins);
+            text.replace_range(pos..pos + deleted, ins);
+            assert_eq!(inc.len(), text.len());
+            assert_eq!(styles, restyled_from_scratch(&text), "after {:?}", ins);
+        }
+    }
+
+    #[test]
+    fn opening_a_comment_restyles_the_lines_below() {
+        let text = format!("{}*/", TEXT);
+        let mut inc = rust();
+        let mut styles = inc.resync(text.clone());
+        let before = styles.clone();
+        edit(&mut inc, &mut styles, 0, 0, "/*");
+        assert_eq!(styles, restyled_from_scratch(&format!("/*{}", text)));
+        assert!(styles.bytes().all(|c| c == styles.as_bytes()[0]));
+        assert_ne!(styles[2..], before[..]);
+    }
+
+    #[test]
+    fn rejects_edits_outside_the_text() {
+        let mut inc = rust();
+        inc.resync("é".to_string());
+        assert!(inc.edit(1, 0, "x").is_none());
+        assert!(inc.edit(0, 5, "").is_none());
+        assert!(inc.edit(2, 0, "x").is_some());
+    }
+}
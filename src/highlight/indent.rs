@@ -0,0 +1,75 @@
+// Indentation from the indent queries: a line is indented one level deeper than
+// the line where the innermost @indent node around it starts, and at that
+// line's level when it starts with the @outdent node closing it.
+use crate::indent::Block;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::ops::Range;
+use std::rc::Rc;
+use tree_sitter::{Language, Query, QueryCursor, StreamingIterator, Tree};
+
+thread_local! {
+    // The compiled indent query of every language, None when it doesn't compile
+    static QUERIES: RefCell<HashMap<&'static str, Option<Rc<Query>>>> =
+        RefCell::new(HashMap::new());
+}
+
+fn query(lang: &'static str, language: &Language, source: &str) -> Option<Rc<Query>> {
+    QUERIES.with(|q| {
+        q.borrow_mut()
+            .entry(lang)
+            .or_insert_with(|| Query::new(language, source).ok().map(Rc::new))
+            .clone()
+    })
+}
+
+// The block around a line break at every position, from the syntax tree of the
+// text. None when the query doesn't compile.
+pub fn blocks(
+    lang: &'static str,
+    language: &Language,
+    source: &str,
+    tree: &Tree,
+    text: &str,
+    positions: &[usize],
+) -> Option<Vec<Option<Block>>> {
+    let query = query(lang, language, source)?;
+    let indent = query.capture_index_for_name("indent")?;
+    let outdent = query.capture_index_for_name("outdent");
+    let mut indents: Vec<Range<usize>> = Vec::new();
+    let mut outdents: Vec<Range<usize>> = Vec::new();
+    let mut cursor = QueryCursor::new();
+    // only the nodes around the positions matter
+    if let (Some(first), Some(last)) = (positions.iter().min(), positions.iter().max()) {
+        cursor.set_byte_range(*first..(*last + 1).min(text.len()).max(*first));
+    }
+    let mut captures = cursor.captures(&query, tree.root_node(), text.as_bytes());
+    while let Some((m, idx)) = captures.next() {
+        let cap = m.captures[*idx];
+        if cap.index == indent {
+            indents.push(cap.node.byte_range());
+        } else if Some(cap.index) == outdent {
+            outdents.push(cap.node.byte_range());
+        }
+    }
+    let blocks = positions.iter().map(|&pos| {
+        // the innermost node is the one starting last
+        let block = indents
+            .iter()
+            .filter(|r| r.start < pos && pos < r.end)
+            .max_by_key(|r| r.start)?;
+        let first = pos
+            + text[pos..]
+                .bytes()
+                .take_while(|b| *b == b' ' || *b == b'\t')
+                .count();
+        let closes = outdents
+            .iter()
+            .any(|o| o.start == first && o.end == block.end);
+        Some(Block {
+            start: block.start,
+            closes,
+        })
+    });
+    Some(blocks.collect())
+}
@@ -14,11 +14,24 @@ pub const STYLES: &[(&str, u32)] = &[
     ("text.uri", DARKYELLOW),
 ];
 
+// Typed pairs, see brackets.rs
+pub const PAIRS: &[(char, char)] = &[('(', ')'), ('[', ']'), ('`', '`'), ('"', '"')];
+
+// Nodes whose brackets and quotes don't pair with the text around them
+pub const LITERALS: &[&str] = &["fenced_code_block", "indented_code_block"];
+
+// Nodes spanning several lines fold, see folds.rs
+pub const FOLDS: &[&str] = &["section", "fenced_code_block"];
+
+pub fn language() -> tree_sitter::Language {
+    ts::LANGUAGE.into()
+}
+
 pub fn lang_data() -> HighlightData {
     let (names, styles) = super::resolve_styles(STYLES);
     let mut config =
         HighlightConfiguration::new(ts::LANGUAGE.into(), "md", ts::HIGHLIGHT_QUERY_BLOCK, "", "")
             .unwrap();
     config.configure(&names);
-    HighlightData::new(styles, config, None)
+    HighlightData::new(names, styles, config, None)
 }
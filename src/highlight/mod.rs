@@ -1,10 +1,14 @@
 use fltk::{
     app,
-    enums::{Color, Font},
+    enums::Color,
     prelude::DisplayExt,
     text::{StyleTableEntryExt, TextAttr, TextBuffer, TextEditor},
 };
-use std::path::Path;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use tree_sitter::{Language, Parser, Tree};
 use tree_sitter_highlight::HighlightConfiguration;
 use tree_sitter_highlight::HighlightEvent;
 use tree_sitter_highlight::Highlighter;
@@ -12,23 +16,28 @@ use tree_sitter_highlight::Highlighter;
 use crate::diagnostics;
 
 mod colors;
+mod incremental;
+mod indent;
 mod md;
 mod rust;
 mod toml;
 
+use crate::indent::Block;
+
 fn translate_style(idx: usize) -> char {
     char::from_u32(65 + idx as u32).unwrap()
 }
 
 fn resolve_styles(v: &[(&'static str, u32)]) -> (Vec<&'static str>, Vec<StyleTableEntryExt>) {
+    let settings = crate::settings::get();
     let mut names = Vec::new();
     let mut styles = Vec::new();
     for elem in v {
         names.push(elem.0);
         styles.push(StyleTableEntryExt {
             color: Color::from_hex(elem.1),
-            font: Font::Courier,
-            size: app::font_size(),
+            font: settings.font(),
+            size: settings.font_size,
             attr: TextAttr::None,
             bgcolor: Color::Background,
         });
@@ -44,6 +53,7 @@ fn resolve_styles(v: &[(&'static str, u32)]) -> (Vec<&'static str>, Vec<StyleTab
 }
 
 pub struct HighlightData {
+    names: Vec<&'static str>,
     styles: Vec<StyleTableEntryExt>,
     config: HighlightConfiguration,
     exception_fn: Option<fn(usize, &str) -> char>,
@@ -51,11 +61,13 @@ pub struct HighlightData {
 
 impl HighlightData {
     pub fn new(
+        names: Vec<&'static str>,
         styles: Vec<StyleTableEntryExt>,
         config: HighlightConfiguration,
         exception_fn: Option<fn(usize, &str) -> char>,
     ) -> Self {
         Self {
+            names,
             styles,
             config,
             exception_fn,
@@ -76,43 +88,370 @@ fn get_highlight(p: &Path) -> Option<HighlightData> {
     }
 }
 
+// Bracket and quote pairs of the language, see brackets.rs
+pub fn pairs(p: &Path) -> Option<&'static [(char, char)]> {
+    match p.extension()?.to_str()? {
+        "rs" => Some(rust::PAIRS),
+        "toml" => Some(toml::PAIRS),
+        "md" => Some(md::PAIRS),
+        _ => None,
+    }
+}
+
+thread_local! {
+    // The syntax tree of every buffer parsed, with its language and the buffer
+    // generation it's for (see utils::buffer_generation)
+    static TREES: RefCell<HashMap<usize, (u64, &'static str, Tree)>> =
+        RefCell::new(HashMap::new());
+}
+
+fn language(p: &Path) -> Option<(&'static str, Language)> {
+    match p.extension()?.to_str()? {
+        "rs" => Some(("rs", rust::language())),
+        "toml" => Some(("toml", toml::language())),
+        "md" => Some(("md", md::language())),
+        _ => None,
+    }
+}
+
+fn store_tree(buf: &TextBuffer, lang: &'static str, tree: Tree) {
+    let gen = crate::utils::buffer_generation(buf);
+    TREES.with(|t| {
+        t.borrow_mut()
+            .insert(crate::utils::buffer_key(buf), (gen, lang, tree))
+    });
+}
+
+// The syntax tree of the buffer, whose text is given, parsed again only after
+// the text changed
+fn syntax_tree(p: &Path, buf: &TextBuffer, text: &str) -> Option<Tree> {
+    let (lang, language) = language(p)?;
+    let key = crate::utils::buffer_key(buf);
+    // large files have theirs kept up to date by the incremental highlighting
+    let live = STYLERS.with(|s| match s.borrow().get(&key) {
+        Some(Styler::Incremental(l, inc, _)) if *l == lang && inc.len() == text.len() => inc.tree(),
+        _ => None,
+    });
+    if live.is_some() {
+        return live;
+    }
+    let gen = crate::utils::buffer_generation(buf);
+    let cached = TREES.with(|t| {
+        t.borrow()
+            .get(&key)
+            .filter(|(g, l, _)| *g == gen && *l == lang)
+            .map(|(_, _, tree)| tree.clone())
+    });
+    if cached.is_some() {
+        return cached;
+    }
+    let mut parser = Parser::new();
+    parser.set_language(&language).ok()?;
+    let tree = parser.parse(text, None)?;
+    store_tree(buf, lang, tree.clone());
+    Some(tree)
+}
+
+// Sorted byte ranges of the strings and comments (code blocks in Markdown) in
+// the buffer, whose text is given
+pub fn literal_ranges(p: &Path, buf: &TextBuffer, text: &str) -> Option<Vec<Range<usize>>> {
+    let kinds = match p.extension()?.to_str()? {
+        "rs" => rust::LITERALS,
+        "toml" => toml::LITERALS,
+        "md" => md::LITERALS,
+        _ => return None,
+    };
+    let tree = syntax_tree(p, buf, text)?;
+    let mut ranges = Vec::new();
+    let mut cursor = tree.walk();
+    // depth first, without descending into the literals
+    loop {
+        let node = cursor.node();
+        if kinds.contains(&node.kind()) {
+            ranges.push(node.byte_range());
+        } else if cursor.goto_first_child() {
+            continue;
+        }
+        while !cursor.goto_next_sibling() {
+            if !cursor.goto_parent() {
+                return Some(ranges);
+            }
+        }
+    }
+}
+
+// The blocks around line breaks at the positions in the buffer, whose text is
+// given, from the language's indent query. None for languages without one, e.g.
+// Markdown keeps the indentation.
+pub fn indent_blocks(
+    p: &Path,
+    buf: &TextBuffer,
+    text: &str,
+    positions: &[usize],
+) -> Option<Vec<Option<Block>>> {
+    let source = match p.extension()?.to_str()? {
+        "rs" => rust::INDENTS_QUERY,
+        "toml" => toml::INDENTS_QUERY,
+        _ => return None,
+    };
+    let (lang, language) = language(p)?;
+    let tree = syntax_tree(p, buf, text)?;
+    indent::blocks(lang, &language, source, &tree, text, positions)
+}
+
+// Line ranges that fold as (first line, last hidden line), from the nodes of
+// the language spanning several lines. The line closing a bracket stays shown.
+pub fn fold_ranges(p: &Path, text: &str) -> Option<Vec<(usize, usize)>> {
+    let (language, kinds) = match p.extension()?.to_str()? {
+        "rs" => (rust::language(), rust::FOLDS),
+        "toml" => (toml::language(), toml::FOLDS),
+        "md" => (md::language(), md::FOLDS),
+        _ => return None,
+    };
+    let mut parser = Parser::new();
+    parser.set_language(&language).ok()?;
+    let tree = parser.parse(text, None)?;
+    let mut ranges = Vec::new();
+    let mut cursor = tree.walk();
+    'walk: loop {
+        let node = cursor.node();
+        if kinds.contains(&node.kind()) {
+            let first = node.start_position().row;
+            let end = node.end_position();
+            let last = if end.column == 0 {
+                // ends at a line start, e.g. a Markdown section
+                end.row.saturating_sub(1)
+            } else if text[node.end_byte() - end.column..node.end_byte()]
+                .trim_start()
+                .starts_with(['}', ')', ']'])
+            {
+                end.row.saturating_sub(1)
+            } else {
+                end.row
+            };
+            if last > first {
+                ranges.push((first, last));
+            }
+        }
+        if cursor.goto_first_child() {
+            continue;
+        }
+        while !cursor.goto_next_sibling() {
+            if !cursor.goto_parent() {
+                break 'walk;
+            }
+        }
+    }
+    // one range per line, the outermost
+    ranges.sort_by_key(|&(first, last)| (first, std::cmp::Reverse(last)));
+    ranges.dedup_by_key(|r| r.0);
+    Some(ranges)
+}
+
+// The style table of the language, the default style for other files
+pub fn styles(p: Option<&Path>) -> Vec<StyleTableEntryExt> {
+    match p.and_then(get_highlight) {
+        Some(data) => data.styles,
+        None => resolve_styles(&[("DEFAULT", colors::WHITE)]).1,
+    }
+}
+
+// Rebuilds the style table of an already highlighted editor, e.g. after a font change
+pub fn refresh_styles(p: &Path, ed: &mut TextEditor) {
+    if let (Some(sbuf), Some(data)) = (ed.style_buffer(), get_highlight(p)) {
+        ed.set_highlight_data_ext(sbuf, data.styles);
+    }
+}
+
+// Lets a split view display the highlighting of the editor owning the buffer
+pub fn share(p: &Path, from: &TextEditor, to: &mut TextEditor) {
+    if let (Some(sbuf), Some(data)) = (crate::folds::style_buffer(from), get_highlight(p)) {
+        to.set_highlight_data_ext(sbuf, data.styles);
+    }
+}
+
+// What keeps the style buffer of a document in step with its text
+enum Styler {
+    // highlights the whole text again
+    Full {
+        path: PathBuf,
+        highlighter: Box<Highlighter>,
+        config: Box<HighlightConfiguration>,
+        exception_fn: Option<fn(usize, &str) -> char>,
+        sbuf: TextBuffer,
+    },
+    // large files, see incremental.rs
+    Incremental(&'static str, incremental::Incremental, TextBuffer),
+    // the default style everywhere, the style buffer can't be detached
+    Plain(TextBuffer),
+}
+
+impl Styler {
+    // Restyles after an edit, or everything without one
+    fn edited(&mut self, buf: &TextBuffer, edit: Option<(i32, i32, i32)>) {
+        match self {
+            Styler::Full {
+                path,
+                highlighter,
+                config,
+                exception_fn,
+                sbuf,
+            } => apply(path, highlighter, config, &buf.text(), sbuf, exception_fn),
+            Styler::Incremental(_, inc, sbuf) => {
+                let applied = edit.is_some_and(|(pos, inserted, deleted)| {
+                    let txt = if inserted > 0 {
+                        buf.text_range(pos, pos + inserted).unwrap_or_default()
+                    } else {
+                        String::new()
+                    };
+                    let restyled = match inc.edit(pos as usize, deleted as usize, &txt) {
+                        Some(r) => r,
+                        None => return false,
+                    };
+                    // keep the style buffer aligned before writing the new styles
+                    sbuf.replace(pos, pos + deleted, &"A".repeat(txt.len()));
+                    for (start, styles) in restyled {
+                        let end = start + styles.len();
+                        sbuf.replace(start as i32, end as i32, &styles);
+                    }
+                    true
+                });
+                // start over rather than let the mirror drift from the buffer
+                if !applied || inc.len() != buf.length() as usize {
+                    sbuf.set_text(&inc.resync(buf.text()));
+                }
+            }
+            Styler::Plain(sbuf) => sbuf.set_text(&"A".repeat(buf.length() as usize)),
+        }
+    }
+}
+
+thread_local! {
+    // The styler of every highlighted buffer. A buffer has one modify callback
+    // for good, running whichever styler it has when called.
+    static STYLERS: RefCell<HashMap<usize, Styler>> = RefCell::new(HashMap::new());
+}
+
+// Makes the styler the buffer's, replacing the one of another language. The
+// buffer is edited on the main thread, so that's where this happens.
+fn install(buf: &TextBuffer, styler: Styler) {
+    let mut pending = Some((buf.clone(), styler));
+    let mut install = move || {
+        let (mut buf, mut styler) = match pending.take() {
+            Some(p) => p,
+            None => return,
+        };
+        // the text may have changed while the styles were computed
+        let synced = match &styler {
+            Styler::Full { sbuf, .. } | Styler::Plain(sbuf) => sbuf.length() == buf.length(),
+            Styler::Incremental(_, inc, _) => inc.len() == buf.length() as usize,
+        };
+        if !synced {
+            styler.edited(&buf, None);
+        }
+        let key = crate::utils::buffer_key(&buf);
+        if STYLERS.with(|s| s.borrow_mut().insert(key, styler).is_none()) {
+            buf.add_modify_callback({
+                let buf = buf.clone();
+                move |pos, inserted, deleted, _, _| {
+                    if inserted > 0 || deleted > 0 {
+                        restyle(&buf, pos, inserted, deleted);
+                    }
+                }
+            });
+        }
+    };
+    // the buffers belong to the program's main thread
+    if std::thread::current().name() == Some("main") {
+        install();
+    } else {
+        app::awake_callback(install);
+    }
+}
+
+fn restyle(buf: &TextBuffer, pos: i32, inserted: i32, deleted: i32) {
+    let key = crate::utils::buffer_key(buf);
+    // out of the map while it runs, restyling notifies the style buffer's
+    // callbacks
+    let styler = STYLERS.with(|s| s.borrow_mut().remove(&key));
+    if let Some(mut styler) = styler {
+        styler.edited(buf, Some((pos, inserted, deleted)));
+        // unless another language took over meanwhile
+        STYLERS.with(|s| {
+            s.borrow_mut().entry(key).or_insert(styler);
+        });
+    }
+}
+
+// Drops the styler and the syntax tree of a closed document's buffer
+pub fn forget(buf: &TextBuffer) {
+    let key = crate::utils::buffer_key(buf);
+    STYLERS.with(|s| s.borrow_mut().remove(&key));
+    TREES.with(|t| t.borrow_mut().remove(&key));
+}
+
+// Switches an editor to the language of a new path, e.g. after Save as...
+pub fn rehighlight(p: &Path, ed: &mut TextEditor, buf: &mut TextBuffer) {
+    if get_highlight(p).is_some() {
+        highlight(p, ed, buf);
+    } else if let Some(sbuf) = ed.style_buffer() {
+        let (_, styles) = resolve_styles(&[("DEFAULT", colors::WHITE)]);
+        ed.set_highlight_data_ext(sbuf.clone(), styles);
+        // the styles of the previous language aren't in the table anymore
+        let mut styler = Styler::Plain(sbuf);
+        styler.edited(buf, None);
+        install(buf, styler);
+    }
+}
+
 pub fn highlight(p: &Path, ed: &mut TextEditor, buf: &mut TextBuffer) {
     if let Some(HighlightData {
+        names,
         styles,
         config,
         exception_fn,
     }) = get_highlight(p)
     {
-        let mut highlighter = Highlighter::new();
         let mut sbuf = TextBuffer::default();
         let base_styles = styles.len() / 2; // we doubled styles to include underline variants
         ed.set_highlight_data_ext(sbuf.clone(), styles);
         // Register style buffer for diagnostics overlay
         #[cfg(feature = "lsp")]
         diagnostics::register_style_buf(p, &sbuf, base_styles);
+        if crate::settings::get().is_large(buf.length() as usize) {
+            // only the edited regions are reparsed and restyled
+            let lang = language(p).map(|(l, _)| l).unwrap_or_default();
+            let text = buf.text();
+            let path = p.to_path_buf();
+            if let Some(mut inc) = incremental::Incremental::new(config, &names, exception_fn, path)
+            {
+                sbuf.set_text(&inc.resync(text));
+                install(buf, Styler::Incremental(lang, inc, sbuf));
+            }
+            return;
+        }
+        let mut highlighter = Highlighter::new();
         apply(
+            p,
             &mut highlighter,
             &config,
             &buf.text(),
             &mut sbuf,
             &exception_fn,
         );
-        buf.add_modify_callback({
-            let buf = buf.clone();
-            move |_, _, _, _, _| {
-                apply(
-                    &mut highlighter,
-                    &config,
-                    &buf.text(),
-                    &mut sbuf,
-                    &exception_fn,
-                );
-            }
-        });
+        let styler = Styler::Full {
+            path: p.to_path_buf(),
+            highlighter: Box::new(highlighter),
+            config: Box::new(config),
+            exception_fn,
+            sbuf,
+        };
+        install(buf, styler);
     }
 }
 
 fn apply(
+    _p: &Path,
     highlighter: &mut Highlighter,
     config: &HighlightConfiguration,
     s: &str,
@@ -141,6 +480,13 @@ fn apply(
             HighlightEvent::HighlightEnd => curr = 0,
         }
     }
+    // the restyling drops the error underlines, put them back
+    #[cfg(feature = "lsp")]
+    let local_buf = {
+        let mut bytes = local_buf.into_bytes();
+        diagnostics::underline(_p, 0, &mut bytes);
+        String::from_utf8(bytes).unwrap_or_default()
+    };
     // Set base syntax highlight
     sbuf.set_text(&local_buf);
 }
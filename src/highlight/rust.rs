@@ -27,11 +27,71 @@ pub const STYLES: &[(&str, u32)] = &[
     ("label", WHITE),
 ];
 
+// Typed pairs, see brackets.rs. No single quote, it starts lifetimes too.
+pub const PAIRS: &[(char, char)] = &[('(', ')'), ('[', ']'), ('{', '}'), ('"', '"')];
+
+// Nodes whose brackets and quotes don't pair with the code around them
+pub const LITERALS: &[&str] = &[
+    "string_literal",
+    "raw_string_literal",
+    "char_literal",
+    "line_comment",
+    "block_comment",
+];
+
+// Nodes spanning several lines fold, see folds.rs
+pub const FOLDS: &[&str] = &[
+    "block",
+    "declaration_list",
+    "field_declaration_list",
+    "enum_variant_list",
+    "match_block",
+    "use_list",
+    "token_tree",
+    "arguments",
+    "field_initializer_list",
+    "array_expression",
+    "block_comment",
+];
+
+// Lines inside these nodes are indented, see indent.rs
+pub const INDENTS_QUERY: &str = r#"
+[
+  (block)
+  (declaration_list)
+  (field_declaration_list)
+  (ordered_field_declaration_list)
+  (enum_variant_list)
+  (match_block)
+  (field_initializer_list)
+  (use_list)
+  (arguments)
+  (parameters)
+  (closure_parameters)
+  (type_arguments)
+  (type_parameters)
+  (tuple_expression)
+  (tuple_type)
+  (tuple_pattern)
+  (tuple_struct_pattern)
+  (struct_pattern)
+  (slice_pattern)
+  (array_expression)
+  (token_tree)
+] @indent
+
+["}" ")" "]"] @outdent
+"#;
+
+pub fn language() -> tree_sitter::Language {
+    ts::LANGUAGE.into()
+}
+
 pub fn lang_data() -> HighlightData {
     let (names, styles) = super::resolve_styles(STYLES);
     let mut config =
         HighlightConfiguration::new(ts::LANGUAGE.into(), "rust", ts::HIGHLIGHTS_QUERY, "", "")
             .unwrap();
     config.configure(&names);
-    HighlightData::new(styles, config, None)
+    HighlightData::new(names, styles, config, None)
 }
@@ -15,11 +15,34 @@ pub const STYLES: &[(&str, u32)] = &[
     ("constant.builtin", DARKYELLOW),
 ];
 
+// Typed pairs, see brackets.rs
+pub const PAIRS: &[(char, char)] = &[('[', ']'), ('{', '}'), ('"', '"'), ('\'', '\'')];
+
+// Nodes whose brackets and quotes don't pair with the code around them
+pub const LITERALS: &[&str] = &["string", "comment"];
+
+// Nodes spanning several lines fold, see folds.rs
+pub const FOLDS: &[&str] = &["table", "table_array_element", "array", "inline_table"];
+
+// Lines inside these nodes are indented, see indent.rs
+pub const INDENTS_QUERY: &str = r#"
+[
+  (array)
+  (inline_table)
+] @indent
+
+["]" "}"] @outdent
+"#;
+
+pub fn language() -> tree_sitter::Language {
+    ts::LANGUAGE.into()
+}
+
 pub fn lang_data() -> HighlightData {
     let (names, styles) = super::resolve_styles(STYLES);
     let mut config =
         HighlightConfiguration::new(ts::LANGUAGE.into(), "toml", ts::HIGHLIGHTS_QUERY, "", "")
             .unwrap();
     config.configure(&names);
-    HighlightData::new(styles, config, None)
+    HighlightData::new(names, styles, config, None)
 }
@@ -0,0 +1,285 @@
+// Auto-indent: Enter keeps the indentation of the line, one level more inside a
+// block and one less on the line closing it. The blocks come from the
+// language's indent query (see highlight), other files go by the brackets at
+// the end of the line. Reindent applies the same to the selected lines.
+use crate::state::STATE;
+use fltk::{enums::*, prelude::*, text};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+const OPENING: &[char] = &['{', '(', '['];
+const CLOSING: &[char] = &['}', ')', ']'];
+
+// The block around a line, from the language's indent query (see highlight) or
+// the brackets
+pub struct Block {
+    // Where the block starts, its line gives the base indentation
+    pub start: usize,
+    // The line starts with the block's closing bracket
+    pub closes: bool,
+}
+
+fn is_blank(b: u8) -> bool {
+    b == b' ' || b == b'\t'
+}
+
+fn line_start(text: &str, pos: usize) -> usize {
+    text[..pos].rfind('\n').map(|i| i + 1).unwrap_or(0)
+}
+
+fn line_end(text: &str, pos: usize) -> usize {
+    text[pos..]
+        .find('\n')
+        .map(|i| pos + i)
+        .unwrap_or(text.len())
+}
+
+// The indentation of the line starting at ls
+fn leading(text: &str, ls: usize) -> &str {
+    let n = text[ls..].bytes().take_while(|b| is_blank(*b)).count();
+    &text[ls..ls + n]
+}
+
+// One level, a tab where the indentation has tabs
+fn unit(indent: &str) -> String {
+    if indent.contains('\t') {
+        "\t".to_string()
+    } else {
+        " ".repeat(crate::settings::get().tab_distance.max(1) as usize)
+    }
+}
+
+fn deeper(indent: &str) -> String {
+    format!("{}{}", indent, unit(indent))
+}
+
+fn shallower(indent: &str) -> String {
+    let mut s = indent.to_string();
+    if s.ends_with('\t') {
+        s.pop();
+    } else {
+        let width = crate::settings::get().tab_distance.max(1) as usize;
+        for _ in 0..width {
+            if !s.ends_with(' ') {
+                break;
+            }
+            s.pop();
+        }
+    }
+    s
+}
+
+fn path_of(ed: &text::TextEditor) -> Option<PathBuf> {
+    let view = ed.as_widget_ptr() as usize;
+    STATE.with(move |s| {
+        s.map
+            .get(&s.doc_id(view))
+            .and_then(|d| d.current_file.clone())
+    })
+}
+
+// The blocks around line breaks at the positions, None without an indent query
+fn blocks(
+    _path: Option<&Path>,
+    _buf: &text::TextBuffer,
+    text: &str,
+    _positions: &[usize],
+) -> Option<Vec<Option<Block>>> {
+    if crate::settings::get().is_large(text.len()) {
+        return None;
+    }
+    #[cfg(feature = "highlight")]
+    if let Some(v) = _path.and_then(|p| crate::highlight::indent_blocks(p, _buf, text, _positions))
+    {
+        return Some(v);
+    }
+    None
+}
+
+// Lines starting inside a string or comment keep their indentation
+fn in_literals(
+    _path: Option<&Path>,
+    _buf: &text::TextBuffer,
+    _text: &str,
+    starts: &[usize],
+) -> Vec<bool> {
+    #[cfg(feature = "highlight")]
+    if let Some(l) = _path.and_then(|p| crate::highlight::literal_ranges(p, _buf, _text)) {
+        return starts
+            .iter()
+            .map(|&ls| l.iter().any(|r| r.start < ls && ls < r.end))
+            .collect();
+    }
+    vec![false; starts.len()]
+}
+
+// The indentation inside a block, the one of the line it starts on unless
+// given in `indents`, one level deeper unless the line closes it
+fn block_indent(text: &str, block: &Block, indents: &HashMap<usize, String>) -> String {
+    let ls = line_start(text, block.start);
+    let base = match indents.get(&ls) {
+        Some(i) => i.clone(),
+        None => leading(text, ls).to_string(),
+    };
+    if block.closes {
+        base
+    } else {
+        deeper(&base)
+    }
+}
+
+// Replaces the selection (or nothing) at the caret with an indented line break
+fn newline(ed: &mut text::TextEditor) -> bool {
+    // a folded view shows a copy of the document, see folds.rs
+    let mut buf = match crate::folds::doc_buffer(ed) {
+        Some(b) => b,
+        None => return false,
+    };
+    let text = buf.text();
+    let pos = crate::folds::to_doc(ed, ed.insert_position()) as usize;
+    let (start, end) = crate::folds::selection(ed)
+        .filter(|(s, e)| s != e)
+        .map(|(s, e)| (s as usize, e as usize))
+        .unwrap_or((pos, pos));
+    let ls = line_start(&text, start);
+    let current = leading(&text, ls);
+    let before = text[ls..start].trim_end();
+    // the blanks after the caret make way for the new indentation
+    let end = end
+        + text[end..line_end(&text, end)]
+            .bytes()
+            .take_while(|b| is_blank(*b))
+            .count();
+    let after = text[end..].chars().next();
+    let opens = before.ends_with(OPENING);
+    let closes = after.is_some_and(|c| CLOSING.contains(&c));
+    let path = path_of(ed);
+    // an unclosed block at the end isn't around the caret yet, the brackets
+    // tell then
+    let block =
+        blocks(path.as_deref(), &buf, &text, &[start]).and_then(|v| v.into_iter().next()?);
+    let (indent, outer) = match block {
+        Some(b) if b.closes && opens => {
+            let outer = block_indent(&text, &b, &HashMap::new());
+            (deeper(&outer), Some(outer))
+        }
+        Some(b) => (block_indent(&text, &b, &HashMap::new()), None),
+        None if opens && closes => (deeper(current), Some(current.to_string())),
+        None if opens => (deeper(current), None),
+        None if closes => (shallower(current), None),
+        None => (current.to_string(), None),
+    };
+    // a line left blank loses its indentation
+    let from = if before.is_empty() { ls } else { start };
+    let mut insert = format!("\n{}", indent);
+    let caret = from + insert.len();
+    if let Some(outer) = outer {
+        insert.push('\n');
+        insert.push_str(&outer);
+    }
+    buf.replace(from as i32, end as i32, &insert);
+    ed.set_insert_position(crate::folds::to_shown(ed, caret as i32));
+    ed.show_insert_position();
+    ed.do_callback();
+    true
+}
+
+// Reindents the lines of the selection, or the caret's line
+pub fn reindent() {
+    let mut ed = match STATE.with(|s| s.current_editor()) {
+        Some(e) => e,
+        None => return,
+    };
+    let mut buf = match crate::folds::doc_buffer(&ed) {
+        Some(b) => b,
+        None => return,
+    };
+    let text = buf.text();
+    let pos = crate::folds::to_doc(&ed, ed.insert_position()) as usize;
+    let (start, end) = crate::folds::selection(&ed)
+        .filter(|(s, e)| s != e)
+        .map(|(s, e)| (s as usize, e as usize))
+        .unwrap_or((pos, pos));
+    let first = line_start(&text, start);
+    // a selection ending at a line start doesn't take that line
+    let last = if end > start && line_start(&text, end) == end {
+        end - 1
+    } else {
+        end
+    };
+    let last = line_end(&text, last.max(first));
+    let mut starts = vec![first];
+    while let Some(i) = text[*starts.last().unwrap()..last].find('\n') {
+        let next = starts.last().unwrap() + i + 1;
+        starts.push(next);
+    }
+    let path = path_of(&ed);
+    let blocks = blocks(path.as_deref(), &buf, &text, &starts);
+    let kept = in_literals(path.as_deref(), &buf, &text, &starts);
+    // without blocks, a line follows the line before it
+    let mut prev = text[..first]
+        .trim_end()
+        .rsplit('\n')
+        .next()
+        .filter(|_| first > 0)
+        .map(|l| (leading(l, 0).to_string(), l.trim_end().ends_with(OPENING)));
+    let mut indents: HashMap<usize, String> = HashMap::new();
+    let mut lines = Vec::with_capacity(starts.len());
+    for (i, &ls) in starts.iter().enumerate() {
+        let le = line_end(&text, ls);
+        let own = leading(&text, ls);
+        let content = text[ls..le].trim_start_matches([' ', '\t']);
+        if kept[i] {
+            lines.push(text[ls..le].to_string());
+            indents.insert(ls, own.to_string());
+            continue;
+        }
+        let indent = match &blocks {
+            Some(b) => match &b[i] {
+                Some(block) => block_indent(&text, block, &indents),
+                None => String::new(),
+            },
+            None => match &prev {
+                Some((p, true)) if content.starts_with(CLOSING) => p.clone(),
+                Some((p, true)) => deeper(p),
+                Some((p, false)) if content.starts_with(CLOSING) => shallower(p),
+                _ => own.to_string(),
+            },
+        };
+        if !content.is_empty() {
+            prev = Some((indent.clone(), content.trim_end().ends_with(OPENING)));
+            lines.push(format!("{}{}", indent, content));
+        } else {
+            lines.push(String::new());
+        }
+        indents.insert(ls, indent);
+    }
+    let new = lines.join("\n");
+    if new != text[first..last] {
+        buf.replace(first as i32, last as i32, &new);
+        let (from, to) = (
+            crate::folds::to_shown(&ed, first as i32),
+            crate::folds::to_shown(&ed, (first + new.len()) as i32),
+        );
+        if let Some(mut shown) = ed.buffer() {
+            shown.select(from, to);
+        }
+        ed.set_insert_position(to);
+        ed.do_callback();
+    }
+}
+
+// Called from the editor's handler, takes Enter
+pub fn handle(ed: &mut text::TextEditor, ev: Event) -> bool {
+    if ev != Event::KeyDown || !crate::settings::get().auto_indent {
+        return false;
+    }
+    let state = crate::macros::event_state();
+    if state.intersects(Shortcut::Ctrl | Shortcut::Alt | Shortcut::Meta) {
+        return false;
+    }
+    match crate::macros::event_key() {
+        Key::Enter | Key::KPEnter => newline(ed),
+        _ => false,
+    }
+}
@@ -0,0 +1,551 @@
+// Key bindings: command ids mapped to key chords, including multi key chords
+// like "Ctrl+K Ctrl+C". A command keeps the shortcut it was registered with
+// unless keymap.toml in the data dir binds it, the file is reloaded when it
+// changes. A key profile (settings) can replace the registered shortcuts.
+use crate::{commands, ctx, settings, utils};
+use fltk::{app, dialog, enums::*, menu, prelude::*};
+use std::collections::HashMap;
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::{Mutex, OnceLock};
+use std::time::SystemTime;
+
+const HEADER: &str = "\
+# Key bindings: command id = chord or list of chords, e.g.
+# \"edit.copy\" = [\"Ctrl+C\", \"Ctrl+Insert\"]
+# \"file.save_all\" = \"Ctrl+K S\"
+# An empty list removes the default binding. Command ids are listed under
+# Edit/Key Bindings..., this file is reloaded when saved.
+";
+
+const MODIFIERS: &[(Shortcut, &str)] = &[
+    (Shortcut::Ctrl, "Ctrl"),
+    (Shortcut::Alt, "Alt"),
+    (Shortcut::Shift, "Shift"),
+    (Shortcut::Meta, "Meta"),
+];
+
+const KEY_NAMES: &[(Key, &str)] = &[
+    (Key::Tab, "Tab"),
+    (Key::Enter, "Enter"),
+    (Key::Escape, "Escape"),
+    (Key::BackSpace, "Backspace"),
+    (Key::Delete, "Delete"),
+    (Key::Insert, "Insert"),
+    (Key::Home, "Home"),
+    (Key::End, "End"),
+    (Key::PageUp, "PageUp"),
+    (Key::PageDown, "PageDown"),
+    (Key::Up, "Up"),
+    (Key::Down, "Down"),
+    (Key::Left, "Left"),
+    (Key::Right, "Right"),
+    (Key::from_char(' '), "Space"),
+];
+
+// One key press of a chord, letters are stored lowercase
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct KeyPress {
+    mods: i32,
+    key: i32,
+}
+
+pub type Chord = Vec<KeyPress>;
+
+impl KeyPress {
+    fn new(state: Shortcut, key: Key) -> Self {
+        let mask = MODIFIERS.iter().fold(0, |m, (s, _)| m | s.bits());
+        let mut key = key.bits();
+        if (b'A' as i32..=b'Z' as i32).contains(&key) {
+            key += 32;
+        }
+        Self {
+            mods: state.bits() & mask,
+            key,
+        }
+    }
+
+    pub fn from_event() -> Self {
+        Self::new(app::event_state(), app::event_key())
+    }
+
+    fn from_shortcut(sc: Shortcut) -> Option<Self> {
+        if sc == Shortcut::None {
+            None
+        } else {
+            Some(Self::new(sc, sc.key()))
+        }
+    }
+
+    pub fn shortcut(&self) -> Shortcut {
+        Shortcut::from_i32(self.mods | self.key)
+    }
+
+    // Shift, Ctrl, Alt and the like pressed on their own
+    pub fn is_modifier(&self) -> bool {
+        (Key::ShiftL.bits()..=Key::AltR.bits() + 4).contains(&self.key)
+    }
+
+    fn parse(s: &str) -> Option<Self> {
+        let (mods, key) = if s == "+" {
+            ("", "+")
+        } else if let Some(mods) = s.strip_suffix("++") {
+            (mods, "+")
+        } else {
+            match s.rfind('+') {
+                Some(i) => (&s[..i], &s[i + 1..]),
+                None => ("", s),
+            }
+        };
+        let mut state = Shortcut::None;
+        for m in mods.split('+').filter(|m| !m.is_empty()) {
+            state |= match m.to_lowercase().as_str() {
+                "ctrl" | "control" => Shortcut::Ctrl,
+                "alt" | "option" => Shortcut::Alt,
+                "shift" => Shortcut::Shift,
+                "meta" | "cmd" | "super" => Shortcut::Meta,
+                _ => return None,
+            };
+        }
+        let key = if let Some((k, _)) = KEY_NAMES.iter().find(|(_, n)| n.eq_ignore_ascii_case(key))
+        {
+            *k
+        } else if let Some(n) = key
+            .strip_prefix(['F', 'f'])
+            .and_then(|n| n.parse::<i32>().ok())
+        {
+            if !(1..=12).contains(&n) {
+                return None;
+            }
+            Key::from_i32(Key::F1.bits() + n - 1)
+        } else {
+            let mut chars = key.chars();
+            match (chars.next(), chars.next()) {
+                (Some(c), None) => Key::from_char(c),
+                _ => return None,
+            }
+        };
+        Some(Self::new(state, key))
+    }
+}
+
+impl fmt::Display for KeyPress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (m, name) in MODIFIERS {
+            if self.mods & m.bits() != 0 {
+                write!(f, "{}+", name)?;
+            }
+        }
+        let key = Key::from_i32(self.key);
+        if let Some((_, name)) = KEY_NAMES.iter().find(|(k, _)| *k == key) {
+            write!(f, "{}", name)
+        } else if (Key::F1.bits()..=Key::F12.bits()).contains(&self.key) {
+            write!(f, "F{}", self.key - Key::F1.bits() + 1)
+        } else {
+            match char::from_u32(self.key as u32) {
+                Some(c) => write!(f, "{}", c.to_ascii_uppercase()),
+                None => write!(f, "#{:x}", self.key),
+            }
+        }
+    }
+}
+
+// "Ctrl+K Ctrl+C" is Ctrl+K followed by Ctrl+C
+pub fn parse_chord(s: &str) -> Option<Chord> {
+    let chord: Option<Chord> = s.split_whitespace().map(KeyPress::parse).collect();
+    chord.filter(|c| !c.is_empty())
+}
+
+pub fn chord_label(chord: &[KeyPress]) -> String {
+    chord
+        .iter()
+        .map(|k| k.to_string())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[derive(Default)]
+struct Keymap {
+    // Bindings from keymap.toml, replacing the defaults of these commands
+    overrides: HashMap<String, Vec<Chord>>,
+    // The effective bindings of all commands
+    bindings: Vec<(Chord, String)>,
+    // Keys pressed so far of a multi key chord
+    pending: Chord,
+    modified: Option<SystemTime>,
+}
+
+static KEYMAP: OnceLock<Mutex<Keymap>> = OnceLock::new();
+
+fn with_keymap<T, F: FnOnce(&mut Keymap) -> T>(f: F) -> T {
+    let m = KEYMAP.get_or_init(|| Mutex::new(Keymap::default()));
+    f(&mut m.lock().unwrap())
+}
+
+pub fn path() -> Option<PathBuf> {
+    utils::data_dir().map(|d| d.join("keymap.toml"))
+}
+
+fn modified() -> Option<SystemTime> {
+    std::fs::metadata(path()?).and_then(|m| m.modified()).ok()
+}
+
+// Reads keymap.toml, the errors are reported but don't stop the other bindings
+fn read_file() -> (HashMap<String, Vec<Chord>>, Vec<String>) {
+    let mut overrides = HashMap::new();
+    let mut errors = Vec::new();
+    let text = match path().and_then(|p| std::fs::read_to_string(p).ok()) {
+        Some(t) => t,
+        None => return (overrides, errors),
+    };
+    let table = match text.parse::<toml::Table>() {
+        Ok(t) => t,
+        Err(e) => {
+            errors.push(e.to_string());
+            return (overrides, errors);
+        }
+    };
+    for (id, value) in table {
+        if commands::get(&id).is_none() {
+            errors.push(format!("Unknown command \"{}\"", id));
+            continue;
+        }
+        let list = match value {
+            toml::Value::String(s) => vec![toml::Value::String(s)],
+            toml::Value::Array(a) => a,
+            _ => {
+                errors.push(format!("\"{}\": expected a chord or a list of chords", id));
+                continue;
+            }
+        };
+        let mut chords = Vec::new();
+        for v in list {
+            match v.as_str().and_then(parse_chord) {
+                Some(c) => chords.push(c),
+                None => errors.push(format!("\"{}\": can't parse {}", id, v)),
+            }
+        }
+        overrides.insert(id, chords);
+    }
+    (overrides, errors)
+}
+
+fn write_file(overrides: &HashMap<String, Vec<Chord>>) -> std::io::Result<()> {
+    let path = match path() {
+        Some(p) => p,
+        None => return Ok(()),
+    };
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let mut ids: Vec<_> = overrides.keys().collect();
+    ids.sort();
+    let mut text = HEADER.to_string();
+    for id in ids {
+        let chords: Vec<String> = overrides[id]
+            .iter()
+            .map(|c| toml::Value::String(chord_label(c)).to_string())
+            .collect();
+        text.push_str(&format!(
+            "{} = [{}]\n",
+            toml::Value::String(id.clone()),
+            chords.join(", ")
+        ));
+    }
+    std::fs::write(path, text)
+}
+
+// Chords of the key profile in the settings
+fn profile_bindings() -> Vec<(Chord, String)> {
+    let table: &[(&str, &str)] = match settings::get().key_profile {
+        settings::KeyProfile::Default => &[],
+        settings::KeyProfile::Emacs => crate::emacs::BINDINGS,
+    };
+    table
+        .iter()
+        .filter(|(id, _)| commands::get(id).is_some())
+        .filter_map(|(id, chord)| parse_chord(chord).map(|c| (c, id.to_string())))
+        .collect()
+}
+
+fn rebuild(k: &mut Keymap) {
+    k.bindings.clear();
+    k.pending.clear();
+    let profile = profile_bindings();
+    for cmd in commands::all() {
+        let in_profile: Vec<Chord> = profile
+            .iter()
+            .filter(|(_, id)| *id == cmd.id)
+            .map(|(c, _)| c.clone())
+            .collect();
+        let chords = match k.overrides.get(&cmd.id) {
+            Some(c) => c.clone(),
+            None if !in_profile.is_empty() => in_profile,
+            // the profile's chords take over the shortcuts they clash with
+            None => KeyPress::from_shortcut(cmd.shortcut)
+                .map(|k| vec![vec![k]])
+                .unwrap_or_default()
+                .into_iter()
+                .filter(|c| {
+                    !profile
+                        .iter()
+                        .any(|(p, _)| p.starts_with(c) || c.starts_with(p))
+                })
+                .collect(),
+        };
+        for c in chords {
+            k.bindings.push((c, cmd.id.clone()));
+        }
+    }
+}
+
+// Loads the keymap, call after the commands are registered. Polls the file for changes.
+pub fn init() {
+    let (overrides, errors) = read_file();
+    with_keymap(|k| {
+        k.overrides = overrides;
+        k.modified = modified();
+        rebuild(k);
+    });
+    report(errors);
+    fn schedule_poll() {
+        app::add_timeout3(1.0, |_| {
+            let changed = with_keymap(|k| k.modified) != modified();
+            if changed {
+                reload();
+            }
+            schedule_poll();
+        });
+    }
+    schedule_poll();
+}
+
+// Rereads keymap.toml and updates the menus
+pub fn reload() {
+    let (overrides, errors) = read_file();
+    with_keymap(|k| {
+        k.overrides = overrides;
+        k.modified = modified();
+        rebuild(k);
+    });
+    update_menus();
+    report(errors);
+}
+
+fn report(mut errors: Vec<String>) {
+    errors.extend(conflicts());
+    if !errors.is_empty() {
+        // deferred, this can run before the main window is shown
+        app::add_timeout3(0.0, move |_| {
+            dialog::alert_default(&format!("keymap.toml:\n{}", errors.join("\n")));
+        });
+    }
+}
+
+// Rebuilds the bindings after the key profile changed
+pub fn refresh() {
+    with_keymap(rebuild);
+    update_menus();
+}
+
+// Binds a command to the given chords, None restores its default
+pub fn set_bindings(id: &str, chords: Option<Vec<Chord>>) -> std::io::Result<()> {
+    let overrides = with_keymap(|k| {
+        match chords {
+            Some(c) => k.overrides.insert(id.to_string(), c),
+            None => k.overrides.remove(id),
+        };
+        k.overrides.clone()
+    });
+    write_file(&overrides)?;
+    with_keymap(|k| {
+        k.modified = modified();
+        rebuild(k);
+    });
+    update_menus();
+    Ok(())
+}
+
+// Writes keymap.toml with the current overrides if it doesn't exist yet
+pub fn ensure_file() -> std::io::Result<()> {
+    match path() {
+        Some(p) if !p.exists() => write_file(&with_keymap(|k| k.overrides.clone())),
+        _ => Ok(()),
+    }
+}
+
+pub fn bindings(id: &str) -> Vec<Chord> {
+    with_keymap(|k| {
+        k.bindings
+            .iter()
+            .filter(|(_, c)| c == id)
+            .map(|(chord, _)| chord.clone())
+            .collect()
+    })
+}
+
+pub fn is_overridden(id: &str) -> bool {
+    with_keymap(|k| k.overrides.contains_key(id))
+}
+
+// The chords of a command for display, e.g. "Ctrl+K Ctrl+C, Ctrl+/"
+pub fn label(id: &str) -> String {
+    bindings(id)
+        .iter()
+        .map(|c| chord_label(c))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+// Menus can only show single key chords
+pub fn menu_shortcut(id: &str) -> Shortcut {
+    bindings(id)
+        .iter()
+        .find(|c| c.len() == 1)
+        .map(|c| c[0].shortcut())
+        .unwrap_or(Shortcut::None)
+}
+
+// Chords bound to several commands, or hiding the longer chords they start
+pub fn conflicts() -> Vec<String> {
+    with_keymap(|k| {
+        let mut out = Vec::new();
+        for (i, (a, id_a)) in k.bindings.iter().enumerate() {
+            for (b, id_b) in &k.bindings[i + 1..] {
+                if id_a == id_b {
+                    continue;
+                }
+                if a == b {
+                    out.push(format!(
+                        "{} is bound to {} and {}",
+                        chord_label(a),
+                        id_a,
+                        id_b
+                    ));
+                } else if b.starts_with(a) || a.starts_with(b) {
+                    let (short, long, id_short, id_long) = if a.len() < b.len() {
+                        (a, b, id_a, id_b)
+                    } else {
+                        (b, a, id_b, id_a)
+                    };
+                    out.push(format!(
+                        "{} ({}) hides {} ({})",
+                        chord_label(short),
+                        id_short,
+                        chord_label(long),
+                        id_long
+                    ));
+                }
+            }
+        }
+        out
+    })
+}
+
+// Command ids involved in a conflict
+pub fn conflicting_ids() -> Vec<String> {
+    with_keymap(|k| {
+        k.bindings
+            .iter()
+            .filter(|(a, id_a)| {
+                k.bindings
+                    .iter()
+                    .any(|(b, id_b)| id_a != id_b && (b.starts_with(a) || a.starts_with(b)))
+            })
+            .map(|(_, id)| id.clone())
+            .collect()
+    })
+}
+
+// Runs the command bound to the key pressed, called on KeyDown. Keys starting a
+// multi key chord are held until the chord completes or stops matching.
+pub fn handle_key() -> bool {
+    let press = KeyPress::from_event();
+    if press.is_modifier() {
+        return false;
+    }
+    let action = with_keymap(|k| {
+        k.pending.push(press);
+        if let Some((_, id)) = k.bindings.iter().find(|(c, _)| *c == k.pending) {
+            let id = id.clone();
+            k.pending.clear();
+            return Some(Some(id));
+        }
+        if k.bindings.iter().any(|(c, _)| c.starts_with(&k.pending)) {
+            return Some(None);
+        }
+        // the end of an unbound chord is swallowed too
+        let was_pending = k.pending.len() > 1;
+        k.pending.clear();
+        if was_pending {
+            Some(None)
+        } else {
+            None
+        }
+    });
+    match action {
+        Some(Some(id)) => {
+            commands::run(&id);
+            true
+        }
+        Some(None) => true,
+        None => false,
+    }
+}
+
+fn set_menu_shortcuts(m: &impl MenuExt, cmds: &[(Shortcut, commands::Command)], popup: bool) {
+    for (sc, cmd) in cmds {
+        if let Some(mut item) = m.find_item(&commands::menu_path(cmd, popup)) {
+            item.set_shortcut(*sc);
+        }
+    }
+}
+
+// Shows the current single key bindings in the menus of every window
+pub fn update_menus() {
+    let cmds: Vec<_> = commands::all()
+        .into_iter()
+        .filter(|c| c.menu.is_some())
+        .map(|c| (menu_shortcut(&c.id), c))
+        .collect();
+    for win in ctx::windows() {
+        if let Some(m) = ctx::widget_in::<menu::SysMenuBar>(win, "menu") {
+            set_menu_shortcuts(&m, &cmds, false);
+        }
+        for id in ["popup", "fmt_status"] {
+            if let Some(m) = ctx::widget_in::<menu::MenuButton>(win, id) {
+                set_menu_shortcuts(&m, &cmds, true);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn label(s: &str) -> Option<String> {
+        parse_chord(s).map(|c| chord_label(&c))
+    }
+
+    #[test]
+    fn parses_chords() {
+        assert_eq!(label("Ctrl+K Ctrl+C").as_deref(), Some("Ctrl+K Ctrl+C"));
+        // modifiers in any order and case, letters are stored lowercase
+        assert_eq!(parse_chord("shift+ctrl+s"), parse_chord("Ctrl+Shift+S"));
+        assert_eq!(label("ctrl+shift+s").as_deref(), Some("Ctrl+Shift+S"));
+        assert_eq!(label("Alt+F4").as_deref(), Some("Alt+F4"));
+        assert_eq!(label("ctrl+space").as_deref(), Some("Ctrl+Space"));
+        assert_eq!(label("Ctrl++").as_deref(), Some("Ctrl++"));
+        assert_eq!(label("+").as_deref(), Some("+"));
+    }
+
+    #[test]
+    fn rejects_bad_chords() {
+        assert_eq!(parse_chord(""), None);
+        assert_eq!(parse_chord("Hyper+K"), None);
+        assert_eq!(parse_chord("F13"), None);
+        assert_eq!(parse_chord("Ctrl+KC"), None);
+        assert_eq!(parse_chord("Ctrl+K Bogus+C"), None);
+    }
+}
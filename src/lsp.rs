@@ -1,5 +1,5 @@
 use std::fmt;
-use crossbeam_channel::{unbounded, Receiver, Sender};
+use crossbeam_channel::{bounded, unbounded, Receiver, Sender};
 use lsp_types as lsp;
 use serde::{Deserialize, Serialize};
 use serde_json::Value as JsonValue;
@@ -12,6 +12,7 @@ use std::sync::{
     atomic::{AtomicBool, AtomicU64, Ordering},
     Arc, Mutex, OnceLock,
 };
+use std::time::Duration;
 use lsp_types::Url;
 
 use crate::diagnostics;
@@ -51,7 +52,6 @@ impl From<serde_json::Error> for LspError {
 type Result<T> = std::result::Result<T, LspError>;
 
 static REQ_ID: AtomicU64 = AtomicU64::new(1);
-static AVAILABLE: AtomicBool = AtomicBool::new(false);
 static DISABLED: AtomicBool = AtomicBool::new(false);
 
 #[derive(Debug)]
@@ -107,18 +107,18 @@ pub(crate) fn lsp_log(msg: &str) {
 // Full JSON logging helper intentionally omitted to keep logs concise.
 
 pub struct LspClient {
-    _child: Child,
+    child: Mutex<Child>,
     tx: Sender<Outgoing>,
     _writer: std::thread::JoinHandle<()>,
     _reader: std::thread::JoinHandle<()>,
-    _caps: Arc<Mutex<Option<lsp::ServerCapabilities>>>,
+    caps: Arc<Mutex<Option<lsp::ServerCapabilities>>>,
     ready: Arc<std::sync::atomic::AtomicBool>,
     queue: Arc<Mutex<Vec<Outgoing>>>,
 }
 
 impl LspClient {
     fn spawn_server(root: &Path) -> Result<Child> {
-        let ra = std::env::var("RED_RA_PATH").unwrap_or_else(|_| "rust-analyzer".to_string());
+        let ra = crate::settings::get().ra_path;
         let mut cmd = Command::new(ra);
         cmd.current_dir(root)
             .stdin(Stdio::piped())
@@ -174,11 +174,11 @@ impl LspClient {
         });
 
         let client = Arc::new(LspClient {
-            _child: child,
+            child: Mutex::new(child),
             tx,
             _writer: writer,
             _reader: reader,
-            _caps: caps,
+            caps,
             ready,
             queue,
         });
@@ -340,6 +340,90 @@ impl LspClient {
         }
     }
 
+    // Whether the server computes folding ranges, see folds.rs
+    pub fn supports_folding(&self) -> bool {
+        self.caps
+            .lock()
+            .ok()
+            .and_then(|c| c.as_ref()?.folding_range_provider.clone())
+            .is_some_and(|p| !matches!(p, lsp::FoldingRangeProviderCapability::Simple(false)))
+    }
+
+    pub fn folding_range<F>(&self, path: &Path, cb: F)
+    where
+        F: FnOnce(Vec<lsp::FoldingRange>) + Send + 'static,
+    {
+        if let Ok(uri) = Url::from_file_path(path) {
+            let id = REQ_ID.fetch_add(1, Ordering::Relaxed);
+            if let Ok(mut p) = pending().lock() {
+                p.insert(id, Pending::FoldingRange(Box::new(cb)));
+            } else {
+                lsp_log("failed to lock pending map for foldingRange");
+                return;
+            }
+            let params = lsp::FoldingRangeParams {
+                text_document: lsp::TextDocumentIdentifier { uri },
+                work_done_progress_params: Default::default(),
+                partial_result_params: Default::default(),
+            };
+            lsp_log(&format!("request foldingRange id={id} {}", path.display()));
+            if let Some(params) = to_json(&params) {
+                self.send_or_queue(Outgoing::Request {
+                    id,
+                    method: "textDocument/foldingRange".into(),
+                    params,
+                });
+            } else {
+                lsp_log("failed to serialize foldingRange params");
+            }
+        }
+    }
+
+    // Asks the server to shut down and exit, its reader thread ends with the pipe.
+    // Blocks for a few seconds at most, a server that doesn't comply is killed.
+    fn shutdown(&self) {
+        let id = REQ_ID.fetch_add(1, Ordering::Relaxed);
+        let (done_tx, done_rx) = bounded::<()>(1);
+        if let Ok(mut p) = pending().lock() {
+            p.insert(id, Pending::Shutdown(done_tx));
+        }
+        lsp_log("sending shutdown");
+        self.tx
+            .send(Outgoing::Request {
+                id,
+                method: "shutdown".into(),
+                params: JsonValue::Null,
+            })
+            .ok();
+        // exit must only follow the response to shutdown
+        if done_rx.recv_timeout(Duration::from_secs(2)).is_err() {
+            lsp_log("no response to shutdown");
+        }
+        if let Ok(mut p) = pending().lock() {
+            p.remove(&id);
+        }
+        self.tx
+            .send(Outgoing::Notification {
+                method: "exit".into(),
+                params: JsonValue::Null,
+            })
+            .ok();
+        if let Ok(mut child) = self.child.lock() {
+            for _ in 0..20 {
+                if !matches!(child.try_wait(), Ok(None)) {
+                    break;
+                }
+                std::thread::sleep(Duration::from_millis(50));
+            }
+            if matches!(child.try_wait(), Ok(None)) {
+                lsp_log("server still running after exit, killing it");
+                child.kill().ok();
+            }
+            // reap it either way
+            child.wait().ok();
+        }
+    }
+
     fn send_or_queue(&self, msg: Outgoing) {
         if self.ready.load(Ordering::Relaxed) {
             let _ = self.tx.send(msg);
@@ -361,6 +445,10 @@ fn client_caps() -> lsp::ClientCapabilities {
                 dynamic_registration: Some(false),
             }),
             completion: Some(lsp::CompletionClientCapabilities::default()),
+            folding_range: Some(lsp::FoldingRangeClientCapabilities {
+                line_folding_only: Some(true),
+                ..Default::default()
+            }),
             ..Default::default()
         }),
         ..Default::default()
@@ -420,9 +508,12 @@ impl Writer {
 struct Reader;
 
 type CompletionCb = Box<dyn FnOnce(lsp::CompletionResponse) + Send + 'static>;
+type FoldingRangeCb = Box<dyn FnOnce(Vec<lsp::FoldingRange>) + Send + 'static>;
 
 enum Pending {
     Completion(CompletionCb),
+    FoldingRange(FoldingRangeCb),
+    Shutdown(Sender<()>),
 }
 
 static PENDING: OnceLock<Arc<Mutex<HashMap<u64, Pending>>>> = OnceLock::new();
@@ -537,6 +628,18 @@ fn handle_incoming(
         }
         return;
     }
+    // the response to shutdown has a null result
+    if let Some(id) = raw.id.as_ref().and_then(|i| i.as_u64()) {
+        if let Ok(mut pend_map) = pending().lock() {
+            if matches!(pend_map.get(&id), Some(Pending::Shutdown(_))) {
+                if let Some(Pending::Shutdown(done)) = pend_map.remove(&id) {
+                    lsp_log(&format!("<- response shutdown #{id}"));
+                    done.send(()).ok();
+                }
+                return;
+            }
+        }
+    }
     if let (Some(idv), Some(result)) = (raw.id, raw.result) {
         if let Some(id) = idv.as_u64() {
             // handle known pending requests
@@ -555,6 +658,16 @@ fn handle_incoming(
                                 lsp_log(&format!("<- response completion #{id} ({count} items)"));
                                 cb(resp);
                             }
+                            Pending::FoldingRange(cb) => {
+                                let ranges: Vec<lsp::FoldingRange> =
+                                    serde_json::from_value(result.clone()).unwrap_or_default();
+                                lsp_log(&format!(
+                                    "<- response foldingRange #{id} ({} ranges)",
+                                    ranges.len()
+                                ));
+                                cb(ranges);
+                            }
+                            Pending::Shutdown(_) => {}
                         }
                     } else if let Ok(init) =
                         serde_json::from_value::<lsp::InitializeResult>(result.clone())
@@ -596,19 +709,35 @@ fn handle_incoming(
 
 // ---- Global access ----
 
-static CLIENT: OnceLock<Arc<LspClient>> = OnceLock::new();
+// One client per workspace root, shared by the windows opened on it
+static CLIENTS: OnceLock<Mutex<HashMap<PathBuf, Arc<LspClient>>>> = OnceLock::new();
+
+fn clients() -> &'static Mutex<HashMap<PathBuf, Arc<LspClient>>> {
+    CLIENTS.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+// The client whose root contains the path, the innermost root wins
+fn client_for(path: &Path) -> Option<Arc<LspClient>> {
+    let clients = clients().lock().ok()?;
+    clients
+        .iter()
+        .filter(|(root, _)| path.starts_with(root))
+        .max_by_key(|(root, _)| root.components().count())
+        .map(|(_, c)| c.clone())
+}
 
 pub fn init(root: PathBuf) {
-    if disabled_by_env() {
+    if !crate::settings::get().lsp_enabled {
         DISABLED.store(true, Ordering::Relaxed);
-        AVAILABLE.store(false, Ordering::Relaxed);
-        lsp_log("LSP disabled by environment");
+        lsp_log("LSP disabled by settings");
         app::awake();
         return;
     }
+    if has_client(&root) {
+        return;
+    }
     // Only start LSP if a Cargo.toml exists in the working directory
     if !root.join("Cargo.toml").exists() {
-        AVAILABLE.store(false, Ordering::Relaxed);
         lsp_log(&format!(
             "LSP not started: missing Cargo.toml in {}",
             root.display()
@@ -616,46 +745,72 @@ pub fn init(root: PathBuf) {
         app::awake();
         return;
     }
-    let start = || LspClient::start(root);
-    match start() {
+    match LspClient::start(root.clone()) {
         Ok(c) => {
-            lsp_log("LSP client started");
-            AVAILABLE.store(true, Ordering::Relaxed);
-            CLIENT.set(c).ok();
+            lsp_log(&format!("LSP client started for {}", root.display()));
+            if let Ok(mut clients) = clients().lock() {
+                clients.insert(root, c);
+            }
         }
         Err(err) => {
             lsp_log(&format!("LSP init failed: {err}"));
-            AVAILABLE.store(false, Ordering::Relaxed);
         }
     }
 }
 
-pub fn with_client<F: FnOnce(&LspClient)>(f: F) {
-    if let Some(c) = CLIENT.get() {
-        f(c);
+pub fn has_client(root: &Path) -> bool {
+    clients()
+        .lock()
+        .map(|c| c.contains_key(root))
+        .unwrap_or(false)
+}
+
+// Stops the client started for a workspace root
+pub fn shutdown(root: &Path) {
+    let client = clients().lock().ok().and_then(|mut c| c.remove(root));
+    if let Some(c) = client {
+        lsp_log(&format!("LSP client stopped for {}", root.display()));
+        // off the GUI thread, it waits for the server to exit
+        std::thread::spawn(move || c.shutdown());
+    }
+}
+
+// Runs f with the client responsible for the document at path
+pub fn with_client<F: FnOnce(&LspClient)>(path: &Path, f: F) {
+    if let Some(c) = client_for(path) {
+        f(&c);
     } else {
         lsp_log("no LSP client available");
     }
 }
 
-pub fn is_ready() -> bool {
-    if let Some(c) = CLIENT.get() {
+pub fn is_ready(root: &Path) -> bool {
+    if let Some(c) = client_for(root) {
         c.ready.load(Ordering::Relaxed)
     } else {
         false
     }
 }
 
+// Whether any client is still initializing
+pub fn is_starting() -> bool {
+    !is_disabled()
+        && clients()
+            .lock()
+            .map(|c| c.values().any(|c| !c.ready.load(Ordering::Relaxed)))
+            .unwrap_or(false)
+}
+
 pub fn is_available() -> bool {
-    AVAILABLE.load(Ordering::Relaxed) && !is_disabled()
+    !is_disabled() && clients().lock().map(|c| !c.is_empty()).unwrap_or(false)
 }
 
-pub fn status_text() -> String {
+pub fn status_text(root: &Path) -> String {
     if is_disabled() {
         "disabled".into()
-    } else if !AVAILABLE.load(Ordering::Relaxed) {
+    } else if client_for(root).is_none() {
         "unavailable".into()
-    } else if is_ready() {
+    } else if is_ready(root) {
         "ready".into()
     } else {
         "starting".into()
@@ -666,13 +821,6 @@ pub fn is_disabled() -> bool {
     DISABLED.load(Ordering::Relaxed)
 }
 
-fn disabled_by_env() -> bool {
-    match std::env::var("RED_LSP_DISABLE") {
-        Ok(v) => matches!(v.to_ascii_lowercase().as_str(), "1" | "true" | "yes" | "on"),
-        Err(_) => false,
-    }
-}
-
 // ---- Helpers ----
 
 pub fn compute_position_from_offset(text: &str, offset: usize) -> lsp::Position {
@@ -0,0 +1,640 @@
+// Keyboard macros: while recording, the commands that run, the keys reaching
+// the editors and the find, replace and completion actions are kept as steps.
+// Playing feeds the keys back through the editor's own handlers (vim.rs,
+// cursors.rs), reading them with the event functions below. Named macros are
+// saved to macros.toml in the data dir.
+use crate::{commands, dialogs, gui, palette, state::STATE, utils};
+use fltk::{app, dialog, enums::*, prelude::*, text};
+use std::cell::{Cell, RefCell};
+use std::path::PathBuf;
+
+// Upper bound of the repetitions of "Play to End of File" and "Play Several Times"
+const MAX_REPEAT: usize = 100_000;
+
+// Commands that only open a dialog, what's done in it is recorded instead
+const NOT_RECORDED: &[&str] = &[
+    "edit.find",
+    "edit.replace",
+    "edit.trigger_completion",
+    "emacs.isearch_forward",
+    "emacs.isearch_backward",
+    "view.command_palette",
+];
+
+#[derive(Clone, Debug, PartialEq)]
+struct RecordedKey {
+    key: i32,
+    state: i32,
+    text: String,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Step {
+    Command(String),
+    // Typed text without modifiers
+    Text(String),
+    Key(RecordedKey),
+    Find {
+        pattern: String,
+        regex: bool,
+        forward: bool,
+    },
+    Replace {
+        pattern: String,
+        replacement: String,
+        regex: bool,
+        in_selection: bool,
+        all: bool,
+    },
+    // An accepted completion, replacing before..after around the caret
+    Complete {
+        before: i32,
+        after: i32,
+        text: String,
+    },
+    // A line entered in vim's : / or ? prompt
+    Prompt {
+        prompt: char,
+        line: String,
+    },
+}
+
+#[derive(Default)]
+struct Macros {
+    recording: bool,
+    steps: Vec<Step>,
+    // The last recorded or loaded macro, what Play runs
+    last: Vec<Step>,
+}
+
+thread_local! {
+    static MACROS: RefCell<Macros> = RefCell::new(Macros::default());
+    // Set while a macro plays, nothing is recorded then
+    static PLAYING: Cell<bool> = const { Cell::new(false) };
+    // Set while a handler takes a key, the commands it runs are part of the key
+    static IN_KEY: Cell<bool> = const { Cell::new(false) };
+    // The key being played, returned by the event functions
+    static REPLAYED: RefCell<Option<RecordedKey>> = const { RefCell::new(None) };
+}
+
+fn replayed<T, F: FnOnce(&RecordedKey) -> T>(f: F) -> Option<T> {
+    REPLAYED.with(|r| r.borrow().as_ref().map(f))
+}
+
+// app::event_key, or the key being played
+pub fn event_key() -> Key {
+    replayed(|k| Key::from_i32(k.key)).unwrap_or_else(app::event_key)
+}
+
+pub fn event_state() -> Shortcut {
+    replayed(|k| Shortcut::from_i32(k.state)).unwrap_or_else(app::event_state)
+}
+
+pub fn event_text() -> String {
+    replayed(|k| k.text.clone()).unwrap_or_else(app::event_text)
+}
+
+pub fn playing() -> bool {
+    PLAYING.with(|p| p.get())
+}
+
+pub fn recording() -> bool {
+    !PLAYING.with(|p| p.get()) && MACROS.with(|m| m.borrow().recording)
+}
+
+fn record(step: Step) {
+    if !recording() {
+        return;
+    }
+    MACROS.with(|m| {
+        let mut m = m.borrow_mut();
+        // typing adds to the text before it
+        match (m.steps.last_mut(), step) {
+            (Some(Step::Text(t)), Step::Text(more)) => t.push_str(&more),
+            (_, step) => m.steps.push(step),
+        }
+    });
+}
+
+// Called by commands::run
+pub fn record_command(id: &str) {
+    if !IN_KEY.with(|k| k.get()) && !id.starts_with("macro.") && !NOT_RECORDED.contains(&id) {
+        record(Step::Command(id.to_string()));
+    }
+}
+
+pub fn record_find(pattern: &str, regex: bool, forward: bool) {
+    record(Step::Find {
+        pattern: pattern.to_string(),
+        regex,
+        forward,
+    });
+}
+
+pub fn record_replace(
+    pattern: &str,
+    replacement: &str,
+    regex: bool,
+    in_selection: bool,
+    all: bool,
+) {
+    record(Step::Replace {
+        pattern: pattern.to_string(),
+        replacement: replacement.to_string(),
+        regex,
+        in_selection,
+        all,
+    });
+}
+
+#[cfg(feature = "lsp")]
+pub fn record_completion(before: i32, after: i32, text: &str) {
+    record(Step::Complete {
+        before,
+        after,
+        text: text.to_string(),
+    });
+}
+
+// Runs a line of vim's prompt, the keys typed in the prompt don't reach the
+// editor and the commands it runs are part of the step
+pub fn record_prompt<F: FnOnce()>(prompt: char, line: &str, f: F) {
+    record(Step::Prompt {
+        prompt,
+        line: line.to_string(),
+    });
+    let outer = IN_KEY.with(|k| k.replace(true));
+    f();
+    IN_KEY.with(|k| k.set(outer));
+}
+
+const SPECIAL_KEYS: &[Key] = &[
+    Key::Enter,
+    Key::KPEnter,
+    Key::Tab,
+    Key::Escape,
+    Key::BackSpace,
+    Key::Delete,
+    Key::Home,
+    Key::End,
+    Key::PageUp,
+    Key::PageDown,
+    Key::Left,
+    Key::Right,
+    Key::Up,
+    Key::Down,
+];
+
+// Called from the editor's handler with the events it handled itself or left
+// to the editor, the keys bound to commands are recorded as the commands
+pub fn record_key(ev: Event) {
+    if ev != Event::KeyDown || !recording() {
+        return;
+    }
+    let key = app::event_key();
+    let state =
+        app::event_state() & (Shortcut::Ctrl | Shortcut::Alt | Shortcut::Shift | Shortcut::Meta);
+    let text = app::event_text();
+    let plain = !state.intersects(Shortcut::Ctrl | Shortcut::Alt | Shortcut::Meta);
+    if plain && !text.is_empty() && !text.chars().any(|c| c.is_control()) {
+        record(Step::Text(text));
+    } else if SPECIAL_KEYS.contains(&key) || (!plain && !text.is_empty()) {
+        record(Step::Key(RecordedKey {
+            key: key.bits(),
+            state: state.bits(),
+            text,
+        }));
+    }
+}
+
+// Runs one of the editor's key handlers, the key is recorded when it's taken
+pub fn handle_key<F: FnOnce() -> bool>(ev: Event, f: F) -> bool {
+    if ev != Event::KeyDown {
+        return f();
+    }
+    let outer = IN_KEY.with(|k| k.replace(true));
+    let handled = f();
+    IN_KEY.with(|k| k.set(outer));
+    if handled {
+        record_key(ev);
+    }
+    handled
+}
+
+// Shown in front of the status text of every window
+pub fn indicator() -> &'static str {
+    if MACROS.with(|m| m.try_borrow().map(|m| m.recording).unwrap_or(false)) {
+        "Recording   |   "
+    } else {
+        ""
+    }
+}
+
+// Starts recording, or stops and keeps the macro for Play
+pub fn toggle_record() {
+    MACROS.with(|m| {
+        let mut m = m.borrow_mut();
+        if m.recording {
+            m.recording = false;
+            if !m.steps.is_empty() {
+                m.last = std::mem::take(&mut m.steps);
+            }
+        } else {
+            m.recording = true;
+            m.steps.clear();
+        }
+    });
+    gui::refresh_info();
+}
+
+fn current_editor() -> Option<text::TextEditor> {
+    STATE.with(|s| s.current_editor())
+}
+
+// What the editor does with a key no handler took
+fn default_key(ed: &mut text::TextEditor, k: &RecordedKey) {
+    let key = Key::from_i32(k.key);
+    let state = Shortcut::from_i32(k.state);
+    let ctrl = state.contains(Shortcut::Ctrl);
+    let shift = state.contains(Shortcut::Shift);
+    match key {
+        Key::Enter | Key::KPEnter => ed.kf_enter(),
+        Key::BackSpace => ed.kf_backspace(),
+        Key::Delete => ed.kf_delete(),
+        Key::Home
+        | Key::End
+        | Key::PageUp
+        | Key::PageDown
+        | Key::Left
+        | Key::Right
+        | Key::Up
+        | Key::Down => match (ctrl, shift) {
+            (false, false) => ed.kf_move(key),
+            (false, true) => ed.kf_shift_move(key),
+            (true, false) => ed.kf_ctrl_move(key),
+            (true, true) => ed.kf_c_s_move(key),
+        },
+        _ if ctrl || state.intersects(Shortcut::Alt | Shortcut::Meta) => (),
+        _ if k.text == "\t" || !k.text.chars().any(|c| c.is_control()) => {
+            if let Some((s, e)) = ed.buffer().and_then(|b| b.selection_position()) {
+                crate::folds::replace(ed, s, e, "");
+            }
+            ed.insert(&k.text);
+            ed.show_insert_position();
+            ed.do_callback();
+        }
+        _ => (),
+    }
+}
+
+fn play_key(k: RecordedKey) {
+    let mut ed = match current_editor() {
+        Some(e) => e,
+        None => return,
+    };
+    REPLAYED.with(|r| *r.borrow_mut() = Some(k.clone()));
+    let handled = crate::vim::handle(&mut ed, Event::KeyDown)
+        || crate::cursors::handle(&mut ed, Event::KeyDown)
+        || crate::brackets::handle(&mut ed, Event::KeyDown)
+        || crate::indent::handle(&mut ed, Event::KeyDown);
+    REPLAYED.with(|r| *r.borrow_mut() = None);
+    if !handled {
+        default_key(&mut ed, &k);
+    }
+}
+
+// Selects the next match from the caret, without wrapping around. The whole
+// document is searched in a folded view, the folds hiding the match open.
+fn find(pattern: &str, regex: bool, forward: bool) -> bool {
+    let (mut ed, buf) =
+        match current_editor().and_then(|e| crate::folds::doc_buffer(&e).map(|b| (e, b))) {
+            Some(e) => e,
+            None => return false,
+        };
+    let matches = dialogs::find_matches(&buf.text(), pattern, regex);
+    let (from, to) = match crate::folds::selection(&ed) {
+        Some((s, e)) if s != e => (e as usize, s as usize),
+        _ => {
+            let pos = crate::folds::to_doc(&ed, ed.insert_position()) as usize;
+            (pos, pos)
+        }
+    };
+    let found = if forward {
+        matches.iter().find(|m| m.start >= from)
+    } else {
+        matches.iter().rev().find(|m| m.end <= to)
+    };
+    match found {
+        Some(m) => {
+            let (s, e) = crate::folds::reveal(&mut ed, m.start as i32, m.end as i32);
+            if let Some(mut shown) = ed.buffer() {
+                shown.select(s, e);
+            }
+            ed.set_insert_position(if forward { e } else { s });
+            ed.show_insert_position();
+            true
+        }
+        None => false,
+    }
+}
+
+fn complete(before: i32, after: i32, text: &str) {
+    let mut ed = match current_editor() {
+        Some(e) => e,
+        None => return,
+    };
+    if crate::cursors::replace_around(&mut ed, before, after, text) {
+        return;
+    }
+    if let Some(buf) = ed.buffer() {
+        let pos = ed.insert_position();
+        let start = (pos - before).max(0);
+        crate::folds::replace(&ed, start, (pos + after).min(buf.length()), text);
+        ed.set_insert_position(start + text.len() as i32);
+        ed.do_callback();
+    }
+}
+
+// Runs the steps once, false when a search found nothing
+fn play_steps(steps: &[Step]) -> bool {
+    for step in steps {
+        match step {
+            Step::Command(id) => commands::run(id),
+            Step::Text(t) => {
+                for c in t.chars() {
+                    play_key(RecordedKey {
+                        key: Key::from_char(c).bits(),
+                        state: 0,
+                        text: c.to_string(),
+                    });
+                }
+            }
+            Step::Key(k) => play_key(k.clone()),
+            Step::Find {
+                pattern,
+                regex,
+                forward,
+            } => {
+                if !find(pattern, *regex, *forward) {
+                    return false;
+                }
+            }
+            Step::Replace {
+                pattern,
+                replacement,
+                regex,
+                in_selection,
+                all,
+            } => {
+                if *all {
+                    dialogs::replace_all(pattern, replacement, *regex, *in_selection);
+                } else if !dialogs::replace_next(pattern, replacement, *regex, *in_selection) {
+                    return false;
+                }
+            }
+            Step::Complete {
+                before,
+                after,
+                text,
+            } => complete(*before, *after, text),
+            Step::Prompt { prompt, line } => crate::vim::run_line(*prompt, line),
+        }
+    }
+    true
+}
+
+// Plays the last macro count times, or until the end of the file is reached
+// or it stops moving forward. A pass that changes nothing ends it either way.
+fn play_macro(count: Option<usize>) {
+    let steps = MACROS.with(|m| m.borrow().last.clone());
+    if steps.is_empty() || recording() {
+        return;
+    }
+    let position = || {
+        current_editor()
+            .and_then(|e| {
+                e.buffer().map(|b| {
+                    (
+                        e.insert_position(),
+                        b.length(),
+                        utils::buffer_generation(&b),
+                    )
+                })
+            })
+            .unwrap_or((0, 0, 0))
+    };
+    PLAYING.with(|p| p.set(true));
+    for _ in 0..count.unwrap_or(MAX_REPEAT).min(MAX_REPEAT) {
+        let (before, _, gen) = position();
+        if !play_steps(&steps) {
+            break;
+        }
+        let (after, len, new_gen) = position();
+        if after == before && new_gen == gen {
+            break;
+        }
+        if count.is_none() && (after <= before || after >= len) {
+            break;
+        }
+    }
+    PLAYING.with(|p| p.set(false));
+}
+
+pub fn play() {
+    play_macro(Some(1));
+}
+
+pub fn play_times() {
+    let count = dialog::input_default("Play the macro how many times?", "10")
+        .and_then(|c| c.trim().parse::<usize>().ok());
+    if let Some(n) = count {
+        play_macro(Some(n));
+    }
+}
+
+pub fn play_to_end() {
+    play_macro(None);
+}
+
+pub fn path() -> Option<PathBuf> {
+    utils::data_dir().map(|d| d.join("macros.toml"))
+}
+
+fn step_to_toml(step: &Step) -> toml::Value {
+    let mut t = toml::Table::new();
+    match step {
+        Step::Command(id) => {
+            t.insert("command".into(), id.clone().into());
+        }
+        Step::Text(s) => {
+            t.insert("text".into(), s.clone().into());
+        }
+        Step::Key(k) => {
+            t.insert("key".into(), (k.key as i64).into());
+            t.insert("state".into(), (k.state as i64).into());
+            t.insert("text".into(), k.text.clone().into());
+        }
+        Step::Find {
+            pattern,
+            regex,
+            forward,
+        } => {
+            t.insert("find".into(), pattern.clone().into());
+            t.insert("regex".into(), (*regex).into());
+            t.insert("forward".into(), (*forward).into());
+        }
+        Step::Replace {
+            pattern,
+            replacement,
+            regex,
+            in_selection,
+            all,
+        } => {
+            t.insert("replace".into(), pattern.clone().into());
+            t.insert("with".into(), replacement.clone().into());
+            t.insert("regex".into(), (*regex).into());
+            t.insert("in_selection".into(), (*in_selection).into());
+            t.insert("all".into(), (*all).into());
+        }
+        Step::Complete {
+            before,
+            after,
+            text,
+        } => {
+            t.insert("complete".into(), text.clone().into());
+            t.insert("before".into(), (*before as i64).into());
+            t.insert("after".into(), (*after as i64).into());
+        }
+        Step::Prompt { prompt, line } => {
+            t.insert("prompt".into(), prompt.to_string().into());
+            t.insert("line".into(), line.clone().into());
+        }
+    }
+    toml::Value::Table(t)
+}
+
+fn step_from_toml(v: &toml::Value) -> Option<Step> {
+    let t = v.as_table()?;
+    let s = |k: &str| t.get(k).and_then(|v| v.as_str()).map(|s| s.to_string());
+    let b = |k: &str| t.get(k).and_then(|v| v.as_bool()).unwrap_or(false);
+    let i = |k: &str| t.get(k).and_then(|v| v.as_integer()).unwrap_or(0) as i32;
+    Some(if let Some(id) = s("command") {
+        Step::Command(id)
+    } else if t.contains_key("key") {
+        Step::Key(RecordedKey {
+            key: i("key"),
+            state: i("state"),
+            text: s("text").unwrap_or_default(),
+        })
+    } else if let Some(pattern) = s("find") {
+        Step::Find {
+            pattern,
+            regex: b("regex"),
+            forward: t.get("forward").and_then(|v| v.as_bool()).unwrap_or(true),
+        }
+    } else if let Some(pattern) = s("replace") {
+        Step::Replace {
+            pattern,
+            replacement: s("with").unwrap_or_default(),
+            regex: b("regex"),
+            in_selection: b("in_selection"),
+            all: b("all"),
+        }
+    } else if let Some(text) = s("complete") {
+        Step::Complete {
+            before: i("before"),
+            after: i("after"),
+            text,
+        }
+    } else if let Some(line) = s("line") {
+        Step::Prompt {
+            prompt: s("prompt")?.chars().next()?,
+            line,
+        }
+    } else {
+        Step::Text(s("text")?)
+    })
+}
+
+fn read_file() -> Vec<(String, Vec<Step>)> {
+    let table = match path()
+        .and_then(|p| std::fs::read_to_string(p).ok())
+        .and_then(|t| t.parse::<toml::Table>().ok())
+    {
+        Some(t) => t,
+        None => return Vec::new(),
+    };
+    table
+        .into_iter()
+        .filter_map(|(name, v)| {
+            let steps = v.as_array()?.iter().filter_map(step_from_toml).collect();
+            Some((name, steps))
+        })
+        .collect()
+}
+
+fn write_file(macros: &[(String, Vec<Step>)]) -> std::io::Result<()> {
+    let path = match path() {
+        Some(p) => p,
+        None => return Ok(()),
+    };
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let mut text = String::from("# Keyboard macros saved with Edit/Macros/Save...\n");
+    for (name, steps) in macros {
+        text.push_str(&format!("{} = [\n", toml::Value::String(name.clone())));
+        for step in steps {
+            text.push_str(&format!("    {},\n", step_to_toml(step)));
+        }
+        text.push_str("]\n");
+    }
+    std::fs::write(path, text)
+}
+
+// Saves the last macro under a name, replacing a macro of the same name
+pub fn save() {
+    let steps = MACROS.with(|m| m.borrow().last.clone());
+    if steps.is_empty() {
+        dialog::message_default("Record a macro first");
+        return;
+    }
+    let name = match dialog::input_default("Macro name:", "") {
+        Some(n) if !n.trim().is_empty() => n.trim().to_string(),
+        _ => return,
+    };
+    let mut macros = read_file();
+    macros.retain(|(n, _)| *n != name);
+    macros.push((name, steps));
+    if let Err(e) = write_file(&macros) {
+        dialog::alert_default(&format!("Failed to save the macro:\n{}", e));
+    }
+}
+
+// Lists the saved macros in the palette, the one picked becomes the last
+// macro and plays once
+pub fn play_saved() {
+    let entries: Vec<palette::Entry> = read_file()
+        .into_iter()
+        .map(|(name, steps)| palette::Entry {
+            key: name.clone(),
+            label: name,
+            detail: format!("{} steps", steps.len()),
+        })
+        .collect();
+    if entries.is_empty() {
+        dialog::message_default("No saved macros");
+        return;
+    }
+    palette::open(
+        entries,
+        |name| {
+            let steps = read_file().into_iter().find(|(n, _)| n == name);
+            if let Some((_, steps)) = steps {
+                MACROS.with(|m| m.borrow_mut().last = steps);
+                play();
+            }
+        },
+        None,
+    );
+}
@@ -1,17 +1,36 @@
 use std::env;
 
+mod autosave;
+mod brackets;
 mod cbs;
+mod commands;
 #[cfg(feature = "lsp")]
 mod completion;
+mod ctx;
+mod cursors;
 #[cfg(feature = "lsp")]
 mod diagnostics;
 mod dialogs;
+mod emacs;
+mod encoding;
 mod fbr;
+mod folds;
+mod goto;
 mod gui;
+mod indent;
+mod keymap;
 #[cfg(feature = "lsp")]
 mod lsp;
+mod macros;
+mod palette;
+mod recent;
+mod session;
+mod settings;
 mod state;
+mod tabs;
 mod utils;
+mod vim;
+mod watch;
 
 #[cfg(feature = "highlight")]
 mod highlight;
@@ -21,11 +40,17 @@ fn main() {
     // Install diagnostics UI wake handler early
     #[cfg(feature = "lsp")]
     diagnostics::install_awake_handler();
+    settings::init();
+    commands::init();
+    keymap::init();
+    watch::init();
+    autosave::init();
     let a = gui::init_gui(&current_file, &current_path);
-    state::init_state(current_file, current_path);
     // Initialize LSP (rust-analyzer) using current directory as root
-    // This will be a no-op if rust-analyzer is not available
+    // This will be a no-op if rust-analyzer is not available.
+    // Started before the state so restored tabs get their didOpen queued.
     #[cfg(feature = "lsp")]
-    lsp::init(std::env::current_dir().unwrap());
+    lsp::init(current_path);
+    state::init_state(current_file);
     a.run().unwrap();
 }
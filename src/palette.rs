@@ -0,0 +1,311 @@
+// Command palette (Ctrl+Shift+P): an input filtering a list of entries with
+// fuzzy matching. Commands used from the palette are listed first next time.
+// Go to File (goto.rs) reuses it with a preview of the highlighted entry.
+use crate::{commands, ctx, keymap, settings, utils};
+use fltk::{prelude::*, *};
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Mutex,
+};
+
+const MAX_RECENT: usize = 20;
+const MAX_SHOWN: usize = 200;
+
+#[derive(Clone, Debug)]
+pub struct Entry {
+    // What accept receives, e.g. the command id
+    pub key: String,
+    pub label: String,
+    // Shown in the second column, e.g. the shortcut
+    pub detail: String,
+}
+
+struct Palette {
+    entries: Vec<Entry>,
+    // Indices of the entries matching the query, in display order
+    shown: Vec<usize>,
+    // Their scores, so that added entries are merged in without re-scoring
+    scores: Vec<i32>,
+    accept: fn(&str),
+    // Shows the highlighted entry in the preview pane
+    preview: Option<fn(&str) -> String>,
+    // Key of the highlighted entry, kept while entries are added
+    selected: Option<String>,
+}
+
+static PALETTE: Mutex<Option<Palette>> = Mutex::new(None);
+// Bumped every time the palette opens
+static OPENED: AtomicUsize = AtomicUsize::new(0);
+
+const LIST_H: i32 = 300;
+const PREVIEW_H: i32 = 260;
+
+fn widgets() -> Option<(window::Window, input::Input, browser::HoldBrowser)> {
+    Some((
+        app::widget_from_id("palette")?,
+        app::widget_from_id("palette_input")?,
+        app::widget_from_id("palette_list")?,
+    ))
+}
+
+fn load_recent() -> Vec<String> {
+    let mut g = match utils::prefs_group("palette") {
+        Some(g) => g,
+        None => return Vec::new(),
+    };
+    let count = g.get_int("count").unwrap_or(0);
+    (0..count)
+        .filter_map(|i| g.get_str(&format!("entry{}", i)).ok())
+        .collect()
+}
+
+fn push_recent(id: &str) {
+    let mut recent = load_recent();
+    recent.retain(|r| r != id);
+    recent.insert(0, id.to_string());
+    recent.truncate(MAX_RECENT);
+    if let Some(mut g) = utils::prefs_group("palette") {
+        g.clear().ok();
+        g.set_int("count", recent.len() as i32).ok();
+        for (i, r) in recent.iter().enumerate() {
+            g.set_str(&format!("entry{}", i), r).ok();
+        }
+    }
+}
+
+fn run_command(id: &str) {
+    push_recent(id);
+    commands::run(id);
+}
+
+// Lists every registered command with its key bindings, recently used ones first
+pub fn show() {
+    let recent = load_recent();
+    let mut entries: Vec<Entry> = commands::all()
+        .into_iter()
+        .map(|c| Entry {
+            detail: keymap::label(&c.id),
+            key: c.id,
+            label: c.title,
+        })
+        .collect();
+    // stable, the others keep the registration order
+    entries.sort_by_key(|e| {
+        recent
+            .iter()
+            .position(|r| *r == e.key)
+            .unwrap_or(usize::MAX)
+    });
+    open(entries, run_command, None);
+}
+
+// Shows the palette over the active window with the given entries. With a
+// preview function, the highlighted entry is previewed below the list.
+// Returns a token for is_open.
+pub fn open(entries: Vec<Entry>, accept: fn(&str), preview: Option<fn(&str) -> String>) -> usize {
+    let opened = OPENED.fetch_add(1, Ordering::Relaxed) + 1;
+    let (mut win, mut input, _) = match widgets() {
+        Some(w) => w,
+        None => return opened,
+    };
+    *PALETTE.lock().unwrap() = Some(Palette {
+        entries,
+        shown: Vec::new(),
+        scores: Vec::new(),
+        accept,
+        preview,
+        selected: None,
+    });
+    if let Some(mut p) = app::widget_from_id::<text::TextDisplay>("palette_preview") {
+        if preview.is_some() {
+            let s = settings::get();
+            p.set_text_font(s.font());
+            p.set_text_size(s.font_size);
+            if let Some(mut buf) = p.buffer() {
+                buf.set_text("");
+            }
+            p.show();
+            win.set_size(win.w(), 30 + LIST_H + PREVIEW_H);
+        } else {
+            p.hide();
+            win.set_size(win.w(), 30 + LIST_H);
+        }
+    }
+    input.set_value("");
+    filter();
+    if let Some(main) = ctx::widget::<group::Tabs>("tabs").and_then(|t| t.window()) {
+        win.set_pos(main.x() + (main.w() - win.w()) / 2, main.y() + 60);
+    }
+    win.show();
+    input.take_focus().ok();
+    opened
+}
+
+// Adds entries to the open palette, e.g. as they are found in the background
+pub fn add_entries(entries: Vec<Entry>) {
+    if entries.is_empty() {
+        return;
+    }
+    let query = match widgets() {
+        Some((_, input, _)) => input.value(),
+        None => return,
+    };
+    let changed = match PALETTE.lock().unwrap().as_mut() {
+        Some(p) => {
+            // only the new entries are scored, the shown ones keep their place
+            let start = p.entries.len();
+            p.entries.extend(entries);
+            let mut scored: Vec<(i32, usize)> = p
+                .entries
+                .iter()
+                .enumerate()
+                .skip(start)
+                .filter_map(|(i, e)| utils::fuzzy_score(&query, &e.label).map(|s| (s, i)))
+                .collect();
+            if scored.is_empty() {
+                false
+            } else {
+                let old = p.shown.clone();
+                scored.extend(p.scores.iter().copied().zip(p.shown.iter().copied()));
+                rank(p, scored);
+                p.shown != old
+            }
+        }
+        None => return,
+    };
+    if changed {
+        relist(true);
+    }
+}
+
+// Keeps the best scored entries as the shown ones
+fn rank(p: &mut Palette, mut scored: Vec<(i32, usize)>) {
+    // equal scores keep the entries' order, e.g. the recently used one first
+    scored.sort_by(|a, b| b.0.cmp(&a.0).then(a.1.cmp(&b.1)));
+    scored.truncate(MAX_SHOWN);
+    p.scores = scored.iter().map(|(s, _)| *s).collect();
+    p.shown = scored.into_iter().map(|(_, i)| i).collect();
+}
+
+// Refills the list with the entries matching the query after it changed, best
+// matches first
+pub fn filter() {
+    let query = match widgets() {
+        Some((_, input, _)) => input.value(),
+        None => return,
+    };
+    if let Some(p) = PALETTE.lock().unwrap().as_mut() {
+        let scored: Vec<(i32, usize)> = p
+            .entries
+            .iter()
+            .enumerate()
+            .filter_map(|(i, e)| utils::fuzzy_score(&query, &e.label).map(|s| (s, i)))
+            .collect();
+        rank(p, scored);
+    }
+    relist(false);
+}
+
+// Lists the shown entries. With keep, the highlighted entry stays highlighted
+// if it still matches.
+fn relist(keep: bool) {
+    let (_, _, mut list) = match widgets() {
+        Some(w) => w,
+        None => return,
+    };
+    let selected = {
+        let mut guard = PALETTE.lock().unwrap();
+        let p = match guard.as_mut() {
+            Some(p) => p,
+            None => return,
+        };
+        list.clear();
+        for i in &p.shown {
+            let e = &p.entries[*i];
+            list.add(&format!("@.{}\t@.{}", e.label, e.detail));
+        }
+        let line = if keep {
+            p.shown
+                .iter()
+                .position(|i| Some(&p.entries[*i].key) == p.selected.as_ref())
+                .unwrap_or(0)
+        } else {
+            0
+        };
+        line as i32 + 1
+    };
+    if list.size() > 0 {
+        list.select(selected);
+        list.middle_line(selected);
+    }
+    update_preview();
+}
+
+pub fn move_selection(delta: i32) {
+    if let Some((_, _, mut list)) = widgets() {
+        let n = list.size();
+        if n > 0 {
+            let line = (list.value() + delta).clamp(1, n);
+            list.select(line);
+            list.middle_line(line);
+            update_preview();
+        }
+    }
+}
+
+// Shows the highlighted entry in the preview pane, if it changed
+pub fn update_preview() {
+    let list = match widgets() {
+        Some((_, _, list)) => list,
+        None => return,
+    };
+    let line = list.value();
+    let changed = PALETTE.lock().unwrap().as_mut().and_then(|p| {
+        let key = match line {
+            0 => None,
+            _ => p
+                .shown
+                .get(line as usize - 1)
+                .map(|i| p.entries[*i].key.clone()),
+        };
+        if key == p.selected {
+            return None;
+        }
+        p.selected = key.clone();
+        Some((p.preview?, key))
+    });
+    if let Some((preview, key)) = changed {
+        // the preview runs unlocked, it may read files
+        let text = key.map(|k| preview(&k)).unwrap_or_default();
+        if let Some(mut buf) =
+            app::widget_from_id::<text::TextDisplay>("palette_preview").and_then(|p| p.buffer())
+        {
+            buf.set_text(&text);
+        }
+    }
+}
+
+// Hides the palette and hands the selected entry to its accept function
+pub fn accept() {
+    let (mut win, _, list) = match widgets() {
+        Some(w) => w,
+        None => return,
+    };
+    let line = list.value();
+    let picked = PALETTE.lock().unwrap().as_ref().and_then(|p| {
+        let i = *p.shown.get((line - 1).max(0) as usize)?;
+        Some((p.accept, p.entries[i].key.clone()))
+    });
+    win.hide();
+    if line > 0 {
+        if let Some((accept, key)) = picked {
+            accept(&key);
+        }
+    }
+}
+
+// The palette opened with this token is still open
+pub fn is_open(opened: usize) -> bool {
+    OPENED.load(Ordering::Relaxed) == opened
+        && widgets().map(|(w, _, _)| w.shown()).unwrap_or(false)
+}
@@ -0,0 +1,135 @@
+// Recently opened files and workspace folders, persisted in the preferences and
+// listed under File/Open Recent and File/Open Recent Folder of every main window
+use crate::{cbs, ctx, utils};
+use fltk::{app, dialog, enums::Shortcut, menu, prelude::*};
+use std::path::{Path, PathBuf};
+
+const MAX_ENTRIES: usize = 10;
+const FILES_MENU: &str = "&File/Open Recent";
+const FOLDERS_MENU: &str = "&File/Open Recent Folder";
+
+fn load(kind: &str) -> Vec<PathBuf> {
+    let mut g = match utils::prefs_group(&format!("recent/{}", kind)) {
+        Some(g) => g,
+        None => return Vec::new(),
+    };
+    let count = g.get_int("count").unwrap_or(0);
+    (0..count)
+        .filter_map(|i| g.get_str(&format!("entry{}", i)).ok())
+        .map(PathBuf::from)
+        .collect()
+}
+
+fn store(kind: &str, entries: &[PathBuf]) {
+    if let Some(mut g) = utils::prefs_group(&format!("recent/{}", kind)) {
+        g.clear().ok();
+        g.set_int("count", entries.len() as i32).ok();
+        for (i, p) in entries.iter().enumerate() {
+            g.set_str(&format!("entry{}", i), &p.to_string_lossy()).ok();
+        }
+    }
+}
+
+fn push(kind: &str, path: &Path) {
+    let mut entries = load(kind);
+    if entries.first().map(|p| p == path).unwrap_or(false) {
+        return;
+    }
+    entries.retain(|p| p != path);
+    entries.insert(0, path.to_path_buf());
+    entries.truncate(MAX_ENTRIES);
+    store(kind, &entries);
+    refresh_menus();
+}
+
+fn forget(kind: &str, path: &Path) {
+    let mut entries = load(kind);
+    entries.retain(|p| p != path);
+    store(kind, &entries);
+    refresh_menus();
+}
+
+pub fn add_file(path: &Path) {
+    push("files", path);
+}
+
+pub fn add_folder(path: &Path) {
+    push("folders", path);
+}
+
+// Menu labels treat '/' as a submenu separator, '&' as a shortcut marker and '@' as a symbol
+fn menu_label(path: &Path) -> String {
+    utils::strip_unc_path(path)
+        .replace('\\', "\\\\")
+        .replace('/', "\\/")
+        .replace('&', "&&")
+        .replace('@', "@@")
+}
+
+fn open_file(path: PathBuf) {
+    if !path.is_file() {
+        dialog::alert_default(&format!("{} no longer exists.", path.display()));
+        forget("files", &path);
+        return;
+    }
+    crate::state::open(path);
+}
+
+fn open_folder(path: PathBuf) {
+    if !path.is_dir() {
+        dialog::alert_default(&format!("{} no longer exists.", path.display()));
+        forget("folders", &path);
+        return;
+    }
+    cbs::open_folder(path);
+}
+
+fn fill_menu(m: &mut (impl MenuExt + 'static)) {
+    for (root, kind, open) in [
+        (FILES_MENU, "files", open_file as fn(PathBuf)),
+        (FOLDERS_MENU, "folders", open_folder as fn(PathBuf)),
+    ] {
+        let mut last = None;
+        for path in load(kind) {
+            last = Some(m.add(
+                &format!("{}/{}", root, menu_label(&path)),
+                Shortcut::None,
+                menu::MenuFlag::Normal,
+                move |_| open(path.clone()),
+            ));
+        }
+        if let Some(mut item) = last.and_then(|idx| m.at(idx)) {
+            item.set_flag(menu::MenuFlag::MenuDivider);
+        }
+        m.add(
+            &format!("{}/Clear Recent\t", root),
+            Shortcut::None,
+            menu::MenuFlag::Normal,
+            move |_| {
+                store(kind, &[]);
+                refresh_menus();
+            },
+        );
+    }
+}
+
+pub fn init_menu(m: &mut (impl MenuExt + 'static)) {
+    fill_menu(m);
+}
+
+// Rebuilds the recent submenus, deferred since this can run from one of their callbacks
+fn refresh_menus() {
+    app::add_timeout3(0.0, |_| {
+        for win in ctx::windows() {
+            if let Some(mut m) = ctx::widget_in::<menu::SysMenuBar>(win, "menu") {
+                for root in [FILES_MENU, FOLDERS_MENU] {
+                    let idx = m.find_index(root);
+                    if idx >= 0 {
+                        m.clear_submenu(idx).ok();
+                    }
+                }
+                fill_menu(&mut m);
+            }
+        }
+    });
+}
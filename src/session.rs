@@ -0,0 +1,258 @@
+use crate::{
+    ctx,
+    state::{State, STATE},
+    utils,
+};
+use fltk::{app, group, menu, prelude::*, text};
+use std::path::{Path, PathBuf};
+
+#[derive(Clone, Debug, Default)]
+pub struct TabSession {
+    pub path: PathBuf,
+    pub insert_position: i32,
+    pub top_line: i32,
+    pub pinned: bool,
+    // Folded lines of the document, see folds.rs
+    pub folds: Vec<(usize, usize)>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Session {
+    pub tabs: Vec<TabSession>,
+    pub active: i32,
+    pub fbr_width: i32,
+    pub term_height: i32,
+    pub show_fbr: bool,
+    pub show_term: bool,
+}
+
+// Sessions are stored per workspace in a group named after the sanitized root path
+fn workspace_key(root: &Path) -> String {
+    let s: String = root
+        .to_string_lossy()
+        .chars()
+        .map(|c| if c.is_alphanumeric() { c } else { '_' })
+        .collect();
+    format!("sessions/{}", s)
+}
+
+fn open_group(root: &Path) -> Option<utils::PrefsGroup> {
+    utils::prefs_group(&workspace_key(root))
+}
+
+fn toggle_value(m: &menu::SysMenuBar, path: &str) -> bool {
+    m.find_item(path).map(|i| i.value()).unwrap_or(false)
+}
+
+fn collect(s: &State, win: usize) -> Session {
+    let mut tabs_out = Vec::new();
+    let mut active = 0;
+    let tabs: group::Tabs = ctx::widget_in(win, "tabs").unwrap();
+    let current = tabs
+        .value()
+        .and_then(|g| group::Group::from_dyn_widget(&g))
+        .and_then(|g| g.child(0))
+        .map(|c| c.as_widget_ptr() as usize);
+    for i in 0..tabs.children() {
+        let edrow = match tabs.child(i).and_then(|c| group::Flex::from_dyn_widget(&c)) {
+            Some(g) => g,
+            None => continue,
+        };
+        if let Some(ed) = edrow
+            .child(0)
+            .and_then(|c| text::TextEditor::from_dyn_widget(&c))
+        {
+            let id = ed.as_widget_ptr() as usize;
+            if let Some(v) = s.map.get(&id) {
+                let path = match v.current_file.clone() {
+                    Some(p) => p,
+                    None => continue,
+                };
+                if Some(id) == current {
+                    active = tabs_out.len() as i32;
+                }
+                tabs_out.push(TabSession {
+                    path,
+                    insert_position: crate::folds::to_doc(&ed, ed.insert_position()),
+                    top_line: ed.get_absolute_top_line_number(),
+                    pinned: v.pinned,
+                    folds: crate::folds::folded_lines(&ed),
+                });
+            }
+        }
+    }
+    let fbr: group::Group = ctx::widget_in(win, "fbr_group").unwrap();
+    let m: menu::SysMenuBar = ctx::widget_in(win, "menu").unwrap();
+    let show_fbr = toggle_value(&m, "&View/File browser\t");
+    #[cfg(feature = "term")]
+    let (term_height, show_term) = {
+        let term: group::Scroll = ctx::widget_in(win, "term_group").unwrap();
+        (term.h(), toggle_value(&m, "&View/Terminal\t"))
+    };
+    #[cfg(not(feature = "term"))]
+    let (term_height, show_term) = (160, false);
+    Session {
+        tabs: tabs_out,
+        active,
+        fbr_width: fbr.w(),
+        term_height,
+        show_fbr,
+        show_term,
+    }
+}
+
+// Saves the session of every open main window
+pub fn save() {
+    for win in ctx::windows() {
+        save_window(win);
+    }
+}
+
+pub fn save_window(win: usize) {
+    let root = ctx::root(win);
+    STATE.with(move |s| {
+        let session = collect(s, win);
+        if let Some(mut g) = open_group(&root) {
+            g.clear().ok();
+            g.set_str("root", &root.to_string_lossy()).ok();
+            g.set_int("tabs", session.tabs.len() as i32).ok();
+            for (i, t) in session.tabs.iter().enumerate() {
+                g.set_str(&format!("tab{}.path", i), &t.path.to_string_lossy())
+                    .ok();
+                g.set_int(&format!("tab{}.pos", i), t.insert_position).ok();
+                g.set_int(&format!("tab{}.top", i), t.top_line).ok();
+                g.set_int(&format!("tab{}.pinned", i), t.pinned as i32).ok();
+                let folds: Vec<String> = t
+                    .folds
+                    .iter()
+                    .map(|(a, b)| format!("{}-{}", a, b))
+                    .collect();
+                g.set_str(&format!("tab{}.folds", i), &folds.join(",")).ok();
+            }
+            g.set_int("active", session.active).ok();
+            g.set_int("fbr_width", session.fbr_width).ok();
+            g.set_int("term_height", session.term_height).ok();
+            g.set_int("show_fbr", session.show_fbr as i32).ok();
+            g.set_int("show_term", session.show_term as i32).ok();
+        }
+    });
+}
+
+pub fn load(root: &Path) -> Option<Session> {
+    let mut g = open_group(root)?;
+    if !g.entry_exists("tabs") {
+        return None;
+    }
+    let count = g.get_int("tabs").unwrap_or(0);
+    let mut tabs = Vec::new();
+    for i in 0..count {
+        if let Ok(path) = g.get_str(&format!("tab{}.path", i)) {
+            tabs.push(TabSession {
+                path: PathBuf::from(path),
+                insert_position: g.get_int(&format!("tab{}.pos", i)).unwrap_or(0),
+                top_line: g.get_int(&format!("tab{}.top", i)).unwrap_or(1),
+                pinned: g.get_int(&format!("tab{}.pinned", i)).unwrap_or(0) != 0,
+                folds: g
+                    .get_str(&format!("tab{}.folds", i))
+                    .unwrap_or_default()
+                    .split(',')
+                    .filter_map(|r| {
+                        let (a, b) = r.split_once('-')?;
+                        Some((a.parse().ok()?, b.parse().ok()?))
+                    })
+                    .collect(),
+            });
+        }
+    }
+    Some(Session {
+        tabs,
+        active: g.get_int("active").unwrap_or(0),
+        fbr_width: g.get_int("fbr_width").unwrap_or(180),
+        term_height: g.get_int("term_height").unwrap_or(160),
+        show_fbr: g.get_int("show_fbr").unwrap_or(1) != 0,
+        show_term: g.get_int("show_term").unwrap_or(1) != 0,
+    })
+}
+
+fn apply_layout(session: &Session) {
+    let mut m: menu::SysMenuBar = ctx::widget("menu").unwrap();
+    let fbr: group::Group = ctx::widget("fbr_group").unwrap();
+    let mut parent = group::Flex::from_dyn_widget(&fbr.parent().unwrap()).unwrap();
+    if let Some(mut item) = m.find_item("&View/File browser\t") {
+        if session.show_fbr {
+            parent.fixed(&fbr, session.fbr_width.max(1));
+            item.set();
+        } else {
+            parent.fixed(&fbr, 1);
+            item.clear();
+        }
+    }
+    #[cfg(feature = "term")]
+    {
+        let term: group::Scroll = ctx::widget("term_group").unwrap();
+        let mut parent = group::Flex::from_dyn_widget(&term.parent().unwrap()).unwrap();
+        if let Some(mut item) = m.find_item("&View/Terminal\t") {
+            if session.show_term {
+                parent.fixed(&term, session.term_height.max(1));
+                item.set();
+            } else {
+                parent.fixed(&term, 1);
+                item.clear();
+            }
+        }
+    }
+    m.redraw();
+    app::redraw();
+}
+
+// Reopens the tabs of a saved session, returns whether anything was restored
+pub fn restore(state: &mut State, session: &Session) -> bool {
+    apply_layout(session);
+    let mut tabs: group::Tabs = ctx::widget("tabs").unwrap();
+    let mut restored = 0;
+    let mut active = None;
+    for (i, t) in session.tabs.iter().enumerate() {
+        if !t.path.exists() {
+            continue;
+        }
+        let id = match state.append(Some(t.path.clone())) {
+            Ok(id) => id,
+            Err(_) => continue,
+        };
+        let mut ed = match state.editor(id) {
+            Some(ed) => ed,
+            None => continue,
+        };
+        // a document already open in another window keeps its view there
+        let row = ed.parent();
+        let in_tabs = row
+            .as_ref()
+            .and_then(|r| r.parent())
+            .map(|t| t.as_widget_ptr() == tabs.as_widget_ptr())
+            .unwrap_or(false);
+        if !in_tabs {
+            continue;
+        }
+        // the caret is saved in the document, the top line in the view
+        crate::folds::restore(&mut ed, t.path.clone(), &t.folds);
+        let len = ed.buffer().map(|b| b.length()).unwrap_or(0);
+        let pos = crate::folds::to_shown(&ed, t.insert_position.max(0));
+        ed.set_insert_position(pos.clamp(0, len));
+        ed.scroll(t.top_line.max(1), 0);
+        if t.pinned {
+            state.set_pinned(id, true);
+        }
+        if i as i32 == session.active {
+            active = row;
+        }
+        restored += 1;
+    }
+    if restored == 0 {
+        return false;
+    }
+    if let Some(active) = active {
+        tabs.set_value(&active).ok();
+        tabs.set_damage(true);
+    }
+    true
+}
@@ -0,0 +1,378 @@
+use crate::{gui, state::STATE, utils};
+use fltk::{app, enums::Font, prelude::*, text};
+use fltk_theme::{color_themes::fleet, ColorMap, ColorTheme};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+
+pub const THEMES: &[(&str, &[ColorMap])] = &[
+    ("Gruvbox Dark", &fleet::GRUVBOX_DARK),
+    ("Gruvbox Light", &fleet::GRUVBOX_LIGHT),
+    ("Monokai", &fleet::MONOKAI),
+    ("Dracula", &fleet::DRACULA),
+    ("Nord", &fleet::NORD),
+    ("Solarized Dark", &fleet::SOLARIZED_DARK),
+    ("Solarized Light", &fleet::SOLARIZED_LIGHT),
+    ("Oceanic Next", &fleet::OCEANIC_NEXT),
+    ("Material Dark", &fleet::MATERIAL_DARK),
+    ("High Contrast", &fleet::HIGH_CONTRAST),
+    ("Light", &fleet::LIGHT),
+];
+
+pub const FONTS: &[(&str, Font)] = &[
+    ("Courier", Font::Courier),
+    ("Courier Bold", Font::CourierBold),
+    ("Screen", Font::Screen),
+    ("Screen Bold", Font::ScreenBold),
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AutoSave {
+    Off,
+    Interval,
+    FocusLoss,
+    TabSwitch,
+}
+
+pub const AUTOSAVE_MODES: &[(&str, AutoSave)] = &[
+    ("Off", AutoSave::Off),
+    ("After interval", AutoSave::Interval),
+    ("On focus loss", AutoSave::FocusLoss),
+    ("On tab switch", AutoSave::TabSwitch),
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KeyProfile {
+    Default,
+    Emacs,
+}
+
+pub const KEY_PROFILES: &[(&str, KeyProfile)] = &[
+    ("Default", KeyProfile::Default),
+    ("Emacs", KeyProfile::Emacs),
+];
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Settings {
+    pub theme: String,
+    pub font: String,
+    pub font_size: i32,
+    pub tab_distance: i32,
+    pub linenumber_width: i32,
+    pub lsp_enabled: bool,
+    pub lsp_debounce_ms: u64,
+    pub ra_path: String,
+    pub autosave: AutoSave,
+    pub autosave_interval: i32,
+    pub backup: bool,
+    // Backups go next to the file as <name>.bak when empty
+    pub backup_dir: String,
+    // Files above this size (KB) get incremental highlighting and no LSP sync
+    pub large_file_kb: i32,
+    pub large_file_lsp: bool,
+    // Modal editing in the editors, see vim.rs
+    pub vim: bool,
+    // Default bindings the keymap starts from, see keymap.rs and emacs.rs
+    pub key_profile: KeyProfile,
+    // Typing a bracket or quote inserts the closing one, see brackets.rs
+    pub auto_close: bool,
+    // Enter indents the new line, see indent.rs
+    pub auto_indent: bool,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            theme: THEMES[0].0.to_string(),
+            font: FONTS[0].0.to_string(),
+            font_size: 14,
+            tab_distance: 4,
+            linenumber_width: 40,
+            lsp_enabled: true,
+            lsp_debounce_ms: 200,
+            ra_path: "rust-analyzer".to_string(),
+            autosave: AutoSave::Off,
+            autosave_interval: 30,
+            backup: false,
+            backup_dir: String::new(),
+            large_file_kb: 1024,
+            large_file_lsp: false,
+            vim: false,
+            key_profile: KeyProfile::Default,
+            auto_close: true,
+            auto_indent: true,
+        }
+    }
+}
+
+impl Settings {
+    pub fn font(&self) -> Font {
+        FONTS
+            .iter()
+            .find(|(n, _)| *n == self.font)
+            .map(|(_, f)| *f)
+            .unwrap_or(Font::Courier)
+    }
+
+    pub fn theme_map(&self) -> &'static [ColorMap] {
+        THEMES
+            .iter()
+            .find(|(n, _)| *n == self.theme)
+            .map(|(_, m)| *m)
+            .unwrap_or(THEMES[0].1)
+    }
+
+    pub fn autosave_name(&self) -> &'static str {
+        AUTOSAVE_MODES
+            .iter()
+            .find(|(_, m)| *m == self.autosave)
+            .map(|(n, _)| *n)
+            .unwrap_or("Off")
+    }
+
+    pub fn key_profile_name(&self) -> &'static str {
+        KEY_PROFILES
+            .iter()
+            .find(|(_, p)| *p == self.key_profile)
+            .map(|(n, _)| *n)
+            .unwrap_or("Default")
+    }
+
+    // Where the previous version of a file is kept on save, if backups are enabled
+    pub fn backup_path(&self, file: &Path) -> Option<PathBuf> {
+        if !self.backup {
+            return None;
+        }
+        let name = format!("{}.bak", file.file_name()?.to_string_lossy());
+        if self.backup_dir.is_empty() {
+            Some(file.with_file_name(name))
+        } else {
+            // the directory is shared, so the name spells out the whole path
+            // (like Vim's backupdir), a/main.rs and b/main.rs don't collide.
+            // Separators are percent-encoded along with '%' to keep names distinct.
+            let mut full = String::new();
+            for c in file.to_string_lossy().chars() {
+                match c {
+                    '/' | '\\' | ':' | '%' => full.push_str(&format!("%{:02X}", c as u32)),
+                    _ => full.push(c),
+                }
+            }
+            Some(PathBuf::from(&self.backup_dir).join(format!("{}.bak", full)))
+        }
+    }
+
+    pub fn is_large(&self, len: usize) -> bool {
+        len > self.large_file_kb.max(1) as usize * 1024
+    }
+
+    fn load() -> Self {
+        let mut s = Settings::default();
+        if let Some(mut g) = utils::prefs_group("settings") {
+            if let Ok(v) = g.get_str("theme") {
+                s.theme = v;
+            }
+            if let Ok(v) = g.get_str("font") {
+                s.font = v;
+            }
+            if let Ok(v) = g.get_int("font_size") {
+                s.font_size = v;
+            }
+            if let Ok(v) = g.get_int("tab_distance") {
+                s.tab_distance = v;
+            }
+            if let Ok(v) = g.get_int("linenumber_width") {
+                s.linenumber_width = v;
+            }
+            if let Ok(v) = g.get_int("lsp_enabled") {
+                s.lsp_enabled = v != 0;
+            }
+            if let Ok(v) = g.get_int("lsp_debounce_ms") {
+                s.lsp_debounce_ms = v.max(0) as u64;
+            }
+            if let Ok(v) = g.get_str("ra_path") {
+                if !v.is_empty() {
+                    s.ra_path = v;
+                }
+            }
+            if let Ok(v) = g.get_str("autosave") {
+                if let Some((_, mode)) = AUTOSAVE_MODES.iter().find(|(n, _)| *n == v) {
+                    s.autosave = *mode;
+                }
+            }
+            if let Ok(v) = g.get_int("autosave_interval") {
+                s.autosave_interval = v.max(1);
+            }
+            if let Ok(v) = g.get_int("backup") {
+                s.backup = v != 0;
+            }
+            if let Ok(v) = g.get_str("backup_dir") {
+                s.backup_dir = v;
+            }
+            if let Ok(v) = g.get_int("large_file_kb") {
+                s.large_file_kb = v.max(1);
+            }
+            if let Ok(v) = g.get_int("large_file_lsp") {
+                s.large_file_lsp = v != 0;
+            }
+            if let Ok(v) = g.get_int("vim") {
+                s.vim = v != 0;
+            }
+            if let Ok(v) = g.get_str("key_profile") {
+                if let Some((_, p)) = KEY_PROFILES.iter().find(|(n, _)| *n == v) {
+                    s.key_profile = *p;
+                }
+            }
+            if let Ok(v) = g.get_int("auto_close") {
+                s.auto_close = v != 0;
+            }
+            if let Ok(v) = g.get_int("auto_indent") {
+                s.auto_indent = v != 0;
+            }
+        }
+        s
+    }
+
+    // Environment variables take precedence over stored preferences, for this
+    // run only
+    fn with_env(mut self) -> Self {
+        let env = env();
+        if let Some(v) = env.lsp_debounce_ms {
+            self.lsp_debounce_ms = v;
+        }
+        if let Some(v) = env.ra_path.as_ref() {
+            self.ra_path = v.clone();
+        }
+        if env.lsp_disable {
+            self.lsp_enabled = false;
+        }
+        self
+    }
+
+    fn save(&self) {
+        if let Some(mut g) = utils::prefs_group("settings") {
+            g.set_str("theme", &self.theme).ok();
+            g.set_str("font", &self.font).ok();
+            g.set_int("font_size", self.font_size).ok();
+            g.set_int("tab_distance", self.tab_distance).ok();
+            g.set_int("linenumber_width", self.linenumber_width).ok();
+            g.set_int("lsp_enabled", self.lsp_enabled as i32).ok();
+            g.set_int("lsp_debounce_ms", self.lsp_debounce_ms as i32)
+                .ok();
+            g.set_str("ra_path", &self.ra_path).ok();
+            g.set_str("autosave", self.autosave_name()).ok();
+            g.set_int("autosave_interval", self.autosave_interval).ok();
+            g.set_int("backup", self.backup as i32).ok();
+            g.set_str("backup_dir", &self.backup_dir).ok();
+            g.set_int("large_file_kb", self.large_file_kb).ok();
+            g.set_int("large_file_lsp", self.large_file_lsp as i32).ok();
+            g.set_int("vim", self.vim as i32).ok();
+            g.set_str("key_profile", self.key_profile_name()).ok();
+            g.set_int("auto_close", self.auto_close as i32).ok();
+            g.set_int("auto_indent", self.auto_indent as i32).ok();
+        }
+    }
+}
+
+// Overrides from the environment, which doesn't change while running
+struct Env {
+    lsp_debounce_ms: Option<u64>,
+    ra_path: Option<String>,
+    lsp_disable: bool,
+}
+
+static ENV: OnceLock<Env> = OnceLock::new();
+
+fn env() -> &'static Env {
+    ENV.get_or_init(|| Env {
+        lsp_debounce_ms: std::env::var("RED_LSP_DEBOUNCE_MS")
+            .ok()
+            .and_then(|v| v.parse::<u64>().ok()),
+        ra_path: std::env::var("RED_RA_PATH").ok(),
+        lsp_disable: std::env::var("RED_LSP_DISABLE")
+            .map(|v| matches!(v.to_ascii_lowercase().as_str(), "1" | "true" | "yes" | "on"))
+            .unwrap_or(false),
+    })
+}
+
+// Reads the environment overrides at startup
+pub fn init() {
+    env();
+}
+
+static SETTINGS: OnceLock<Mutex<Settings>> = OnceLock::new();
+
+fn settings() -> &'static Mutex<Settings> {
+    SETTINGS.get_or_init(|| Mutex::new(Settings::load()))
+}
+
+// The settings in effect, with the environment overrides
+pub fn get() -> Settings {
+    stored().with_env()
+}
+
+// The settings as persisted, what the settings dialog edits
+pub fn stored() -> Settings {
+    settings().lock().map(|s| s.clone()).unwrap_or_default()
+}
+
+// Stores new settings, persists them and applies them to the running app
+pub fn set(new: Settings) {
+    if let Ok(mut s) = settings().lock() {
+        *s = new.clone();
+    }
+    new.save();
+    apply();
+}
+
+pub fn apply_theme() {
+    ColorTheme::new(get().theme_map()).apply();
+}
+
+// Re-applies the current settings to every open editor
+pub fn apply() {
+    apply_theme();
+    // before the editors are restyled, the cursor follows the vim mode
+    crate::vim::apply_settings();
+    // the profile may have changed the default bindings
+    crate::keymap::refresh();
+    let s = get();
+    // split views are styled like the document's own editor
+    let eds: Vec<(usize, Option<std::path::PathBuf>)> = STATE.with(|st| {
+        st.map
+            .keys()
+            .chain(st.views.keys())
+            .filter_map(|k| Some((*k, st.map.get(&st.doc_id(*k))?.current_file.clone())))
+            .collect()
+    });
+    for (id, _path) in eds {
+        if let Some(mut ed) = text::TextEditor::from_dyn_widget_ptr(id as *mut _) {
+            gui::style_editor(&mut ed);
+            // a folded view shows a copy of the document, see folds.rs
+            for mut buf in ed.buffer().into_iter().chain(crate::folds::doc_buffer(&ed)) {
+                buf.set_tab_distance(s.tab_distance);
+            }
+            #[cfg(feature = "highlight")]
+            if let Some(p) = _path.as_ref() {
+                crate::highlight::refresh_styles(p, &mut ed);
+            }
+        }
+    }
+    app::redraw();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backup_names_are_distinct() {
+        let s = Settings {
+            backup: true,
+            backup_dir: "/bak".into(),
+            ..Default::default()
+        };
+        let a = s.backup_path(Path::new("/a%2Fb/c.rs")).unwrap();
+        let b = s.backup_path(Path::new("/a/b/c.rs")).unwrap();
+        assert_ne!(a, b);
+        assert_eq!(b, Path::new("/bak/%2Fa%2Fb%2Fc.rs.bak"));
+    }
+}
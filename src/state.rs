@@ -1,14 +1,25 @@
 #![allow(dead_code)]
 
-use crate::gui;
-use fltk::{app, group, prelude::*, text, utils::oncelock::Lazy};
+use crate::encoding::{Encoding, LineEnding};
+use crate::{autosave, ctx, gui, recent, session, settings, watch};
+use fltk::{
+    app, dialog, enums::CallbackTrigger, group, menu, prelude::*, text, utils::oncelock::Lazy,
+};
 use std::collections::HashMap;
 use std::{
+    io,
     path::PathBuf,
-    sync::atomic::{AtomicU32, Ordering},
+    sync::atomic::{AtomicU32, AtomicUsize, Ordering},
 };
 
 static COUNT: AtomicU32 = AtomicU32::new(0);
+// Last focused editor view. Kept outside of State since focus events can be
+// triggered while the state is borrowed.
+static FOCUSED: AtomicUsize = AtomicUsize::new(0);
+
+pub fn set_focused(view: usize) {
+    FOCUSED.store(view, Ordering::Relaxed);
+}
 
 #[derive(Clone, Debug)]
 pub struct MyBuffer {
@@ -18,24 +29,71 @@ pub struct MyBuffer {
     pub current_file: Option<PathBuf>,
     pub version: i32,
     pub change_seq: u64,
+    pub encoding: Encoding,
+    pub line_ending: LineEnding,
+    // Large files aren't synced with the language server unless enabled in the settings
+    pub lsp_sync: bool,
+    // Pinned tabs are kept in front and survive Close Others
+    pub pinned: bool,
+}
+
+impl MyBuffer {
+    pub fn name(&self) -> String {
+        self.current_file
+            .as_ref()
+            .and_then(|f| f.file_name())
+            .map(|f| f.to_string_lossy().to_string())
+            .unwrap_or_else(|| "untitled".to_string())
+    }
+    // The path to report to the language server, if this buffer is synced
+    pub fn lsp_path(&self) -> Option<PathBuf> {
+        if self.lsp_sync {
+            self.current_file.clone()
+        } else {
+            None
+        }
+    }
 }
 
+// Documents are keyed by the pointer of the first editor of their tab. Split views
+// are additional editors, mapped to the document whose buffer they display.
+// Documents of every main window share the map, the tabs, file browser and
+// root of the active window are looked up through ctx.
+#[derive(Default)]
 pub struct State {
     pub map: HashMap<usize, MyBuffer>,
-    pub current_dir: PathBuf,
+    pub views: HashMap<usize, usize>,
+    // Documents, most recently used first
+    pub mru: Vec<usize>,
 }
 
 impl State {
-    pub fn new(current_dir: PathBuf) -> Self {
+    pub fn new() -> Self {
         let map = HashMap::default();
-        State { map, current_dir }
+        let views = HashMap::default();
+        let mru = Vec::new();
+        State { map, views, mru }
     }
-    pub fn append(&mut self, current_path: Option<PathBuf>) {
-        let mut tabs: group::Tabs = app::widget_from_id("tabs").unwrap();
+    // Workspace root of the active window
+    pub fn current_dir(&self) -> PathBuf {
+        ctx::root(ctx::active())
+    }
+    // The document shown by an editor view
+    pub fn doc_id(&self, view: usize) -> usize {
+        *self.views.get(&view).unwrap_or(&view)
+    }
+    // Opens a file, or an untitled buffer without a path, in a new tab of the
+    // active window. A file already open gets its tab brought up instead.
+    // Returns the document's id.
+    pub fn append(&mut self, current_path: Option<PathBuf>) -> io::Result<usize> {
+        // the file may have gone since it was chosen
+        let current_path = current_path.map(|p| p.canonicalize()).transpose()?;
+        let mut tabs: group::Tabs = ctx::widget("tabs").unwrap();
         let mut open = false;
         let mut edid = 0;
         for (k, v) in &self.map {
-            if v.current_file == current_path {
+            // untitled buffers are never shared
+            if current_path.is_some() && v.current_file == current_path {
                 open = true;
                 edid = *k;
                 break;
@@ -45,63 +103,160 @@ impl State {
             let old_count = COUNT.load(Ordering::Relaxed);
             let id = format!("edrow{}", old_count);
             COUNT.store(old_count + 1, Ordering::Relaxed);
-            let ed = gui::create_ed(&mut tabs, &id, &current_path);
+            let (ed, encoding, line_ending) = gui::create_ed(&mut tabs, &id, &current_path)?;
+            let len = ed.buffer().map(|b| b.length()).unwrap_or(0) as usize;
+            let cfg = settings::get();
             let mybuf = MyBuffer {
                 modified: false,
                 id,
                 buf: ed.buffer().unwrap(),
-                current_file: current_path.map(|p| p.canonicalize().unwrap()),
+                current_file: current_path,
                 version: 1,
                 change_seq: 0,
+                encoding,
+                line_ending,
+                lsp_sync: !cfg.is_large(len) || cfg.large_file_lsp,
+                pinned: false,
             };
-            self.map.insert(ed.as_widget_ptr() as usize, mybuf);
+            if let Some(path) = mybuf.current_file.as_ref() {
+                watch::watch(path);
+                recent::add_file(path);
+            }
+            edid = ed.as_widget_ptr() as usize;
+            self.map.insert(edid, mybuf);
             // Notify LSP didOpen
             #[cfg(feature = "lsp")]
-            if let Some(path) = self.current_file() {
+            if let Some(path) = self.current_id().and_then(|id| self.map[&id].lsp_path()) {
                 let text = self.buf().map(|b| b.text()).unwrap_or_default();
-                crate::lsp::with_client(|c| c.did_open(&path, &text, 1));
+                crate::lsp::with_client(&path, |c| c.did_open(&path, &text, 1));
             }
         } else {
-            tabs.set_value(
-                &text::TextEditor::from_dyn_widget_ptr(edid as *mut _)
-                    .unwrap()
-                    .parent()
-                    .unwrap(),
-            )
-            .ok();
+            let ed = text::TextEditor::from_dyn_widget_ptr(edid as *mut _).unwrap();
+            let edrow = ed.parent().unwrap();
+            // the document may be open in another main window, bring that one up
+            let mut tabs = edrow
+                .parent()
+                .and_then(|t| group::Tabs::from_dyn_widget(&t))
+                .unwrap_or(tabs);
+            tabs.set_value(&edrow).ok();
             tabs.set_damage(true);
+            if let Some(mut win) = ed.window() {
+                win.show();
+            }
         }
+        self.touch(edid);
+        self.update_format_status();
+        Ok(edid)
     }
-    pub fn current_id(&self) -> Option<usize> {
-        let tabs: group::Tabs = app::widget_from_id("tabs").unwrap();
-        if tabs.children() == 0 {
-            return None;
+    // Moves a document to the front of the most recently used list
+    pub fn touch(&mut self, id: usize) {
+        self.mru.retain(|x| *x != id);
+        self.mru.insert(0, id);
+    }
+    // Shows the encoding and line ending of the current buffer in the status bar
+    pub fn update_format_status(&self) {
+        if let Some(mut fmt) = ctx::widget::<menu::MenuButton>("fmt_status") {
+            match self.current_id().and_then(|id| self.map.get(&id)) {
+                Some(mb) => {
+                    fmt.set_label(&format!(
+                        "{}   |   {}",
+                        mb.encoding.name(),
+                        mb.line_ending.name()
+                    ));
+                    fmt.activate();
+                }
+                None => {
+                    fmt.set_label("");
+                    fmt.deactivate();
+                }
+            }
         }
-        tabs.value()
-            .unwrap()
-            .child(0)
-            .map(|ed| ed.as_widget_ptr() as usize)
+    }
+    pub fn current_id(&self) -> Option<usize> {
+        self.current_editor()
+            .map(|ed| self.doc_id(ed.as_widget_ptr() as usize))
     }
     pub fn was_modified(&mut self, flag: bool) {
-        let mut tabs: group::Tabs = app::widget_from_id("tabs").unwrap();
-        if tabs.children() == 0 {
-            return;
+        if let Some(id) = self.current_id() {
+            self.set_modified(id, flag);
         }
-        let mut edrow = tabs.value().unwrap();
-        if let Some(c) = edrow.child(0) {
-            let id = c.as_widget_ptr() as usize;
-            let mybuf = self.map.get_mut(&id).unwrap();
+    }
+    pub fn set_modified(&mut self, id: usize, flag: bool) {
+        if let Some(mybuf) = self.map.get_mut(&id) {
             mybuf.modified = flag;
-            if let Some(f) = mybuf.current_file.as_ref() {
-                if flag {
-                    edrow.set_label(&format!("\t{} *", f.file_name().unwrap().to_str().unwrap()));
-                } else {
-                    edrow.set_label(&format!("\t{}", f.file_name().unwrap().to_str().unwrap()));
-                }
-                tabs.redraw();
+            if !flag {
+                autosave::remove_swap(mybuf);
+            }
+            if mybuf.current_file.is_some() {
+                self.update_label(id);
             }
         }
     }
+    // Tab label from the file name, modified and pinned state
+    fn update_label(&self, id: usize) {
+        let mybuf = match self.map.get(&id) {
+            Some(b) => b,
+            None => return,
+        };
+        let mut edrow = text::TextEditor::from_dyn_widget_ptr(id as *mut _)
+            .unwrap()
+            .parent()
+            .unwrap();
+        edrow.set_label(&format!(
+            "\t{}{}{}",
+            if mybuf.pinned { "^ " } else { "" },
+            mybuf.name(),
+            if mybuf.modified { " *" } else { "" }
+        ));
+        // the tabs of the window the document lives in
+        if let Some(mut tabs) = edrow.parent() {
+            tabs.redraw();
+        }
+    }
+    // Pinned tabs lose their close button and are kept before the other tabs
+    pub fn set_pinned(&mut self, id: usize, pinned: bool) {
+        match self.map.get_mut(&id) {
+            Some(b) if b.pinned != pinned => b.pinned = pinned,
+            _ => return,
+        }
+        let mut edrow =
+            match text::TextEditor::from_dyn_widget_ptr(id as *mut _).and_then(|ed| ed.parent()) {
+                Some(g) => g,
+                None => return,
+            };
+        let mut tabs = match edrow.parent() {
+            Some(t) => t,
+            None => return,
+        };
+        // pinned tabs of this window, not counting this one
+        let others = (0..tabs.children())
+            .filter_map(|i| tabs.child(i))
+            .filter_map(|g| group::Group::from_dyn_widget(&g))
+            .filter_map(|g| g.child(0))
+            .map(|c| c.as_widget_ptr() as usize)
+            .filter(|d| *d != id && self.map.get(d).map(|b| b.pinned).unwrap_or(false))
+            .count() as i32;
+        if pinned {
+            edrow.set_trigger(CallbackTrigger::Never);
+            tabs.insert(&edrow, others);
+        } else {
+            edrow.set_trigger(CallbackTrigger::Closed);
+            // insert() accounts for the tab being removed from before the index
+            tabs.insert(&edrow, others + 1);
+        }
+        self.update_label(id);
+    }
+    // Ids and display names of all buffers with unsaved changes
+    pub fn modified_buffers(&self) -> Vec<(usize, String)> {
+        let mut v: Vec<_> = self
+            .map
+            .iter()
+            .filter(|(_, b)| b.modified)
+            .map(|(k, b)| (*k, b.name()))
+            .collect();
+        v.sort_by(|a, b| a.1.cmp(&b.1));
+        v
+    }
     pub fn modified(&self) -> bool {
         if let Some(current_id) = self.current_id() {
             let mybuf = self.map.get(&current_id).unwrap();
@@ -132,22 +287,218 @@ impl State {
             mybuf.current_file = Some(path)
         }
     }
+    // Points a buffer at a new file after Save as..., switching the watcher,
+    // highlighting and LSP document over to the new path
+    pub fn rebind(&mut self, id: usize, path: PathBuf) {
+        let path = path.canonicalize().unwrap_or(path);
+        let mybuf = match self.map.get_mut(&id) {
+            Some(b) => b,
+            None => return,
+        };
+        let old = mybuf.current_file.replace(path.clone());
+        if old.as_ref() == Some(&path) {
+            return;
+        }
+        if let Some(old) = old.as_ref() {
+            watch::unwatch(old);
+            #[cfg(feature = "lsp")]
+            if mybuf.lsp_sync {
+                crate::lsp::with_client(old, |c| c.did_close(old));
+            }
+        }
+        watch::watch(&path);
+        mybuf.version += 1;
+        #[cfg(feature = "lsp")]
+        if mybuf.lsp_sync {
+            let text = mybuf.buf.text();
+            let version = mybuf.version;
+            crate::lsp::with_client(&path, |c| c.did_open(&path, &text, version));
+        }
+        #[cfg(feature = "highlight")]
+        if let Some(mut ed) = text::TextEditor::from_dyn_widget_ptr(id as *mut _) {
+            let mut buf = mybuf.buf.clone();
+            crate::highlight::rehighlight(&path, &mut ed, &mut buf);
+            // split views share the style buffer of the document's editor
+            for (v, _) in self.views.iter().filter(|(_, d)| **d == id) {
+                if let Some(mut view) = text::TextEditor::from_dyn_widget_ptr(*v as *mut _) {
+                    crate::highlight::share(&path, &ed, &mut view);
+                }
+            }
+        }
+    }
+    // The editor of a view that is still open, views are only known by their
+    // pointer which is dangling once the view is deleted
+    pub fn editor(&self, view: usize) -> Option<text::TextEditor> {
+        if !self.map.contains_key(&view) && !self.views.contains_key(&view) {
+            return None;
+        }
+        text::TextEditor::from_dyn_widget_ptr(view as *mut _)
+    }
+    // The focused view of the current tab, or its first editor
     pub fn current_editor(&self) -> Option<text::TextEditor> {
-        let tabs: group::Tabs = app::widget_from_id("tabs").unwrap();
+        let tabs: group::Tabs = ctx::widget("tabs").unwrap();
         if tabs.children() == 0 {
             return None;
         }
-        tabs.value()
-            .unwrap()
-            .child(0)
+        let grp = tabs.value().unwrap();
+        let focused = FOCUSED.load(Ordering::Relaxed);
+        (0..grp.children())
+            .filter_map(|i| grp.child(i))
+            .find(|c| c.as_widget_ptr() as usize == focused)
+            .or_else(|| grp.child(0))
             .map(|c| text::TextEditor::from_dyn_widget(&c).unwrap())
     }
+    // Splits the current tab, the new view shows the current document
+    pub fn split(&mut self, vertical: bool) {
+        let id = match self.current_id() {
+            Some(id) => id,
+            None => return,
+        };
+        let mut edrow = match text::TextEditor::from_dyn_widget_ptr(id as *mut _)
+            .and_then(|ed| ed.parent())
+            .and_then(|p| group::Flex::from_dyn_widget(&p))
+        {
+            Some(f) => f,
+            None => return,
+        };
+        if edrow.children() > 1 {
+            self.unsplit();
+        }
+        edrow.set_type(if vertical {
+            group::FlexType::Column
+        } else {
+            group::FlexType::Row
+        });
+        self.open_view(&mut edrow, id);
+    }
+    fn open_view(&mut self, edrow: &mut group::Flex, doc: usize) {
+        let mybuf = match self.map.get(&doc) {
+            Some(b) => b,
+            None => return,
+        };
+        let mut view = gui::create_view(edrow, mybuf.buf.clone());
+        #[cfg(feature = "highlight")]
+        if let (Some(p), Some(ed)) = (
+            mybuf.current_file.as_ref(),
+            text::TextEditor::from_dyn_widget_ptr(doc as *mut _),
+        ) {
+            crate::highlight::share(p, &ed, &mut view);
+        }
+        self.views.insert(view.as_widget_ptr() as usize, doc);
+        view.take_focus().ok();
+        set_focused(view.as_widget_ptr() as usize);
+    }
+    // Closes the split view of the current tab
+    pub fn unsplit(&mut self) {
+        let tabs: group::Tabs = ctx::widget("tabs").unwrap();
+        if let Some(grp) = tabs.value() {
+            let views: Vec<usize> = (1..grp.children())
+                .filter_map(|i| grp.child(i))
+                .map(|c| c.as_widget_ptr() as usize)
+                .collect();
+            for v in views {
+                self.close_view(v);
+            }
+        }
+    }
+    // Shows the next open document in the split view of the current tab
+    pub fn cycle_split(&mut self) {
+        let tabs: group::Tabs = ctx::widget("tabs").unwrap();
+        let mut edrow = match tabs.value().and_then(|g| group::Flex::from_dyn_widget(&g)) {
+            Some(f) => f,
+            None => return,
+        };
+        let view = match edrow.child(1) {
+            Some(v) => v.as_widget_ptr() as usize,
+            None => return,
+        };
+        let docs = window_docs(ctx::active());
+        let current = self.doc_id(view);
+        let next = docs
+            .iter()
+            .position(|d| *d == current)
+            .map(|i| docs[(i + 1) % docs.len()])
+            .unwrap_or(current);
+        self.close_view(view);
+        self.open_view(&mut edrow, next);
+    }
+    fn close_view(&mut self, view: usize) {
+        self.views.remove(&view);
+        crate::cursors::forget(view);
+        crate::folds::forget(view);
+        if let Some(ed) = text::TextEditor::from_dyn_widget_ptr(view as *mut _) {
+            if let Some(mut parent) = ed.parent() {
+                parent.remove(&ed);
+                if let Some(f) = group::Flex::from_dyn_widget(&parent) {
+                    f.layout();
+                }
+                if let Some(first) = parent.child(0) {
+                    set_focused(first.as_widget_ptr() as usize);
+                }
+                parent.redraw();
+            }
+            // the view may be handling the key that closes it
+            app::delete_widget(ed);
+        }
+    }
+    // Called before a document's tab is closed, removes the views showing it
+    // as well as the views inside its tab
+    pub fn close_views(&mut self, doc: usize) {
+        let parent_of = |id: usize| {
+            text::TextEditor::from_dyn_widget_ptr(id as *mut _)
+                .and_then(|ed| ed.parent())
+                .map(|p| p.as_widget_ptr() as usize)
+        };
+        let tab = parent_of(doc);
+        let views: Vec<usize> = self
+            .views
+            .iter()
+            .filter(|(v, d)| **d == doc || parent_of(**v) == tab)
+            .map(|(v, _)| *v)
+            .collect();
+        for v in views {
+            self.close_view(v);
+        }
+    }
 }
 
 pub static STATE: Lazy<app::GlobalState<State>> = Lazy::new(app::GlobalState::<State>::get);
 
-pub fn init_state(current_file: Option<PathBuf>, current_path: PathBuf) {
-    let mut state = State::new(current_path);
-    state.append(current_file);
+// Documents opened in the tabs of a main window
+pub fn window_docs(win: usize) -> Vec<usize> {
+    match ctx::widget_in::<group::Tabs>(win, "tabs") {
+        Some(tabs) => (0..tabs.children())
+            .filter_map(|i| tabs.child(i))
+            .filter_map(|g| group::Group::from_dyn_widget(&g))
+            .filter_map(|g| g.child(0))
+            .map(|c| c.as_widget_ptr() as usize)
+            .collect(),
+        None => Vec::new(),
+    }
+}
+
+// Opens a file in the active window, telling when it can't be
+pub fn open(path: PathBuf) {
+    let res = STATE.with(move |s| {
+        s.append(Some(path.clone()))
+            .map_err(|e| format!("Could not open {}: {}", path.display(), e))
+    });
+    if let Err(e) = res {
+        dialog::alert_default(&e);
+    }
+}
+
+pub fn init_state(current_file: Option<PathBuf>) {
+    let mut state = State::new();
+    let restored = session::load(&state.current_dir())
+        .map(|sess| session::restore(&mut state, &sess))
+        .unwrap_or(false);
+    if !restored || current_file.is_some() {
+        if let Err(e) = state.append(current_file) {
+            dialog::alert_default(&format!("Could not open the file: {}", e));
+            state.append(None).ok();
+        }
+    }
+    autosave::recover(&mut state);
     app::GlobalState::new(state);
 }
@@ -0,0 +1,345 @@
+// Tab management: reordering by dragging, the tab context menu, pinned tabs and
+// switching between tabs in most recently used order (Ctrl+Tab)
+use crate::{cbs, ctx, state::STATE, utils};
+use fltk::{enums::*, prelude::*, *};
+use std::path::Path;
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Mutex,
+};
+
+// Height of the tab bar, see group::Tabs::auto_layout
+const TAB_HEIGHT: i32 = 30;
+
+// Tab being dragged, 0 when none
+static DRAGGED: AtomicUsize = AtomicUsize::new(0);
+// Documents listed by the switcher popup, most recently used first
+static CYCLE: Mutex<Vec<usize>> = Mutex::new(Vec::new());
+
+// The tab showing a document
+pub fn tab_of(id: usize) -> Option<group::Group> {
+    text::TextEditor::from_dyn_widget_ptr(id as *mut _).and_then(|ed| ed.parent())
+}
+
+// Documents of a tabs widget, in tab order
+pub fn tab_docs(tabs: &group::Tabs) -> Vec<usize> {
+    (0..tabs.children())
+        .filter_map(|i| tabs.child(i))
+        .filter_map(|g| group::Group::from_dyn_widget(&g))
+        .filter_map(|g| g.child(0))
+        .map(|c| c.as_widget_ptr() as usize)
+        .collect()
+}
+
+// Makes a document's tab the visible one and gives its editor the focus
+pub fn activate(id: usize) {
+    let tab = match tab_of(id) {
+        Some(t) => t,
+        None => return,
+    };
+    if let Some(mut tabs) = tab.parent().and_then(|t| group::Tabs::from_dyn_widget(&t)) {
+        tabs.set_value(&tab).ok();
+        tabs.set_damage(true);
+    }
+    if let Some(mut ed) = text::TextEditor::from_dyn_widget_ptr(id as *mut _) {
+        ed.take_focus().ok();
+    }
+    crate::autosave::on_tab_switch();
+    STATE.with(move |s| {
+        s.touch(id);
+        s.update_format_status();
+    });
+}
+
+pub fn handle(
+    t: &mut group::Tabs,
+    ev: Event,
+    popup: &mut menu::MenuButton,
+    tab_popup: &menu::MenuButton,
+) -> bool {
+    let in_bar = app::event_y() <= t.y() + TAB_HEIGHT;
+    match ev {
+        Event::Push => {
+            DRAGGED.store(0, Ordering::Relaxed);
+            if app::event_mouse_button() == app::MouseButton::Right && !in_bar && t.children() > 0 {
+                popup.popup();
+                true
+            } else {
+                false
+            }
+        }
+        Event::Drag => {
+            // the pushed tab is the one the drag started on
+            if app::event_mouse_button() == app::MouseButton::Left
+                && DRAGGED.load(Ordering::Relaxed) == 0
+            {
+                if let Some(g) = t.push() {
+                    DRAGGED.store(g.as_widget_ptr() as usize, Ordering::Relaxed);
+                }
+            }
+            false
+        }
+        Event::Released => {
+            let dragged = DRAGGED.swap(0, Ordering::Relaxed);
+            // the tabs select the tab under the mouse once they handled the release
+            if app::event_mouse_button() == app::MouseButton::Right && in_bar {
+                let tab_popup = tab_popup.clone();
+                app::add_timeout3(0.0, move |_| show_menu(&tab_popup));
+            } else if let Some(target) = t.push() {
+                let target = target.as_widget_ptr() as usize;
+                if dragged != 0 && dragged != target {
+                    let mut t = t.clone();
+                    app::add_timeout3(0.0, move |_| move_tab(&mut t, dragged, target));
+                }
+            }
+            false
+        }
+        _ => false,
+    }
+}
+
+// Moves the dragged tab to the place of the target tab, pinned tabs stay in front
+fn move_tab(t: &mut group::Tabs, dragged: usize, target: usize) {
+    let src = match group::Group::from_dyn_widget_ptr(dragged as *mut _) {
+        Some(g) => g,
+        None => return,
+    };
+    let dst = match group::Group::from_dyn_widget_ptr(target as *mut _) {
+        Some(g) => g,
+        None => return,
+    };
+    let (src_idx, dst_idx) = (t.find(&src), t.find(&dst));
+    if src_idx >= t.children() || dst_idx >= t.children() {
+        return;
+    }
+    let docs = tab_docs(t);
+    let pinned = STATE.with(move |s| {
+        docs.iter()
+            .filter(|d| s.map.get(d).map(|b| b.pinned).unwrap_or(false))
+            .count()
+    }) as i32;
+    // insert() accounts for the tab being removed from before the index
+    let idx = if dst_idx > src_idx {
+        dst_idx + 1
+    } else {
+        dst_idx
+    };
+    let idx = if src_idx < pinned {
+        idx.min(pinned)
+    } else {
+        idx.max(pinned)
+    };
+    t.insert(&src, idx);
+    if let Some(ed) = src.child(0) {
+        activate(ed.as_widget_ptr() as usize);
+    }
+    t.redraw();
+}
+
+pub fn init_menu(m: &mut (impl MenuExt + 'static)) {
+    for (label, flag) in [
+        ("Close\t", menu::MenuFlag::Normal),
+        ("Close Others\t", menu::MenuFlag::Normal),
+        ("Close to the Right\t", menu::MenuFlag::Normal),
+        ("Close Saved\t", menu::MenuFlag::MenuDivider),
+        ("Pin Tab\t", menu::MenuFlag::Toggle),
+        ("Copy Path\t", menu::MenuFlag::Normal),
+        ("Reveal in File Browser\t", menu::MenuFlag::Normal),
+    ] {
+        m.add(label, Shortcut::None, flag, menu_cb);
+    }
+}
+
+fn show_menu(m: &menu::MenuButton) {
+    let (pinned, has_file) = STATE.with(|s| match s.current_id().and_then(|id| s.map.get(&id)) {
+        Some(b) => (b.pinned, b.current_file.is_some()),
+        None => (false, false),
+    });
+    if let Some(mut item) = m.find_item("Pin Tab\t") {
+        if pinned {
+            item.set();
+        } else {
+            item.clear();
+        }
+    }
+    for label in ["Copy Path\t", "Reveal in File Browser\t"] {
+        if let Some(mut item) = m.find_item(label) {
+            if has_file {
+                item.activate();
+            } else {
+                item.deactivate();
+            }
+        }
+    }
+    m.popup();
+}
+
+pub fn menu_cb(m: &mut impl MenuExt) {
+    let id = match STATE.with(|s| s.current_id()) {
+        Some(id) => id,
+        None => return,
+    };
+    let docs = match tab_of(id)
+        .and_then(|g| g.parent())
+        .and_then(|t| group::Tabs::from_dyn_widget(&t))
+    {
+        Some(tabs) => tab_docs(&tabs),
+        None => return,
+    };
+    // documents of this window that bulk closing may touch
+    let (closable, saved, pinned, path) = STATE.with({
+        let docs = docs.clone();
+        move |s| {
+            let closable: Vec<usize> = docs
+                .iter()
+                .copied()
+                .filter(|d| s.map.get(d).map(|b| !b.pinned).unwrap_or(false))
+                .collect();
+            let saved = closable
+                .iter()
+                .copied()
+                .filter(|d| !s.map[d].modified)
+                .collect::<Vec<_>>();
+            let b = &s.map[&id];
+            (closable, saved, b.pinned, b.current_file.clone())
+        }
+    });
+    if let Ok(mpath) = m.item_pathname(None) {
+        match mpath.as_str() {
+            "/Close\t" => cbs::close_doc(id),
+            "/Close Others\t" => {
+                cbs::close_docs(closable.into_iter().filter(|d| *d != id).collect());
+            }
+            "/Close to the Right\t" => {
+                let right: Vec<usize> = docs
+                    .iter()
+                    .copied()
+                    .skip_while(|d| *d != id)
+                    .skip(1)
+                    .collect();
+                cbs::close_docs(closable.into_iter().filter(|d| right.contains(d)).collect());
+            }
+            "/Close Saved\t" => {
+                cbs::close_docs(saved);
+            }
+            "/Pin Tab\t" => STATE.with(move |s| s.set_pinned(id, !pinned)),
+            "/Copy Path\t" => {
+                if let Some(path) = path {
+                    app::copy(&utils::strip_unc_path(&path));
+                }
+            }
+            "/Reveal in File Browser\t" => {
+                if let Some(path) = path {
+                    reveal(&path);
+                }
+            }
+            _ => (),
+        }
+    }
+}
+
+// Shows the file's directory in the file browser of the active window and selects it
+pub fn reveal(path: &Path) {
+    let win = ctx::active();
+    let dir = match path.parent() {
+        Some(d) => d.to_path_buf(),
+        None => return,
+    };
+    crate::fbr::chdir(win, dir);
+    let mut fbr: browser::FileBrowser = match ctx::widget_in(win, "fbr") {
+        Some(f) => f,
+        None => return,
+    };
+    let name = path
+        .file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_default();
+    if let Some(line) = (1..=fbr.size()).find(|i| fbr.text(*i).as_deref() == Some(name.as_str())) {
+        fbr.select(line);
+        fbr.middle_line(line);
+    }
+    // the file browser may be hidden
+    let m: menu::SysMenuBar = ctx::widget_in(win, "menu").unwrap();
+    if let Some(mut item) = m.find_item("&View/File browser\t") {
+        if !item.value() {
+            let fbr_group: group::Group = ctx::widget_in(win, "fbr_group").unwrap();
+            let mut parent = group::Flex::from_dyn_widget(&fbr_group.parent().unwrap()).unwrap();
+            parent.fixed(&fbr_group, 180);
+            item.set();
+        }
+    }
+    app::redraw();
+}
+
+// Ctrl+Tab / Ctrl+Shift+Tab: opens the switcher on the previously used tab, or
+// moves its selection while it's shown. Releasing Ctrl switches to the selection.
+pub fn cycle(forward: bool) {
+    let (mut win, mut list) = match (
+        app::widget_from_id::<window::Window>("switcher"),
+        app::widget_from_id::<browser::HoldBrowser>("switcher_list"),
+    ) {
+        (Some(w), Some(l)) => (w, l),
+        _ => return,
+    };
+    if win.shown() {
+        let n = list.size();
+        if n > 0 {
+            let next = if forward {
+                list.value() % n + 1
+            } else {
+                (list.value() + n - 2) % n + 1
+            };
+            list.select(next);
+        }
+        return;
+    }
+    let docs = match ctx::widget::<group::Tabs>("tabs") {
+        Some(tabs) => tab_docs(&tabs),
+        None => return,
+    };
+    let entries = STATE.with(move |s| {
+        // documents never switched to (yet) go last, in tab order
+        let mut ids: Vec<usize> = s.mru.iter().copied().filter(|d| docs.contains(d)).collect();
+        ids.extend(docs.iter().copied().filter(|d| !s.mru.contains(d)));
+        ids.into_iter()
+            .filter_map(|d| s.map.get(&d).map(|b| (d, b.name(), b.current_file.clone())))
+            .collect::<Vec<_>>()
+    });
+    if entries.len() < 2 {
+        return;
+    }
+    list.clear();
+    for (_, name, path) in &entries {
+        let dir = path
+            .as_ref()
+            .and_then(|p| p.parent())
+            .map(utils::strip_unc_path)
+            .unwrap_or_default();
+        list.add(&format!("{}\t{}", name, dir));
+    }
+    *CYCLE.lock().unwrap() = entries.iter().map(|(d, _, _)| *d).collect();
+    list.select(if forward { 2 } else { entries.len() as i32 });
+    if let Some(main) = ctx::widget::<group::Tabs>("tabs").and_then(|t| t.window()) {
+        win.set_pos(main.x() + (main.w() - win.w()) / 2, main.y() + main.h() / 4);
+    }
+    win.show();
+    list.take_focus().ok();
+}
+
+// Switches to the selected entry of the switcher and hides it
+pub fn commit() {
+    if let (Some(mut win), Some(list)) = (
+        app::widget_from_id::<window::Window>("switcher"),
+        app::widget_from_id::<browser::HoldBrowser>("switcher_list"),
+    ) {
+        win.hide();
+        let id = CYCLE
+            .lock()
+            .unwrap()
+            .get(list.value().max(1) as usize - 1)
+            .copied();
+        if let Some(id) = id {
+            activate(id);
+        }
+    }
+}
@@ -1,15 +1,23 @@
+use fltk::app::prefs::{Preferences, Root};
+use fltk::text::TextBuffer;
 use std::{
-    env,
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    env, fs,
+    io::{self, Write},
+    ops::{Deref, DerefMut},
     path::{Path, PathBuf},
     process::Command,
+    rc::Rc,
 };
 
 pub fn strip_unc_path(p: &Path) -> String {
-    let p = p.to_str().unwrap();
+    // names that aren't UTF-8 are shown, not used to open the file
+    let p = p.to_string_lossy();
     if let Some(end) = p.strip_prefix("\\\\?\\") {
         end.to_string()
     } else {
-        p.to_string()
+        p.into_owned()
     }
 }
 
@@ -61,3 +69,378 @@ pub fn can_use_xterm() -> bool {
         false
     }
 }
+
+// A group of the per-user preferences store. The root owns the group's
+// entries and writes them to disk when dropped, so it lives as long as the
+// group and goes after it.
+pub struct PrefsGroup {
+    group: Preferences,
+    _root: Preferences,
+}
+
+impl Deref for PrefsGroup {
+    type Target = Preferences;
+    fn deref(&self) -> &Preferences {
+        &self.group
+    }
+}
+
+impl DerefMut for PrefsGroup {
+    fn deref_mut(&mut self) -> &mut Preferences {
+        &mut self.group
+    }
+}
+
+// Opens (creating if needed) a group in the per-user preferences store, what's
+// set in it is saved once it's dropped
+pub fn prefs_group(name: &str) -> Option<PrefsGroup> {
+    let mut root = Preferences::new(Root::USER_L, "red", "RustyEd")?;
+    let group = Preferences::new_group(&mut root, name)?;
+    Some(PrefsGroup { group, _root: root })
+}
+
+// Directory next to the preferences file, used for swap files and other app data
+pub fn data_dir() -> Option<PathBuf> {
+    let prefs = Preferences::new(Root::USER_L, "red", "RustyEd")?;
+    let (file, _) = prefs.filename().ok()?;
+    Some(file.parent()?.join("RustyEd"))
+}
+
+thread_local! {
+    // Last generation handed out, generations are never reused
+    static LAST_GENERATION: Cell<u64> = const { Cell::new(0) };
+    // Generation of every tracked buffer, by its address
+    static GENERATIONS: RefCell<HashMap<usize, Rc<Cell<u64>>>> = RefCell::new(HashMap::new());
+}
+
+// Identifies a buffer while it's alive, e.g. as the key of a cache
+pub fn buffer_key(buf: &TextBuffer) -> usize {
+    // only compared, never dereferenced
+    unsafe { buf.as_ptr() as usize }
+}
+
+fn next_generation() -> u64 {
+    LAST_GENERATION.with(|g| {
+        g.set(g.get() + 1);
+        g.get()
+    })
+}
+
+// Starts tracking the edits of a buffer, see buffer_generation. Called where
+// buffers are created, as the address of a deleted one may be reused.
+pub fn track_edits(buf: &mut TextBuffer) {
+    let gen = Rc::new(Cell::new(next_generation()));
+    GENERATIONS.with(|g| g.borrow_mut().insert(buffer_key(buf), gen.clone()));
+    buf.add_modify_callback(move |_, inserted, deleted, _, _| {
+        if inserted > 0 || deleted > 0 {
+            gen.set(next_generation());
+        }
+    });
+}
+
+// Changes whenever the text of the buffer does, so that what's computed from
+// the text can be cached until then
+pub fn buffer_generation(buf: &TextBuffer) -> u64 {
+    let gen = GENERATIONS.with(|g| g.borrow().get(&buffer_key(buf)).map(|c| c.get()));
+    match gen {
+        Some(gen) => gen,
+        None => {
+            track_edits(&mut buf.clone());
+            buffer_generation(buf)
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DiffOp {
+    Same,
+    Removed,
+    Added,
+}
+
+// Line based diff: common prefix/suffix are trimmed, the middle goes through an LCS
+// table unless it is too large, in which case it is reported as a block replacement.
+pub fn line_diff<'a>(old: &'a str, new: &'a str) -> Vec<(DiffOp, &'a str)> {
+    let a: Vec<&str> = old.lines().collect();
+    let b: Vec<&str> = new.lines().collect();
+    let prefix = a.iter().zip(&b).take_while(|(x, y)| x == y).count();
+    let suffix = a[prefix..]
+        .iter()
+        .rev()
+        .zip(b[prefix..].iter().rev())
+        .take_while(|(x, y)| x == y)
+        .count();
+    let (am, bm) = (&a[prefix..a.len() - suffix], &b[prefix..b.len() - suffix]);
+    let mut out: Vec<(DiffOp, &str)> = a[..prefix].iter().map(|l| (DiffOp::Same, *l)).collect();
+    if am.len() * bm.len() > 4_000_000 {
+        out.extend(am.iter().map(|l| (DiffOp::Removed, *l)));
+        out.extend(bm.iter().map(|l| (DiffOp::Added, *l)));
+    } else {
+        let (n, m) = (am.len(), bm.len());
+        let mut lcs = vec![0u32; (n + 1) * (m + 1)];
+        for i in (0..n).rev() {
+            for j in (0..m).rev() {
+                lcs[i * (m + 1) + j] = if am[i] == bm[j] {
+                    lcs[(i + 1) * (m + 1) + j + 1] + 1
+                } else {
+                    lcs[(i + 1) * (m + 1) + j].max(lcs[i * (m + 1) + j + 1])
+                };
+            }
+        }
+        let (mut i, mut j) = (0, 0);
+        while i < n && j < m {
+            if am[i] == bm[j] {
+                out.push((DiffOp::Same, am[i]));
+                i += 1;
+                j += 1;
+            } else if lcs[(i + 1) * (m + 1) + j] >= lcs[i * (m + 1) + j + 1] {
+                out.push((DiffOp::Removed, am[i]));
+                i += 1;
+            } else {
+                out.push((DiffOp::Added, bm[j]));
+                j += 1;
+            }
+        }
+        out.extend(am[i..].iter().map(|l| (DiffOp::Removed, *l)));
+        out.extend(bm[j..].iter().map(|l| (DiffOp::Added, *l)));
+    }
+    out.extend(a[a.len() - suffix..].iter().map(|l| (DiffOp::Same, *l)));
+    out
+}
+
+// Writes a file without ever leaving it truncated: the contents go to a temp file in
+// the same directory which is synced, given the original's permissions and renamed
+// over the target. The previous version is copied to backup first if given.
+pub fn atomic_write(path: &Path, contents: &[u8], backup: Option<&Path>) -> io::Result<()> {
+    // write through symlinks instead of replacing them
+    let path = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+    let dir = match path.parent() {
+        Some(d) if !d.as_os_str().is_empty() => d.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    let name = path
+        .file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let meta = fs::metadata(&path).ok();
+    // the rename would replace a read-only file
+    if meta.as_ref().is_some_and(|m| m.permissions().readonly()) {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("{} is read-only", path.display()),
+        ));
+    }
+    if let (Some(backup), Some(_)) = (backup, meta.as_ref()) {
+        if let Some(parent) = backup.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::copy(&path, backup)?;
+    }
+    let tmp = dir.join(format!(".{}.{}.tmp", name, std::process::id()));
+    let res = (|| {
+        let mut f = fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&tmp)?;
+        f.write_all(contents)?;
+        f.sync_all()?;
+        if let Some(meta) = meta.as_ref() {
+            fs::set_permissions(&tmp, meta.permissions())?;
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::MetadataExt;
+                // only root can give files away, keeping our own ownership is fine otherwise
+                std::os::unix::fs::chown(&tmp, Some(meta.uid()), Some(meta.gid())).ok();
+            }
+        }
+        fs::rename(&tmp, &path)
+    })();
+    if res.is_err() {
+        fs::remove_file(&tmp).ok();
+        return res;
+    }
+    // make the rename itself durable
+    #[cfg(unix)]
+    if let Ok(d) = fs::File::open(&dir) {
+        d.sync_all().ok();
+    }
+    Ok(())
+}
+
+// Scores text against a fuzzy pattern: the pattern chars must appear in order,
+// ignoring case. Consecutive matches and matches at word starts score higher,
+// gaps between matches lower. None if the text doesn't match.
+pub fn fuzzy_score(pattern: &str, text: &str) -> Option<i32> {
+    let mut pat = pattern
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .flat_map(char::to_lowercase)
+        .peekable();
+    let mut score = 0;
+    let mut prev: Option<char> = None;
+    let mut consecutive = false;
+    let mut started = false;
+    for c in text.chars() {
+        let p = match pat.peek() {
+            Some(p) => *p,
+            None => break,
+        };
+        if c.to_lowercase().eq(std::iter::once(p)) {
+            pat.next();
+            score += 1;
+            if consecutive {
+                score += 5;
+            }
+            let word_start = match prev {
+                None => true,
+                Some(pc) => !pc.is_alphanumeric() || (pc.is_lowercase() && c.is_uppercase()),
+            };
+            if word_start {
+                score += 8;
+            }
+            consecutive = true;
+            started = true;
+        } else {
+            if started {
+                score -= 1;
+            }
+            consecutive = false;
+        }
+        prev = Some(c);
+    }
+    if pat.peek().is_some() {
+        None
+    } else {
+        Some(score)
+    }
+}
+
+// Replaces the matches of search in text, all of them or the first one of each
+// line. Regex replacements may refer to groups as $1. Returns the new text and
+// the number of replacements, None for an invalid regex.
+pub fn replace_matches(
+    text: &str,
+    search: &str,
+    replace: &str,
+    regex: bool,
+    first_per_line: bool,
+) -> Option<(String, usize)> {
+    let re = if regex {
+        regex::Regex::new(search).ok()?
+    } else {
+        regex::Regex::new(&regex::escape(search)).ok()?
+    };
+    let replace = if regex {
+        replace.to_string()
+    } else {
+        replace.replace('$', "$$")
+    };
+    if !first_per_line {
+        let count = re.find_iter(text).count();
+        return Some((re.replace_all(text, replace.as_str()).into_owned(), count));
+    }
+    let mut count = 0;
+    let mut out = String::with_capacity(text.len());
+    for line in text.split_inclusive('\n') {
+        if re.is_match(line) {
+            count += 1;
+        }
+        out.push_str(&re.replacen(line, 1, replace.as_str()));
+    }
+    Some((out, count))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("red-test-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn atomic_write_replaces_and_backs_up() {
+        let dir = temp_dir("atomic");
+        let file = dir.join("a.txt");
+        let backup = dir.join("bak").join("a.txt.bak");
+        atomic_write(&file, b"one", None).unwrap();
+        atomic_write(&file, b"two", Some(&backup)).unwrap();
+        assert_eq!(fs::read(&file).unwrap(), b"two");
+        assert_eq!(fs::read(&backup).unwrap(), b"one");
+        // no temporary file is left behind
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 2);
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn atomic_write_keeps_read_only_files() {
+        let dir = temp_dir("readonly");
+        let file = dir.join("a.txt");
+        fs::write(&file, b"one").unwrap();
+        let writable = fs::metadata(&file).unwrap().permissions();
+        let mut perms = writable.clone();
+        perms.set_readonly(true);
+        fs::set_permissions(&file, perms).unwrap();
+        let err = atomic_write(&file, b"two", None).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        assert_eq!(fs::read(&file).unwrap(), b"one");
+        fs::set_permissions(&file, writable).unwrap();
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn line_diff_keeps_common_lines() {
+        use DiffOp::*;
+        assert_eq!(
+            line_diff("a\nb\nc\nd", "a\nx\nc\nd\ne"),
+            vec![
+                (Same, "a"),
+                (Removed, "b"),
+                (Added, "x"),
+                (Same, "c"),
+                (Same, "d"),
+                (Added, "e"),
+            ]
+        );
+        assert_eq!(line_diff("a\nb", "a\nb"), vec![(Same, "a"), (Same, "b")]);
+        assert_eq!(line_diff("", "a"), vec![(Added, "a")]);
+        assert_eq!(line_diff("a", ""), vec![(Removed, "a")]);
+    }
+
+    #[test]
+    fn fuzzy_score_ranks_word_starts() {
+        assert_eq!(fuzzy_score("xyz", "file save"), None);
+        assert_eq!(fuzzy_score("fs", "sf"), None);
+        assert!(fuzzy_score("FS", "file save").is_some());
+        // word starts and runs beat scattered letters
+        let starts = fuzzy_score("fs", "file save").unwrap();
+        let scattered = fuzzy_score("fs", "offset").unwrap();
+        assert!(starts > scattered);
+        let camel = fuzzy_score("ol", "openLine").unwrap();
+        let inner = fuzzy_score("ol", "pool").unwrap();
+        assert!(camel > inner);
+        assert!(fuzzy_score("sav", "save").unwrap() > fuzzy_score("sav", "sxaxv").unwrap());
+    }
+
+    #[test]
+    fn replace_matches_literal_and_regex() {
+        // literal text, '$' in the replacement is kept
+        assert_eq!(
+            replace_matches("a.b a.b", "a.b", "$1", false, false),
+            Some(("$1 $1".to_string(), 2))
+        );
+        assert_eq!(
+            replace_matches("ab ab\nab", "(a)(b)", "$2$1", true, false),
+            Some(("ba ba\nba".to_string(), 3))
+        );
+        // the first match of each line, counted by line
+        assert_eq!(
+            replace_matches("aa\nba\nc", "a", "x", false, true),
+            Some(("xa\nbx\nc".to_string(), 2))
+        );
+        assert_eq!(replace_matches("a", "(", "", true, false), None);
+    }
+}
@@ -0,0 +1,2116 @@
+// Vim emulation on top of the editor's key handling: normal, insert, visual and
+// visual line modes, counts, operators with motions and text objects, registers,
+// `.` repeat, searches and a few ex commands. Enabled in the settings.
+use crate::{cbs, commands, ctx, gui, settings, state::STATE, utils};
+use fltk::{app, draw, enums::*, frame, input, prelude::*, text, window};
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+// Larger counts are clamped, e.g. 99999999999p
+const MAX_COUNT: usize = 10000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Mode {
+    Normal,
+    Insert,
+    Visual,
+    VisualLine,
+}
+
+// A key press as vim sees it
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum VKey {
+    Char(char),
+    Ctrl(char),
+    Esc,
+    Enter,
+    Backspace,
+    Delete,
+    Left,
+    Right,
+    Up,
+    Down,
+    Home,
+    End,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Motion {
+    Left,
+    Right,
+    Up,
+    Down,
+    // true for WORDs, which only stop at whitespace
+    WordNext(bool),
+    WordEnd(bool),
+    WordBack(bool),
+    LineStart,
+    FirstNonBlank,
+    LineEnd,
+    // gg and G, to the line of the count if any
+    FirstLine,
+    LastLine,
+    Find { ch: char, forward: bool, till: bool },
+    // ; and , (reversed)
+    RepeatFind(bool),
+    MatchPair,
+    ParagraphNext,
+    ParagraphPrev,
+    // n and N (reversed)
+    SearchNext(bool),
+    // The target of dd, yy, cc...: count - 1 lines down
+    CurrentLine,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Object {
+    Word {
+        big: bool,
+        around: bool,
+    },
+    Pair {
+        open: char,
+        close: char,
+        around: bool,
+    },
+    Quote {
+        q: char,
+        around: bool,
+    },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Op {
+    Delete,
+    Change,
+    Yank,
+    Indent,
+    Outdent,
+    ToggleCase,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Target {
+    Motion(Motion),
+    Object(Object),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Action {
+    Move(Motion),
+    Operate(Op, Target),
+    // i a I A o O
+    Insert(char),
+    Put { before: bool },
+    Join,
+    Undo,
+    Redo,
+    ReplaceChar(char),
+    // ~
+    ToggleCaseChar,
+    Repeat,
+    Visual(bool),
+    // An operator applied to the visual selection
+    VisualOp(Op),
+    VisualObject(Object),
+    // o in visual mode
+    SwapAnchor,
+    // : / or ?
+    Prompt(char),
+    // * and # (reversed)
+    SearchWord(bool),
+    // Ctrl+D and Ctrl+U
+    Scroll(bool),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Cmd {
+    reg: Option<char>,
+    count: Option<usize>,
+    action: Action,
+}
+
+enum Parse<T> {
+    Done(T, usize),
+    More,
+    Invalid,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Kind {
+    Exclusive,
+    Inclusive,
+    Linewise,
+}
+
+#[derive(Clone, Default)]
+struct Register {
+    text: String,
+    linewise: bool,
+}
+
+struct Vim {
+    enabled: bool,
+    mode: Mode,
+    // Keys typed so far of the current command
+    pending: Vec<VKey>,
+    registers: HashMap<char, Register>,
+    // The last change and the text typed in insert mode after it, for `.`
+    last_change: Option<(Cmd, Option<String>)>,
+    // Text typed since entering insert mode, None once it can't be repeated
+    inserted: Option<String>,
+    recording: bool,
+    anchor: i32,
+    // Lines of the last visual selection, for :'<,'>
+    last_visual: Option<(i32, i32)>,
+    // Column kept by j and k across shorter lines
+    want_col: Option<usize>,
+    last_find: Option<(char, bool, bool)>,
+    last_search: Option<(String, bool)>,
+    // Error shown instead of the mode until the next key
+    message: Option<String>,
+    // What the command line was opened for
+    prompt: char,
+}
+
+impl Default for Vim {
+    fn default() -> Self {
+        Self {
+            enabled: settings::get().vim,
+            mode: Mode::Normal,
+            pending: Vec::new(),
+            registers: HashMap::new(),
+            last_change: None,
+            inserted: None,
+            recording: false,
+            anchor: 0,
+            last_visual: None,
+            want_col: None,
+            last_find: None,
+            last_search: None,
+            message: None,
+            prompt: ':',
+        }
+    }
+}
+
+thread_local! {
+    static VIM: RefCell<Vim> = RefCell::new(Vim::default());
+    // Last indicator text, for status updates made while a key is handled
+    static SHOWN: RefCell<String> = const { RefCell::new(String::new()) };
+}
+
+// False while a vim command runs, so the events it causes (pasting, undo)
+// reach the editor as usual
+fn enabled() -> bool {
+    VIM.with(|v| v.try_borrow().map(|v| v.enabled).unwrap_or(false))
+}
+
+// Shown in front of the status text of every window
+pub fn indicator() -> String {
+    let text = VIM.with(|v| {
+        let v = v.try_borrow().ok()?;
+        if !v.enabled {
+            return Some(String::new());
+        }
+        if let Some(m) = &v.message {
+            return Some(format!("{}   |   ", m));
+        }
+        let mode = match v.mode {
+            Mode::Normal => "NORMAL",
+            Mode::Insert => "INSERT",
+            Mode::Visual => "VISUAL",
+            Mode::VisualLine => "VISUAL LINE",
+        };
+        Some(format!("-- {} --   |   ", mode))
+    });
+    match text {
+        Some(t) => {
+            SHOWN.with(|s| *s.borrow_mut() = t.clone());
+            t
+        }
+        None => SHOWN.with(|s| s.borrow().clone()),
+    }
+}
+
+pub fn cursor_style() -> text::Cursor {
+    VIM.with(|v| {
+        let v = match v.try_borrow() {
+            Ok(v) => v,
+            Err(_) => return text::Cursor::Block,
+        };
+        if v.enabled && v.mode != Mode::Insert {
+            text::Cursor::Block
+        } else {
+            text::Cursor::Normal
+        }
+    })
+}
+
+// Follows the settings: starts in normal mode when enabled
+pub fn apply_settings() {
+    let on = settings::get().vim;
+    VIM.with(|v| {
+        let mut v = v.borrow_mut();
+        if v.enabled != on {
+            v.enabled = on;
+            v.mode = Mode::Normal;
+            v.pending.clear();
+            v.message = None;
+        }
+    });
+    for win in ctx::windows() {
+        if let Some(m) = ctx::widget_in::<fltk::menu::SysMenuBar>(win, "menu") {
+            if let Some(mut item) = m.find_item("&Edit/Vim Mode\t") {
+                if on {
+                    item.set();
+                } else {
+                    item.clear();
+                }
+            }
+        }
+    }
+    gui::refresh_info();
+}
+
+pub fn toggle() {
+    let mut s = settings::stored();
+    s.vim = !s.vim;
+    settings::set(s);
+}
+
+fn set_mode(v: &mut Vim, ed: &mut text::TextEditor, mode: Mode) {
+    if matches!(v.mode, Mode::Visual | Mode::VisualLine) {
+        // lines of the document, for '<,'>
+        if let Some(buf) = crate::folds::doc_buffer(ed) {
+            let a = buf.count_lines(0, crate::folds::to_doc(ed, v.anchor));
+            let b = buf.count_lines(0, crate::folds::to_doc(ed, ed.insert_position()));
+            v.last_visual = Some((a.min(b), a.max(b)));
+        }
+    }
+    v.mode = mode;
+    ed.set_cursor_style(if mode == Mode::Insert {
+        text::Cursor::Normal
+    } else {
+        text::Cursor::Block
+    });
+}
+
+fn vkey() -> Option<VKey> {
+    let state = crate::macros::event_state();
+    if state.intersects(Shortcut::Alt | Shortcut::Meta) {
+        return None;
+    }
+    let key = crate::macros::event_key();
+    if state.contains(Shortcut::Ctrl) {
+        let c = char::from_u32(key.bits() as u32)?;
+        return Some(VKey::Ctrl(c.to_ascii_lowercase()));
+    }
+    Some(match key {
+        Key::Escape => VKey::Esc,
+        Key::Enter | Key::KPEnter => VKey::Enter,
+        Key::BackSpace => VKey::Backspace,
+        Key::Delete => VKey::Delete,
+        Key::Left => VKey::Left,
+        Key::Right => VKey::Right,
+        Key::Up => VKey::Up,
+        Key::Down => VKey::Down,
+        Key::Home => VKey::Home,
+        Key::End => VKey::End,
+        Key::Tab => VKey::Char('\t'),
+        _ => VKey::Char(
+            crate::macros::event_text()
+                .chars()
+                .next()
+                .filter(|c| !c.is_control())?,
+        ),
+    })
+}
+
+// Called from the editor's handler before anything else sees the event
+pub fn handle(ed: &mut text::TextEditor, ev: Event) -> bool {
+    if ev != Event::KeyDown || !enabled() {
+        return false;
+    }
+    let before = indicator();
+    let (handled, prompt) = VIM.with(|v| key_down(&mut v.borrow_mut(), ed));
+    if indicator() != before {
+        gui::refresh_info();
+    }
+    if let Some(c) = prompt {
+        open_prompt(c, ed);
+    }
+    handled
+}
+
+// Returns whether the key was taken and the command line to open, if any
+fn key_down(v: &mut Vim, ed: &mut text::TextEditor) -> (bool, Option<char>) {
+    v.message = None;
+    let key = match vkey() {
+        Some(k) => k,
+        None => return (false, None),
+    };
+    if v.mode == Mode::Insert {
+        return (insert_key(v, ed, key), None);
+    }
+    match key {
+        VKey::Esc | VKey::Ctrl('[') => {
+            v.pending.clear();
+            if v.mode != Mode::Normal {
+                if let Some(mut buf) = ed.buffer() {
+                    buf.unselect();
+                }
+                set_mode(v, ed, Mode::Normal);
+            }
+            return (true, None);
+        }
+        // other Ctrl keys are left to the key bindings
+        VKey::Ctrl(c) if !matches!(c, 'r' | 'd' | 'u') => {
+            v.pending.clear();
+            return (false, None);
+        }
+        _ => (),
+    }
+    v.pending.push(key);
+    let visual = v.mode != Mode::Normal;
+    match parse(&v.pending, visual) {
+        Parse::Done(cmd, _) => {
+            v.pending.clear();
+            let prompt = execute(v, ed, cmd, false);
+            (true, prompt)
+        }
+        Parse::More => (true, None),
+        Parse::Invalid => {
+            v.pending.clear();
+            (true, None)
+        }
+    }
+}
+
+// Insert mode keys go to the editor, they're only recorded for `.`
+fn insert_key(v: &mut Vim, ed: &mut text::TextEditor, key: VKey) -> bool {
+    match key {
+        VKey::Esc | VKey::Ctrl('[') => {
+            finish_insert(v, ed);
+            true
+        }
+        VKey::Ctrl(_) => false,
+        VKey::Char(_) => {
+            if let Some(t) = v.inserted.as_mut() {
+                t.push_str(&crate::macros::event_text());
+            }
+            false
+        }
+        VKey::Enter => {
+            if let Some(t) = v.inserted.as_mut() {
+                t.push('\n');
+            }
+            false
+        }
+        VKey::Backspace => {
+            let popped = v.inserted.as_mut().map(|t| t.pop().is_some());
+            if popped == Some(false) {
+                v.inserted = None;
+            }
+            false
+        }
+        _ => {
+            // moving around while inserting can't be repeated
+            v.inserted = None;
+            false
+        }
+    }
+}
+
+fn finish_insert(v: &mut Vim, ed: &mut text::TextEditor) {
+    if v.recording {
+        if let Some(change) = v.last_change.as_mut() {
+            change.1 = v.inserted.take();
+        }
+        v.recording = false;
+    }
+    set_mode(v, ed, Mode::Normal);
+    if let Some(buf) = ed.buffer() {
+        let pos = ed.insert_position();
+        if pos > buf.line_start(pos) {
+            ed.set_insert_position(prev(&buf, pos));
+        }
+    }
+}
+
+// Parsing
+
+fn parse_count(keys: &[VKey]) -> (Option<usize>, usize) {
+    let mut n = 0;
+    let mut count: Option<usize> = None;
+    for k in keys {
+        match k {
+            VKey::Char(c) if c.is_ascii_digit() && !(*c == '0' && count.is_none()) => {
+                let d = c.to_digit(10).unwrap() as usize;
+                count = Some(
+                    count
+                        .unwrap_or(0)
+                        .saturating_mul(10)
+                        .saturating_add(d)
+                        .min(MAX_COUNT),
+                );
+                n += 1;
+            }
+            _ => break,
+        }
+    }
+    (count, n)
+}
+
+fn parse_motion(keys: &[VKey]) -> Parse<Motion> {
+    let key = match keys.first() {
+        Some(k) => *k,
+        None => return Parse::More,
+    };
+    let m = match key {
+        VKey::Char('h') | VKey::Left | VKey::Backspace => Motion::Left,
+        VKey::Char('l') | VKey::Right | VKey::Char(' ') => Motion::Right,
+        VKey::Char('j') | VKey::Down | VKey::Enter => Motion::Down,
+        VKey::Char('k') | VKey::Up => Motion::Up,
+        VKey::Char('w') => Motion::WordNext(false),
+        VKey::Char('W') => Motion::WordNext(true),
+        VKey::Char('e') => Motion::WordEnd(false),
+        VKey::Char('E') => Motion::WordEnd(true),
+        VKey::Char('b') => Motion::WordBack(false),
+        VKey::Char('B') => Motion::WordBack(true),
+        VKey::Char('0') | VKey::Home => Motion::LineStart,
+        VKey::Char('^') => Motion::FirstNonBlank,
+        VKey::Char('$') | VKey::End => Motion::LineEnd,
+        VKey::Char('G') => Motion::LastLine,
+        VKey::Char(';') => Motion::RepeatFind(false),
+        VKey::Char(',') => Motion::RepeatFind(true),
+        VKey::Char('%') => Motion::MatchPair,
+        VKey::Char('}') => Motion::ParagraphNext,
+        VKey::Char('{') => Motion::ParagraphPrev,
+        VKey::Char('n') => Motion::SearchNext(false),
+        VKey::Char('N') => Motion::SearchNext(true),
+        VKey::Char('g') => {
+            return match keys.get(1) {
+                None => Parse::More,
+                Some(VKey::Char('g')) => Parse::Done(Motion::FirstLine, 2),
+                _ => Parse::Invalid,
+            }
+        }
+        VKey::Char(c @ ('f' | 'F' | 't' | 'T')) => {
+            return match keys.get(1) {
+                None => Parse::More,
+                Some(VKey::Char(ch)) => Parse::Done(
+                    Motion::Find {
+                        ch: *ch,
+                        forward: c.is_lowercase(),
+                        till: c == 't' || c == 'T',
+                    },
+                    2,
+                ),
+                _ => Parse::Invalid,
+            }
+        }
+        _ => return Parse::Invalid,
+    };
+    Parse::Done(m, 1)
+}
+
+fn parse_object(keys: &[VKey]) -> Parse<Object> {
+    let around = match keys.first() {
+        None => return Parse::More,
+        Some(VKey::Char('i')) => false,
+        Some(VKey::Char('a')) => true,
+        _ => return Parse::Invalid,
+    };
+    let c = match keys.get(1) {
+        None => return Parse::More,
+        Some(VKey::Char(c)) => *c,
+        _ => return Parse::Invalid,
+    };
+    let obj = match c {
+        'w' => Object::Word { big: false, around },
+        'W' => Object::Word { big: true, around },
+        '(' | ')' | 'b' => Object::Pair {
+            open: '(',
+            close: ')',
+            around,
+        },
+        '{' | '}' | 'B' => Object::Pair {
+            open: '{',
+            close: '}',
+            around,
+        },
+        '[' | ']' => Object::Pair {
+            open: '[',
+            close: ']',
+            around,
+        },
+        '<' | '>' => Object::Pair {
+            open: '<',
+            close: '>',
+            around,
+        },
+        '"' | '\'' | '`' => Object::Quote { q: c, around },
+        _ => return Parse::Invalid,
+    };
+    Parse::Done(obj, 2)
+}
+
+fn operator(c: char) -> Option<Op> {
+    Some(match c {
+        'd' => Op::Delete,
+        'c' => Op::Change,
+        'y' => Op::Yank,
+        '>' => Op::Indent,
+        '<' => Op::Outdent,
+        _ => return None,
+    })
+}
+
+fn valid_register(c: char) -> bool {
+    c.is_ascii_alphanumeric() || matches!(c, '"' | '-' | '_' | '+' | '*')
+}
+
+fn parse(keys: &[VKey], visual: bool) -> Parse<Cmd> {
+    let mut i = 0;
+    let mut reg = None;
+    if keys.first() == Some(&VKey::Char('"')) {
+        match keys.get(1) {
+            None => return Parse::More,
+            Some(VKey::Char(c)) if valid_register(*c) => {
+                reg = Some(*c);
+                i = 2;
+            }
+            _ => return Parse::Invalid,
+        }
+    }
+    let (count, n) = parse_count(&keys[i..]);
+    i += n;
+    let key = match keys.get(i) {
+        Some(k) => *k,
+        None => return Parse::More,
+    };
+    let rest = &keys[i + 1..];
+    let done = |action, used: usize| Parse::Done(Cmd { reg, count, action }, i + 1 + used);
+    let op_motion = |op, m| Action::Operate(op, Target::Motion(m));
+    if visual {
+        let action = match key {
+            VKey::Char('d' | 'x' | 'X' | 'D') | VKey::Delete => Action::VisualOp(Op::Delete),
+            VKey::Char('c' | 's' | 'S' | 'C') => Action::VisualOp(Op::Change),
+            VKey::Char('y' | 'Y') => Action::VisualOp(Op::Yank),
+            VKey::Char('>') => Action::VisualOp(Op::Indent),
+            VKey::Char('<') => Action::VisualOp(Op::Outdent),
+            VKey::Char('~') => Action::VisualOp(Op::ToggleCase),
+            VKey::Char('J') => Action::Join,
+            VKey::Char('p' | 'P') => Action::Put { before: false },
+            VKey::Char('o') => Action::SwapAnchor,
+            VKey::Char('v') => Action::Visual(false),
+            VKey::Char('V') => Action::Visual(true),
+            VKey::Char(':') => Action::Prompt(':'),
+            VKey::Char('i' | 'a') => {
+                return match parse_object(&keys[i..]) {
+                    Parse::Done(o, _) => done(Action::VisualObject(o), 1),
+                    Parse::More => Parse::More,
+                    Parse::Invalid => Parse::Invalid,
+                }
+            }
+            _ => {
+                return match parse_motion(&keys[i..]) {
+                    Parse::Done(m, used) => done(Action::Move(m), used - 1),
+                    Parse::More => Parse::More,
+                    Parse::Invalid => Parse::Invalid,
+                }
+            }
+        };
+        return done(action, 0);
+    }
+    if let VKey::Char(c) = key {
+        if let Some(op) = operator(c) {
+            // an operator takes a count, then a motion, a text object or itself
+            let (count2, n2) = parse_count(rest);
+            let count = match (count, count2) {
+                (Some(a), Some(b)) => Some(a.saturating_mul(b).min(MAX_COUNT)),
+                (a, b) => a.or(b),
+            };
+            let rest2 = &rest[n2..];
+            let target = match rest2.first() {
+                None => return Parse::More,
+                Some(VKey::Char(c2)) if *c2 == c => {
+                    Parse::Done(Target::Motion(Motion::CurrentLine), 1)
+                }
+                Some(VKey::Char('i' | 'a')) => match parse_object(rest2) {
+                    Parse::Done(o, used) => Parse::Done(Target::Object(o), used),
+                    Parse::More => Parse::More,
+                    Parse::Invalid => Parse::Invalid,
+                },
+                Some(_) => match parse_motion(rest2) {
+                    Parse::Done(m, used) => Parse::Done(Target::Motion(m), used),
+                    Parse::More => Parse::More,
+                    Parse::Invalid => Parse::Invalid,
+                },
+            };
+            return match target {
+                Parse::Done(t, used) => Parse::Done(
+                    Cmd {
+                        reg,
+                        count,
+                        action: Action::Operate(op, t),
+                    },
+                    i + 1 + n2 + used,
+                ),
+                Parse::More => Parse::More,
+                Parse::Invalid => Parse::Invalid,
+            };
+        }
+    }
+    let action = match key {
+        VKey::Char('x') | VKey::Delete => op_motion(Op::Delete, Motion::Right),
+        VKey::Char('X') => op_motion(Op::Delete, Motion::Left),
+        VKey::Char('s') => op_motion(Op::Change, Motion::Right),
+        VKey::Char('S') => op_motion(Op::Change, Motion::CurrentLine),
+        VKey::Char('D') => op_motion(Op::Delete, Motion::LineEnd),
+        VKey::Char('C') => op_motion(Op::Change, Motion::LineEnd),
+        VKey::Char('Y') => op_motion(Op::Yank, Motion::CurrentLine),
+        VKey::Char(c @ ('i' | 'a' | 'I' | 'A' | 'o' | 'O')) => Action::Insert(c),
+        VKey::Char('p') => Action::Put { before: false },
+        VKey::Char('P') => Action::Put { before: true },
+        VKey::Char('J') => Action::Join,
+        VKey::Char('u') => Action::Undo,
+        VKey::Ctrl('r') => Action::Redo,
+        VKey::Ctrl('d') => Action::Scroll(true),
+        VKey::Ctrl('u') => Action::Scroll(false),
+        VKey::Char('~') => Action::ToggleCaseChar,
+        VKey::Char('.') => Action::Repeat,
+        VKey::Char('v') => Action::Visual(false),
+        VKey::Char('V') => Action::Visual(true),
+        VKey::Char(c @ (':' | '/' | '?')) => Action::Prompt(c),
+        VKey::Char('*') => Action::SearchWord(false),
+        VKey::Char('#') => Action::SearchWord(true),
+        VKey::Char('r') => {
+            return match rest.first() {
+                None => Parse::More,
+                Some(VKey::Char(c)) => done(Action::ReplaceChar(*c), 1),
+                Some(VKey::Enter) => done(Action::ReplaceChar('\n'), 1),
+                _ => Parse::Invalid,
+            }
+        }
+        _ => {
+            return match parse_motion(&keys[i..]) {
+                Parse::Done(m, used) => done(Action::Move(m), used - 1),
+                Parse::More => Parse::More,
+                Parse::Invalid => Parse::Invalid,
+            }
+        }
+    };
+    done(action, 0)
+}
+
+// Buffer helpers, positions are byte offsets like everywhere in the buffer
+
+fn char_at(buf: &text::TextBuffer, pos: i32) -> char {
+    if pos < 0 || pos >= buf.length() {
+        '\n'
+    } else {
+        buf.char_at(pos).unwrap_or('\n')
+    }
+}
+
+fn next(buf: &text::TextBuffer, pos: i32) -> i32 {
+    let len = buf.length();
+    if pos >= len {
+        return len;
+    }
+    let mut p = pos + 1;
+    while p < len && buf.byte_at(p) & 0xC0 == 0x80 {
+        p += 1;
+    }
+    p
+}
+
+fn prev(buf: &text::TextBuffer, pos: i32) -> i32 {
+    if pos <= 0 {
+        return 0;
+    }
+    let mut p = pos - 1;
+    while p > 0 && buf.byte_at(p) & 0xC0 == 0x80 {
+        p -= 1;
+    }
+    p
+}
+
+fn first_non_blank(buf: &text::TextBuffer, pos: i32) -> i32 {
+    let end = buf.line_end(pos);
+    let mut p = buf.line_start(pos);
+    while p < end && matches!(char_at(buf, p), ' ' | '\t') {
+        p = next(buf, p);
+    }
+    p
+}
+
+fn column(buf: &text::TextBuffer, pos: i32) -> usize {
+    buf.text_range(buf.line_start(pos), pos)
+        .map(|t| t.chars().count())
+        .unwrap_or(0)
+}
+
+// The position at a column of a line, clamped to its last character
+fn at_column(buf: &text::TextBuffer, line_start: i32, col: usize, past_end: bool) -> i32 {
+    let end = buf.line_end(line_start);
+    let mut p = line_start;
+    for _ in 0..col {
+        if p >= end {
+            break;
+        }
+        p = next(buf, p);
+    }
+    if p >= end && end > line_start && !past_end {
+        prev(buf, end)
+    } else {
+        p
+    }
+}
+
+// Start of the line n lines below (or above) the one of pos, stopping at the ends
+fn line_offset(buf: &text::TextBuffer, pos: i32, n: i64) -> i32 {
+    let mut p = buf.line_start(pos);
+    if n >= 0 {
+        for _ in 0..n {
+            let e = buf.line_end(p);
+            if e >= buf.length() {
+                break;
+            }
+            p = e + 1;
+        }
+    } else {
+        for _ in 0..-n {
+            if p == 0 {
+                break;
+            }
+            p = buf.line_start(p - 1);
+        }
+    }
+    p
+}
+
+fn class(c: char, big: bool) -> u8 {
+    if c.is_whitespace() {
+        0
+    } else if big || c.is_alphanumeric() || c == '_' {
+        1
+    } else {
+        2
+    }
+}
+
+fn word_next(buf: &text::TextBuffer, mut p: i32, big: bool) -> i32 {
+    let len = buf.length();
+    let c = class(char_at(buf, p), big);
+    if c != 0 {
+        while p < len && class(char_at(buf, p), big) == c {
+            p = next(buf, p);
+        }
+    }
+    while p < len && char_at(buf, p).is_whitespace() {
+        // an empty line counts as a word
+        if char_at(buf, p) == '\n' && char_at(buf, p + 1) == '\n' {
+            return p + 1;
+        }
+        p = next(buf, p);
+    }
+    p
+}
+
+fn word_end(buf: &text::TextBuffer, p: i32, big: bool) -> i32 {
+    let len = buf.length();
+    let mut p = next(buf, p);
+    while p < len && char_at(buf, p).is_whitespace() {
+        p = next(buf, p);
+    }
+    let c = class(char_at(buf, p), big);
+    while next(buf, p) < len && class(char_at(buf, next(buf, p)), big) == c {
+        p = next(buf, p);
+    }
+    p
+}
+
+fn word_back(buf: &text::TextBuffer, p: i32, big: bool) -> i32 {
+    let mut p = prev(buf, p);
+    while p > 0 && char_at(buf, p).is_whitespace() {
+        p = prev(buf, p);
+    }
+    let c = class(char_at(buf, p), big);
+    while p > 0 && class(char_at(buf, prev(buf, p)), big) == c {
+        p = prev(buf, p);
+    }
+    p
+}
+
+// The bracket matching the one at or after pos on its line
+fn match_pair(buf: &text::TextBuffer, pos: i32) -> Option<i32> {
+    const PAIRS: [(char, char); 3] = [('(', ')'), ('[', ']'), ('{', '}')];
+    let end = buf.line_end(pos);
+    let mut p = pos;
+    let (open, close, forward) = loop {
+        if p >= end {
+            return None;
+        }
+        let c = char_at(buf, p);
+        if let Some((o, cl)) = PAIRS.iter().find(|(o, cl)| *o == c || *cl == c) {
+            break (*o, *cl, c == *o);
+        }
+        p = next(buf, p);
+    };
+    find_unmatched(buf, p, open, close, forward)
+}
+
+// Scans from the bracket at pos for the one closing (or opening) it
+fn find_unmatched(
+    buf: &text::TextBuffer,
+    pos: i32,
+    open: char,
+    close: char,
+    forward: bool,
+) -> Option<i32> {
+    let mut depth = 0;
+    let mut p = pos;
+    loop {
+        let c = char_at(buf, p);
+        if c == open {
+            depth += if forward { 1 } else { -1 };
+        } else if c == close {
+            depth += if forward { -1 } else { 1 };
+        }
+        if depth == 0 {
+            return Some(p);
+        }
+        if forward {
+            p = next(buf, p);
+            if p >= buf.length() {
+                return None;
+            }
+        } else {
+            if p == 0 {
+                return None;
+            }
+            p = prev(buf, p);
+        }
+    }
+}
+
+fn search(buf: &text::TextBuffer, pattern: &str, from: i32, backward: bool) -> Option<i32> {
+    let re = regex::Regex::new(pattern).ok()?;
+    let text = buf.text();
+    let from = from.clamp(0, text.len() as i32) as usize;
+    if backward {
+        let mut last = None;
+        let mut before = None;
+        for m in re.find_iter(&text) {
+            if m.start() < from {
+                before = Some(m.start());
+            }
+            last = Some(m.start());
+        }
+        before.or(last).map(|p| p as i32)
+    } else {
+        let start = if from < text.len() {
+            next(buf, from as i32) as usize
+        } else {
+            from
+        };
+        re.find_at(&text, start.min(text.len()))
+            .or_else(|| re.find(&text))
+            .map(|m| m.start() as i32)
+    }
+}
+
+// Motions
+
+// Where a motion ends, None if it can't move. `op` allows going past the last
+// character of the line, as the end of an operator's range.
+fn motion_target(
+    v: &mut Vim,
+    buf: &text::TextBuffer,
+    pos: i32,
+    m: Motion,
+    count: Option<usize>,
+    op: bool,
+) -> Option<(i32, Kind)> {
+    let n = count.unwrap_or(1).max(1);
+    let len = buf.length();
+    let vertical = matches!(m, Motion::Up | Motion::Down);
+    if !vertical {
+        v.want_col = None;
+    }
+    let target = match m {
+        Motion::Left => {
+            let ls = buf.line_start(pos);
+            let mut p = pos;
+            for _ in 0..n {
+                if p <= ls {
+                    break;
+                }
+                p = prev(buf, p);
+            }
+            (p, Kind::Exclusive)
+        }
+        Motion::Right => {
+            let le = buf.line_end(pos);
+            let last = if op || le == buf.line_start(pos) {
+                le
+            } else {
+                prev(buf, le)
+            };
+            let mut p = pos;
+            for _ in 0..n {
+                if p >= last {
+                    break;
+                }
+                p = next(buf, p);
+            }
+            (p, Kind::Exclusive)
+        }
+        Motion::Up | Motion::Down => {
+            let col = *v.want_col.get_or_insert(column(buf, pos));
+            let delta = if m == Motion::Up {
+                -(n as i64)
+            } else {
+                n as i64
+            };
+            let ls = line_offset(buf, pos, delta);
+            if ls == buf.line_start(pos) {
+                return None;
+            }
+            (at_column(buf, ls, col, false), Kind::Linewise)
+        }
+        Motion::WordNext(big) => {
+            let mut p = pos;
+            for _ in 0..n {
+                let np = word_next(buf, p, big);
+                if np == p {
+                    break;
+                }
+                p = np;
+            }
+            (p, Kind::Exclusive)
+        }
+        Motion::WordEnd(big) => {
+            let mut p = pos;
+            for _ in 0..n {
+                let np = word_end(buf, p, big);
+                if np == p {
+                    break;
+                }
+                p = np;
+            }
+            (p.min(len), Kind::Inclusive)
+        }
+        Motion::WordBack(big) => {
+            let mut p = pos;
+            for _ in 0..n {
+                let np = word_back(buf, p, big);
+                if np == p {
+                    break;
+                }
+                p = np;
+            }
+            (p, Kind::Exclusive)
+        }
+        Motion::LineStart => (buf.line_start(pos), Kind::Exclusive),
+        Motion::FirstNonBlank => (first_non_blank(buf, pos), Kind::Exclusive),
+        Motion::LineEnd => {
+            v.want_col = Some(usize::MAX);
+            let ls = line_offset(buf, pos, n as i64 - 1);
+            let le = buf.line_end(ls);
+            let p = if op || le == ls { le } else { prev(buf, le) };
+            (p, Kind::Inclusive)
+        }
+        Motion::FirstLine | Motion::LastLine => {
+            let ls = match count {
+                Some(c) => line_offset(buf, 0, c as i64 - 1),
+                None if m == Motion::FirstLine => 0,
+                None => buf.line_start(len),
+            };
+            (first_non_blank(buf, ls), Kind::Linewise)
+        }
+        Motion::Find { ch, forward, till } => {
+            v.last_find = Some((ch, forward, till));
+            (
+                find_char(buf, pos, ch, forward, till, n)?,
+                kind_of_find(forward),
+            )
+        }
+        Motion::RepeatFind(reverse) => {
+            let (ch, forward, till) = v.last_find?;
+            let forward = forward != reverse;
+            // repeating a till from right before the char would not move
+            let from = if till {
+                if forward {
+                    next(buf, pos)
+                } else {
+                    prev(buf, pos)
+                }
+            } else {
+                pos
+            };
+            (
+                find_char(buf, from, ch, forward, till, n)?,
+                kind_of_find(forward),
+            )
+        }
+        Motion::MatchPair => (match_pair(buf, pos)?, Kind::Inclusive),
+        Motion::ParagraphNext | Motion::ParagraphPrev => {
+            let forward = m == Motion::ParagraphNext;
+            let mut ls = buf.line_start(pos);
+            for _ in 0..n {
+                if ls == if forward { len } else { 0 } {
+                    break;
+                }
+                // skip the empty lines, then to the next empty line
+                let empty = |ls: i32| buf.line_end(ls) == ls;
+                let mut seen_text = false;
+                loop {
+                    let nl = line_offset(buf, ls, if forward { 1 } else { -1 });
+                    if nl == ls {
+                        ls = if forward { len } else { 0 };
+                        break;
+                    }
+                    ls = nl;
+                    if !empty(ls) {
+                        seen_text = true;
+                    } else if seen_text {
+                        break;
+                    }
+                }
+            }
+            (ls, Kind::Exclusive)
+        }
+        Motion::SearchNext(reverse) => {
+            let (pattern, backward) = v.last_search.clone()?;
+            let mut p = pos;
+            for _ in 0..n {
+                p = search(buf, &pattern, p, backward != reverse)?;
+            }
+            (p, Kind::Exclusive)
+        }
+        Motion::CurrentLine => (line_offset(buf, pos, n as i64 - 1), Kind::Linewise),
+    };
+    Some(target)
+}
+
+fn kind_of_find(forward: bool) -> Kind {
+    if forward {
+        Kind::Inclusive
+    } else {
+        Kind::Exclusive
+    }
+}
+
+fn find_char(
+    buf: &text::TextBuffer,
+    pos: i32,
+    ch: char,
+    forward: bool,
+    till: bool,
+    n: usize,
+) -> Option<i32> {
+    let ls = buf.line_start(pos);
+    let le = buf.line_end(pos);
+    let mut p = pos;
+    for _ in 0..n {
+        loop {
+            p = if forward {
+                if p >= le {
+                    return None;
+                }
+                next(buf, p)
+            } else {
+                if p <= ls {
+                    return None;
+                }
+                prev(buf, p)
+            };
+            if p >= le {
+                return None;
+            }
+            if char_at(buf, p) == ch {
+                break;
+            }
+        }
+    }
+    Some(match (till, forward) {
+        (false, _) => p,
+        (true, true) => prev(buf, p),
+        (true, false) => next(buf, p),
+    })
+}
+
+// Text objects, as [start, end)
+
+fn object_range(buf: &text::TextBuffer, pos: i32, obj: Object) -> Option<(i32, i32)> {
+    match obj {
+        Object::Word { big, around } => {
+            let ls = buf.line_start(pos);
+            let le = buf.line_end(pos);
+            let c = class(char_at(buf, pos), big);
+            let mut s = pos;
+            while s > ls && class(char_at(buf, prev(buf, s)), big) == c {
+                s = prev(buf, s);
+            }
+            let mut e = pos;
+            while e < le && class(char_at(buf, e), big) == c {
+                e = next(buf, e);
+            }
+            if around {
+                let e0 = e;
+                while e < le && matches!(char_at(buf, e), ' ' | '\t') {
+                    e = next(buf, e);
+                }
+                // without trailing blanks, the leading ones go
+                if e == e0 {
+                    while s > ls && matches!(char_at(buf, prev(buf, s)), ' ' | '\t') {
+                        s = prev(buf, s);
+                    }
+                }
+            }
+            Some((s, e))
+        }
+        Object::Pair {
+            open,
+            close,
+            around,
+        } => {
+            let o = if char_at(buf, pos) == open {
+                pos
+            } else if char_at(buf, pos) == close {
+                find_unmatched(buf, pos, open, close, false)?
+            } else {
+                // the nearest unclosed open before pos
+                let mut depth = 0;
+                let mut p = pos;
+                loop {
+                    if p == 0 {
+                        return None;
+                    }
+                    p = prev(buf, p);
+                    let c = char_at(buf, p);
+                    if c == close {
+                        depth += 1;
+                    } else if c == open {
+                        if depth == 0 {
+                            break p;
+                        }
+                        depth -= 1;
+                    }
+                }
+            };
+            let c = find_unmatched(buf, o, open, close, true)?;
+            if around {
+                Some((o, next(buf, c)))
+            } else {
+                Some((next(buf, o), c))
+            }
+        }
+        Object::Quote { q, around } => {
+            let ls = buf.line_start(pos);
+            let le = buf.line_end(pos);
+            let mut quotes = Vec::new();
+            let mut p = ls;
+            while p < le {
+                if char_at(buf, p) == q && (p == ls || char_at(buf, prev(buf, p)) != '\\') {
+                    quotes.push(p);
+                }
+                p = next(buf, p);
+            }
+            let (a, b) = quotes
+                .chunks(2)
+                .filter(|c| c.len() == 2)
+                .map(|c| (c[0], c[1]))
+                .find(|(_, b)| pos <= *b)?;
+            if around {
+                let mut e = next(buf, b);
+                while e < le && matches!(char_at(buf, e), ' ' | '\t') {
+                    e = next(buf, e);
+                }
+                Some((a, e))
+            } else {
+                Some((next(buf, a), b))
+            }
+        }
+    }
+}
+
+// Registers
+
+fn store(v: &mut Vim, reg: Option<char>, op: Op, text: String, linewise: bool) {
+    let r = Register {
+        text: text.clone(),
+        linewise,
+    };
+    match reg {
+        Some('_') => return,
+        Some(c) if c.is_ascii_uppercase() => {
+            let e = v.registers.entry(c.to_ascii_lowercase()).or_default();
+            if linewise && !e.linewise && !e.text.is_empty() {
+                e.text.push('\n');
+            }
+            e.text.push_str(&text);
+            e.linewise |= linewise;
+        }
+        Some('+' | '*') => app::copy(&text),
+        Some(c) => {
+            v.registers.insert(c, r.clone());
+        }
+        None => (),
+    }
+    if op == Op::Yank {
+        if reg.is_none() {
+            v.registers.insert('0', r.clone());
+        }
+    } else if linewise || text.contains('\n') {
+        for i in (1..9).rev() {
+            let d = char::from_digit(i, 10).unwrap();
+            if let Some(prev) = v.registers.get(&d).cloned() {
+                v.registers
+                    .insert(char::from_digit(i + 1, 10).unwrap(), prev);
+            }
+        }
+        v.registers.insert('1', r.clone());
+    } else {
+        v.registers.insert('-', r.clone());
+    }
+    v.registers.insert('"', r);
+}
+
+// Operators
+
+// Applies an operator to the range [start, end), or to its whole lines if linewise
+fn apply(
+    v: &mut Vim,
+    ed: &mut text::TextEditor,
+    buf: &text::TextBuffer,
+    op: Op,
+    reg: Option<char>,
+    (start, end, linewise): (i32, i32, bool),
+) {
+    let len = buf.length();
+    let (mut s, mut e) = (start.min(end), start.max(end).min(len));
+    if linewise {
+        s = buf.line_start(s);
+        e = buf.line_end(e);
+    }
+    let text = crate::folds::text_range(ed, s, e).unwrap_or_default();
+    match op {
+        Op::Yank => {
+            let text = if linewise { text + "\n" } else { text };
+            store(v, reg, op, text, linewise);
+            if !linewise {
+                ed.set_insert_position(s);
+            }
+        }
+        Op::Delete | Op::Change => {
+            let stored = if linewise { text + "\n" } else { text };
+            store(v, reg, op, stored, linewise);
+            if linewise && op == Op::Delete {
+                // take the line break along, the one before for the last line
+                let last = e >= len;
+                if !last {
+                    e += 1;
+                } else if s > 0 {
+                    s -= 1;
+                }
+                crate::folds::replace(ed, s, e, "");
+                let p = if last { buf.line_start(s) } else { s };
+                ed.set_insert_position(first_non_blank(buf, p));
+            } else if linewise {
+                let s = first_non_blank(buf, s);
+                crate::folds::replace(ed, s, e, "");
+                ed.set_insert_position(s);
+            } else {
+                crate::folds::replace(ed, s, e, "");
+                ed.set_insert_position(s);
+            }
+            if op == Op::Change {
+                start_insert(v, ed);
+            }
+        }
+        Op::Indent | Op::Outdent => {
+            let width = settings::get().tab_distance.max(1) as usize;
+            let first = buf.line_start(s);
+            let mut ls = buf.line_start(e);
+            loop {
+                let le = buf.line_end(ls);
+                if op == Op::Indent {
+                    if le > ls {
+                        crate::folds::replace(ed, ls, ls, &" ".repeat(width));
+                    }
+                } else {
+                    let mut p = ls;
+                    let mut n = 0;
+                    while p < le && n < width {
+                        match char_at(buf, p) {
+                            ' ' => n += 1,
+                            '\t' => n = width,
+                            _ => break,
+                        }
+                        p += 1;
+                    }
+                    crate::folds::replace(ed, ls, p, "");
+                }
+                if ls <= first {
+                    break;
+                }
+                ls = buf.line_start(ls - 1);
+            }
+            ed.set_insert_position(first_non_blank(buf, first));
+        }
+        Op::ToggleCase => {
+            let swapped: String = text
+                .chars()
+                .map(|c| {
+                    if c.is_uppercase() {
+                        c.to_lowercase().next().unwrap_or(c)
+                    } else {
+                        c.to_uppercase().next().unwrap_or(c)
+                    }
+                })
+                .collect();
+            crate::folds::replace(ed, s, e, &swapped);
+            ed.set_insert_position(s);
+        }
+    }
+}
+
+fn operate(
+    v: &mut Vim,
+    ed: &mut text::TextEditor,
+    buf: &text::TextBuffer,
+    cmd: &Cmd,
+    op: Op,
+    target: Target,
+) -> bool {
+    let pos = ed.insert_position();
+    let (s, e, linewise) = match target {
+        Target::Object(o) => match object_range(buf, pos, o) {
+            Some((s, e)) => (s, e, false),
+            None => return false,
+        },
+        Target::Motion(m) => {
+            // cw on a word changes to its end like ce
+            let m = match m {
+                Motion::WordNext(big) if op == Op::Change && !char_at(buf, pos).is_whitespace() => {
+                    Motion::WordEnd(big)
+                }
+                _ => m,
+            };
+            let (t, kind) = match motion_target(v, buf, pos, m, cmd.count, true) {
+                Some(t) => t,
+                None => return false,
+            };
+            let (s, e) = (pos.min(t), pos.max(t));
+            match kind {
+                Kind::Linewise => (s, e, true),
+                Kind::Inclusive => (s, next(buf, e), false),
+                // an exclusive motion to the start of a line stops at the end of the
+                // previous one, and covers whole lines when it started at the indent
+                Kind::Exclusive if e > s && e == buf.line_start(e) && buf.line_start(s) != e => {
+                    (s, e - 1, s <= first_non_blank(buf, s))
+                }
+                Kind::Exclusive => (s, e, false),
+            }
+        }
+    };
+    if s == e && !linewise {
+        return false;
+    }
+    apply(v, ed, buf, op, cmd.reg, (s, e, linewise));
+    true
+}
+
+fn start_insert(v: &mut Vim, ed: &mut text::TextEditor) {
+    v.inserted = Some(String::new());
+    set_mode(v, ed, Mode::Insert);
+}
+
+// The register to put, None for the system clipboard which can only be read
+// through a paste event
+fn register(v: &Vim, reg: Option<char>) -> Option<Option<Register>> {
+    match reg.unwrap_or('"').to_ascii_lowercase() {
+        '+' | '*' => Some(None),
+        c => v.registers.get(&c).cloned().map(Some),
+    }
+}
+
+fn put(
+    ed: &mut text::TextEditor,
+    buf: &text::TextBuffer,
+    r: Option<Register>,
+    count: Option<usize>,
+    before: bool,
+) {
+    let pos = ed.insert_position();
+    let r = match r {
+        Some(r) => r,
+        None => {
+            if !before && pos < buf.line_end(pos) {
+                ed.set_insert_position(next(buf, pos));
+            }
+            ed.paste();
+            return;
+        }
+    };
+    let n = count.unwrap_or(1).max(1);
+    if r.linewise {
+        let text = r.text.repeat(n);
+        let at = if before {
+            buf.line_start(pos)
+        } else {
+            let le = buf.line_end(pos);
+            if le >= buf.length() {
+                // no line break to insert after
+                crate::folds::replace(ed, le, le, "\n");
+                let t = text.strip_suffix('\n').unwrap_or(&text);
+                crate::folds::replace(ed, le + 1, le + 1, t);
+                ed.set_insert_position(first_non_blank(buf, le + 1));
+                return;
+            }
+            le + 1
+        };
+        crate::folds::replace(ed, at, at, &text);
+        ed.set_insert_position(first_non_blank(buf, at));
+    } else {
+        let text = r.text.repeat(n);
+        let at = if before || buf.line_end(pos) == buf.line_start(pos) {
+            pos
+        } else {
+            next(buf, pos)
+        };
+        crate::folds::replace(ed, at, at, &text);
+        ed.set_insert_position(prev(buf, at + text.len() as i32).max(at));
+    }
+}
+
+fn join(buf: &text::TextBuffer, ed: &mut text::TextEditor, start: i32, lines: usize) {
+    let mut pos = start;
+    for _ in 1..lines.max(2) {
+        let le = buf.line_end(pos);
+        if le >= buf.length() {
+            break;
+        }
+        let next_start = le + 1;
+        let fnb = first_non_blank(buf, next_start);
+        let mut s = le;
+        while s > buf.line_start(le) && matches!(char_at(buf, prev(buf, s)), ' ' | '\t') {
+            s = prev(buf, s);
+        }
+        let sep = if fnb == buf.line_end(next_start)
+            || char_at(buf, fnb) == ')'
+            || s == buf.line_start(le)
+        {
+            ""
+        } else {
+            " "
+        };
+        crate::folds::replace(ed, s, fnb, sep);
+        pos = s;
+    }
+    ed.set_insert_position(pos);
+}
+
+fn update_selection(v: &Vim, ed: &text::TextEditor, buf: &mut text::TextBuffer) {
+    let c = ed.insert_position();
+    let (a, b) = (v.anchor.min(c), v.anchor.max(c));
+    match v.mode {
+        Mode::Visual => buf.select(a, next(buf, b)),
+        Mode::VisualLine => buf.select(buf.line_start(a), (buf.line_end(b) + 1).min(buf.length())),
+        _ => (),
+    }
+}
+
+fn visual_range(v: &Vim, ed: &text::TextEditor, buf: &text::TextBuffer) -> (i32, i32, bool) {
+    let c = ed.insert_position();
+    let (a, b) = (v.anchor.min(c), v.anchor.max(c));
+    if v.mode == Mode::VisualLine {
+        (a, b, true)
+    } else {
+        (a, next(buf, b).min(buf.length()), false)
+    }
+}
+
+fn is_change(a: &Action) -> bool {
+    match a {
+        Action::Operate(op, _) => *op != Op::Yank,
+        Action::Insert(_)
+        | Action::Put { .. }
+        | Action::Join
+        | Action::ReplaceChar(_)
+        | Action::ToggleCaseChar => true,
+        _ => false,
+    }
+}
+
+// Runs a parsed command, returns the command line to open if any
+fn execute(v: &mut Vim, ed: &mut text::TextEditor, cmd: Cmd, replay: bool) -> Option<char> {
+    let mut buf = ed.buffer()?;
+    let pos = ed.insert_position();
+    let gen = utils::buffer_generation(&buf);
+    let visual = matches!(v.mode, Mode::Visual | Mode::VisualLine);
+    if !replay && !visual && is_change(&cmd.action) {
+        v.last_change = Some((cmd, None));
+        v.recording = true;
+    }
+    match cmd.action {
+        Action::Move(m) => {
+            if let Some((t, _)) = motion_target(v, &buf, pos, m, cmd.count, visual) {
+                ed.set_insert_position(t);
+            }
+        }
+        Action::Operate(op, target) => {
+            if !operate(v, ed, &buf, &cmd, op, target) && !replay {
+                v.recording = false;
+            }
+        }
+        Action::Insert(c) => {
+            let p = match c {
+                'a' if pos < buf.line_end(pos) => next(&buf, pos),
+                'I' => first_non_blank(&buf, pos),
+                'A' => buf.line_end(pos),
+                'o' => {
+                    let le = buf.line_end(pos);
+                    crate::folds::replace(ed, le, le, "\n");
+                    le + 1
+                }
+                'O' => {
+                    let ls = buf.line_start(pos);
+                    crate::folds::replace(ed, ls, ls, "\n");
+                    ls
+                }
+                _ => pos,
+            };
+            ed.set_insert_position(p);
+            start_insert(v, ed);
+        }
+        Action::Put { before } => {
+            let r = register(v, cmd.reg);
+            if visual {
+                // the selection is replaced, without overwriting the register
+                let range = visual_range(v, ed, &buf);
+                let at_end = buf.line_end(range.1) >= buf.length();
+                set_mode(v, ed, Mode::Normal);
+                buf.unselect();
+                apply(v, ed, &buf, Op::Delete, Some('_'), range);
+                if let Some(r) = r {
+                    put(ed, &buf, r, cmd.count, !range.2 || !at_end);
+                }
+            } else if let Some(r) = r {
+                put(ed, &buf, r, cmd.count, before);
+            }
+        }
+        Action::Join => {
+            if visual {
+                let (s, e, _) = visual_range(v, ed, &buf);
+                let lines = buf.count_lines(s, e) as usize + 1;
+                set_mode(v, ed, Mode::Normal);
+                buf.unselect();
+                join(&buf, ed, s, lines);
+            } else {
+                join(&buf, ed, pos, cmd.count.unwrap_or(2));
+            }
+        }
+        Action::Undo | Action::Redo => {
+            for _ in 0..cmd.count.unwrap_or(1) {
+                // nothing left to undo or redo
+                let gen = utils::buffer_generation(&buf);
+                if cmd.action == Action::Undo {
+                    crate::folds::undo(ed);
+                } else {
+                    crate::folds::redo(ed);
+                }
+                if utils::buffer_generation(&buf) == gen {
+                    break;
+                }
+            }
+            buf.unselect();
+        }
+        Action::ReplaceChar(c) => {
+            let n = cmd.count.unwrap_or(1).max(1);
+            let le = buf.line_end(pos);
+            let mut e = pos;
+            let mut k = 0;
+            while k < n && e < le {
+                e = next(&buf, e);
+                k += 1;
+            }
+            // not enough characters left on the line
+            if k < n {
+                v.recording = false;
+            } else if c == '\n' {
+                crate::folds::replace(ed, pos, e, "\n");
+                ed.set_insert_position(pos + 1);
+            } else {
+                crate::folds::replace(ed, pos, e, &c.to_string().repeat(n));
+                ed.set_insert_position(pos + (c.len_utf8() * (n - 1)) as i32);
+            }
+        }
+        Action::ToggleCaseChar => {
+            let n = cmd.count.unwrap_or(1).max(1);
+            let le = buf.line_end(pos);
+            let mut e = pos;
+            for _ in 0..n {
+                if e >= le {
+                    break;
+                }
+                e = next(&buf, e);
+            }
+            if e > pos {
+                apply(v, ed, &buf, Op::ToggleCase, None, (pos, e, false));
+                ed.set_insert_position(e);
+            }
+        }
+        Action::Repeat => {
+            if let Some((last, inserted)) = v.last_change.clone() {
+                let c = Cmd {
+                    count: cmd.count.or(last.count),
+                    ..last
+                };
+                execute(v, ed, c, true);
+                if v.mode == Mode::Insert {
+                    if let Some(t) = inserted {
+                        let p = ed.insert_position();
+                        crate::folds::replace(ed, p, p, &t);
+                        ed.set_insert_position(ed.insert_position() + t.len() as i32);
+                    }
+                    finish_insert(v, ed);
+                }
+            }
+        }
+        Action::Visual(line) => {
+            let mode = if line { Mode::VisualLine } else { Mode::Visual };
+            if v.mode == mode {
+                buf.unselect();
+                set_mode(v, ed, Mode::Normal);
+            } else {
+                if !visual {
+                    v.anchor = pos;
+                }
+                set_mode(v, ed, mode);
+            }
+        }
+        Action::VisualOp(op) => {
+            let range = visual_range(v, ed, &buf);
+            set_mode(v, ed, Mode::Normal);
+            buf.unselect();
+            apply(v, ed, &buf, op, cmd.reg, range);
+        }
+        Action::VisualObject(o) => {
+            if let Some((s, e)) = object_range(&buf, pos, o) {
+                if e > s {
+                    v.anchor = s;
+                    ed.set_insert_position(prev(&buf, e));
+                }
+            }
+        }
+        Action::SwapAnchor => {
+            let a = v.anchor;
+            v.anchor = pos;
+            ed.set_insert_position(a);
+        }
+        Action::Prompt(c) => return Some(c),
+        Action::SearchWord(backward) => {
+            let (s, e) = object_range(
+                &buf,
+                pos,
+                Object::Word {
+                    big: false,
+                    around: false,
+                },
+            )?;
+            let word = buf.text_range(s, e)?;
+            if class(word.chars().next()?, false) != 1 {
+                return None;
+            }
+            v.last_search = Some((format!(r"\b{}\b", regex::escape(&word)), backward));
+            if let Some(p) = search(&buf, &v.last_search.clone()?.0, pos, backward) {
+                ed.set_insert_position(p);
+            }
+        }
+        Action::Scroll(down) => {
+            draw::set_font(ed.text_font(), ed.text_size());
+            let half = (ed.h() / draw::height().max(1) / 2).max(1);
+            let top = ed.get_absolute_top_line_number();
+            let delta = if down { half } else { -half };
+            ed.scroll((top + delta).max(1), 0);
+            let ls = line_offset(&buf, pos, delta as i64);
+            ed.set_insert_position(first_non_blank(&buf, ls));
+        }
+    }
+    // the cursor stays on a character in normal mode
+    if v.mode == Mode::Normal {
+        let p = ed.insert_position();
+        let ls = buf.line_start(p);
+        if p >= buf.line_end(p) && p > ls {
+            ed.set_insert_position(prev(&buf, p));
+        }
+    }
+    if matches!(v.mode, Mode::Visual | Mode::VisualLine) {
+        update_selection(v, ed, &mut buf);
+    }
+    if !replay && v.mode != Mode::Insert {
+        v.recording = false;
+    }
+    // edits made through the buffer don't run the editor's callback, which marks
+    // the document modified and notifies the language server
+    let edited = match cmd.action {
+        // the clipboard is pasted through the editor
+        Action::Put { .. } if !visual && matches!(cmd.reg, Some('+' | '*')) => false,
+        _ => utils::buffer_generation(&buf) != gen,
+    };
+    if edited && !replay {
+        ed.do_callback();
+    }
+    ed.show_insert_position();
+    None
+}
+
+// The command line for :, / and ?
+
+fn prompt_widgets() -> Option<(window::Window, frame::Frame, input::Input)> {
+    Some((
+        app::widget_from_id("vim_cmd")?,
+        app::widget_from_id("vim_cmd_prefix")?,
+        app::widget_from_id("vim_cmd_input")?,
+    ))
+}
+
+// Opens the command line below the editor. From visual mode, the selected
+// lines become the range as '<,'>.
+fn open_prompt(c: char, ed: &mut text::TextEditor) {
+    let visual = VIM.with(|v| {
+        let mut v = v.borrow_mut();
+        v.prompt = c;
+        let visual = matches!(v.mode, Mode::Visual | Mode::VisualLine);
+        if visual {
+            if let Some(mut buf) = ed.buffer() {
+                buf.unselect();
+            }
+            set_mode(&mut v, ed, Mode::Normal);
+        }
+        visual
+    });
+    let (mut win, mut prefix, mut input) = match prompt_widgets() {
+        Some(w) => w,
+        None => return,
+    };
+    // a played macro runs the command line it recorded, see run_line
+    if crate::macros::playing() {
+        return;
+    }
+    prefix.set_label(&c.to_string());
+    input.set_value(if visual { "'<,'>" } else { "" });
+    if let Some(main) = ctx::widget::<fltk::group::Tabs>("tabs").and_then(|t| t.window()) {
+        win.resize(main.x(), main.y() + main.h() - 50, main.w(), 30);
+    }
+    win.show();
+    input.take_focus().ok();
+}
+
+// Hides the command line and gives the focus back to the editor
+pub fn close_prompt() {
+    if let Some((mut win, _, _)) = prompt_widgets() {
+        win.hide();
+    }
+    if let Some(mut ed) = STATE.with(|s| s.current_editor()) {
+        ed.take_focus().ok();
+    }
+}
+
+// Enter in the command line
+pub fn run_prompt() {
+    let line = match prompt_widgets() {
+        Some((_, _, input)) => input.value(),
+        None => return,
+    };
+    close_prompt();
+    let prompt = VIM.with(|v| v.borrow().prompt);
+    crate::macros::record_prompt(prompt, &line, || run_line(prompt, &line));
+}
+
+// Runs a command line of the : / or ? prompt
+pub fn run_line(prompt: char, line: &str) {
+    let mut ed = match STATE.with(|s| s.current_editor()) {
+        Some(e) => e,
+        None => return,
+    };
+    VIM.with(|v| v.borrow_mut().prompt = prompt);
+    if prompt == ':' {
+        run_ex(&mut ed, line.trim());
+    } else {
+        let pattern = convert_pattern(line);
+        if let Err(e) = regex::Regex::new(&pattern) {
+            return invalid_pattern(&e);
+        }
+        VIM.with(|v| {
+            let mut v = v.borrow_mut();
+            if !line.is_empty() {
+                v.last_search = Some((pattern, prompt == '?'));
+            }
+            let cmd = Cmd {
+                reg: None,
+                count: None,
+                action: Action::Move(Motion::SearchNext(false)),
+            };
+            execute(&mut v, &mut ed, cmd, false);
+        });
+    }
+    gui::refresh_info();
+}
+
+fn invalid_pattern(e: &regex::Error) {
+    // the message ends with the reason, after the pattern and a caret under it
+    let e = e.to_string();
+    let reason = e
+        .lines()
+        .next_back()
+        .unwrap_or_default()
+        .trim_start_matches("error: ");
+    message(&format!("E383: Invalid search string: {}", reason));
+}
+
+fn message(text: &str) {
+    VIM.with(|v| v.borrow_mut().message = Some(text.to_string()));
+    gui::refresh_info();
+}
+
+// Line range of an ex command, as 0 based first and last line of the document
+fn ex_range(ed: &text::TextEditor, range: &str) -> Option<(i32, i32)> {
+    let buf = crate::folds::doc_buffer(ed)?;
+    let current = buf.count_lines(0, crate::folds::to_doc(ed, ed.insert_position()));
+    let last = buf.count_lines(0, buf.length());
+    let line = |s: &str| -> Option<i32> {
+        match s {
+            "." | "" => Some(current),
+            "$" => Some(last),
+            _ => s.parse::<i32>().ok().map(|n| (n - 1).clamp(0, last)),
+        }
+    };
+    match range {
+        "" => Some((current, current)),
+        "%" => Some((0, last)),
+        "'<,'>" => VIM.with(|v| v.borrow().last_visual),
+        _ => match range.split_once(',') {
+            Some((a, b)) => Some((line(a)?, line(b)?)),
+            None => line(range).map(|l| (l, l)),
+        },
+    }
+}
+
+// Vim's magic patterns as Rust regex syntax: \( \) \| \{n,m} \+ \? and \< \> are
+// the special ones, bare ( ) | { } + ? match themselves. & and \1 in
+// replacements read as $0 and $1.
+fn convert_pattern(p: &str) -> String {
+    let mut out = String::new();
+    let mut chars = p.chars();
+    // inside \{n,m}, closed by a bare }
+    let mut braces = false;
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some(d @ ('(' | ')' | '|' | '+' | '?')) => out.push(d),
+                Some('{') => {
+                    out.push('{');
+                    braces = true;
+                }
+                Some('}') if braces => {
+                    out.push('}');
+                    braces = false;
+                }
+                Some('<' | '>') => out.push_str(r"\b"),
+                Some(d) => {
+                    out.push('\\');
+                    out.push(d);
+                }
+                None => out.push_str(r"\\"),
+            },
+            '}' if braces => {
+                out.push('}');
+                braces = false;
+            }
+            '(' | ')' | '|' | '{' | '}' | '+' | '?' => {
+                out.push('\\');
+                out.push(c);
+            }
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+fn convert_replacement(r: &str) -> String {
+    let mut out = String::new();
+    let mut chars = r.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '$' => out.push_str("$$"),
+            '&' => out.push_str("${0}"),
+            '\\' => match chars.next() {
+                Some(d) if d.is_ascii_digit() => out.push_str(&format!("${{{}}}", d)),
+                Some('n') => out.push('\n'),
+                Some('t') => out.push('\t'),
+                Some(o) => out.push(o),
+                None => out.push('\\'),
+            },
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+// Splits "/pat/rep/flags" on its (unescaped) separator
+fn split_substitute(s: &str) -> Option<(String, String, String)> {
+    let mut chars = s.chars();
+    let sep = chars.next()?;
+    let mut parts = vec![String::new()];
+    let mut escaped = false;
+    for c in chars {
+        if escaped {
+            if c != sep {
+                parts.last_mut()?.push('\\');
+            }
+            parts.last_mut()?.push(c);
+            escaped = false;
+        } else if c == '\\' {
+            escaped = true;
+        } else if c == sep && parts.len() < 3 {
+            parts.push(String::new());
+        } else {
+            parts.last_mut()?.push(c);
+        }
+    }
+    while parts.len() < 3 {
+        parts.push(String::new());
+    }
+    Some((parts[0].clone(), parts[1].clone(), parts[2].clone()))
+}
+
+fn substitute(ed: &mut text::TextEditor, range: &str, args: &str) {
+    let (pattern, replacement, flags) = match split_substitute(args) {
+        Some(p) => p,
+        None => return message("E486: Missing pattern"),
+    };
+    let pattern = if pattern.is_empty() {
+        match VIM.with(|v| v.borrow().last_search.clone()) {
+            Some((p, _)) => p,
+            None => return message("E35: No previous regular expression"),
+        }
+    } else {
+        convert_pattern(&pattern)
+    };
+    let pattern = if flags.contains('i') {
+        format!("(?i){}", pattern)
+    } else {
+        pattern
+    };
+    if let Err(e) = regex::Regex::new(&pattern) {
+        return invalid_pattern(&e);
+    }
+    let (first, last) = match ex_range(ed, range) {
+        Some(r) => r,
+        None => return message("E16: Invalid range"),
+    };
+    // the lines folded in a view are substituted too
+    let mut buf = match crate::folds::doc_buffer(ed) {
+        Some(b) => b,
+        None => return,
+    };
+    let start = line_offset(&buf, 0, first as i64);
+    let end = buf.line_end(line_offset(&buf, 0, last as i64));
+    let text = buf.text_range(start, end).unwrap_or_default();
+    match utils::replace_matches(
+        &text,
+        &pattern,
+        &convert_replacement(&replacement),
+        true,
+        !flags.contains('g'),
+    ) {
+        None => message("E486: Invalid pattern"),
+        Some((_, 0)) => message(&format!("E486: Pattern not found: {}", pattern)),
+        Some((new, _)) => {
+            buf.replace(start, end, &new);
+            let p = first_non_blank(&buf, start);
+            let (p, _) = crate::folds::reveal(ed, p, p);
+            ed.set_insert_position(p);
+            ed.do_callback();
+        }
+    }
+}
+
+fn current_doc() -> Option<(usize, bool)> {
+    STATE.with(|s| {
+        let id = s.current_id()?;
+        s.map.get(&id).map(|d| (id, d.modified))
+    })
+}
+
+fn run_ex(ed: &mut text::TextEditor, line: &str) {
+    // the range is what comes before the command name
+    let split = line
+        .find(|c: char| c.is_ascii_alphabetic() && c != '$')
+        .unwrap_or(line.len());
+    let (range, rest) = line.split_at(split);
+    let name_len = rest
+        .find(|c: char| !c.is_ascii_alphabetic())
+        .unwrap_or(rest.len());
+    let (name, args) = rest.split_at(name_len);
+    let (bang, args) = match args.strip_prefix('!') {
+        Some(a) => (true, a.trim()),
+        None => (false, args.trim()),
+    };
+    match name {
+        "" => {
+            if let Some((first, _)) = ex_range(ed, range) {
+                if let Some(buf) = crate::folds::doc_buffer(ed) {
+                    let p = first_non_blank(&buf, line_offset(&buf, 0, first as i64));
+                    let (p, _) = crate::folds::reveal(ed, p, p);
+                    ed.set_insert_position(p);
+                    ed.show_insert_position();
+                }
+            }
+        }
+        "s" | "substitute" => substitute(ed, range, args),
+        "w" | "write" => commands::run("file.save"),
+        "wa" | "wall" => commands::run("file.save_all"),
+        "q" | "quit" | "clo" | "close" => match current_doc() {
+            Some((_, true)) if !bang => {
+                message("E37: No write since last change (add ! to override)")
+            }
+            Some((id, _)) => cbs::discard_doc(id),
+            None => (),
+        },
+        "wq" | "x" | "xit" => {
+            if let Some((id, modified)) = current_doc() {
+                // :x only writes when there are changes
+                let write = modified || !matches!(name, "x" | "xit");
+                if write && !cbs::save_buffer(id) {
+                    return;
+                }
+                cbs::discard_doc(id);
+            }
+        }
+        "qa" | "qall" | "quita" | "quitall" => cbs::quit(),
+        "wqa" | "wqall" | "xa" | "xall" => {
+            cbs::save_all();
+            cbs::quit();
+        }
+        "e" | "edit" if !args.is_empty() => {
+            let path = ctx::root(ctx::active()).join(args);
+            crate::state::open(path);
+        }
+        "sp" | "split" => commands::run("view.split_down"),
+        "vs" | "vsplit" => commands::run("view.split_right"),
+        "noh" | "nohlsearch" => {
+            if let Some(mut buf) = ed.buffer() {
+                buf.unselect();
+            }
+        }
+        _ => message(&format!("E492: Not an editor command: {}", line)),
+    }
+}
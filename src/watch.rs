@@ -0,0 +1,169 @@
+// Watches the files of open buffers and reloads them when changed by other tools
+use crate::encoding::{self, Encoding, LineEnding};
+use crate::{dialogs, state::STATE};
+use fltk::{app, dialog, prelude::*, text};
+use notify::{event::EventKind, Event, RecommendedWatcher, RecursiveMode, Watcher};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::path::{Path, PathBuf};
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Mutex, OnceLock,
+};
+
+#[derive(Default)]
+struct WatchState {
+    watcher: Option<RecommendedWatcher>,
+    // Parent directories are watched (refcounted), so that atomic renames are caught too
+    dirs: HashMap<PathBuf, usize>,
+    // Changed paths, stored until handled on the main thread
+    pending: VecDeque<PathBuf>,
+}
+
+static WATCH_STATE: OnceLock<Mutex<WatchState>> = OnceLock::new();
+static PROMPTING: AtomicBool = AtomicBool::new(false);
+
+fn with_state<T, F: FnOnce(&mut WatchState) -> T>(f: F) -> Option<T> {
+    let m = WATCH_STATE.get_or_init(|| Mutex::new(WatchState::default()));
+    m.lock().ok().map(|mut s| f(&mut s))
+}
+
+fn on_event(res: Result<Event, notify::Error>) {
+    match res {
+        Ok(event) => {
+            if matches!(
+                event.kind,
+                EventKind::Modify(_) | EventKind::Create(_) | EventKind::Remove(_)
+            ) {
+                with_state(|s| s.pending.extend(event.paths));
+                app::awake();
+            }
+        }
+        Err(e) => eprintln!("{}", e),
+    }
+}
+
+// Call once at startup to poll for changes on the main thread
+pub fn init() {
+    fn schedule_poll() {
+        app::add_timeout3(0.3, |_| {
+            if !PROMPTING.load(Ordering::Relaxed) {
+                apply_pending();
+            }
+            schedule_poll();
+        });
+    }
+    schedule_poll();
+}
+
+pub fn watch(path: &Path) {
+    let dir = match path.parent() {
+        Some(d) => d.to_path_buf(),
+        None => return,
+    };
+    with_state(move |s| {
+        if s.watcher.is_none() {
+            s.watcher = notify::recommended_watcher(on_event).ok();
+        }
+        let count = s.dirs.entry(dir.clone()).or_insert(0);
+        *count += 1;
+        if *count == 1 {
+            if let Some(w) = s.watcher.as_mut() {
+                w.watch(&dir, RecursiveMode::NonRecursive).ok();
+            }
+        }
+    });
+}
+
+pub fn unwatch(path: &Path) {
+    let dir = match path.parent() {
+        Some(d) => d.to_path_buf(),
+        None => return,
+    };
+    with_state(move |s| {
+        if let Some(count) = s.dirs.get_mut(&dir) {
+            *count -= 1;
+            if *count == 0 {
+                s.dirs.remove(&dir);
+                if let Some(w) = s.watcher.as_mut() {
+                    w.unwatch(&dir).ok();
+                }
+            }
+        }
+    });
+}
+
+fn apply_pending() {
+    let items: Vec<PathBuf> = with_state(|s| s.pending.drain(..).collect()).unwrap_or_default();
+    let mut seen = HashSet::new();
+    for p in items {
+        if seen.insert(p.clone()) {
+            check_path(p);
+        }
+    }
+}
+
+fn check_path(path: PathBuf) {
+    let p = path.clone();
+    let found = STATE.with(move |s| {
+        s.map
+            .iter()
+            .find(|(_, v)| v.current_file.as_ref() == Some(&p))
+            .map(|(k, v)| (*k, v.modified, v.name(), v.buf.text()))
+    });
+    let (id, modified, name, text) = match found {
+        Some(v) => v,
+        None => return,
+    };
+    // Removed files keep their buffer, saving will recreate them
+    let (disk, enc, eol) = match encoding::read_file(&path) {
+        Ok(v) => v,
+        Err(_) => return,
+    };
+    if disk == text {
+        return;
+    }
+    if !modified {
+        reload(id, &disk, enc, eol);
+        return;
+    }
+    PROMPTING.store(true, Ordering::Relaxed);
+    let msg = format!(
+        "{} was changed on disk, but the editor has unsaved changes.",
+        name
+    );
+    match dialog::choice2_default(&msg, "Keep Mine", "Reload", "Show Diff") {
+        Some(1) => reload(id, &disk, enc, eol),
+        Some(2) if dialogs::diff_dialog(&format!("{} (editor vs disk)", name), &text, &disk) => {
+            reload(id, &disk, enc, eol)
+        }
+        _ => (),
+    }
+    PROMPTING.store(false, Ordering::Relaxed);
+}
+
+// Replaces the buffer contents keeping the caret and scroll position
+pub fn reload(id: usize, contents: &str, enc: Encoding, eol: LineEnding) {
+    if let Some(mut ed) = text::TextEditor::from_dyn_widget_ptr(id as *mut _) {
+        if let Some(mut buf) = ed.buffer() {
+            let pos = ed.insert_position();
+            let top = ed.get_absolute_top_line_number();
+            buf.set_text(contents);
+            ed.set_insert_position(pos.min(buf.length()));
+            ed.scroll(top.max(1), 0);
+        }
+    }
+    STATE.with(move |s| {
+        s.set_modified(id, false);
+        if let Some(mb) = s.map.get_mut(&id) {
+            mb.encoding = enc;
+            mb.line_ending = eol;
+            mb.version += 1;
+            #[cfg(feature = "lsp")]
+            if let Some(path) = mb.lsp_path() {
+                let text = mb.buf.text();
+                let version = mb.version;
+                crate::lsp::with_client(&path, |c| c.did_change_full(&path, &text, version));
+            }
+        }
+    });
+}